@@ -1,146 +1,672 @@
+use std::collections::HashMap;
 use std::fs;
-use std::io::{self, Read, Write};
-use std::path::Path;
-use tracing::info;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
 use anyhow::{bail, Context, Result};
 use flate2::read::GzDecoder;
+use minisign_verify::{PublicKey, Signature};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tar::Archive;
 
+use crate::config::load_global_config;
+use crate::images::{self, DEFAULT_IMAGE};
+
 const GITHUB_REPO: &str = "superhq-ai/shuru";
 pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-/// Check if OS image assets exist and match the expected version.
-pub fn assets_ready(data_dir: &str) -> bool {
-    let kernel = format!("{}/Image", data_dir);
-    let rootfs = format!("{}/rootfs.ext4", data_dir);
-    let initramfs = format!("{}/initramfs.cpio.gz", data_dir);
+/// minisign public key for release artifacts, in `minisign.pub` format.
+/// Its secret half is the release workflow's `MINISIGN_SECRET_KEY`, which
+/// signs every release's `SHA256SUMS`; the workflow checks the signature
+/// against this file before publishing. See "Release signing" in the README.
+const RELEASE_PUBLIC_KEY: &str = include_str!("../release-key.pub");
+
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// Attempts per download; each retry resumes where the previous one stopped.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Release channel followed by `shuru upgrade`.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Full releases only
+    Stable,
+    /// Full releases and pre-releases (vX.Y.Z-beta.N)
+    Beta,
+}
 
-    if !Path::new(&kernel).exists()
-        || !Path::new(&rootfs).exists()
-        || !Path::new(&initramfs).exists()
-    {
-        return false;
+impl Channel {
+    fn as_str(self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+        }
     }
+}
 
-    let version_file = format!("{}/VERSION", data_dir);
-    match fs::read_to_string(&version_file) {
-        Ok(v) => v.trim() == CURRENT_VERSION,
-        Err(_) => false,
-    }
+pub struct UpgradeOptions<'a> {
+    /// Channel to follow; falls back to the global config, then stable.
+    pub channel: Option<Channel>,
+    /// Exact version to install instead of the channel's latest.
+    pub version: Option<&'a str>,
+    /// Only report what would be installed.
+    pub check: bool,
 }
 
-/// Download and extract OS image assets from GitHub Releases.
-///
-/// Streams directly: HTTP → gzip decompress → tar extract → disk.
-/// No temp files needed.
+/// Environment variable overriding the release download base URL.
+pub const MIRROR_ENV: &str = "SHURU_MIRROR";
+
+/// Check if the default OS image for this version is installed.
+pub fn assets_ready(data_dir: &str) -> bool {
+    matches!(
+        images::find(data_dir, DEFAULT_IMAGE, Some(CURRENT_VERSION)),
+        Ok(Some(_))
+    )
+}
+
+/// Download and install the default OS image from GitHub Releases (or the
+/// configured mirror).
 pub fn download_os_image(data_dir: &str) -> Result<()> {
-    download_os_image_version(data_dir, CURRENT_VERSION)
+    download_image(data_dir, DEFAULT_IMAGE, CURRENT_VERSION)
 }
 
-fn download_os_image_version(data_dir: &str, version: &str) -> Result<()> {
+/// Download `name@version` into the image catalog.
+///
+/// The tarball is downloaded into `<data_dir>/cache`, resuming any partial
+/// download left by an earlier attempt, and kept there once it matches the
+/// signed checksum manifest so reinstalling does not fetch it again.
+pub(crate) fn download_image(data_dir: &str, name: &str, version: &str) -> Result<()> {
     let tag = format!("v{}", version);
-    let tarball_name = format!("shuru-os-{}-aarch64.tar.gz", tag);
-    let url = format!(
-        "https://github.com/{}/releases/download/{}/{}",
-        GITHUB_REPO, tag, tarball_name
-    );
+    let tarball_name = release_tarball_name(name, version)?;
+
+    let checksums = fetch_checksums(&tag)?;
+    let expected = expected_checksum(&checksums, &tarball_name)?;
+
+    let cached = Path::new(&cache_dir(data_dir)).join(&tarball_name);
+    if cached.exists() && file_sha256(&cached)? == expected {
+        info!("shuru: using cached {}", tarball_name);
+    } else {
+        let url = release_url(&tag, &tarball_name)?;
+        info!("shuru: downloading OS image {}@{}...", name, version);
+        info!("shuru: {}", url);
+        download_verified(&url, &cached, expected)
+            .with_context(|| format!("download failed — is version {} released?", tag))?;
+    }
 
-    fs::create_dir_all(data_dir)
-        .with_context(|| format!("failed to create data directory: {}", data_dir))?;
+    install_os_image(data_dir, name, version, &cached)
+}
 
-    info!("shuru: downloading OS image ({})...", tag);
-    info!("shuru: {}", url);
+/// Delete the cached release tarball for `name@version`, if any.
+pub(crate) fn remove_cached(data_dir: &str, name: &str, version: &str) {
+    let cached = Path::new(&cache_dir(data_dir)).join(os_tarball_name(name, version));
+    let _ = fs::remove_file(partial_path(&cached));
+    let _ = fs::remove_file(cached);
+}
 
-    let response = ureq::get(&url)
-        .call()
-        .with_context(|| format!("download failed — is version {} released?", tag))?;
+fn cache_dir(data_dir: &str) -> String {
+    format!("{}/cache", data_dir)
+}
 
-    let total_bytes = response
-        .headers()
-        .get("content-length")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse::<u64>().ok());
+/// Install an OS image from a local tarball, e.g. one fetched earlier with
+/// `shuru image fetch`. The `SHA256SUMS` and `SHA256SUMS.minisig` files
+/// must sit next to the tarball.
+pub fn install_os_image_from(data_dir: &str, tarball: &str) -> Result<()> {
+    let path = Path::new(tarball);
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("invalid tarball path: {}", tarball))?;
+    let (name, version) = parse_tarball_name(file_name).with_context(|| {
+        format!(
+            "unexpected tarball name '{}' (expected shuru-os-[NAME-]vX.Y.Z-aarch64.tar.gz)",
+            file_name
+        )
+    })?;
+    if name == DEFAULT_IMAGE && version != CURRENT_VERSION {
+        bail!(
+            "{} is for version {}, but this shuru expects {}",
+            file_name,
+            version,
+            CURRENT_VERSION
+        );
+    }
 
-    let reader = ProgressReader::new(response.into_body().into_reader(), total_bytes);
-    let decoder = GzDecoder::new(reader);
-    let mut archive = Archive::new(decoder);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let sums_path = dir.join(CHECKSUMS_FILE);
+    let sig_path = dir.join(format!("{}.minisig", CHECKSUMS_FILE));
+    let sums = fs::read(&sums_path).with_context(|| {
+        format!(
+            "failed to read {} (fetch it alongside the tarball with `shuru image fetch`)",
+            sums_path.display()
+        )
+    })?;
+    let signature = fs::read_to_string(&sig_path)
+        .with_context(|| format!("failed to read {}", sig_path.display()))?;
+    let checksums = verify_checksums(&sums, &signature)?;
+    let expected = expected_checksum(&checksums, file_name)?;
+
+    info!("shuru: installing OS image from {}", tarball);
+    let actual = file_sha256(path)?;
+    if actual != expected {
+        bail!(
+            "checksum mismatch for {} (expected {}, got {})",
+            file_name,
+            expected,
+            actual
+        );
+    }
+    info!("shuru: checksum verified");
+    install_os_image(data_dir, name, version, path)
+}
 
-    archive
-        .unpack(data_dir)
-        .context("failed to extract OS image")?;
+/// Download an OS image tarball plus its signed checksum manifest into
+/// `output_dir` for later offline installation with `shuru init --from`.
+pub fn fetch_os_image(output_dir: &str, name: &str, version: Option<&str>) -> Result<String> {
+    let version = version.unwrap_or(CURRENT_VERSION);
+    let tag = format!("v{}", version);
+    let tarball_name = release_tarball_name(name, version)?;
+
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("failed to create output directory: {}", output_dir))?;
+
+    let (sums, signature) = fetch_checksum_files(&tag)?;
+    let checksums = verify_checksums(&sums, &signature)?;
+    let expected = expected_checksum(&checksums, &tarball_name)?;
+
+    let dest = Path::new(output_dir).join(&tarball_name);
+    if dest.exists() && file_sha256(&dest)? == expected {
+        info!("shuru: {} already downloaded", tarball_name);
+    } else {
+        let url = release_url(&tag, &tarball_name)?;
+        info!("shuru: downloading OS image {}@{}...", name, version);
+        info!("shuru: {}", url);
+        download_verified(&url, &dest, expected)
+            .with_context(|| format!("download failed — is version {} released?", tag))?;
+    }
+    fs::write(Path::new(output_dir).join(CHECKSUMS_FILE), &sums)?;
+    fs::write(
+        Path::new(output_dir).join(format!("{}.minisig", CHECKSUMS_FILE)),
+        &signature,
+    )?;
 
-    // Write VERSION file
-    let version_file = format!("{}/VERSION", data_dir);
-    fs::write(&version_file, format!("{}\n", version)).context("failed to write VERSION file")?;
+    Ok(dest.to_string_lossy().into_owned())
+}
 
-    info!("shuru: OS image ready ({})", version);
-    Ok(())
+/// Release asset name for an image. The default image keeps the original
+/// `shuru-os-vX.Y.Z-aarch64.tar.gz` name; others are
+/// `shuru-os-<name>-vX.Y.Z-aarch64.tar.gz`.
+fn os_tarball_name(name: &str, version: &str) -> String {
+    if name == DEFAULT_IMAGE {
+        format!("shuru-os-v{}-aarch64.tar.gz", version)
+    } else {
+        format!("shuru-os-{}-v{}-aarch64.tar.gz", name, version)
+    }
 }
 
-#[derive(Deserialize)]
-struct GithubRelease {
-    tag_name: String,
+/// Release asset name for an image that releases publish. Only the default
+/// image is built by the release workflow; others are built locally with
+/// the scripts in `scripts/`.
+fn release_tarball_name(name: &str, version: &str) -> Result<String> {
+    if name != DEFAULT_IMAGE {
+        bail!(
+            "image '{}' is not published with shuru releases (only '{}' is); \
+             build it locally and use {}@local",
+            name,
+            DEFAULT_IMAGE,
+            name
+        );
+    }
+    Ok(os_tarball_name(name, version))
 }
 
-/// Check for a newer release and upgrade the CLI binary + OS image.
-pub fn upgrade(data_dir: &str) -> Result<()> {
-    info!("shuru: checking for updates...");
+fn parse_tarball_name(file_name: &str) -> Option<(&str, &str)> {
+    let rest = file_name
+        .strip_prefix("shuru-os-")?
+        .strip_suffix("-aarch64.tar.gz")?;
+    let (name, version) = match rest.strip_prefix('v') {
+        Some(version) => (DEFAULT_IMAGE, version),
+        None => rest.rsplit_once("-v")?,
+    };
+    images::parse_ref(&format!("{}@{}", name, version)).ok()?;
+    Some((name, version))
+}
 
-    let api_url = format!(
-        "https://api.github.com/repos/{}/releases/latest",
-        GITHUB_REPO
+/// Extract a verified OS image tarball into a staging directory, then move
+/// it into the image catalog.
+fn install_os_image(data_dir: &str, name: &str, version: &str, tarball: &Path) -> Result<()> {
+    let staging_dir = format!(
+        "{}/.staging-{}@{}",
+        images::images_dir(data_dir),
+        name,
+        version
     );
+    let _ = fs::remove_dir_all(&staging_dir);
+    fs::create_dir_all(&staging_dir)
+        .with_context(|| format!("failed to create staging directory: {}", staging_dir))?;
+
+    info!("shuru: extracting OS image...");
+    let file =
+        fs::File::open(tarball).with_context(|| format!("failed to open {}", tarball.display()))?;
+    let result = Archive::new(GzDecoder::new(file))
+        .unpack(&staging_dir)
+        .context("failed to extract OS image")
+        .and_then(|()| images::install_staged(data_dir, Path::new(&staging_dir), name, version));
+    match result {
+        Ok(image) => {
+            info!("shuru: OS image ready ({})", image.reference());
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging_dir);
+            Err(e)
+        }
+    }
+}
+
+/// Download `url` to `dest` via `<dest>.part`, resuming a partial file left
+/// by an earlier attempt and retrying interrupted transfers. `dest` only
+/// appears once the whole file hashes to `expected`.
+fn download_verified(url: &str, dest: &Path, expected: &str) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let part = partial_path(dest);
+
+    let mut attempt = 1;
+    while let Err(e) = download_resume(url, &part) {
+        if attempt >= DOWNLOAD_ATTEMPTS {
+            return Err(e.context("download interrupted (run the command again to resume)"));
+        }
+        info!("shuru: download interrupted ({:#}), resuming...", e);
+        std::thread::sleep(Duration::from_secs(2 * attempt as u64));
+        attempt += 1;
+    }
+
+    let actual = file_sha256(&part)?;
+    if actual != expected {
+        let _ = fs::remove_file(&part);
+        bail!(
+            "checksum mismatch for {} (expected {}, got {}); download discarded",
+            dest.file_name().unwrap_or_default().to_string_lossy(),
+            expected,
+            actual
+        );
+    }
+    info!("shuru: checksum verified");
+    fs::rename(&part, dest).with_context(|| format!("failed to save {}", dest.display()))?;
+    Ok(())
+}
+
+/// Append the rest of `url` to `part`, starting from its current length.
+fn download_resume(url: &str, part: &Path) -> Result<()> {
+    let offset = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let (body, total_bytes, start) = open_url_at(url, offset)?;
+
+    let mut file = if start > 0 {
+        info!("shuru: resuming at {} MB", start / (1024 * 1024));
+        fs::OpenOptions::new().append(true).open(part)?
+    } else {
+        fs::File::create(part).with_context(|| format!("failed to create {}", part.display()))?
+    };
+    let mut reader = ProgressReader::new(body, total_bytes).starting_at(start);
+    let result = io::copy(&mut reader, &mut file);
+    eprintln!();
+    result?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn partial_path(dest: &Path) -> PathBuf {
+    let mut name = dest.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let file =
+        fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut reader = HashingReader::new(file);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.hex_digest())
+}
+
+/// Base URL release artifacts are fetched from: `$SHURU_MIRROR`, then the
+/// `mirror` key of the global config, then GitHub Releases. A mirror must
+/// serve `<base>/<tag>/<file>`, like GitHub's download URLs.
+fn release_base() -> Result<Option<String>> {
+    if let Ok(mirror) = std::env::var(MIRROR_ENV) {
+        if !mirror.is_empty() {
+            return Ok(Some(mirror));
+        }
+    }
+    Ok(load_global_config()?.mirror)
+}
+
+fn release_url(tag: &str, file: &str) -> Result<String> {
+    Ok(match release_base()? {
+        Some(base) => format!("{}/{}/{}", base.trim_end_matches('/'), tag, file),
+        None => format!(
+            "https://github.com/{}/releases/download/{}/{}",
+            GITHUB_REPO, tag, file
+        ),
+    })
+}
+
+/// Open an `http(s)://` or `file://` URL for reading. Returns the body and
+/// its length when known.
+fn open_url(url: &str) -> Result<(Box<dyn Read + Send>, Option<u64>)> {
+    let (body, total_bytes, _) = open_url_at(url, 0)?;
+    Ok((body, total_bytes))
+}
+
+/// Like `open_url`, but asks for the body from byte `offset` on (an HTTP
+/// Range request). Returns the body, the full length when known, and the
+/// offset the body actually starts at: 0 if the server ignored the range.
+fn open_url_at(url: &str, offset: u64) -> Result<(Box<dyn Read + Send>, Option<u64>, u64)> {
+    if let Some(path) = url.strip_prefix("file://") {
+        let mut file = fs::File::open(path).with_context(|| format!("failed to open {}", path))?;
+        let len = file.metadata()?.len();
+        let start = offset.min(len);
+        file.seek(SeekFrom::Start(start))?;
+        return Ok((Box::new(file), Some(len), start));
+    }
+
+    let mut request = ureq::get(url);
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={}-", offset));
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        // The partial file already holds the whole body.
+        Err(ureq::Error::StatusCode(416)) if offset > 0 => {
+            return Ok((Box::new(io::empty()), Some(offset), offset));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let content_length = header("content-length").and_then(|v| v.parse::<u64>().ok());
+
+    if response.status().as_u16() == 206 {
+        // Content-Range: bytes <start>-<end>/<total>
+        let range = header("content-range").unwrap_or_default();
+        let (span, total) = range
+            .strip_prefix("bytes ")
+            .and_then(|r| r.split_once('/'))
+            .with_context(|| format!("invalid Content-Range '{}'", range))?;
+        let start = span.split('-').next().and_then(|v| v.parse::<u64>().ok());
+        if start != Some(offset) {
+            bail!("server resumed at the wrong offset ({})", range);
+        }
+        let total = total
+            .parse::<u64>()
+            .ok()
+            .or(content_length.map(|l| l + offset));
+        return Ok((Box::new(response.into_body().into_reader()), total, offset));
+    }
+
+    Ok((
+        Box::new(response.into_body().into_reader()),
+        content_length,
+        0,
+    ))
+}
+
+fn fetch_bytes(url: &str) -> Result<Vec<u8>> {
+    let (mut body, _) = open_url(url)?;
+    let mut buf = Vec::new();
+    body.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Fetch a release's `SHA256SUMS` manifest and verify its minisign signature
+/// against the embedded release key. Returns file name → hex digest.
+fn fetch_checksums(tag: &str) -> Result<HashMap<String, String>> {
+    let (sums, signature) = fetch_checksum_files(tag)?;
+    verify_checksums(&sums, &signature)
+}
+
+/// Download the raw `SHA256SUMS` manifest and its `.minisig` signature.
+fn fetch_checksum_files(tag: &str) -> Result<(Vec<u8>, String)> {
+    let sums_url = release_url(tag, CHECKSUMS_FILE)?;
+    let sig_url = release_url(tag, &format!("{}.minisig", CHECKSUMS_FILE))?;
+
+    let sums = match fetch_bytes(&sums_url) {
+        Ok(sums) => sums,
+        Err(e) if is_not_found(&e) => bail!(
+            "release {} is unsigned: it has no {} manifest, so it cannot be verified \
+             (releases published before signing was introduced cannot be installed \
+             by this version of shuru)",
+            tag,
+            CHECKSUMS_FILE
+        ),
+        Err(e) => {
+            return Err(e.context(format!("failed to download checksum manifest for {}", tag)))
+        }
+    };
+    let signature = fetch_bytes(&sig_url)
+        .with_context(|| format!("failed to download checksum signature for {}", tag))?;
+    let signature = String::from_utf8(signature).context("checksum signature is not UTF-8")?;
+    Ok((sums, signature))
+}
+
+/// Whether fetching a release file failed because it does not exist.
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<ureq::Error>(),
+            Some(ureq::Error::StatusCode(404))
+        ) || cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+    })
+}
+
+fn verify_checksums(sums: &[u8], signature: &str) -> Result<HashMap<String, String>> {
+    verify_checksums_with(RELEASE_PUBLIC_KEY, sums, signature)
+}
+
+/// Check `signature` over the `SHA256SUMS` manifest `sums` against
+/// `public_key` (the contents of a `minisign.pub` file), then parse the
+/// manifest into file name -> hex digest.
+fn verify_checksums_with(
+    public_key: &str,
+    sums: &[u8],
+    signature: &str,
+) -> Result<HashMap<String, String>> {
+    let public_key = PublicKey::decode(public_key).context("invalid release public key")?;
+    let signature =
+        Signature::decode(signature).context("malformed checksum manifest signature")?;
+    public_key
+        .verify(sums, &signature, false)
+        .context("checksum manifest signature verification failed")?;
+
+    let text = std::str::from_utf8(sums).context("checksum manifest is not UTF-8")?;
+    let mut checksums = HashMap::new();
+    for line in text.lines() {
+        // `sha256sum` format: "<hex>  <name>" (binary mode uses "<hex> *<name>")
+        let Some((digest, name)) = line.split_once(char::is_whitespace) else {
+            continue;
+        };
+        let name = name.trim_start().trim_start_matches('*');
+        checksums.insert(name.to_string(), digest.to_ascii_lowercase());
+    }
+    Ok(checksums)
+}
+
+fn expected_checksum<'a>(checksums: &'a HashMap<String, String>, file: &str) -> Result<&'a str> {
+    checksums
+        .get(file)
+        .map(|s| s.as_str())
+        .with_context(|| format!("{} is not listed in the release checksum manifest", file))
+}
 
-    let response = ureq::get(&api_url)
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "shuru")
-        .call()
-        .context("failed to check for updates")?;
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
+}
 
-    let release: GithubRelease = response
-        .into_body()
-        .read_json()
+/// Latest released version on `channel`: `<mirror>/LATEST` (or
+/// `LATEST-beta`) when a mirror is configured, otherwise the GitHub
+/// releases API.
+fn latest_version(channel: Channel) -> Result<String> {
+    let version = if let Some(base) = release_base()? {
+        let file = match channel {
+            Channel::Stable => "LATEST",
+            Channel::Beta => "LATEST-beta",
+        };
+        let url = format!("{}/{}", base.trim_end_matches('/'), file);
+        let latest = fetch_bytes(&url).with_context(|| format!("failed to read {}", url))?;
+        let latest = String::from_utf8_lossy(&latest).trim().to_string();
+        if latest.is_empty() {
+            bail!("no {} release found ({} is empty)", channel.as_str(), url);
+        }
+        latest
+    } else {
+        // releases/latest skips pre-releases; the full list (newest first)
+        // includes them.
+        let api_url = match channel {
+            Channel::Stable => format!(
+                "https://api.github.com/repos/{}/releases/latest",
+                GITHUB_REPO
+            ),
+            Channel::Beta => format!(
+                "https://api.github.com/repos/{}/releases?per_page=20",
+                GITHUB_REPO
+            ),
+        };
+
+        let response = ureq::get(&api_url)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "shuru")
+            .call()
+            .context("failed to check for updates")?;
+
+        let mut body = response.into_body();
+        let release = match channel {
+            Channel::Stable => body.read_json::<GithubRelease>().map(Some),
+            Channel::Beta => body
+                .read_json::<Vec<GithubRelease>>()
+                .map(|releases| releases.into_iter().find(|r| !r.draft)),
+        }
         .context("failed to parse release info")?;
+        match release {
+            Some(release) => release.tag_name,
+            None => bail!("no {} release found", channel.as_str()),
+        }
+    };
 
-    let latest = release
-        .tag_name
-        .strip_prefix('v')
-        .unwrap_or(&release.tag_name);
+    Ok(version.strip_prefix('v').unwrap_or(&version).to_string())
+}
 
-    if latest == CURRENT_VERSION {
-        info!("shuru: already on latest version ({})", CURRENT_VERSION);
+/// Upgrade (or, with a pinned version, move) the CLI binary and default OS
+/// image to another release. The replaced binary and its OS image are kept
+/// so `shuru upgrade --rollback` can restore them.
+pub fn upgrade(data_dir: &str, opts: &UpgradeOptions) -> Result<()> {
+    let target = match opts.version {
+        Some(v) => {
+            let v = v.strip_prefix('v').unwrap_or(v);
+            if v.is_empty()
+                || !v
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+            {
+                bail!("invalid version '{}'", v);
+            }
+            v.to_string()
+        }
+        None => {
+            let channel = match opts.channel {
+                Some(channel) => channel,
+                None => load_global_config()?.channel.unwrap_or(Channel::Stable),
+            };
+            info!(
+                "shuru: checking for updates ({} channel)...",
+                channel.as_str()
+            );
+            latest_version(channel)?
+        }
+    };
+    let target = target.as_str();
+
+    if opts.check {
+        if target == CURRENT_VERSION {
+            println!("shuru {} is up to date", CURRENT_VERSION);
+        } else {
+            println!(
+                "shuru {} is available (current: {})",
+                target, CURRENT_VERSION
+            );
+        }
+        return Ok(());
+    }
+
+    if target == CURRENT_VERSION {
+        info!("shuru: already on version {}", CURRENT_VERSION);
         return Ok(());
     }
 
-    info!("shuru: upgrading {} -> {}", CURRENT_VERSION, latest);
+    info!("shuru: upgrading {} -> {}", CURRENT_VERSION, target);
+
+    // Fetch the OS image first: it installs next to the current one, so a
+    // failure here leaves the working setup untouched.
+    if images::find(data_dir, DEFAULT_IMAGE, Some(target))?.is_none() {
+        download_image(data_dir, DEFAULT_IMAGE, target)?;
+    }
 
     // Update CLI binary
-    let cli_tarball = format!("shuru-v{}-darwin-aarch64.tar.gz", latest);
-    let cli_url = format!(
-        "https://github.com/{}/releases/download/v{}/{}",
-        GITHUB_REPO, latest, cli_tarball
+    let cli_tarball = format!(
+        "shuru-v{}-{}-{}.tar.gz",
+        target,
+        cli_platform(),
+        std::env::consts::ARCH
     );
+    let cli_url = release_url(&format!("v{}", target), &cli_tarball)?;
 
     let current_exe = std::env::current_exe().context("failed to determine current binary path")?;
 
-    info!("shuru: downloading CLI ({})...", latest);
+    info!("shuru: downloading CLI ({})...", target);
     info!("shuru: {}", cli_url);
 
-    let response = ureq::get(&cli_url)
-        .call()
-        .with_context(|| format!("failed to download CLI v{}", latest))?;
-
-    let total_bytes = response
-        .headers()
-        .get("content-length")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.parse::<u64>().ok());
+    // The CLI tarball is small: buffer it so it can be verified before
+    // anything is extracted.
+    let checksums = fetch_checksums(&format!("v{}", target))?;
+    let expected = expected_checksum(&checksums, &cli_tarball)?;
+
+    let (body, total_bytes) =
+        open_url(&cli_url).with_context(|| format!("failed to download CLI v{}", target))?;
+
+    let mut reader = HashingReader::new(ProgressReader::new(body, total_bytes));
+    let mut tarball = Vec::new();
+    reader
+        .read_to_end(&mut tarball)
+        .with_context(|| format!("failed to download CLI v{}", target))?;
+    eprintln!();
+    let actual = reader.hex_digest();
+    if actual != expected {
+        bail!(
+            "checksum mismatch for {} (expected {}, got {}); upgrade aborted",
+            cli_tarball,
+            expected,
+            actual
+        );
+    }
+    info!("shuru: checksum verified");
 
-    let reader = ProgressReader::new(response.into_body().into_reader(), total_bytes);
-    let decoder = GzDecoder::new(reader);
-    let mut archive = Archive::new(decoder);
+    let mut archive = Archive::new(GzDecoder::new(tarball.as_slice()));
 
     // Extract to a temp file next to the current binary
     let tmp_path = current_exe.with_extension("new");
@@ -157,39 +683,138 @@ pub fn upgrade(data_dir: &str) -> Result<()> {
         bail!("'shuru' binary not found in CLI archive");
     }
 
+    // Keep the running binary for --rollback (one level deep).
+    let rollback_dir = rollback_dir(data_dir);
+    let _ = fs::remove_dir_all(&rollback_dir);
+    fs::create_dir_all(&rollback_dir)?;
+    fs::copy(&current_exe, Path::new(&rollback_dir).join("shuru"))
+        .context("failed to keep a copy of the current binary")?;
+    fs::write(
+        Path::new(&rollback_dir).join("VERSION"),
+        format!("{}\n", CURRENT_VERSION),
+    )?;
+
+    replace_binary(&current_exe, &tmp_path)?;
+    info!("shuru: CLI updated to {}", target);
+
+    // Keep only the new and the previous default image.
+    for image in images::list(data_dir)? {
+        let version = image.manifest.version.as_str();
+        if image.manifest.name == DEFAULT_IMAGE
+            && version != target
+            && version != CURRENT_VERSION
+            && version != "local"
+        {
+            images::remove(data_dir, &image.reference())?;
+        }
+    }
+
+    info!("shuru: upgrade complete ({})", target);
+    info!(
+        "shuru: run `shuru upgrade --rollback` to return to {}",
+        CURRENT_VERSION
+    );
+    Ok(())
+}
+
+/// Restore the CLI binary replaced by the last upgrade. Its OS image was
+/// kept in the catalog, so it boots exactly as before.
+pub fn rollback(data_dir: &str) -> Result<()> {
+    let rollback_dir = rollback_dir(data_dir);
+    let version = match fs::read_to_string(Path::new(&rollback_dir).join("VERSION")) {
+        Ok(v) => v.trim().to_string(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            bail!("nothing to roll back to (no previous upgrade recorded)")
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if images::find(data_dir, DEFAULT_IMAGE, Some(&version))?.is_none() {
+        warn!(
+            "shuru: OS image {}@{} is no longer installed and will be downloaded on next run",
+            DEFAULT_IMAGE, version
+        );
+    }
+
+    let current_exe = std::env::current_exe().context("failed to determine current binary path")?;
+    let tmp_path = current_exe.with_extension("new");
+    fs::copy(Path::new(&rollback_dir).join("shuru"), &tmp_path)
+        .context("failed to stage previous binary")?;
+    replace_binary(&current_exe, &tmp_path)?;
+    let _ = fs::remove_dir_all(&rollback_dir);
+
+    info!("shuru: rolled back {} -> {}", CURRENT_VERSION, version);
+    Ok(())
+}
+
+/// Platform part of the CLI tarball name (`darwin` or `linux`).
+fn cli_platform() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    }
+}
+
+fn rollback_dir(data_dir: &str) -> String {
+    format!("{}/rollback", data_dir)
+}
+
+/// Swap `new_binary` in for `current_exe`.
+fn replace_binary(current_exe: &Path, new_binary: &Path) -> Result<()> {
     // Set executable permission
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o755))?;
+        fs::set_permissions(new_binary, fs::Permissions::from_mode(0o755))?;
     }
 
     // Atomic-ish replace: rename current -> .old, rename new -> current, remove .old
     let old_path = current_exe.with_extension("old");
     let _ = fs::remove_file(&old_path);
-    fs::rename(&current_exe, &old_path)
-        .context("failed to move current binary (try with sudo?)")?;
-    if let Err(e) = fs::rename(&tmp_path, &current_exe) {
+    fs::rename(current_exe, &old_path).context("failed to move current binary (try with sudo?)")?;
+    if let Err(e) = fs::rename(new_binary, current_exe) {
         // Rollback
-        let _ = fs::rename(&old_path, &current_exe);
+        let _ = fs::rename(&old_path, current_exe);
         return Err(e).context("failed to install new binary");
     }
     let _ = fs::remove_file(&old_path);
+    Ok(())
+}
 
-    info!("shuru: CLI updated to {}", latest);
+/// Wraps a reader to compute the SHA-256 of everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
 
-    // Update OS image
-    download_os_image_version(data_dir, latest)?;
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
 
-    info!("shuru: upgrade complete ({})", latest);
-    Ok(())
+    fn hex_digest(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
 
 /// Wraps a reader to print download progress to stderr.
 struct ProgressReader<R> {
     inner: R,
     bytes_read: u64,
+    start_bytes: u64,
     total_bytes: Option<u64>,
+    started: Instant,
     last_printed_mb: u64,
 }
 
@@ -198,10 +823,39 @@ impl<R> ProgressReader<R> {
         Self {
             inner,
             bytes_read: 0,
+            start_bytes: 0,
             total_bytes,
+            started: Instant::now(),
             last_printed_mb: u64::MAX, // force first print
         }
     }
+
+    /// Count `offset` bytes as already downloaded (when resuming).
+    fn starting_at(mut self, offset: u64) -> Self {
+        self.bytes_read = offset;
+        self.start_bytes = offset;
+        self
+    }
+
+    /// Average speed of this transfer in bytes per second.
+    fn speed(&self) -> f64 {
+        let secs = self.started.elapsed().as_secs_f64();
+        if secs < 0.5 {
+            return 0.0;
+        }
+        (self.bytes_read - self.start_bytes) as f64 / secs
+    }
+}
+
+/// Format a duration in seconds as e.g. `45s`, `3m 07s` or `1h 02m`.
+fn format_eta(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
 }
 
 impl<R: Read> Read for ProgressReader<R> {
@@ -212,16 +866,36 @@ impl<R: Read> Read for ProgressReader<R> {
         let current_mb = self.bytes_read / (1024 * 1024);
         if current_mb != self.last_printed_mb {
             self.last_printed_mb = current_mb;
+            let speed = self.speed();
+            let rate = if speed > 0.0 {
+                format!(" ({:.1} MB/s", speed / (1024.0 * 1024.0))
+            } else {
+                String::new()
+            };
+
             let mut stderr = io::stderr().lock();
             if let Some(total) = self.total_bytes {
                 let total_mb = total / (1024 * 1024);
+                let eta = if speed > 0.0 {
+                    let remaining = total.saturating_sub(self.bytes_read) as f64 / speed;
+                    format!(", ETA {})", format_eta(remaining as u64))
+                } else if !rate.is_empty() {
+                    ")".to_string()
+                } else {
+                    String::new()
+                };
                 let _ = write!(
                     stderr,
-                    "\rshuru: downloaded {} / {} MB",
-                    current_mb, total_mb
+                    "\rshuru: downloaded {} / {} MB{}{}    ",
+                    current_mb, total_mb, rate, eta
                 );
             } else {
-                let _ = write!(stderr, "\rshuru: downloaded {} MB", current_mb);
+                let close = if rate.is_empty() { "" } else { ")" };
+                let _ = write!(
+                    stderr,
+                    "\rshuru: downloaded {} MB{}{}    ",
+                    current_mb, rate, close
+                );
             }
             let _ = stderr.flush();
         }
@@ -229,3 +903,66 @@ impl<R: Read> Read for ProgressReader<R> {
         Ok(n)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Throwaway minisign key; the secret half was discarded after signing
+    // SUMS below.
+    const TEST_PUBLIC_KEY: &str = "untrusted comment: minisign public key 0807060504030201
+RWQBAgMEBQYHCFn+s5aI7tQtI8ojxcpiZjeY6wjM9WTwVaWUu2VBdnYZ
+";
+    const SUMS: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  shuru-os-v1.0.0-aarch64.tar.gz\n";
+    const SIGNATURE: &str = "untrusted comment: signature from test key
+RUQBAgMEBQYHCBgcOyTAcUaAmjqM7gazaZB2iuyAVMecfbnHhvkksvUOSMmgq05b4njEnAY1UG4PtRd3JjHvuGNQPw9PtxsnzgY=
+trusted comment: timestamp:1700000000\tfile:SHA256SUMS\thashed
+YPXMV8sYh9AUNNjEyIVGhX+Z9NZFEP9ZJ/SVbospvwgpkIQ28IpjPMJKmk/XwCx9LhhgPRUtp6LKGIDTBCTpAg==
+";
+
+    #[test]
+    fn accepts_signed_manifest() {
+        let checksums = verify_checksums_with(TEST_PUBLIC_KEY, SUMS.as_bytes(), SIGNATURE).unwrap();
+        assert_eq!(
+            expected_checksum(&checksums, "shuru-os-v1.0.0-aarch64.tar.gz").unwrap(),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert!(expected_checksum(&checksums, "other.tar.gz").is_err());
+    }
+
+    #[test]
+    fn only_the_default_image_is_released() {
+        assert_eq!(
+            release_tarball_name(DEFAULT_IMAGE, "1.0.0").unwrap(),
+            "shuru-os-v1.0.0-aarch64.tar.gz"
+        );
+        let err = release_tarball_name("debian-12", "1.0.0").unwrap_err();
+        assert!(err.to_string().contains("debian-12@local"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_tampered_manifest() {
+        let tampered = SUMS.replacen("9f86", "0f86", 1);
+        assert!(verify_checksums_with(TEST_PUBLIC_KEY, tampered.as_bytes(), SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let bad = SIGNATURE.replacen("RUQBAgMEBQYHCBgc", "RUQBAgMEBQYHCBgd", 1);
+        assert!(verify_checksums_with(TEST_PUBLIC_KEY, SUMS.as_bytes(), &bad).is_err());
+        // Signed with a different key than the embedded release key
+        assert!(verify_checksums(SUMS.as_bytes(), SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn release_key_is_valid() {
+        PublicKey::decode(RELEASE_PUBLIC_KEY).unwrap();
+    }
+
+    #[test]
+    fn missing_release_file_is_not_found() {
+        let e = fetch_bytes("file:///nonexistent/SHA256SUMS").unwrap_err();
+        assert!(is_not_found(&e));
+        assert!(!is_not_found(&anyhow::anyhow!("connection reset")));
+    }
+}
@@ -1,10 +1,18 @@
-use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
 use shuru_vm::default_data_dir;
 
 use crate::cli::VmArgs;
 use crate::config::load_config;
-use crate::vm;
+use crate::ext4::{Ext4Image, FileType, Inode};
+use crate::images;
+use crate::logs;
+use crate::oci;
+use crate::vm::{self, PreparedVm};
 
 pub(crate) fn create(
     name: String,
@@ -20,21 +28,73 @@ pub(crate) fn create(
         vec!["/bin/sh".to_string()]
     };
 
+    checkpoint_path(&default_data_dir(), &name)?;
+
     let prepared = vm::prepare_vm(vm_args, &cfg, from)?;
-    let exit_code = vm::run_command(&prepared, &command)?;
+    let exit_code = vm::run_command(&prepared, &command, None)?;
 
     // Save working copy as checkpoint
-    let checkpoints_dir = format!("{}/checkpoints", prepared.data_dir);
-    std::fs::create_dir_all(&checkpoints_dir)?;
-    let checkpoint_path = format!("{}/{}.ext4", checkpoints_dir, name);
     eprintln!("shuru: saving checkpoint '{}'...", name);
-    std::fs::copy(&prepared.work_rootfs, &checkpoint_path)?;
+    save(&prepared, &name)?;
     eprintln!("shuru: checkpoint '{}' saved", name);
 
-    let _ = std::fs::remove_dir_all(&prepared.instance_dir);
+    logs::remove_instance(&prepared.instance_dir);
     Ok(exit_code)
 }
 
+/// Path of checkpoint `name`, rejecting names that would escape the
+/// checkpoints directory.
+pub(crate) fn checkpoint_path(data_dir: &str, name: &str) -> Result<String> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        bail!("invalid checkpoint name: '{}'", name);
+    }
+    Ok(format!("{}/checkpoints/{}.ext4", data_dir, name))
+}
+
+/// Metadata kept next to checkpoint `NAME.ext4` as `NAME.json`.
+#[derive(Serialize, Deserialize)]
+struct CheckpointMeta {
+    /// Image rootfs the checkpoint descends from.
+    base_rootfs: String,
+}
+
+fn meta_path(data_dir: &str, name: &str) -> String {
+    format!("{}/checkpoints/{}.json", data_dir, name)
+}
+
+/// Copy a stopped VM's root disk to checkpoint `name`, recording the image
+/// it descends from.
+pub(crate) fn save(prepared: &PreparedVm, name: &str) -> Result<()> {
+    let data_dir = &prepared.data_dir;
+    let path = checkpoint_path(data_dir, name)?;
+    std::fs::create_dir_all(format!("{}/checkpoints", data_dir))?;
+    std::fs::copy(&prepared.work_rootfs, &path)?;
+
+    let meta_path = meta_path(data_dir, name);
+    match &prepared.base_rootfs {
+        Some(base) => {
+            let base_rootfs = std::fs::canonicalize(base)
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| base.clone());
+            let meta = serde_json::to_string(&CheckpointMeta { base_rootfs })?;
+            std::fs::write(&meta_path, meta)?;
+        }
+        // Don't leave the metadata of a checkpoint this one replaces
+        None => {
+            let _ = std::fs::remove_file(&meta_path);
+        }
+    }
+    Ok(())
+}
+
+/// Image rootfs checkpoint `name` descends from, if it was recorded.
+pub(crate) fn recorded_base(data_dir: &str, name: &str) -> Option<String> {
+    let meta = std::fs::read_to_string(meta_path(data_dir, name)).ok()?;
+    serde_json::from_str::<CheckpointMeta>(&meta)
+        .ok()
+        .map(|m| m.base_rootfs)
+}
+
 pub(crate) fn list() -> Result<()> {
     let data_dir = default_data_dir();
     let checkpoints_dir = format!("{}/checkpoints", data_dir);
@@ -98,11 +158,285 @@ pub(crate) fn list() -> Result<()> {
 
 pub(crate) fn delete(name: &str) -> Result<()> {
     let data_dir = default_data_dir();
-    let checkpoint_path = format!("{}/checkpoints/{}.ext4", data_dir, name);
+    let checkpoint_path = checkpoint_path(&data_dir, name)?;
     if !std::path::Path::new(&checkpoint_path).exists() {
         bail!("Checkpoint '{}' not found", name);
     }
     std::fs::remove_file(&checkpoint_path)?;
+    let _ = std::fs::remove_file(meta_path(&data_dir, name));
     eprintln!("shuru: checkpoint '{}' deleted", name);
     Ok(())
 }
+
+/// Metadata recorded for each path when diffing two images.
+struct DiffEntry {
+    inode: Inode,
+    link_target: Option<Vec<u8>>,
+}
+
+/// Compare two checkpoints (or the base rootfs and a checkpoint) and print
+/// added (A), modified (M) and deleted (D) paths.
+pub(crate) fn diff(
+    a: &str,
+    b: Option<&str>,
+    base_rootfs: Option<&str>,
+    base_image: Option<&str>,
+) -> Result<()> {
+    let data_dir = default_data_dir();
+    let (old_path, new_path) = match b {
+        Some(b) => (
+            checkpoint_image(&data_dir, a)?,
+            checkpoint_image(&data_dir, b)?,
+        ),
+        None => {
+            let base = match (base_rootfs, base_image) {
+                (Some(path), _) => path.to_string(),
+                (None, Some(spec)) => images::resolve(&data_dir, Some(spec))?.rootfs_path(),
+                (None, None) => {
+                    checkpoint_image(&data_dir, a)?;
+                    recorded_base(&data_dir, a).with_context(|| {
+                        format!(
+                            "checkpoint '{}' does not record the image it was created from; \
+                             pass --image or --rootfs",
+                            a
+                        )
+                    })?
+                }
+            };
+            if !std::path::Path::new(&base).exists() {
+                bail!(
+                    "Rootfs not found at {}. Run `shuru init` to download.",
+                    base
+                );
+            }
+            (base, checkpoint_image(&data_dir, a)?)
+        }
+    };
+
+    let old_image = Ext4Image::open(&old_path)?;
+    let new_image = Ext4Image::open(&new_path)?;
+    let old_tree = collect_tree(&old_image)?;
+    let new_tree = collect_tree(&new_image)?;
+
+    let mut stdout = std::io::stdout().lock();
+    let (mut added, mut modified, mut deleted) = (0u32, 0u32, 0u32);
+
+    for (path, old) in &old_tree {
+        match new_tree.get(path) {
+            None => {
+                print_diff_line(&mut stdout, 'D', path, &old.inode)?;
+                deleted += 1;
+            }
+            Some(new) => {
+                if entry_changed(&old_image, old, &new_image, new)? {
+                    print_diff_line(&mut stdout, 'M', path, &new.inode)?;
+                    modified += 1;
+                }
+            }
+        }
+    }
+    for (path, new) in &new_tree {
+        if !old_tree.contains_key(path) {
+            print_diff_line(&mut stdout, 'A', path, &new.inode)?;
+            added += 1;
+        }
+    }
+
+    eprintln!(
+        "shuru: {} added, {} modified, {} deleted",
+        added, modified, deleted
+    );
+    Ok(())
+}
+
+/// Write a single file from a checkpoint to stdout. `spec` is `NAME:/path`.
+pub(crate) fn cat(spec: &str) -> Result<()> {
+    let Some((name, path)) = spec.split_once(':') else {
+        bail!("expected NAME:/path (e.g. myenv:/etc/os-release)");
+    };
+    if !path.starts_with('/') {
+        bail!("path must be absolute (start with /): '{}'", path);
+    }
+
+    let data_dir = default_data_dir();
+    let image = Ext4Image::open(&checkpoint_image(&data_dir, name)?)?;
+    let inode = image.resolve(path)?;
+    if inode.file_type() != FileType::Regular {
+        bail!("{}: not a regular file", path);
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    std::io::copy(&mut image.reader(&inode)?, &mut stdout)
+        .with_context(|| format!("failed to read {}", path))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn checkpoint_image(data_dir: &str, name: &str) -> Result<String> {
+    let path = checkpoint_path(data_dir, name)?;
+    if !std::path::Path::new(&path).exists() {
+        bail!("Checkpoint '{}' not found", name);
+    }
+    Ok(path)
+}
+
+fn collect_tree(image: &Ext4Image) -> Result<BTreeMap<String, DiffEntry>> {
+    let mut tree = BTreeMap::new();
+    image.walk(|path, inode| {
+        if path == "lost+found" || path.starts_with("lost+found/") {
+            return Ok(());
+        }
+        let link_target = match inode.file_type() {
+            FileType::Symlink => Some(image.read_link(inode)?),
+            _ => None,
+        };
+        tree.insert(
+            format!("/{}", path),
+            DiffEntry {
+                inode: inode.clone(),
+                link_target,
+            },
+        );
+        Ok(())
+    })?;
+    Ok(tree)
+}
+
+fn entry_changed(
+    old_image: &Ext4Image,
+    old: &DiffEntry,
+    new_image: &Ext4Image,
+    new: &DiffEntry,
+) -> Result<bool> {
+    let (o, n) = (&old.inode, &new.inode);
+    if o.mode != n.mode || o.uid != n.uid || o.gid != n.gid {
+        return Ok(true);
+    }
+    match n.file_type() {
+        // Directory size/mtime change whenever an entry is added or removed;
+        // those changes are reported on the entries themselves.
+        FileType::Directory => Ok(false),
+        FileType::Symlink => Ok(old.link_target != new.link_target),
+        FileType::CharDevice | FileType::BlockDevice => Ok(o.device() != n.device()),
+        FileType::Regular => {
+            if o.size != n.size {
+                return Ok(true);
+            }
+            if o.mtime == n.mtime {
+                return Ok(false);
+            }
+            // Same size, different mtime: compare contents.
+            Ok(!same_contents(old_image, o, new_image, n)?)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn same_contents(a_image: &Ext4Image, a: &Inode, b_image: &Ext4Image, b: &Inode) -> Result<bool> {
+    let mut a_reader = a_image.reader(a)?;
+    let mut b_reader = b_image.reader(b)?;
+    let mut a_buf = vec![0u8; 64 * 1024];
+    let mut b_buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = read_full(&mut a_reader, &mut a_buf)?;
+        let m = read_full(&mut b_reader, &mut b_buf)?;
+        if n != m || a_buf[..n] != b_buf[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+fn print_diff_line(out: &mut impl Write, status: char, path: &str, inode: &Inode) -> Result<()> {
+    let size = match inode.file_type() {
+        FileType::Regular | FileType::Symlink => inode.size.to_string(),
+        _ => "-".to_string(),
+    };
+    writeln!(
+        out,
+        "{} {} {:>10} {}",
+        status,
+        format_mode(inode),
+        size,
+        path
+    )?;
+    Ok(())
+}
+
+/// `ls -l` style mode string, e.g. `drwxr-xr-x`.
+fn format_mode(inode: &Inode) -> String {
+    let kind = match inode.file_type() {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        FileType::Socket => 's',
+        FileType::Regular | FileType::Unknown => '-',
+    };
+    let perms = inode.permissions();
+    let mut s = String::with_capacity(10);
+    s.push(kind);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (perms >> shift) & 0o7;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 1 != 0, perms & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+pub(crate) fn export_oci(
+    name: &str,
+    output: Option<&str>,
+    tag: Option<&str>,
+    exclude_agent: bool,
+) -> Result<()> {
+    let data_dir = default_data_dir();
+    let checkpoint_path = checkpoint_image(&data_dir, name)?;
+
+    let output = output
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{}.oci.tar", name));
+    let tag = tag.unwrap_or("latest");
+
+    // Scratch space for the layer blob, apart from any VM this process runs;
+    // cleaned up by `shuru prune` if we crash.
+    let scratch_dir = format!("{}/instances/export-{}", data_dir, std::process::id());
+    std::fs::create_dir_all(&scratch_dir)?;
+
+    eprintln!("shuru: exporting checkpoint '{}' to {}...", name, output);
+    let opts = oci::ExportOptions {
+        tag,
+        exclude_agent,
+        created_by: format!("shuru checkpoint export-oci {}", name),
+    };
+    let result = oci::export(&checkpoint_path, &output, &scratch_dir, &opts);
+    let _ = std::fs::remove_dir_all(&scratch_dir);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&output);
+    }
+    result?;
+
+    eprintln!("shuru: checkpoint '{}' exported to {}", name, output);
+    Ok(())
+}
@@ -1,7 +1,10 @@
 use clap::Parser;
 use clap_verbosity_flag::Verbosity;
 
-#[derive(clap::Args)]
+use crate::assets::Channel;
+use crate::config::NetworkMode;
+
+#[derive(clap::Args, Clone)]
 pub(crate) struct VmArgs {
     /// Number of CPU cores
     #[arg(long)]
@@ -15,6 +18,10 @@ pub(crate) struct VmArgs {
     #[arg(long)]
     pub disk_size: Option<u64>,
 
+    /// OS image to boot (NAME[@VERSION], see `shuru image ls`)
+    #[arg(long, env = "SHURU_IMAGE")]
+    pub image: Option<String>,
+
     /// Path to kernel
     #[arg(long, env = "SHURU_KERNEL")]
     pub kernel: Option<String>,
@@ -27,14 +34,42 @@ pub(crate) struct VmArgs {
     #[arg(long, env = "SHURU_INITRD")]
     pub initrd: Option<String>,
 
+    /// Append an argument to the kernel command line (e.g. mitigations=off)
+    #[arg(long = "kernel-arg", value_name = "ARG")]
+    pub kernel_arg: Vec<String>,
+
+    /// Replace the base kernel command line (default: console=hvc0 root=/dev/vda rw)
+    #[arg(long, value_name = "CMDLINE")]
+    pub cmdline: Option<String>,
+
     /// Allow network access (NAT)
     #[arg(long)]
     pub allow_net: bool,
 
+    /// Allow network access only to a domain (and its subdomains) or an IPv4 network; everything else is blocked and logged
+    #[arg(long = "allow-host", value_name = "DOMAIN|CIDR")]
+    pub allow_host: Vec<String>,
+
+    /// Network access (default: filter with --allow-host, nat with --allow-net, else none)
+    #[arg(long, value_enum)]
+    pub network: Option<NetworkMode>,
+
+    /// Write each network request in proxy mode as a JSON line to FILE (default: the instance's egress.log)
+    #[arg(long, value_name = "FILE")]
+    pub egress_log: Option<String>,
+
+    /// Boot the image's init system (systemd/OpenRC) and run the agent as a service
+    #[arg(long)]
+    pub init_system: bool,
+
     /// Forward a host port to a guest port (HOST:GUEST, e.g. 8080:80)
     #[arg(short = 'p', long = "port", value_name = "HOST:GUEST")]
     pub port: Vec<String>,
 
+    /// Let the guest reach a host service: guest 127.0.0.1:GUEST_PORT connects to HOST_ADDR:PORT (e.g. 127.0.0.1:5432:5432)
+    #[arg(long = "expose", value_name = "HOST_ADDR:PORT:GUEST_PORT")]
+    pub expose: Vec<String>,
+
     /// Set environment variables (KEY=VALUE)
     #[arg(short = 'e', long = "env", value_name = "KEY=VALUE")]
     pub env: Vec<String>,
@@ -43,6 +78,14 @@ pub(crate) struct VmArgs {
     #[arg(long = "mount", value_name = "HOST:GUEST[:MODE]")]
     pub mount: Vec<String>,
 
+    /// Attach a volume from `shuru volume create` (NAME:GUEST[:ro|rw])
+    #[arg(long = "volume", value_name = "NAME:GUEST[:MODE]")]
+    pub volume: Vec<String>,
+
+    /// Write the serial console and guest agent logs to FILE (default: the instance's console.log)
+    #[arg(long, value_name = "FILE")]
+    pub console_log: Option<String>,
+
     /// Path to config file (default: ./shuru.json)
     #[arg(long)]
     pub config: Option<String>,
@@ -77,6 +120,18 @@ pub(crate) enum Commands {
         #[arg(long)]
         console: bool,
 
+        /// Run in a pre-booted VM from a `shuru pool` daemon
+        #[arg(long, conflicts_with_all = ["from", "console"])]
+        pool: bool,
+
+        /// Socket of the pool daemon (default: <data dir>/pool.sock)
+        #[arg(long, env = "SHURU_POOL_SOCKET", value_name = "PATH")]
+        pool_socket: Option<String>,
+
+        /// Write lifecycle events as JSON lines to FILE (default: stderr)
+        #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = "-")]
+        events: Option<String>,
+
         /// Command and arguments to run inside the VM
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         command: Vec<String>,
@@ -87,10 +142,38 @@ pub(crate) enum Commands {
         /// Force re-download even if assets exist
         #[arg(long)]
         force: bool,
+
+        /// Install from a local OS image tarball (with SHA256SUMS and
+        /// SHA256SUMS.minisig alongside) instead of downloading
+        #[arg(long, value_name = "TARBALL")]
+        from: Option<String>,
     },
 
     /// Upgrade shuru to the latest release (CLI + OS image)
-    Upgrade,
+    Upgrade {
+        /// Release channel to follow (default: stable, or "channel" in
+        /// ~/.config/shuru/config.json)
+        #[arg(long, value_enum)]
+        channel: Option<Channel>,
+
+        /// Install this exact version instead of the channel's latest
+        #[arg(long, conflicts_with = "channel")]
+        version: Option<String>,
+
+        /// Only report whether a newer version is available
+        #[arg(long)]
+        check: bool,
+
+        /// Restore the CLI and OS image from before the last upgrade
+        #[arg(long, conflicts_with_all = ["channel", "version", "check"])]
+        rollback: bool,
+    },
+
+    /// Manage OS images
+    Image {
+        #[command(subcommand)]
+        action: ImageCommands,
+    },
 
     /// Manage disk checkpoints
     Checkpoint {
@@ -98,10 +181,146 @@ pub(crate) enum Commands {
         action: CheckpointCommands,
     },
 
-    /// Remove leftover instance data from crashed VMs
+    /// Manage persistent volumes
+    Volume {
+        #[command(subcommand)]
+        action: VolumeCommands,
+    },
+
+    /// Keep booted VMs ready for `shuru run --pool`
+    Pool {
+        #[command(flatten)]
+        vm: VmArgs,
+
+        /// Boot pooled VMs from a named checkpoint instead of the base image
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Ready VMs to keep waiting
+        #[arg(long, default_value_t = 1)]
+        min_idle: usize,
+
+        /// Maximum VMs booting, ready and in use together
+        #[arg(long, default_value_t = 4)]
+        max: usize,
+
+        /// Stop ready VMs after this many seconds without a run
+        #[arg(long, value_name = "SECS")]
+        idle_timeout: Option<u64>,
+
+        /// Socket to listen on (default: <data dir>/pool.sock)
+        #[arg(long, env = "SHURU_POOL_SOCKET", value_name = "PATH")]
+        socket: Option<String>,
+    },
+
+    /// Serve sandbox tools to AI agents over MCP (stdio JSON-RPC)
+    Mcp {
+        #[command(flatten)]
+        vm: VmArgs,
+
+        /// Start from a named checkpoint instead of the base image
+        #[arg(long)]
+        from: Option<String>,
+    },
+
+    /// Serve an HTTP API for creating and driving sandboxes
+    Serve {
+        /// Defaults for every sandbox; create requests can override them
+        #[command(flatten)]
+        vm: VmArgs,
+
+        /// Unix socket to listen on (default: <data dir>/serve.sock)
+        #[arg(long, env = "SHURU_SERVE_SOCKET", value_name = "PATH")]
+        socket: Option<String>,
+
+        /// Listen on 127.0.0.1:PORT instead of a socket (needs --token)
+        #[arg(long, value_name = "PORT", conflicts_with = "socket")]
+        tcp: Option<u16>,
+
+        /// Require `Authorization: Bearer TOKEN` on every request
+        #[arg(long, env = "SHURU_SERVE_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+
+    /// Show a VM's serial console and guest agent logs, with timestamps
+    Logs {
+        /// Instance ID (PID, or PID/ID for `shuru serve` sandboxes) or log file (default: the latest)
+        instance: Option<String>,
+
+        /// Keep printing new lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+    },
+
+    /// Remove leftover instance data and console logs of stopped VMs
     Prune,
 }
 
+#[derive(clap::Subcommand)]
+pub(crate) enum ImageCommands {
+    /// List installed OS images
+    Ls,
+
+    /// Download an OS image into the local catalog
+    Pull {
+        /// Image to download (NAME[@VERSION], default version: this CLI's)
+        image: String,
+    },
+
+    /// Remove installed OS images (all versions if no @VERSION is given)
+    Rm {
+        /// Image to remove (NAME[@VERSION])
+        image: String,
+    },
+
+    /// Download an OS image and its signed checksums for offline install
+    Fetch {
+        /// Directory to save the tarball, SHA256SUMS and SHA256SUMS.minisig
+        #[arg(short, long)]
+        output: String,
+
+        /// Image to fetch (default: alpine)
+        #[arg(long)]
+        image: Option<String>,
+
+        /// Release version to fetch (default: this CLI's version)
+        #[arg(long)]
+        version: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub(crate) enum VolumeCommands {
+    /// Create an empty volume; it is formatted as ext4 when first attached
+    Create {
+        /// Volume name
+        name: String,
+
+        /// Size in MB (default: 1024)
+        #[arg(long)]
+        size: Option<u64>,
+    },
+
+    /// List volumes
+    Ls,
+
+    /// Delete a volume and its data
+    Rm {
+        /// Volume name
+        name: String,
+    },
+
+    /// Grow a volume; its filesystem grows when next attached read-write
+    Resize {
+        /// Volume name
+        name: String,
+
+        /// New size in MB
+        #[arg(long)]
+        size: u64,
+    },
+}
+
 #[derive(clap::Subcommand)]
 pub(crate) enum CheckpointCommands {
     /// Run a command and save the resulting disk state as a checkpoint
@@ -129,4 +348,48 @@ pub(crate) enum CheckpointCommands {
         /// Checkpoint name
         name: String,
     },
+
+    /// Show files added, modified or deleted between two checkpoints
+    /// (or between the base rootfs and a checkpoint)
+    Diff {
+        /// Checkpoint to compare (against the base rootfs if B is omitted)
+        a: String,
+
+        /// Second checkpoint; when given, compares A -> B
+        b: Option<String>,
+
+        /// Base rootfs image used when B is omitted (default: the one A was created from)
+        #[arg(long)]
+        rootfs: Option<String>,
+
+        /// OS image whose rootfs is the base when B is omitted
+        #[arg(long, conflicts_with = "rootfs")]
+        image: Option<String>,
+    },
+
+    /// Print a file from a checkpoint without booting it (NAME:/path)
+    Cat {
+        /// Checkpoint and absolute path, e.g. myenv:/etc/os-release
+        #[arg(value_name = "NAME:/PATH")]
+        spec: String,
+    },
+
+    /// Export a checkpoint's filesystem as an OCI image layout tarball
+    #[command(name = "export-oci")]
+    ExportOci {
+        /// Checkpoint name
+        name: String,
+
+        /// Output path (default: NAME.oci.tar)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Image reference name recorded in the index (default: latest)
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// Leave the shuru guest agent out of the image
+        #[arg(long)]
+        exclude_agent: bool,
+    },
 }
@@ -3,18 +3,68 @@ use std::collections::HashMap;
 use anyhow::{bail, Result};
 use serde::Deserialize;
 
+use crate::assets::Channel;
+
 #[derive(Default, Deserialize)]
 pub(crate) struct ShuruConfig {
+    pub image: Option<String>,
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    /// Replaces the base kernel command line.
+    pub cmdline: Option<String>,
+    /// Appended to the kernel command line, before `--kernel-arg`s.
+    pub kernel_args: Option<Vec<String>>,
     pub cpus: Option<usize>,
     pub memory: Option<u64>,
     pub disk_size: Option<u64>,
     pub allow_net: Option<bool>,
+    /// Restricts network access to these domains and networks, merged
+    /// with `--allow-host`.
+    pub allow_hosts: Option<Vec<String>>,
+    pub network: Option<NetworkConfig>,
+    pub init_system: Option<bool>,
     pub ports: Option<Vec<String>>,
+    /// HOST_ADDR:PORT:GUEST_PORT, merged with `--expose`.
+    pub expose: Option<Vec<String>>,
     pub env: Option<HashMap<String, String>>,
     pub mounts: Option<Vec<String>>,
+    pub volumes: Option<Vec<String>>,
     pub command: Option<Vec<String>>,
 }
 
+/// `"network"`: how the guest reaches the network.
+#[derive(Default, Deserialize)]
+pub(crate) struct NetworkConfig {
+    pub mode: Option<NetworkMode>,
+    /// Allowed domains and networks, merged with `allow_hosts`.
+    pub allow: Option<Vec<String>>,
+}
+
+/// Guest network access.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NetworkMode {
+    /// No network access
+    None,
+    /// Full network access (NAT)
+    Nat,
+    /// A network device limited to the allowed hosts
+    Filter,
+    /// No network device; HTTP(S) and DNS for the allowed hosts through a proxy on the host
+    Proxy,
+}
+
+impl NetworkMode {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            NetworkMode::None => "none",
+            NetworkMode::Nat => "nat",
+            NetworkMode::Filter => "filter",
+            NetworkMode::Proxy => "proxy",
+        }
+    }
+}
+
 pub(crate) fn load_config(config_flag: Option<&str>) -> Result<ShuruConfig> {
     let path = match config_flag {
         Some(p) => std::path::PathBuf::from(p),
@@ -36,3 +86,35 @@ pub(crate) fn load_config(config_flag: Option<&str>) -> Result<ShuruConfig> {
         Err(e) => bail!("Failed to read {}: {}", path.display(), e),
     }
 }
+
+/// User-wide settings, read from `$XDG_CONFIG_HOME/shuru/config.json`
+/// (default `~/.config/shuru/config.json`).
+#[derive(Default, Deserialize)]
+pub(crate) struct GlobalConfig {
+    /// Base URL (`https://` or `file://`) serving release assets as
+    /// `<mirror>/<tag>/<file>`, used instead of GitHub Releases.
+    pub mirror: Option<String>,
+    /// Release channel `shuru upgrade` follows by default.
+    pub channel: Option<Channel>,
+}
+
+pub(crate) fn global_config_path() -> std::path::PathBuf {
+    let base = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => {
+            let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".into());
+            std::path::PathBuf::from(home).join(".config")
+        }
+    };
+    base.join("shuru").join("config.json")
+}
+
+pub(crate) fn load_global_config() -> Result<GlobalConfig> {
+    let path = global_config_path();
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(GlobalConfig::default()),
+        Err(e) => bail!("Failed to read {}: {}", path.display(), e),
+    }
+}
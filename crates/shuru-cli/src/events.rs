@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use shuru_vm::{Sandbox, SandboxEvent};
+
+/// One line of `shuru run --events` output.
+#[derive(Serialize)]
+struct EventLine<'a> {
+    /// Milliseconds since the Unix epoch.
+    ts: u128,
+    #[serde(flatten)]
+    event: &'a SandboxEvent,
+}
+
+/// Writes a sandbox's lifecycle events as JSON lines on a background thread.
+pub(crate) struct EventLog {
+    thread: JoinHandle<()>,
+}
+
+impl EventLog {
+    /// Subscribe to `sandbox`'s events and write them to `dest` (`-` for
+    /// stderr).
+    pub(crate) fn start(sandbox: &Sandbox, dest: &str) -> Result<Self> {
+        let mut out: Box<dyn Write + Send> = if dest == "-" {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(BufWriter::new(
+                File::create(dest).with_context(|| format!("Failed to create {}", dest))?,
+            ))
+        };
+        let rx = sandbox.events();
+        let thread = std::thread::spawn(move || {
+            for event in rx {
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or(0);
+                let line = EventLine { ts, event: &event };
+                let Ok(json) = serde_json::to_string(&line) else {
+                    continue;
+                };
+                if writeln!(out, "{}", json).and_then(|_| out.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(EventLog { thread })
+    }
+
+    /// Wait for the remaining events to be written. Call after the sandbox
+    /// has been dropped; the log ends when its event stream closes.
+    pub(crate) fn finish(self) {
+        let _ = self.thread.join();
+    }
+}
@@ -0,0 +1,785 @@
+//! Minimal read-only ext4 parser for inspecting rootfs and checkpoint images
+//! on the host without booting a VM.
+//!
+//! Supports extent-mapped and legacy block-mapped files, fast and slow
+//! symlinks, and linear/htree directories (htree blocks are read linearly).
+//! Inline data and encrypted inodes are not supported.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+
+use anyhow::{bail, Context, Result};
+
+pub(crate) const ROOT_INODE: u32 = 2;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_MAGIC: u16 = 0xEF53;
+const EXTENT_MAGIC: u16 = 0xF30A;
+
+/// Largest block size is 64 KiB (1024 << 6).
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+/// Same bound as the kernel's ext4_ext_check_inode.
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+const INCOMPAT_64BIT: u32 = 0x80;
+
+const EXTENTS_FL: u32 = 0x0008_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+const ENCRYPT_FL: u32 = 0x0000_0800;
+
+const S_IFMT: u16 = 0o170000;
+
+/// Same limit as Linux's MAXSYMLINKS.
+const MAX_SYMLINK_HOPS: u32 = 40;
+/// Deepest directory nesting `walk` descends into.
+const MAX_WALK_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Inode {
+    pub ino: u32,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub mtime: i64,
+    pub links: u16,
+    flags: u32,
+    block: [u8; 60],
+}
+
+impl Inode {
+    pub fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            0o100000 => FileType::Regular,
+            0o040000 => FileType::Directory,
+            0o120000 => FileType::Symlink,
+            0o020000 => FileType::CharDevice,
+            0o060000 => FileType::BlockDevice,
+            0o010000 => FileType::Fifo,
+            0o140000 => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// Permission bits including setuid/setgid/sticky.
+    pub fn permissions(&self) -> u32 {
+        (self.mode & 0o7777) as u32
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    /// Major/minor numbers for character and block devices.
+    pub fn device(&self) -> (u32, u32) {
+        let old = u32::from_le_bytes(self.block[0..4].try_into().unwrap());
+        if old != 0 {
+            return ((old >> 8) & 0xff, old & 0xff);
+        }
+        let new = u32::from_le_bytes(self.block[4..8].try_into().unwrap());
+        let major = (new & 0xfff00) >> 8;
+        let minor = (new & 0xff) | ((new >> 12) & 0xfff00);
+        (major, minor)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DirEntry {
+    pub name: String,
+    pub ino: u32,
+}
+
+/// A contiguous run of file blocks. `physical == None` means unwritten
+/// (preallocated) space, which reads as zeros.
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    logical: u64,
+    physical: Option<u64>,
+    len: u64,
+}
+
+pub(crate) struct Ext4Image {
+    file: File,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    inode_tables: Vec<u64>,
+}
+
+impl Ext4Image {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open {}", path))?;
+        let file_len = file
+            .metadata()
+            .with_context(|| format!("failed to stat {}", path))?
+            .len();
+
+        let mut sb = [0u8; 1024];
+        file.read_exact_at(&mut sb, SUPERBLOCK_OFFSET)
+            .with_context(|| format!("failed to read superblock of {}", path))?;
+
+        if le16(&sb, 0x38) != EXT4_MAGIC {
+            bail!("{} is not an ext2/3/4 filesystem image", path);
+        }
+
+        let inodes_count = le32(&sb, 0x00);
+        let first_data_block = le32(&sb, 0x14) as u64;
+        let log_block_size = le32(&sb, 0x18);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            bail!("{}: corrupt superblock (block size)", path);
+        }
+        let block_size = 1024u64 << log_block_size;
+        let inodes_per_group = le32(&sb, 0x28);
+        let rev_level = le32(&sb, 0x4C);
+        let inode_size = if rev_level >= 1 {
+            le16(&sb, 0x58) as u64
+        } else {
+            128
+        };
+        let incompat = le32(&sb, 0x60);
+        let desc_size = if incompat & INCOMPAT_64BIT != 0 {
+            (le16(&sb, 0xFE) as u64).max(32)
+        } else {
+            32
+        };
+
+        if inodes_per_group == 0 {
+            bail!("{}: corrupt superblock (inodes_per_group = 0)", path);
+        }
+
+        let group_count = inodes_count.div_ceil(inodes_per_group) as u64;
+        let gdt_offset = (first_data_block + 1) * block_size;
+        // The descriptors must fit in the image, which also bounds the
+        // allocation below
+        let gdt_len = group_count
+            .checked_mul(desc_size)
+            .filter(|len| gdt_offset.saturating_add(*len) <= file_len)
+            .with_context(|| format!("{}: corrupt superblock (group count)", path))?;
+        let mut gdt = vec![0u8; gdt_len as usize];
+        file.read_exact_at(&mut gdt, gdt_offset)
+            .with_context(|| format!("failed to read group descriptors of {}", path))?;
+
+        let inode_tables = gdt
+            .chunks_exact(desc_size as usize)
+            .map(|desc| {
+                let lo = le32(desc, 0x08) as u64;
+                let hi = if desc_size >= 64 {
+                    le32(desc, 0x28) as u64
+                } else {
+                    0
+                };
+                (hi << 32) | lo
+            })
+            .collect();
+
+        Ok(Ext4Image {
+            file,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+        })
+    }
+
+    pub fn inode(&self, ino: u32) -> Result<Inode> {
+        if ino == 0 {
+            bail!("invalid inode number 0");
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = *self
+            .inode_tables
+            .get(group)
+            .with_context(|| format!("inode {} out of range", ino))?;
+
+        let offset = table
+            .checked_mul(self.block_size)
+            .and_then(|o| o.checked_add(index * self.inode_size))
+            .with_context(|| format!("inode {} lies outside the image", ino))?;
+
+        let mut raw = [0u8; 160];
+        let len = (self.inode_size as usize).min(raw.len());
+        self.file
+            .read_exact_at(&mut raw[..len], offset)
+            .with_context(|| format!("failed to read inode {}", ino))?;
+
+        let mut block = [0u8; 60];
+        block.copy_from_slice(&raw[0x28..0x64]);
+
+        Ok(Inode {
+            ino,
+            mode: le16(&raw, 0x00),
+            uid: (le16(&raw, 0x02) as u32) | ((le16(&raw, 0x78) as u32) << 16),
+            gid: (le16(&raw, 0x18) as u32) | ((le16(&raw, 0x7A) as u32) << 16),
+            size: (le32(&raw, 0x04) as u64) | ((le32(&raw, 0x6C) as u64) << 32),
+            mtime: le32(&raw, 0x10) as i32 as i64,
+            links: le16(&raw, 0x1A),
+            flags: le32(&raw, 0x20),
+            block,
+        })
+    }
+
+    /// List a directory, excluding `.` and `..`.
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<DirEntry>> {
+        if !dir.is_dir() {
+            bail!("inode {} is not a directory", dir.ino);
+        }
+        let mut data = Vec::new();
+        self.reader(dir)?.read_to_end(&mut data)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0usize;
+        while pos + 8 <= data.len() {
+            let ino = le32(&data, pos);
+            let rec_len = le16(&data, pos + 4) as usize;
+            let name_len = data[pos + 6] as usize;
+            if rec_len < 8 || pos + rec_len > data.len() {
+                bail!("corrupt directory entry in inode {}", dir.ino);
+            }
+            if ino != 0 && name_len > 0 && pos + 8 + name_len <= data.len() {
+                let name = String::from_utf8_lossy(&data[pos + 8..pos + 8 + name_len]);
+                if name != "." && name != ".." {
+                    entries.push(DirEntry {
+                        name: name.into_owned(),
+                        ino,
+                    });
+                }
+            }
+            pos += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// Open the contents of a regular file, directory or slow symlink.
+    pub fn reader(&self, inode: &Inode) -> Result<FileReader<'_>> {
+        if inode.flags & INLINE_DATA_FL != 0 {
+            bail!(
+                "inode {} uses inline data, which is not supported",
+                inode.ino
+            );
+        }
+        if inode.flags & ENCRYPT_FL != 0 {
+            bail!("inode {} is encrypted", inode.ino);
+        }
+        Ok(FileReader {
+            image: self,
+            extents: self.extents(inode)?,
+            size: inode.size,
+            pos: 0,
+        })
+    }
+
+    pub fn read_link(&self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.file_type() != FileType::Symlink {
+            bail!("inode {} is not a symlink", inode.ino);
+        }
+        if inode.size < 60 && inode.flags & EXTENTS_FL == 0 {
+            return Ok(inode.block[..inode.size as usize].to_vec());
+        }
+        let mut target = Vec::new();
+        self.reader(inode)?.read_to_end(&mut target)?;
+        Ok(target)
+    }
+
+    /// Resolve an absolute path to its inode, following symlinks (including
+    /// in the final component) relative to the image root.
+    pub fn resolve(&self, path: &str) -> Result<Inode> {
+        // Chain of directories walked so far, so `..` can step back.
+        let mut chain = vec![self.inode(ROOT_INODE)?];
+        let mut pending: VecDeque<String> = split_path(path);
+        let mut hops = 0;
+
+        while let Some(component) = pending.pop_front() {
+            match component.as_str() {
+                "." => continue,
+                ".." => {
+                    if chain.len() > 1 {
+                        chain.pop();
+                    }
+                    continue;
+                }
+                name => {
+                    let dir = chain.last().unwrap();
+                    let entry = self
+                        .read_dir(dir)?
+                        .into_iter()
+                        .find(|e| e.name == name)
+                        .with_context(|| format!("no such file or directory: {}", path))?;
+                    let inode = self.inode(entry.ino)?;
+
+                    if inode.file_type() == FileType::Symlink {
+                        hops += 1;
+                        if hops > MAX_SYMLINK_HOPS {
+                            bail!("too many levels of symbolic links: {}", path);
+                        }
+                        let target = String::from_utf8_lossy(&self.read_link(&inode)?).into_owned();
+                        if target.starts_with('/') {
+                            chain.truncate(1);
+                        }
+                        for c in split_path(&target).into_iter().rev() {
+                            pending.push_front(c);
+                        }
+                    } else {
+                        chain.push(inode);
+                    }
+                }
+            }
+        }
+
+        Ok(chain.pop().unwrap())
+    }
+
+    /// Depth-first walk of the whole tree in name order. Paths are relative
+    /// to the root, without a leading slash; the root itself is not visited.
+    /// A directory reached twice (a cycle in a corrupt image) or nested
+    /// deeper than `MAX_WALK_DEPTH` is an error.
+    pub fn walk(&self, mut visit: impl FnMut(&str, &Inode) -> Result<()>) -> Result<()> {
+        let root = self.inode(ROOT_INODE)?;
+        let mut seen = HashSet::from([ROOT_INODE]);
+        self.walk_dir(&root, "", 0, &mut seen, &mut visit)
+    }
+
+    fn walk_dir(
+        &self,
+        dir: &Inode,
+        prefix: &str,
+        depth: usize,
+        seen: &mut HashSet<u32>,
+        visit: &mut impl FnMut(&str, &Inode) -> Result<()>,
+    ) -> Result<()> {
+        if depth > MAX_WALK_DEPTH {
+            bail!("directories nested too deeply: {}", prefix);
+        }
+        let mut entries = self.read_dir(dir)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            let path = if prefix.is_empty() {
+                entry.name.clone()
+            } else {
+                format!("{}/{}", prefix, entry.name)
+            };
+            let inode = self.inode(entry.ino)?;
+            visit(&path, &inode)?;
+            if inode.is_dir() {
+                if !seen.insert(inode.ino) {
+                    bail!("directory cycle at {}", path);
+                }
+                self.walk_dir(&inode, &path, depth + 1, seen, visit)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn extents(&self, inode: &Inode) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        if inode.flags & EXTENTS_FL != 0 {
+            self.extent_node(&inode.block, None, &mut extents)?;
+        } else {
+            self.block_map(inode, &mut extents)?;
+        }
+        extents.sort_by_key(|e| e.logical);
+        Ok(extents)
+    }
+
+    /// Collect the extents under `node`. Below the root, `expected_depth`
+    /// is one less than the parent's, so a corrupt tree cannot loop.
+    fn extent_node(
+        &self,
+        node: &[u8],
+        expected_depth: Option<u16>,
+        out: &mut Vec<Extent>,
+    ) -> Result<()> {
+        if le16(node, 0) != EXTENT_MAGIC {
+            bail!("corrupt extent header");
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if depth > MAX_EXTENT_DEPTH || expected_depth.is_some_and(|d| d != depth) {
+            bail!("corrupt extent tree depth");
+        }
+        for i in 0..entries {
+            let e = 12 + i * 12;
+            if e + 12 > node.len() {
+                bail!("extent entries overflow their node");
+            }
+            if depth == 0 {
+                let logical = le32(node, e) as u64;
+                let raw_len = le16(node, e + 4) as u64;
+                let start = ((le16(node, e + 6) as u64) << 32) | le32(node, e + 8) as u64;
+                let (len, unwritten) = if raw_len > 32768 {
+                    (raw_len - 32768, true)
+                } else {
+                    (raw_len, false)
+                };
+                out.push(Extent {
+                    logical,
+                    physical: (!unwritten).then_some(start),
+                    len,
+                });
+            } else {
+                let leaf = ((le16(node, e + 8) as u64) << 32) | le32(node, e + 4) as u64;
+                let child = self.read_block(leaf)?;
+                self.extent_node(&child, Some(depth - 1), out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Legacy ext2/3 direct and indirect block pointers.
+    fn block_map(&self, inode: &Inode, out: &mut Vec<Extent>) -> Result<()> {
+        let mut logical = 0u64;
+        for i in 0..12 {
+            let block = le32(&inode.block, i * 4) as u64;
+            push_block(out, logical, block);
+            logical += 1;
+        }
+        for (level, i) in [(1u32, 12usize), (2, 13), (3, 14)] {
+            let block = le32(&inode.block, i * 4) as u64;
+            let span = (self.block_size / 4).pow(level);
+            if block == 0 {
+                logical += span;
+                continue;
+            }
+            self.indirect(block, level, &mut logical, out)?;
+        }
+        Ok(())
+    }
+
+    fn indirect(
+        &self,
+        block: u64,
+        level: u32,
+        logical: &mut u64,
+        out: &mut Vec<Extent>,
+    ) -> Result<()> {
+        let data = self.read_block(block)?;
+        let per_block = self.block_size / 4;
+        for ptr in data.chunks_exact(4) {
+            let child = u32::from_le_bytes(ptr.try_into().unwrap()) as u64;
+            if level == 1 {
+                push_block(out, *logical, child);
+                *logical += 1;
+            } else if child == 0 {
+                *logical += per_block.pow(level - 1);
+            } else {
+                self.indirect(child, level - 1, logical, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>> {
+        let offset = block
+            .checked_mul(self.block_size)
+            .with_context(|| format!("block {} lies outside the image", block))?;
+        let mut buf = vec![0u8; self.block_size as usize];
+        self.file
+            .read_exact_at(&mut buf, offset)
+            .with_context(|| format!("failed to read block {}", block))?;
+        Ok(buf)
+    }
+}
+
+fn split_path(path: &str) -> VecDeque<String> {
+    path.split('/')
+        .filter(|c| !c.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn push_block(out: &mut Vec<Extent>, logical: u64, block: u64) {
+    if block == 0 {
+        return;
+    }
+    if let Some(last) = out.last_mut() {
+        if let Some(phys) = last.physical {
+            if last.logical + last.len == logical && phys + last.len == block {
+                last.len += 1;
+                return;
+            }
+        }
+    }
+    out.push(Extent {
+        logical,
+        physical: Some(block),
+        len: 1,
+    });
+}
+
+/// Sequential reader over a file's blocks. Holes and unwritten extents read
+/// as zeros; output is truncated to the inode size.
+pub(crate) struct FileReader<'a> {
+    image: &'a Ext4Image,
+    extents: Vec<Extent>,
+    size: u64,
+    pos: u64,
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let bs = self.image.block_size;
+        let remaining = (self.size - self.pos).min(buf.len() as u64);
+        let block = self.pos / bs;
+        let offset_in_block = self.pos % bs;
+
+        let extent = self
+            .extents
+            .iter()
+            .find(|e| block >= e.logical && block < e.logical + e.len);
+
+        let n = match extent {
+            Some(e) => {
+                // Read up to the end of this extent in one go.
+                let extent_end = (e.logical + e.len) * bs;
+                let n = remaining.min(extent_end - self.pos) as usize;
+                match e.physical {
+                    Some(phys) => {
+                        let disk = (phys + block - e.logical)
+                            .checked_mul(bs)
+                            .and_then(|o| o.checked_add(offset_in_block))
+                            .ok_or_else(|| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "extent lies outside the image",
+                                )
+                            })?;
+                        self.image.file.read_exact_at(&mut buf[..n], disk)?;
+                    }
+                    None => buf[..n].fill(0),
+                }
+                n
+            }
+            None => {
+                // Hole: zero-fill up to the next mapped extent.
+                let next = self
+                    .extents
+                    .iter()
+                    .map(|e| e.logical * bs)
+                    .filter(|&start| start > self.pos)
+                    .min()
+                    .unwrap_or(self.size);
+                let n = remaining.min(next - self.pos) as usize;
+                buf[..n].fill(0);
+                n
+            }
+        };
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    /// 512 KiB, 1 KiB blocks, made with `mkfs.ext4 -d` from a tree with
+    /// etc/os-release (0640), big.bin, sparse.bin (eight 1 KiB extents,
+    /// so a depth-1 tree), link -> etc/os-release and an empty directory.
+    const FIXTURE: &[u8] = include_bytes!("../testdata/small.ext4.gz");
+
+    /// Unpack the fixture into a file of its own, passing it to `patch`
+    /// before it is returned.
+    fn fixture(name: &str, patch: impl FnOnce(&File)) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("shuru-ext4-{}-{}.img", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        let mut image = Vec::new();
+        flate2::read::GzDecoder::new(FIXTURE)
+            .read_to_end(&mut image)
+            .unwrap();
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&image).unwrap();
+        patch(&file);
+        path
+    }
+
+    fn read_file(image: &Ext4Image, path: &str) -> Vec<u8> {
+        let inode = image.resolve(path).unwrap();
+        let mut data = Vec::new();
+        image
+            .reader(&inode)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn reads_files_and_metadata() {
+        let path = fixture("read", |_| {});
+        let image = Ext4Image::open(&path).unwrap();
+
+        let release = image.resolve("etc/os-release").unwrap();
+        assert_eq!(release.file_type(), FileType::Regular);
+        assert_eq!(release.permissions(), 0o640);
+        assert_eq!(read_file(&image, "etc/os-release"), b"ID=shuru\n");
+
+        let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(read_file(&image, "big.bin"), big);
+
+        let mut sparse = vec![0u8; 7 * 8192 + 1024];
+        for i in 0..8 {
+            sparse[i * 8192..i * 8192 + 1024].fill(b'A' + i as u8);
+        }
+        assert_eq!(read_file(&image, "sparse.bin"), sparse);
+
+        // resolve() follows the link; the directory entry does not
+        assert_eq!(read_file(&image, "link"), b"ID=shuru\n");
+        let root = image.inode(ROOT_INODE).unwrap();
+        let entry = image.read_dir(&root).unwrap();
+        let entry = entry.iter().find(|e| e.name == "link").unwrap();
+        let link = image.inode(entry.ino).unwrap();
+        assert_eq!(link.file_type(), FileType::Symlink);
+        assert_eq!(image.read_link(&link).unwrap(), b"etc/os-release");
+
+        assert!(image.resolve("empty").unwrap().is_dir());
+        assert!(image.resolve("missing").is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn walks_every_path() {
+        let path = fixture("walk", |_| {});
+        let image = Ext4Image::open(&path).unwrap();
+        let mut paths = Vec::new();
+        image
+            .walk(|path, _| {
+                paths.push(path.to_string());
+                Ok(())
+            })
+            .unwrap();
+        paths.sort();
+        for expected in [
+            "big.bin",
+            "empty",
+            "etc",
+            "etc/os-release",
+            "link",
+            "sparse.bin",
+        ] {
+            assert!(
+                paths.iter().any(|p| p == expected),
+                "{} not walked",
+                expected
+            );
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_oversized_block_size() {
+        let path = fixture("blocksize", |file| {
+            file.write_all_at(&7u32.to_le_bytes(), SUPERBLOCK_OFFSET + 0x18)
+                .unwrap();
+        });
+        let err = Ext4Image::open(&path).err().unwrap();
+        assert!(err.to_string().contains("corrupt superblock"), "{}", err);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_oversized_group_count() {
+        let path = fixture("groups", |file| {
+            // u32::MAX inodes, one per group
+            file.write_all_at(&u32::MAX.to_le_bytes(), SUPERBLOCK_OFFSET)
+                .unwrap();
+            file.write_all_at(&1u32.to_le_bytes(), SUPERBLOCK_OFFSET + 0x28)
+                .unwrap();
+        });
+        let err = Ext4Image::open(&path).err().unwrap();
+        assert!(err.to_string().contains("corrupt superblock"), "{}", err);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_out_of_range_blocks() {
+        let path = fixture("range", |_| {});
+        let image = Ext4Image::open(&path).unwrap();
+        assert!(image.read_block(u64::MAX / 2).is_err());
+
+        let sparse = image.resolve("sparse.bin").unwrap();
+        let mut extents = image.extents(&sparse).unwrap();
+        extents[0].physical = Some(u64::MAX / 1024);
+        let mut reader = FileReader {
+            image: &image,
+            extents,
+            size: sparse.size,
+            pos: 0,
+        };
+        assert!(reader.read(&mut [0; 16]).is_err());
+
+        // Inode table pointing past the end of the address space
+        let mut image = image;
+        image.inode_tables[0] = u64::MAX / 2;
+        assert!(image.inode(ROOT_INODE).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn walk_detects_directory_cycles() {
+        let path = fixture("cycle", |_| {});
+        // Rename the ".." entry of `empty` (after "." at offset 0, with a
+        // 12-byte record) to "up", so the walk sees it as a subdirectory
+        // linking back to the root.
+        let offset = {
+            let image = Ext4Image::open(&path).unwrap();
+            let empty = image.resolve("empty").unwrap();
+            let block = image.extents(&empty).unwrap()[0].physical.unwrap();
+            block * image.block_size + 12 + 8
+        };
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"up", offset).unwrap();
+
+        let image = Ext4Image::open(&path).unwrap();
+        let err = image.walk(|_, _| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("directory cycle"), "{}", err);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_bad_extent_depth() {
+        let path = fixture("depth", |_| {});
+        let image = Ext4Image::open(&path).unwrap();
+        let sparse = image.resolve("sparse.bin").unwrap();
+
+        // Root deeper than the limit
+        let mut inode = sparse.clone();
+        inode.block[6..8].copy_from_slice(&(MAX_EXTENT_DEPTH + 1).to_le_bytes());
+        assert!(image.reader(&inode).is_err());
+
+        // Root claims depth 2 but its child is a leaf
+        let mut inode = sparse.clone();
+        inode.block[6..8].copy_from_slice(&2u16.to_le_bytes());
+        assert!(image.reader(&inode).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
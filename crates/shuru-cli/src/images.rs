@@ -0,0 +1,327 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::assets::{self, CURRENT_VERSION};
+
+/// Image used when neither `--image` nor `"image"` in shuru.json is given.
+/// This is the OS image published with every shuru release.
+pub(crate) const DEFAULT_IMAGE: &str = "alpine";
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes one installed image. Stored as
+/// `<data_dir>/images/<name>@<version>/manifest.json`; file paths are
+/// relative to that directory.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct ImageManifest {
+    pub name: String,
+    pub version: String,
+    pub kernel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initrd: Option<String>,
+    pub rootfs: String,
+    /// Kernel command line; the built-in default is used when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+}
+
+impl ImageManifest {
+    /// Manifest for a release tarball that ships the standard file set.
+    pub(crate) fn standard(name: &str, version: &str) -> Self {
+        ImageManifest {
+            name: name.to_string(),
+            version: version.to_string(),
+            kernel: "Image".to_string(),
+            initrd: Some("initramfs.cpio.gz".to_string()),
+            rootfs: "rootfs.ext4".to_string(),
+            cmdline: None,
+        }
+    }
+}
+
+pub(crate) struct InstalledImage {
+    pub dir: PathBuf,
+    pub manifest: ImageManifest,
+}
+
+impl InstalledImage {
+    pub(crate) fn reference(&self) -> String {
+        format!("{}@{}", self.manifest.name, self.manifest.version)
+    }
+
+    pub(crate) fn kernel_path(&self) -> String {
+        self.path(&self.manifest.kernel)
+    }
+
+    pub(crate) fn initrd_path(&self) -> Option<String> {
+        self.manifest.initrd.as_deref().map(|p| self.path(p))
+    }
+
+    pub(crate) fn rootfs_path(&self) -> String {
+        self.path(&self.manifest.rootfs)
+    }
+
+    /// Total size of the files in the image directory, in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|e| e.metadata().ok())
+                    .map(|m| m.len())
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_string_lossy().into_owned()
+    }
+}
+
+pub(crate) fn images_dir(data_dir: &str) -> String {
+    format!("{}/images", data_dir)
+}
+
+/// Split `name[@version]`, rejecting names that cannot be a directory.
+pub(crate) fn parse_ref(spec: &str) -> Result<(&str, Option<&str>)> {
+    let (name, version) = match spec.split_once('@') {
+        Some((n, v)) => (n, Some(v)),
+        None => (spec, None),
+    };
+    let valid = |s: &str| {
+        !s.is_empty()
+            && !s.starts_with('.')
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+    if !valid(name) || version.is_some_and(|v| !valid(v)) {
+        bail!(
+            "invalid image reference '{}' (expected NAME[@VERSION])",
+            spec
+        );
+    }
+    Ok((name, version))
+}
+
+/// All installed images, sorted by name and then version.
+pub(crate) fn list(data_dir: &str) -> Result<Vec<InstalledImage>> {
+    let entries = match fs::read_dir(images_dir(data_dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut images = Vec::new();
+    for entry in entries {
+        let dir = entry?.path();
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let Ok(contents) = fs::read_to_string(&manifest_path) else {
+            // Staging directories and partial installs have no manifest.
+            continue;
+        };
+        let manifest: ImageManifest = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse {}", manifest_path.display()))?;
+        images.push(InstalledImage { dir, manifest });
+    }
+    images.sort_by(|a, b| {
+        a.manifest
+            .name
+            .cmp(&b.manifest.name)
+            .then_with(|| compare_versions(&a.manifest.version, &b.manifest.version))
+    });
+    Ok(images)
+}
+
+/// Look up an installed image. Without a version, the newest installed
+/// version of `name` is returned.
+pub(crate) fn find(
+    data_dir: &str,
+    name: &str,
+    version: Option<&str>,
+) -> Result<Option<InstalledImage>> {
+    Ok(list(data_dir)?.into_iter().rfind(|img| {
+        img.manifest.name == name && version.is_none_or(|v| img.manifest.version == v)
+    }))
+}
+
+/// Resolve an image reference for booting, downloading it if it is not
+/// installed. `None` selects the default image for this shuru version.
+pub(crate) fn resolve(data_dir: &str, spec: Option<&str>) -> Result<InstalledImage> {
+    let (name, version) = match spec {
+        Some(spec) => parse_ref(spec)?,
+        None => (DEFAULT_IMAGE, Some(CURRENT_VERSION)),
+    };
+    migrate_legacy(data_dir)?;
+    if let Some(image) = find(data_dir, name, version)? {
+        return Ok(image);
+    }
+    pull(data_dir, name, version.unwrap_or(CURRENT_VERSION))
+}
+
+/// Download `name@version` from the release (or mirror) into the catalog.
+pub(crate) fn pull(data_dir: &str, name: &str, version: &str) -> Result<InstalledImage> {
+    assets::download_image(data_dir, name, version)?;
+    find(data_dir, name, Some(version))?
+        .with_context(|| format!("image {}@{} missing after install", name, version))
+}
+
+/// Move a verified, extracted image from `staging` into the catalog,
+/// replacing any existing install of the same version. Tarballs without a
+/// manifest get the standard one.
+pub(crate) fn install_staged(
+    data_dir: &str,
+    staging: &Path,
+    name: &str,
+    version: &str,
+) -> Result<InstalledImage> {
+    let manifest_path = staging.join(MANIFEST_FILE);
+    let mut manifest = match fs::read_to_string(&manifest_path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse {} in image", MANIFEST_FILE))?,
+        Err(_) => ImageManifest::standard(name, version),
+    };
+    manifest.name = name.to_string();
+    manifest.version = version.to_string();
+    for file in [
+        Some(&manifest.kernel),
+        manifest.initrd.as_ref(),
+        Some(&manifest.rootfs),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        // Relative, without `..`, so it stays inside the image directory
+        let contained = !file.is_empty()
+            && Path::new(file)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+        if !contained {
+            bail!(
+                "image {}@{} has an invalid path '{}' in {}",
+                name,
+                version,
+                file,
+                MANIFEST_FILE
+            );
+        }
+        if !staging.join(file).exists() {
+            bail!("image {}@{} is missing {}", name, version, file);
+        }
+    }
+    fs::write(&manifest_path, serde_json::to_vec_pretty(&manifest)?)?;
+
+    let dest = Path::new(&images_dir(data_dir)).join(format!("{}@{}", name, version));
+    if dest.exists() {
+        fs::remove_dir_all(&dest)
+            .with_context(|| format!("failed to replace {}", dest.display()))?;
+    }
+    fs::rename(staging, &dest).with_context(|| format!("failed to install {}", dest.display()))?;
+
+    Ok(InstalledImage {
+        dir: dest,
+        manifest,
+    })
+}
+
+/// Print the installed images as a table.
+pub(crate) fn print_list(data_dir: &str) -> Result<()> {
+    migrate_legacy(data_dir)?;
+    let images = list(data_dir)?;
+    if images.is_empty() {
+        eprintln!("No images installed. Run `shuru init` or `shuru image pull`.");
+        return Ok(());
+    }
+
+    println!("{:<20} {:<12} {:>10}", "NAME", "VERSION", "SIZE");
+    for image in &images {
+        let size = image.size();
+        let size_str = if size >= 1024 * 1024 * 1024 {
+            format!("{:.1} GB", size as f64 / (1024.0 * 1024.0 * 1024.0))
+        } else {
+            format!("{} MB", size / (1024 * 1024))
+        };
+        let default =
+            if image.manifest.name == DEFAULT_IMAGE && image.manifest.version == CURRENT_VERSION {
+                " (default)"
+            } else {
+                ""
+            };
+        println!(
+            "{:<20} {:<12} {:>10}{}",
+            image.manifest.name, image.manifest.version, size_str, default
+        );
+    }
+    Ok(())
+}
+
+/// Remove installed images. A bare name removes every installed version.
+pub(crate) fn remove(data_dir: &str, spec: &str) -> Result<Vec<String>> {
+    let (name, version) = parse_ref(spec)?;
+    let mut removed = Vec::new();
+    for image in list(data_dir)? {
+        if image.manifest.name == name && version.is_none_or(|v| image.manifest.version == v) {
+            fs::remove_dir_all(&image.dir)
+                .with_context(|| format!("failed to remove {}", image.dir.display()))?;
+            assets::remove_cached(data_dir, &image.manifest.name, &image.manifest.version);
+            removed.push(image.reference());
+        }
+    }
+    if removed.is_empty() {
+        bail!("image '{}' is not installed", spec);
+    }
+    Ok(removed)
+}
+
+/// Move assets installed by older releases (kernel, initramfs and rootfs
+/// directly in the data dir) into the catalog so they are not downloaded
+/// again. Run by `shuru init`, `shuru image ls` and before resolving an
+/// image to boot.
+pub(crate) fn migrate_legacy(data_dir: &str) -> Result<()> {
+    let data = Path::new(data_dir);
+    let Ok(version) = fs::read_to_string(data.join("VERSION")) else {
+        return Ok(());
+    };
+    let version = version.trim();
+    let files = ["Image", "initramfs.cpio.gz", "rootfs.ext4"];
+    if !version.is_empty() && files.iter().all(|f| data.join(f).exists()) {
+        let staging = data
+            .join("images")
+            .join(format!(".staging-legacy@{}", version));
+        fs::create_dir_all(&staging)?;
+        for file in files {
+            fs::rename(data.join(file), staging.join(file))?;
+        }
+        let image = install_staged(data_dir, &staging, DEFAULT_IMAGE, version)?;
+        info!("shuru: moved existing OS image to {}", image.dir.display());
+    }
+    let _ = fs::remove_file(data.join("VERSION"));
+    Ok(())
+}
+
+/// Compare dotted versions numerically where both parts are numbers.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                let ord = match (x.parse::<u64>(), y.parse::<u64>()) {
+                    (Ok(x), Ok(y)) => x.cmp(&y),
+                    _ => x.cmp(y),
+                };
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+        }
+    }
+}
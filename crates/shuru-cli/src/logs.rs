@@ -0,0 +1,190 @@
+//! Console logs: the serial console and guest agent records of a VM, kept
+//! in `console.log` in its instance directory until `shuru prune`, next to
+//! the `egress.log` of proxy mode.
+
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use shuru_vm::default_data_dir;
+
+use crate::oci::rfc3339;
+
+const LOG_FILE: &str = "console.log";
+const EGRESS_LOG_FILE: &str = "egress.log";
+
+/// Default console log of the VM using `instance_dir`.
+pub(crate) fn instance_log(instance_dir: &str) -> String {
+    format!("{}/{}", instance_dir, LOG_FILE)
+}
+
+/// Default egress log of the VM using `instance_dir`, in proxy mode.
+pub(crate) fn instance_egress_log(instance_dir: &str) -> String {
+    format!("{}/{}", instance_dir, EGRESS_LOG_FILE)
+}
+
+/// Remove an instance's working files but keep its console and egress
+/// logs, so a failed run can still be looked at with `shuru logs`.
+pub(crate) fn remove_instance(instance_dir: &str) {
+    let Ok(entries) = std::fs::read_dir(instance_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_name() == LOG_FILE || entry.file_name() == EGRESS_LOG_FILE {
+            continue;
+        }
+        if path.is_dir() {
+            remove_instance(&path.to_string_lossy());
+            let _ = std::fs::remove_dir(&path);
+        } else {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    // Only succeeds if there was no log to keep
+    let _ = std::fs::remove_dir(instance_dir);
+}
+
+/// Resolve `shuru logs`'s argument: a log file, an instance id (`PID`, or
+/// `PID/ID` for `shuru serve` sandboxes), or by default the most recently
+/// written instance log.
+fn find_log(instance: Option<&str>) -> Result<String> {
+    let instances_dir = format!("{}/instances", default_data_dir());
+    let Some(instance) = instance else {
+        return latest_log(&instances_dir)?
+            .context("No console logs found (they are kept until `shuru prune`)");
+    };
+    if std::path::Path::new(instance).is_file() {
+        return Ok(instance.to_string());
+    }
+    if instance.split('/').any(|c| c.is_empty() || c == "..") {
+        bail!("invalid instance: '{}'", instance);
+    }
+    let path = instance_log(&format!("{}/{}", instances_dir, instance));
+    if !std::path::Path::new(&path).exists() {
+        bail!("No console log for instance '{}'", instance);
+    }
+    Ok(path)
+}
+
+/// Newest `console.log` in an instance directory or one level below it.
+fn latest_log(instances_dir: &str) -> Result<Option<String>> {
+    let mut latest: Option<(std::time::SystemTime, String)> = None;
+    let mut consider = |dir: &std::path::Path| {
+        let path = dir.join(LOG_FILE);
+        if let Ok(modified) = path.metadata().and_then(|m| m.modified()) {
+            if latest.as_ref().is_none_or(|(t, _)| modified > *t) {
+                latest = Some((modified, path.to_string_lossy().into_owned()));
+            }
+        }
+    };
+    let entries = match std::fs::read_dir(instances_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        consider(&dir);
+        for sub in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            if sub.path().is_dir() {
+                consider(&sub.path());
+            }
+        }
+    }
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Render a log line (`<unix ms> <source> <text>`) with a readable
+/// timestamp. Other lines are passed through.
+fn format_line(line: &str) -> String {
+    let mut parts = line.splitn(3, ' ');
+    let (Some(ts), Some(source)) = (parts.next(), parts.next()) else {
+        return line.to_string();
+    };
+    let Ok(ms) = ts.parse::<u64>() else {
+        return line.to_string();
+    };
+    let time = rfc3339(UNIX_EPOCH + Duration::from_millis(ms));
+    format!(
+        "{}.{:03}Z {:<7} {}",
+        time.trim_end_matches('Z'),
+        ms % 1000,
+        source,
+        parts.next().unwrap_or("")
+    )
+}
+
+/// Print log lines from `path` as they are written, until `stop` is set
+/// and the file has been read to the end.
+fn follow(path: &str, stop: &AtomicBool, out: &mut dyn std::io::Write) -> Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let mut reader = BufReader::new(file);
+    let mut pos = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n > 0 && line.ends_with('\n') {
+            pos += n as u64;
+            writeln!(out, "{}", format_line(line.trim_end_matches('\n')))?;
+            continue;
+        }
+        // Nothing new, or a partial line: wait for the rest
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        // The log is truncated when its instance is reused
+        if std::fs::metadata(path)
+            .map(|m| m.len() < pos)
+            .unwrap_or(false)
+        {
+            pos = 0;
+        }
+        reader.seek(SeekFrom::Start(pos))?;
+    }
+}
+
+/// `shuru logs`: print a console log, optionally following it.
+pub(crate) fn show(instance: Option<&str>, follow_log: bool) -> Result<()> {
+    let path = find_log(instance)?;
+    let stop = AtomicBool::new(!follow_log);
+    match follow(&path, &stop, &mut std::io::stdout().lock()) {
+        // e.g. piped into `head`
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Echo a VM's console log to stderr while it runs (`shuru -v`).
+pub(crate) struct LogEcho {
+    stop: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl LogEcho {
+    pub(crate) fn start(path: &str) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let path = path.to_string();
+        let thread_stop = Arc::clone(&stop);
+        let thread = std::thread::spawn(move || {
+            let _ = follow(&path, &thread_stop, &mut std::io::stderr());
+        });
+        LogEcho { stop, thread }
+    }
+
+    /// Print what is left of the log and stop.
+    pub(crate) fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
@@ -2,62 +2,98 @@ mod assets;
 mod checkpoint;
 mod cli;
 mod config;
+mod events;
+mod ext4;
+mod images;
+mod logs;
+mod mcp;
+mod oci;
+mod pool;
+mod serve;
+mod session;
 mod vm;
+mod volume;
 
 use std::process;
 use tracing::info;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
 
-use shuru_vm::{default_data_dir, Sandbox, VmState};
+use shuru_vm::{default_data_dir, Sandbox, SandboxError, VmState};
 
-use cli::{CheckpointCommands, Cli, Commands};
+use cli::{CheckpointCommands, Cli, Commands, ImageCommands, VolumeCommands};
 use config::load_config;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    tracing_subscriber::fmt().with_max_level(cli.verbose).init();
+    // stdout carries the MCP protocol, so `shuru mcp` logs to stderr
+    if matches!(cli.command, Commands::Mcp { .. }) {
+        tracing_subscriber::fmt()
+            .with_max_level(cli.verbose)
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_max_level(cli.verbose).init();
+    }
 
     match cli.command {
         Commands::Run {
             vm,
             from,
             console,
+            pool,
+            pool_socket,
+            events,
             command,
         } => {
             let mut vm = vm;
             vm.verbose = cli.verbose;
 
-            let cfg = load_config(vm.config.as_deref())?;
+            let run = || -> Result<i32> {
+                let cfg = load_config(vm.config.as_deref())?;
 
-            // Command resolution: CLI args > config > default /bin/sh
-            let command = if !command.is_empty() {
-                command
-            } else if let Some(cfg_cmd) = cfg.command.clone() {
-                cfg_cmd
-            } else {
-                vec!["/bin/sh".to_string()]
-            };
+                // Command resolution: CLI args > config > default /bin/sh
+                let command = if !command.is_empty() {
+                    command
+                } else if let Some(cfg_cmd) = cfg.command.clone() {
+                    cfg_cmd
+                } else {
+                    vec!["/bin/sh".to_string()]
+                };
+
+                if pool {
+                    let socket = pool_socket.unwrap_or_else(pool::default_socket);
+                    return pool::run_command(&vm, &cfg, &socket, &command, events.as_deref());
+                }
 
-            let prepared = vm::prepare_vm(&vm, &cfg, from.as_deref())?;
+                let prepared = vm::prepare_vm(&vm, &cfg, from.as_deref())?;
 
-            let exit_code = if console {
-                run_console(&prepared)?
-            } else {
-                vm::run_command(&prepared, &command)?
+                let exit_code = if console {
+                    run_console(&prepared, events.as_deref())?
+                } else {
+                    vm::run_command(&prepared, &command, events.as_deref())?
+                };
+
+                logs::remove_instance(&prepared.instance_dir);
+                Ok(exit_code)
             };
 
-            let _ = std::fs::remove_dir_all(&prepared.instance_dir);
-            process::exit(exit_code);
+            match run() {
+                Ok(exit_code) => process::exit(exit_code),
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    process::exit(run_error_code(&e));
+                }
+            }
         }
-        Commands::Init { force } => {
+        Commands::Init { force, from } => {
             let data_dir = default_data_dir();
-            if force {
-                let _ = std::fs::remove_file(format!("{}/VERSION", data_dir));
-            }
-            if assets::assets_ready(&data_dir) {
+            images::migrate_legacy(&data_dir)?;
+            if let Some(tarball) = from {
+                assets::install_os_image_from(&data_dir, &tarball)?;
+            } else if !force && assets::assets_ready(&data_dir) {
                 info!(
                     "shuru: OS image already up to date ({})",
                     assets::CURRENT_VERSION
@@ -66,10 +102,92 @@ fn main() -> Result<()> {
                 assets::download_os_image(&data_dir)?;
             }
         }
-        Commands::Upgrade => {
+        Commands::Image { action } => match action {
+            ImageCommands::Ls => images::print_list(&default_data_dir())?,
+            ImageCommands::Pull { image } => {
+                let (name, version) = images::parse_ref(&image)?;
+                images::pull(
+                    &default_data_dir(),
+                    name,
+                    version.unwrap_or(assets::CURRENT_VERSION),
+                )?;
+            }
+            ImageCommands::Rm { image } => {
+                for removed in images::remove(&default_data_dir(), &image)? {
+                    info!("shuru: removed image {}", removed);
+                }
+            }
+            ImageCommands::Fetch {
+                output,
+                image,
+                version,
+            } => {
+                let name = image.as_deref().unwrap_or(images::DEFAULT_IMAGE);
+                images::parse_ref(name)?;
+                let path = assets::fetch_os_image(&output, name, version.as_deref())?;
+                info!("shuru: saved {}", path);
+                info!("shuru: install offline with `shuru init --from {}`", path);
+            }
+        },
+        Commands::Upgrade {
+            channel,
+            version,
+            check,
+            rollback,
+        } => {
             let data_dir = default_data_dir();
-            assets::upgrade(&data_dir)?;
+            if rollback {
+                assets::rollback(&data_dir)?;
+            } else {
+                assets::upgrade(
+                    &data_dir,
+                    &assets::UpgradeOptions {
+                        channel,
+                        version: version.as_deref(),
+                        check,
+                    },
+                )?;
+            }
+        }
+        Commands::Pool {
+            vm,
+            from,
+            min_idle,
+            max,
+            idle_timeout,
+            socket,
+        } => {
+            let mut vm = vm;
+            vm.verbose = cli.verbose;
+            let cfg = load_config(vm.config.as_deref())?;
+            let prepared = vm::resolve_vm(&vm, &cfg, from.as_deref())?;
+            let socket = socket.unwrap_or_else(pool::default_socket);
+            pool::serve(
+                &prepared,
+                &socket,
+                &pool::PoolOptions {
+                    min_idle,
+                    max,
+                    idle_timeout,
+                },
+            )?;
         }
+        Commands::Mcp { vm, from } => {
+            let mut vm = vm;
+            vm.verbose = cli.verbose;
+            mcp::serve(&vm, from.as_deref())?;
+        }
+        Commands::Serve {
+            vm,
+            socket,
+            tcp,
+            token,
+        } => {
+            let mut vm = vm;
+            vm.verbose = cli.verbose;
+            serve::serve(vm, serve::ServeOptions { socket, tcp, token })?;
+        }
+        Commands::Logs { instance, follow } => logs::show(instance.as_deref(), follow)?,
         Commands::Prune => {
             let data_dir = default_data_dir();
             let instances_dir = format!("{}/instances", data_dir);
@@ -86,7 +204,12 @@ fn main() -> Result<()> {
             for entry in entries {
                 let entry = entry?;
                 let name = entry.file_name();
-                let Some(pid) = name.to_str().and_then(|s| s.parse::<i32>().ok()) else {
+                // `<pid>`, or `<kind>-<pid>` (e.g. a sandbox pool's VMs)
+                let Some(pid) = name
+                    .to_str()
+                    .map(|s| s.rsplit_once('-').map_or(s, |(_, pid)| pid))
+                    .and_then(|s| s.parse::<i32>().ok())
+                else {
                     continue;
                 };
                 // Check if the process is still running
@@ -103,6 +226,12 @@ fn main() -> Result<()> {
                 info!("shuru: removed {} orphaned instance(s)", removed);
             }
         }
+        Commands::Volume { action } => match action {
+            VolumeCommands::Create { name, size } => volume::create(&name, size)?,
+            VolumeCommands::Ls => volume::list()?,
+            VolumeCommands::Rm { name } => volume::remove(&name)?,
+            VolumeCommands::Resize { name, size } => volume::resize(&name, size)?,
+        },
         Commands::Checkpoint { action } => match action {
             CheckpointCommands::Create {
                 name,
@@ -117,14 +246,44 @@ fn main() -> Result<()> {
             }
             CheckpointCommands::List => checkpoint::list()?,
             CheckpointCommands::Delete { name } => checkpoint::delete(&name)?,
+            CheckpointCommands::Diff {
+                a,
+                b,
+                rootfs,
+                image,
+            } => checkpoint::diff(&a, b.as_deref(), rootfs.as_deref(), image.as_deref())?,
+            CheckpointCommands::Cat { spec } => checkpoint::cat(&spec)?,
+            CheckpointCommands::ExportOci {
+                name,
+                output,
+                tag,
+                exclude_agent,
+            } => checkpoint::export_oci(&name, output.as_deref(), tag.as_deref(), exclude_agent)?,
         },
     }
 
     Ok(())
 }
 
+/// Exit status for a failed `shuru run`, as with `docker run`: 127 if the
+/// command was not found in the guest, 126 if it could not be executed,
+/// and 125 if shuru itself failed.
+fn run_error_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<SandboxError>() {
+        Some(SandboxError::ExecSpawnFailed {
+            not_found: true, ..
+        }) => 127,
+        Some(SandboxError::ExecSpawnFailed { .. }) => 126,
+        _ => 125,
+    }
+}
+
 /// Run the VM in raw serial console mode (for debugging).
-fn run_console(prepared: &vm::PreparedVm) -> Result<i32> {
+fn run_console(prepared: &vm::PreparedVm, events: Option<&str>) -> Result<i32> {
+    // The proxy starts with the first agent connection, which never comes
+    if prepared.proxy.is_some() {
+        bail!("network mode \"proxy\" is not supported with --console");
+    }
     info!("shuru: kernel={}", prepared.kernel_path);
     info!("shuru: rootfs={} (work copy)", prepared.work_rootfs);
     info!(
@@ -137,21 +296,34 @@ fn run_console(prepared: &vm::PreparedVm) -> Result<i32> {
         .rootfs(&prepared.work_rootfs)
         .cpus(prepared.cpus)
         .memory_mb(prepared.memory)
-        .allow_net(prepared.allow_net);
+        .allow_net(prepared.allow_net)
+        .init_system(prepared.init_system);
 
     if let Some(initrd) = &prepared.initrd_path {
         info!("shuru: using initramfs: {}", initrd);
         builder = builder.initrd(initrd);
     }
 
+    if let Some(cmdline) = &prepared.cmdline {
+        builder = builder.cmdline(cmdline);
+    }
+    builder = builder.kernel_args(&prepared.kernel_args);
+    builder = vm::add_egress(builder, prepared);
+
     for m in &prepared.mounts {
         info!("shuru: mount {} -> {}", m.host_path, m.guest_path);
         builder = builder.mount(m.clone());
     }
+    builder = vm::add_volumes(builder, prepared);
+    // The console is on the terminal; the log gets the guest agent's records
+    builder = builder.console_log(vm::console_log_path(prepared));
 
     let sandbox = builder.build()?;
     info!("shuru: VM created and validated successfully");
 
+    let event_log = events
+        .map(|dest| events::EventLog::start(&sandbox, dest))
+        .transpose()?;
     let state_rx = sandbox.state_channel();
 
     info!("shuru: starting VM...");
@@ -176,5 +348,9 @@ fn run_console(prepared: &vm::PreparedVm) -> Result<i32> {
         }
     }
 
+    drop(sandbox);
+    if let Some(log) = event_log {
+        log.finish();
+    }
     Ok(exit_code)
 }
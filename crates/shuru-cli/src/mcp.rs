@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::time::Duration;
+use tracing::info;
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use shuru_vm::{PortMapping, Stdio};
+
+use crate::cli::VmArgs;
+use crate::config::load_config;
+use crate::session::{self, VmSession};
+use crate::vm;
+
+/// MCP revisions this server speaks, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+const DEFAULT_EXEC_TIMEOUT_SECS: u64 = 120;
+
+/// Output beyond this many bytes per stream is cut from `exec` results, so
+/// a chatty command does not flood the client's context.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Serve MCP on stdin/stdout until stdin closes. All tools share one
+/// sandbox, booted on first use and configured like `shuru run`.
+pub(crate) fn serve(vm_args: &VmArgs, from: Option<&str>) -> Result<()> {
+    let cfg = load_config(vm_args.config.as_deref())?;
+    let prepared = vm::prepare_vm(vm_args, &cfg, from)?;
+    let mut server = Server {
+        vm: VmSession::new(prepared),
+    };
+    info!("shuru: MCP server ready on stdio");
+    server.serve_stdio()
+}
+
+struct Server {
+    vm: VmSession,
+}
+
+impl Server {
+    fn serve_stdio(&mut self) -> Result<()> {
+        let stdin = std::io::stdin();
+        let mut stdout = std::io::stdout();
+        for line in stdin.lock().lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(reply) = self.handle_line(&line) {
+                writeln!(stdout, "{}", reply)?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle one JSON-RPC message, returning the response for requests and
+    /// `None` for notifications.
+    fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let msg: Value = match serde_json::from_str(line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                return Some(error_response(
+                    Value::Null,
+                    RpcError::new(PARSE_ERROR, e.to_string()),
+                ))
+            }
+        };
+        let Some(method) = msg.get("method").and_then(Value::as_str) else {
+            // Responses to requests we never send, or batches
+            let id = msg.get("id").cloned().unwrap_or(Value::Null);
+            return Some(error_response(
+                id,
+                RpcError::new(INVALID_REQUEST, "expected a single request object"),
+            ));
+        };
+        let params = msg.get("params").cloned().unwrap_or(Value::Null);
+
+        let Some(id) = msg.get("id").cloned() else {
+            // Notifications (initialized, cancelled) need no action: calls
+            // are handled one at a time, so there is nothing to cancel.
+            return None;
+        };
+
+        let result = match method {
+            "initialize" => Ok(initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tool_definitions() })),
+            "tools/call" => self.call_tool(params),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method not found: {}", method),
+            )),
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(err) => error_response(id, err),
+        })
+    }
+
+    fn call_tool(&mut self, params: Value) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        struct Call {
+            name: String,
+            #[serde(default)]
+            arguments: Value,
+        }
+        let call: Call = parse_params(params)?;
+        let args = match call.arguments {
+            Value::Null => json!({}),
+            args => args,
+        };
+
+        let result = match call.name.as_str() {
+            "exec" => self.exec(parse_params(args)?),
+            "read_file" => self.read_file(parse_params(args)?),
+            "write_file" => self.write_file(parse_params(args)?),
+            "list_files" => self.list_files(parse_params(args)?),
+            "port_forward" => self.port_forward(parse_params(args)?),
+            "checkpoint_create" => self.checkpoint_create(parse_params(args)?),
+            "checkpoint_restore" => self.checkpoint_restore(parse_params(args)?),
+            name => {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("unknown tool: {}", name),
+                ))
+            }
+        };
+
+        // Tool failures are results the model should see, not protocol errors
+        let (text, is_error) = match result {
+            Ok(text) => (text, false),
+            Err(e) => (format!("{:#}", e), true),
+        };
+        Ok(json!({
+            "content": [{ "type": "text", "text": text }],
+            "isError": is_error,
+        }))
+    }
+
+    // --- Tools ---
+
+    fn exec(&mut self, args: ExecArgs) -> Result<String> {
+        let env = self.vm.prepared().env.clone();
+        let sandbox = self.vm.sandbox()?;
+
+        let mut cmd = sandbox.command("sh");
+        cmd.args(["-c", args.command.as_str()])
+            .envs(env)
+            .envs(args.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(cwd) = &args.cwd {
+            cmd.current_dir(cwd);
+        }
+        let mut child = cmd.spawn()?;
+
+        let timeout = args.timeout_secs.unwrap_or(DEFAULT_EXEC_TIMEOUT_SECS);
+        let timed_out =
+            session::wait_or_kill(&mut child, Some(Duration::from_secs(timeout)), || false)?;
+        let output = child.wait_with_output()?;
+
+        let mut result = json!({
+            "exit_code": output.status.code(),
+            "stdout": truncate_output(&output.stdout),
+            "stderr": truncate_output(&output.stderr),
+        });
+        if let Some(signal) = output.status.signal() {
+            result["signal"] = json!(signal);
+        }
+        if timed_out {
+            result["timed_out"] = json!(true);
+            result["note"] = json!(format!("killed after {}s", timeout));
+        }
+        Ok(serde_json::to_string_pretty(&result)?)
+    }
+
+    fn read_file(&mut self, args: ReadFileArgs) -> Result<String> {
+        let data = self.vm.sandbox()?.read_file(&args.path)?;
+        match args.encoding {
+            Encoding::Base64 => Ok(BASE64.encode(&data)),
+            Encoding::Utf8 => String::from_utf8(data).map_err(|_| {
+                anyhow::anyhow!(
+                    "{} is not UTF-8 text; read it with \"encoding\": \"base64\"",
+                    args.path
+                )
+            }),
+        }
+    }
+
+    fn write_file(&mut self, args: WriteFileArgs) -> Result<String> {
+        let data = match args.encoding {
+            Encoding::Utf8 => args.content.into_bytes(),
+            Encoding::Base64 => BASE64
+                .decode(args.content.as_bytes())
+                .context("content is not valid base64")?,
+        };
+        let mode = args
+            .mode
+            .as_deref()
+            .map(|m| u32::from_str_radix(m, 8).with_context(|| format!("invalid mode: '{}'", m)))
+            .transpose()?;
+        self.vm.sandbox()?.write_file(&args.path, &data, mode)?;
+        Ok(format!("Wrote {} bytes to {}", data.len(), args.path))
+    }
+
+    fn list_files(&mut self, args: ListFilesArgs) -> Result<String> {
+        let output = self
+            .vm
+            .sandbox()?
+            .command("ls")
+            .args(["-lA", "--", args.path.as_str()])
+            .output()?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim_end());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn port_forward(&mut self, args: PortForwardArgs) -> Result<String> {
+        let mapping = PortMapping {
+            host_port: args.host_port.unwrap_or(args.guest_port),
+            guest_port: args.guest_port,
+        };
+        // Boot first so a bind failure is reported now
+        self.vm.sandbox()?;
+        self.vm.add_forward(mapping.clone())?;
+        Ok(format!(
+            "Forwarding 127.0.0.1:{} to guest port {}",
+            mapping.host_port, mapping.guest_port
+        ))
+    }
+
+    fn checkpoint_create(&mut self, args: CheckpointArgs) -> Result<String> {
+        self.vm.save_checkpoint(&args.name)?;
+        Ok(format!(
+            "Saved checkpoint '{}'. The VM was stopped to take it and boots again \
+             on the next call; running processes did not survive.",
+            args.name
+        ))
+    }
+
+    fn checkpoint_restore(&mut self, args: CheckpointArgs) -> Result<String> {
+        self.vm.restore_checkpoint(&args.name)?;
+        Ok(format!(
+            "Restored checkpoint '{}'. The VM boots from it on the next call.",
+            args.name
+        ))
+    }
+}
+
+// --- Tool arguments ---
+
+#[derive(Deserialize)]
+struct ExecArgs {
+    command: String,
+    cwd: Option<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    #[default]
+    #[serde(alias = "utf-8")]
+    Utf8,
+    Base64,
+}
+
+#[derive(Deserialize)]
+struct ReadFileArgs {
+    path: String,
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+#[derive(Deserialize)]
+struct WriteFileArgs {
+    path: String,
+    content: String,
+    #[serde(default)]
+    encoding: Encoding,
+    /// Octal permission bits, e.g. "755".
+    mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListFilesArgs {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct PortForwardArgs {
+    guest_port: u16,
+    host_port: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct CheckpointArgs {
+    name: String,
+}
+
+fn tool_definitions() -> Value {
+    json!([
+        {
+            "name": "exec",
+            "description": "Run a shell command (sh -c) in the sandbox VM. Returns its exit code, stdout and stderr.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "Shell command line" },
+                    "cwd": { "type": "string", "description": "Working directory in the guest" },
+                    "env": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                        "description": "Extra environment variables"
+                    },
+                    "timeout_secs": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "description": format!("Kill the command after this many seconds (default: {})", DEFAULT_EXEC_TIMEOUT_SECS)
+                    }
+                },
+                "required": ["command"]
+            }
+        },
+        {
+            "name": "read_file",
+            "description": "Read a file from the sandbox VM.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Absolute path in the guest" },
+                    "encoding": { "type": "string", "enum": ["utf8", "base64"], "description": "How to return the contents (default: utf8)" }
+                },
+                "required": ["path"]
+            }
+        },
+        {
+            "name": "write_file",
+            "description": "Create or overwrite a file in the sandbox VM, creating parent directories.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Absolute path in the guest" },
+                    "content": { "type": "string" },
+                    "encoding": { "type": "string", "enum": ["utf8", "base64"], "description": "How content is encoded (default: utf8)" },
+                    "mode": { "type": "string", "description": "Octal permissions, e.g. \"755\"" }
+                },
+                "required": ["path", "content"]
+            }
+        },
+        {
+            "name": "list_files",
+            "description": "List a directory in the sandbox VM (ls -lA).",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path in the guest" }
+                },
+                "required": ["path"]
+            }
+        },
+        {
+            "name": "port_forward",
+            "description": "Forward a port on the host's 127.0.0.1 to a port in the sandbox VM.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "guest_port": { "type": "integer", "minimum": 1, "maximum": 65535 },
+                    "host_port": { "type": "integer", "minimum": 1, "maximum": 65535, "description": "Defaults to guest_port" }
+                },
+                "required": ["guest_port"]
+            }
+        },
+        {
+            "name": "checkpoint_create",
+            "description": "Save the sandbox's disk as a named checkpoint. Stops the VM; it boots again on the next call.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string" }
+                },
+                "required": ["name"]
+            }
+        },
+        {
+            "name": "checkpoint_restore",
+            "description": "Replace the sandbox's disk with a named checkpoint. Stops the VM; it boots from the checkpoint on the next call.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string" }
+                },
+                "required": ["name"]
+            }
+        }
+    ])
+}
+
+// --- JSON-RPC ---
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": err.code, "message": err.message },
+    })
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn initialize(params: &Value) -> Value {
+    // Answer with the client's revision if we speak it, else our newest
+    let requested = params.get("protocolVersion").and_then(Value::as_str);
+    let version = requested
+        .filter(|v| PROTOCOL_VERSIONS.contains(v))
+        .unwrap_or(PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": { "name": "shuru", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+// --- Helpers ---
+
+fn truncate_output(data: &[u8]) -> String {
+    if data.len() <= MAX_OUTPUT_BYTES {
+        return String::from_utf8_lossy(data).into_owned();
+    }
+    format!(
+        "{}\n[... {} more bytes truncated]",
+        String::from_utf8_lossy(&data[..MAX_OUTPUT_BYTES]),
+        data.len() - MAX_OUTPUT_BYTES
+    )
+}
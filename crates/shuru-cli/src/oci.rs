@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tar::{EntryType, Header};
+
+use crate::ext4::{Ext4Image, FileType};
+
+/// Guest agent path inside the rootfs (installed by the prepare-rootfs scripts).
+pub(crate) const AGENT_PATH: &str = "usr/bin/shuru-init";
+
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+pub(crate) struct ExportOptions<'a> {
+    pub tag: &'a str,
+    pub exclude_agent: bool,
+    pub created_by: String,
+}
+
+#[derive(Serialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: &'static str,
+    digest: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<HashMap<&'static str, String>>,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    #[serde(rename = "mediaType")]
+    media_type: &'static str,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Serialize)]
+struct Index {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Serialize)]
+struct ImageConfig {
+    created: String,
+    architecture: &'static str,
+    os: &'static str,
+    config: RuntimeConfig,
+    rootfs: RootFs,
+    history: Vec<History>,
+}
+
+#[derive(Serialize)]
+struct RuntimeConfig {
+    #[serde(rename = "Env")]
+    env: Vec<String>,
+    #[serde(rename = "Cmd")]
+    cmd: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RootFs {
+    #[serde(rename = "type")]
+    fs_type: &'static str,
+    diff_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct History {
+    created: String,
+    created_by: String,
+}
+
+/// OCI name of the host architecture, which is also the guest's.
+fn oci_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        "x86_64" => "amd64",
+        arch => arch,
+    }
+}
+
+/// Export the filesystem in an ext4 image as a single-layer OCI image layout
+/// tarball, loadable with e.g. `docker load` or `skopeo copy oci-archive:`.
+pub(crate) fn export(
+    image_path: &str,
+    output: &str,
+    scratch_dir: &str,
+    opts: &ExportOptions,
+) -> Result<()> {
+    let image = Ext4Image::open(image_path)?;
+
+    // Build the gzipped layer in a scratch file, hashing both the
+    // uncompressed stream (diff_id) and the compressed blob (digest).
+    let layer_path = format!("{}/layer.tar.gz", scratch_dir);
+    let layer_file = fs::File::create(&layer_path)
+        .with_context(|| format!("failed to create {}", layer_path))?;
+    let gz = GzEncoder::new(HashWriter::new(layer_file), Compression::default());
+    let mut builder = tar::Builder::new(HashWriter::new(gz));
+    write_layer(&image, &mut builder, opts.exclude_agent)?;
+
+    let diff_writer = builder.into_inner().context("failed to finish layer tar")?;
+    let (gz, diff_id) = diff_writer.finish();
+    let blob_writer = gz.finish().context("failed to finish layer compression")?;
+    let (_, layer_digest) = blob_writer.finish();
+    let layer_size = fs::metadata(&layer_path)?.len();
+
+    let created = rfc3339(SystemTime::now());
+    let config = ImageConfig {
+        created: created.clone(),
+        architecture: oci_architecture(),
+        os: "linux",
+        config: RuntimeConfig {
+            env: vec![
+                "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_string(),
+            ],
+            cmd: vec!["/bin/sh".to_string()],
+        },
+        rootfs: RootFs {
+            fs_type: "layers",
+            diff_ids: vec![diff_id],
+        },
+        history: vec![History {
+            created,
+            created_by: opts.created_by.clone(),
+        }],
+    };
+    let config_json = serde_json::to_vec(&config)?;
+
+    let manifest = Manifest {
+        schema_version: 2,
+        media_type: MEDIA_TYPE_MANIFEST,
+        config: Descriptor {
+            media_type: MEDIA_TYPE_CONFIG,
+            digest: sha256_digest(&config_json),
+            size: config_json.len() as u64,
+            annotations: None,
+        },
+        layers: vec![Descriptor {
+            media_type: MEDIA_TYPE_LAYER,
+            digest: layer_digest.clone(),
+            size: layer_size,
+            annotations: None,
+        }],
+    };
+    let manifest_json = serde_json::to_vec(&manifest)?;
+
+    let index = Index {
+        schema_version: 2,
+        manifests: vec![Descriptor {
+            media_type: MEDIA_TYPE_MANIFEST,
+            digest: sha256_digest(&manifest_json),
+            size: manifest_json.len() as u64,
+            annotations: Some(HashMap::from([(
+                "org.opencontainers.image.ref.name",
+                opts.tag.to_string(),
+            )])),
+        }],
+    };
+    let index_json = serde_json::to_vec(&index)?;
+
+    let out = fs::File::create(output).with_context(|| format!("failed to create {}", output))?;
+    let mut archive = tar::Builder::new(out);
+    append_bytes(
+        &mut archive,
+        "oci-layout",
+        br#"{"imageLayoutVersion":"1.0.0"}"#,
+    )?;
+    append_bytes(&mut archive, "index.json", &index_json)?;
+    append_bytes(
+        &mut archive,
+        &blob_path(&manifest.config.digest),
+        &config_json,
+    )?;
+    append_bytes(
+        &mut archive,
+        &blob_path(&index.manifests[0].digest),
+        &manifest_json,
+    )?;
+
+    let mut header = Header::new_gnu();
+    header.set_size(layer_size);
+    header.set_mode(0o644);
+    header.set_entry_type(EntryType::Regular);
+    archive.append_data(
+        &mut header,
+        blob_path(&layer_digest),
+        fs::File::open(&layer_path)?,
+    )?;
+    archive.into_inner()?.sync_all()?;
+
+    let _ = fs::remove_file(&layer_path);
+    Ok(())
+}
+
+fn write_layer<W: Write>(
+    image: &Ext4Image,
+    builder: &mut tar::Builder<W>,
+    exclude_agent: bool,
+) -> Result<()> {
+    // First path seen for each multiply-linked inode, to emit hard links.
+    let mut hardlinks: HashMap<u32, String> = HashMap::new();
+
+    image.walk(|path, inode| {
+        if path == "lost+found" || path.starts_with("lost+found/") {
+            return Ok(());
+        }
+        if exclude_agent && path == AGENT_PATH {
+            return Ok(());
+        }
+
+        let mut header = Header::new_gnu();
+        header.set_mode(inode.permissions());
+        header.set_uid(inode.uid as u64);
+        header.set_gid(inode.gid as u64);
+        header.set_mtime(inode.mtime.max(0) as u64);
+        header.set_size(0);
+
+        match inode.file_type() {
+            FileType::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                builder.append_data(&mut header, format!("{}/", path), io::empty())?;
+            }
+            FileType::Regular => {
+                if inode.links > 1 {
+                    if let Some(target) = hardlinks.get(&inode.ino) {
+                        header.set_entry_type(EntryType::Link);
+                        builder.append_link(&mut header, path, target)?;
+                        return Ok(());
+                    }
+                    hardlinks.insert(inode.ino, path.to_string());
+                }
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(inode.size);
+                builder
+                    .append_data(&mut header, path, image.reader(inode)?)
+                    .with_context(|| format!("failed to add {} to layer", path))?;
+            }
+            FileType::Symlink => {
+                let target = image.read_link(inode)?;
+                header.set_entry_type(EntryType::Symlink);
+                builder.append_link(
+                    &mut header,
+                    path,
+                    String::from_utf8_lossy(&target).as_ref(),
+                )?;
+            }
+            FileType::CharDevice | FileType::BlockDevice => {
+                let (major, minor) = inode.device();
+                header.set_entry_type(if inode.file_type() == FileType::CharDevice {
+                    EntryType::Char
+                } else {
+                    EntryType::Block
+                });
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+                builder.append_data(&mut header, path, io::empty())?;
+            }
+            FileType::Fifo => {
+                header.set_entry_type(EntryType::Fifo);
+                builder.append_data(&mut header, path, io::empty())?;
+            }
+            // Sockets cannot be represented in a tar layer.
+            FileType::Socket | FileType::Unknown => {}
+        }
+        Ok(())
+    })?;
+
+    builder.finish()?;
+    Ok(())
+}
+
+fn append_bytes(archive: &mut tar::Builder<fs::File>, path: &str, data: &[u8]) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_entry_type(EntryType::Regular);
+    archive.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Writer adapter that hashes everything written through it.
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the inner writer and the `sha256:<hex>` digest.
+    fn finish(self) -> (W, String) {
+        (self.inner, format!("sha256:{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Format a timestamp as RFC 3339 in UTC (e.g. `2024-05-01T12:00:00Z`).
+pub(crate) fn rfc3339(t: SystemTime) -> String {
+    let secs = t
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as i64;
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // Civil-from-days (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,105 @@
+use std::io::IsTerminal;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+use anyhow::{bail, Context, Result};
+
+use shuru_vm::SandboxPool;
+
+use crate::cli::VmArgs;
+use crate::config::ShuruConfig;
+use crate::events::EventLog;
+use crate::vm::{self, PreparedVm};
+
+pub(crate) struct PoolOptions {
+    pub min_idle: usize,
+    pub max: usize,
+    pub idle_timeout: Option<u64>,
+}
+
+pub(crate) fn default_socket() -> String {
+    format!("{}/pool.sock", shuru_vm::default_data_dir())
+}
+
+/// Keep VMs booted from `prepared` and serve `shuru run --pool` until killed.
+pub(crate) fn serve(prepared: &PreparedVm, socket: &str, opts: &PoolOptions) -> Result<()> {
+    if !prepared.forwards.is_empty() || !prepared.exposes.is_empty() {
+        bail!("port forwards are not supported for pooled VMs");
+    }
+    if let Some(v) = prepared.volumes.iter().find(|v| !v.read_only) {
+        bail!(
+            "volume '{}' must be attached read-only (:ro) to be shared by pooled VMs",
+            v.name
+        );
+    }
+
+    if Path::new(socket).exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!("a pool is already listening on {}", socket);
+        }
+        std::fs::remove_file(socket)?;
+    }
+
+    let mut builder = SandboxPool::builder(vm::sandbox_builder(prepared))
+        .work_dir(&prepared.instance_dir)
+        .disk_size_mb(prepared.disk_size)
+        .min_idle(opts.min_idle)
+        .max_size(opts.max);
+    if let Some(secs) = opts.idle_timeout {
+        builder = builder.idle_timeout(Duration::from_secs(secs));
+    }
+    let pool = builder.build()?;
+
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("Failed to bind {}", socket))?;
+    info!(
+        "shuru: pool listening on {} ({} ready, up to {} VMs)",
+        socket, opts.min_idle, opts.max
+    );
+    Ok(pool.serve(listener)?)
+}
+
+/// Run a command in a VM leased from the pool at `socket`.
+pub(crate) fn run_command(
+    vm: &VmArgs,
+    cfg: &ShuruConfig,
+    socket: &str,
+    command: &[String],
+    events: Option<&str>,
+) -> Result<i32> {
+    if !vm.expose.is_empty() {
+        bail!("--expose is not supported with --pool");
+    }
+    if !vm.mount.is_empty() || !vm.port.is_empty() {
+        bail!("--mount and --port are set by the pool (`shuru pool`), not by `run --pool`");
+    }
+    if !Path::new(socket).exists() {
+        bail!(
+            "no pool is listening on {}. Start one with `shuru pool`.",
+            socket
+        );
+    }
+    let env = vm::merge_env(vm, cfg)?;
+
+    let sandbox = SandboxPool::client(socket);
+    let event_log = events
+        .map(|dest| EventLog::start(&sandbox, dest))
+        .transpose()?;
+    let exit_code = if std::io::stdin().is_terminal() {
+        sandbox.shell(command, &env)
+    } else {
+        sandbox.exec(
+            command,
+            &env,
+            &mut std::io::stdout(),
+            &mut std::io::stderr(),
+        )
+    };
+    drop(sandbox);
+    if let Some(log) = event_log {
+        log.finish();
+    }
+    Ok(exit_code?)
+}
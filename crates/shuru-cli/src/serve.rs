@@ -0,0 +1,871 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+
+use shuru_vm::{Child, PortMapping, SandboxError, Stdio};
+
+use crate::cli::VmArgs;
+use crate::config::{load_config, NetworkMode, ShuruConfig};
+use crate::session::{self, VmSession};
+use crate::vm;
+
+const OPENAPI: &str = include_str!("openapi.json");
+
+/// Bytes read from a command's output per streamed event.
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+pub(crate) struct ServeOptions {
+    pub socket: Option<String>,
+    pub tcp: Option<u16>,
+    pub token: Option<String>,
+}
+
+pub(crate) fn default_socket() -> String {
+    format!("{}/serve.sock", shuru_vm::default_data_dir())
+}
+
+/// Serve the HTTP API until killed. `defaults` and the config file apply to
+/// every sandbox unless a create request overrides them.
+pub(crate) fn serve(defaults: VmArgs, opts: ServeOptions) -> Result<()> {
+    let cfg = load_config(defaults.config.as_deref())?;
+
+    let server = match opts.tcp {
+        Some(port) => {
+            if opts.token.is_none() {
+                bail!("a TCP listener needs --token (or SHURU_SERVE_TOKEN)");
+            }
+            let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+                .with_context(|| format!("Failed to bind 127.0.0.1:{}", port))?;
+            info!("shuru: API listening on http://127.0.0.1:{}", port);
+            tiny_http::Server::from_listener(listener, None)
+        }
+        None => {
+            let socket = opts.socket.unwrap_or_else(default_socket);
+            if Path::new(&socket).exists() {
+                if UnixStream::connect(&socket).is_ok() {
+                    bail!("a server is already listening on {}", socket);
+                }
+                std::fs::remove_file(&socket)?;
+            }
+            let listener = UnixListener::bind(&socket)
+                .with_context(|| format!("Failed to bind {}", socket))?;
+            std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))?;
+            info!("shuru: API listening on {}", socket);
+            tiny_http::Server::from_listener(listener, None)
+        }
+    }
+    .map_err(|e| anyhow::anyhow!("Failed to start API server: {}", e))?;
+
+    let api = Arc::new(Api {
+        defaults,
+        cfg,
+        token: opts.token,
+        sandboxes: Mutex::new(BTreeMap::new()),
+        next_id: AtomicU64::new(1),
+    });
+    for request in server.incoming_requests() {
+        let api = Arc::clone(&api);
+        std::thread::spawn(move || api.handle(request));
+    }
+    Ok(())
+}
+
+// --- Routing ---
+
+struct Api {
+    defaults: VmArgs,
+    cfg: ShuruConfig,
+    token: Option<String>,
+    sandboxes: Mutex<BTreeMap<String, Arc<Mutex<VmSession>>>>,
+    next_id: AtomicU64,
+}
+
+type Reply = Response<Cursor<Vec<u8>>>;
+
+impl Api {
+    fn handle(&self, mut request: Request) {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let query = parse_query(query);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let method = request.method().clone();
+
+        let public = matches!(
+            (&method, segments.as_slice()),
+            (Method::Get, ["v1", "health"]) | (Method::Get, ["v1", "openapi.json"])
+        );
+        if !public && !self.authorized(&request) {
+            let _ = request.respond(error_reply(401, "missing or invalid bearer token"));
+            return;
+        }
+
+        // Streaming exec writes its own response
+        if let (Method::Post, ["v1", "sandboxes", id, "exec"]) = (&method, segments.as_slice()) {
+            if accepts_event_stream(&request) {
+                let id = id.to_string();
+                if let Err(e) = self.exec_stream(request, &id) {
+                    warn!("shuru: exec stream failed: {:#}", e);
+                }
+                return;
+            }
+        }
+
+        let reply = match (&method, segments.as_slice()) {
+            (Method::Get, ["v1", "health"]) => Ok(json_reply(
+                200,
+                json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION") }),
+            )),
+            (Method::Get, ["v1", "openapi.json"]) => {
+                Ok(Response::from_string(OPENAPI).with_header(content_type("application/json")))
+            }
+            (Method::Get, ["v1", "sandboxes"]) => Ok(self.list()),
+            (Method::Post, ["v1", "sandboxes"]) => {
+                read_json(&mut request).and_then(|body| self.create(body))
+            }
+            (Method::Get, ["v1", "sandboxes", id]) => self
+                .session(id)
+                .map(|s| json_reply(200, describe(id, &s.lock().unwrap()))),
+            (Method::Delete, ["v1", "sandboxes", id]) => self.delete(id),
+            (Method::Post, ["v1", "sandboxes", id, "exec"]) => {
+                read_json(&mut request).and_then(|body| self.exec(id, body))
+            }
+            (Method::Get, ["v1", "sandboxes", id, "files"]) => self.read_file(id, &query),
+            (Method::Put, ["v1", "sandboxes", id, "files"]) => {
+                self.write_file(id, &query, &mut request)
+            }
+            (Method::Get, ["v1", "sandboxes", id, "forwards"]) => self
+                .session(id)
+                .map(|s| json_reply(200, forwards_json(s.lock().unwrap().forwards()))),
+            (Method::Post, ["v1", "sandboxes", id, "forwards"]) => {
+                read_json(&mut request).and_then(|body| self.add_forward(id, body))
+            }
+            (Method::Delete, ["v1", "sandboxes", id, "forwards", port]) => {
+                self.remove_forward(id, port)
+            }
+            (Method::Post, ["v1", "sandboxes", id, "checkpoints"]) => {
+                read_json(&mut request).and_then(|body| self.checkpoint(id, body))
+            }
+            _ => Err(ApiError::new(404, "no such route")),
+        };
+
+        let reply = reply.unwrap_or_else(|e| error_reply(e.status, &e.message));
+        let _ = request.respond(reply);
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let expected = format!("Bearer {}", token);
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .is_some_and(|h| constant_time_eq(h.value.as_bytes(), expected.as_bytes()))
+    }
+
+    fn session(&self, id: &str) -> ApiResult<Arc<Mutex<VmSession>>> {
+        self.sandboxes
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ApiError::new(404, format!("no sandbox '{}'", id)))
+    }
+
+    // --- Sandboxes ---
+
+    fn list(&self) -> Reply {
+        let sandboxes: Vec<Value> = self
+            .sandboxes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, s)| describe(id, &s.lock().unwrap()))
+            .collect();
+        json_reply(200, json!({ "sandboxes": sandboxes }))
+    }
+
+    fn create(&self, body: CreateSandbox) -> ApiResult<Reply> {
+        let mut args = self.defaults.clone();
+        args.image = body.image.or(args.image);
+        args.cpus = body.cpus.or(args.cpus);
+        args.memory = body.memory.or(args.memory);
+        args.disk_size = body.disk_size.or(args.disk_size);
+        args.allow_net |= body.allow_net;
+        args.allow_host.extend(body.allow_hosts);
+        args.network = body.network.or(args.network);
+        args.init_system |= body.init_system;
+        args.kernel_arg.extend(body.kernel_args);
+        args.env
+            .extend(body.env.iter().map(|(k, v)| format!("{}={}", k, v)));
+        args.mount.extend(body.mounts);
+        args.volume.extend(body.volumes);
+        args.port.extend(body.ports);
+        args.expose.extend(body.expose);
+
+        let id = format!("sb{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut prepared = vm::resolve_vm(&args, &self.cfg, body.from.as_deref())
+            .map_err(|e| ApiError::new(400, format!("{:#}", e)))?;
+        prepared.instance_dir = format!("{}/{}", prepared.instance_dir, id);
+        vm::make_working_copy(&mut prepared)?;
+
+        let mut session = VmSession::new(prepared);
+        session.sandbox()?;
+        info!("shuru: sandbox {} started", id);
+
+        let reply = json_reply(201, describe(&id, &session));
+        self.sandboxes
+            .lock()
+            .unwrap()
+            .insert(id, Arc::new(Mutex::new(session)));
+        Ok(reply)
+    }
+
+    fn delete(&self, id: &str) -> ApiResult<Reply> {
+        let session = self
+            .sandboxes
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| ApiError::new(404, format!("no sandbox '{}'", id)))?;
+        // Stopped once the last in-flight request lets go of it
+        drop(session);
+        info!("shuru: sandbox {} deleted", id);
+        Ok(Response::from_data(Vec::new()).with_status_code(204))
+    }
+
+    // --- Exec ---
+
+    fn spawn(&self, id: &str, body: &ExecRequest) -> ApiResult<Child> {
+        let argv = match (&body.argv, &body.command) {
+            (Some(argv), None) if !argv.is_empty() => argv.clone(),
+            (None, Some(command)) => vec!["sh".into(), "-c".into(), command.clone()],
+            _ => {
+                return Err(ApiError::new(
+                    400,
+                    "give either a non-empty \"argv\" or a \"command\" string",
+                ))
+            }
+        };
+
+        let session = self.session(id)?;
+        let mut session = session.lock().unwrap();
+        let env = session.prepared().env.clone();
+        let sandbox = session.sandbox()?;
+
+        let mut cmd = sandbox.command(&argv[0]);
+        cmd.args(&argv[1..])
+            .envs(env)
+            .envs(body.env.clone())
+            .stdin(if body.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(cwd) = &body.cwd {
+            cmd.current_dir(cwd);
+        }
+        let mut child = cmd.spawn()?;
+
+        // Fed from its own thread, so a command that writes output before
+        // consuming all its input cannot stall the request. A command may
+        // exit without reading it; dropping the pipe sends EOF.
+        if let (Some(input), Some(mut stdin)) = (body.stdin.clone(), child.stdin.take()) {
+            std::thread::spawn(move || {
+                let _ = stdin.write_all(input.as_bytes());
+            });
+        }
+        Ok(child)
+    }
+
+    fn exec(&self, id: &str, body: ExecRequest) -> ApiResult<Reply> {
+        let mut child = self.spawn(id, &body)?;
+        let timed_out = session::wait_or_kill(&mut child, body.timeout(), || false)?;
+        let output = child.wait_with_output()?;
+        Ok(json_reply(
+            200,
+            json!({
+                "exit_code": output.status.code(),
+                "signal": output.status.signal(),
+                "timed_out": timed_out,
+                "stdout": String::from_utf8_lossy(&output.stdout),
+                "stderr": String::from_utf8_lossy(&output.stderr),
+            }),
+        ))
+    }
+
+    /// Exec with output as server-sent events: `stdout` and `stderr` events
+    /// carrying `{"data": ...}` as it arrives, then one `exit` event.
+    fn exec_stream(&self, mut request: Request, id: &str) -> Result<()> {
+        let spawned = read_json(&mut request).and_then(|body: ExecRequest| {
+            let child = self.spawn(id, &body)?;
+            Ok((child, body.timeout()))
+        });
+        let (mut child, timeout) = match spawned {
+            Ok(spawned) => spawned,
+            Err(e) => return Ok(request.respond(error_reply(e.status, &e.message))?),
+        };
+
+        let (tx, rx) = mpsc::channel::<(&'static str, Value)>();
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let mut readers = Vec::new();
+        if let Some(pipe) = child.stdout.take() {
+            readers.push(spawn_reader("stdout", pipe, tx.clone()));
+        }
+        if let Some(pipe) = child.stderr.take() {
+            readers.push(spawn_reader("stderr", pipe, tx.clone()));
+        }
+        let waiter = {
+            let disconnected = Arc::clone(&disconnected);
+            std::thread::spawn(move || {
+                let outcome = session::wait_or_kill(&mut child, timeout, || {
+                    disconnected.load(Ordering::Relaxed)
+                })
+                .and_then(|timed_out| Ok((child.wait()?, timed_out)));
+                for reader in readers {
+                    let _ = reader.join();
+                }
+                let event = match outcome {
+                    Ok((status, timed_out)) => (
+                        "exit",
+                        json!({
+                            "exit_code": status.code(),
+                            "signal": status.signal(),
+                            "timed_out": timed_out,
+                        }),
+                    ),
+                    Err(e) => ("error", json!({ "error": format!("{:#}", e) })),
+                };
+                let _ = tx.send(event);
+            })
+        };
+
+        let mut out = request.into_writer();
+        write!(
+            out,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\n\
+             Transfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n"
+        )?;
+        out.flush()?;
+        for (event, data) in rx {
+            let frame = format!("event: {}\ndata: {}\n\n", event, data);
+            let sent = write!(out, "{:x}\r\n{}\r\n", frame.len(), frame).and_then(|_| out.flush());
+            if sent.is_err() {
+                // Client went away: kill the command rather than let it run on
+                disconnected.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+        let _ = waiter.join();
+        write!(out, "0\r\n\r\n")?;
+        Ok(out.flush()?)
+    }
+
+    // --- Files ---
+
+    fn read_file(&self, id: &str, query: &HashMap<String, String>) -> ApiResult<Reply> {
+        let path = query_path(query)?;
+        let data = self
+            .session(id)?
+            .lock()
+            .unwrap()
+            .sandbox()?
+            .read_file(path)?;
+        Ok(Response::from_data(data).with_header(content_type("application/octet-stream")))
+    }
+
+    fn write_file(
+        &self,
+        id: &str,
+        query: &HashMap<String, String>,
+        request: &mut Request,
+    ) -> ApiResult<Reply> {
+        let path = query_path(query)?;
+        let mode = query
+            .get("mode")
+            .map(|m| {
+                u32::from_str_radix(m, 8)
+                    .map_err(|_| ApiError::new(400, format!("invalid mode: '{}'", m)))
+            })
+            .transpose()?;
+        let mut data = Vec::new();
+        request.as_reader().read_to_end(&mut data)?;
+        self.session(id)?
+            .lock()
+            .unwrap()
+            .sandbox()?
+            .write_file(path, &data, mode)?;
+        Ok(Response::from_data(Vec::new()).with_status_code(204))
+    }
+
+    // --- Port forwards ---
+
+    fn add_forward(&self, id: &str, body: ForwardRequest) -> ApiResult<Reply> {
+        let mapping = PortMapping {
+            host_port: body.host_port.unwrap_or(body.guest_port),
+            guest_port: body.guest_port,
+        };
+        let session = self.session(id)?;
+        let mut session = session.lock().unwrap();
+        session.sandbox()?;
+        session
+            .add_forward(mapping.clone())
+            .map_err(|e| ApiError::from(e).or_status(409))?;
+        Ok(json_reply(
+            201,
+            json!({ "host_port": mapping.host_port, "guest_port": mapping.guest_port }),
+        ))
+    }
+
+    fn remove_forward(&self, id: &str, port: &str) -> ApiResult<Reply> {
+        let port: u16 = port
+            .parse()
+            .map_err(|_| ApiError::new(400, format!("invalid port: '{}'", port)))?;
+        if !self.session(id)?.lock().unwrap().remove_forward(port) {
+            return Err(ApiError::new(
+                404,
+                format!("port {} is not forwarded", port),
+            ));
+        }
+        Ok(Response::from_data(Vec::new()).with_status_code(204))
+    }
+
+    // --- Checkpoints ---
+
+    /// Save the sandbox's disk as a checkpoint, then boot it again so it
+    /// stays usable; running processes do not survive.
+    fn checkpoint(&self, id: &str, body: CheckpointRequest) -> ApiResult<Reply> {
+        let session = self.session(id)?;
+        let mut session = session.lock().unwrap();
+        session
+            .save_checkpoint(&body.name)
+            .map_err(|e| ApiError::from(e).or_status(400))?;
+        session.sandbox()?;
+        info!("shuru: sandbox {} saved as checkpoint '{}'", id, body.name);
+        Ok(json_reply(201, json!({ "name": body.name })))
+    }
+}
+
+fn spawn_reader(
+    event: &'static str,
+    mut pipe: impl Read + Send + 'static,
+    tx: mpsc::Sender<(&'static str, Value)>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let data = json!({ "data": String::from_utf8_lossy(&buf[..n]) });
+                    if tx.send((event, data)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+// --- Request bodies ---
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateSandbox {
+    image: Option<String>,
+    from: Option<String>,
+    cpus: Option<usize>,
+    memory: Option<u64>,
+    disk_size: Option<u64>,
+    #[serde(default)]
+    allow_net: bool,
+    /// DOMAIN or CIDR, as for `--allow-host`.
+    #[serde(default)]
+    allow_hosts: Vec<String>,
+    /// As for `--network`.
+    network: Option<NetworkMode>,
+    #[serde(default)]
+    init_system: bool,
+    /// Appended to the kernel command line, as for `--kernel-arg`.
+    #[serde(default)]
+    kernel_args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// HOST:GUEST[:ro|rw], as for `--mount`.
+    #[serde(default)]
+    mounts: Vec<String>,
+    /// NAME:GUEST[:ro|rw], as for `--volume`.
+    #[serde(default)]
+    volumes: Vec<String>,
+    /// HOST:GUEST, as for `--port`.
+    #[serde(default)]
+    ports: Vec<String>,
+    /// HOST_ADDR:PORT:GUEST_PORT, as for `--expose`.
+    #[serde(default)]
+    expose: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExecRequest {
+    argv: Option<Vec<String>>,
+    /// Shell command line, run with `sh -c`.
+    command: Option<String>,
+    cwd: Option<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    stdin: Option<String>,
+    timeout_secs: Option<u64>,
+}
+
+impl ExecRequest {
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardRequest {
+    guest_port: u16,
+    host_port: Option<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckpointRequest {
+    name: String,
+}
+
+// --- Responses and errors ---
+
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// Use `status` instead of a generic 500.
+    fn or_status(mut self, status: u16) -> Self {
+        if self.status == 500 {
+            self.status = status;
+        }
+        self
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        let status = match err.downcast_ref::<SandboxError>() {
+            Some(SandboxError::InvalidConfig(_))
+            | Some(SandboxError::Guest(_))
+            | Some(SandboxError::ExecSpawnFailed { .. }) => 400,
+            Some(SandboxError::PortBindFailed { .. }) => 409,
+            Some(SandboxError::Unsupported(_)) => 501,
+            Some(SandboxError::PoolUnavailable(_)) => 503,
+            _ => 500,
+        };
+        ApiError::new(status, format!("{:#}", err))
+    }
+}
+
+impl From<SandboxError> for ApiError {
+    fn from(err: SandboxError) -> Self {
+        anyhow::Error::from(err).into()
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(err: std::io::Error) -> Self {
+        anyhow::Error::from(err).into()
+    }
+}
+
+fn describe(id: &str, session: &VmSession) -> Value {
+    json!({
+        "id": id,
+        "running": session.is_running(),
+        "forwards": forwards_json(session.forwards()),
+    })
+}
+
+fn forwards_json(forwards: &[PortMapping]) -> Value {
+    forwards
+        .iter()
+        .map(|m| json!({ "host_port": m.host_port, "guest_port": m.guest_port }))
+        .collect()
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).unwrap()
+}
+
+fn json_reply(status: u16, body: Value) -> Reply {
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type("application/json"))
+}
+
+fn error_reply(status: u16, message: &str) -> Reply {
+    json_reply(status, json!({ "error": message }))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(request: &mut Request) -> ApiResult<T> {
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body)?;
+    serde_json::from_slice(&body).map_err(|e| ApiError::new(400, format!("invalid body: {}", e)))
+}
+
+fn accepts_event_stream(request: &Request) -> bool {
+    request
+        .headers()
+        .iter()
+        .any(|h| h.field.equiv("Accept") && h.value.as_str().contains("text/event-stream"))
+}
+
+fn query_path(query: &HashMap<String, String>) -> ApiResult<&str> {
+    match query.get("path") {
+        Some(path) if path.starts_with('/') => Ok(path),
+        _ => Err(ApiError::new(
+            400,
+            "\"path\" must be an absolute guest path",
+        )),
+    }
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Args, FromArgMatches};
+
+    /// An API server on a fresh Unix socket; returns the socket path.
+    fn start(token: Option<&str>) -> String {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let socket = std::env::temp_dir().join(format!(
+            "shuru-serve-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let server =
+            tiny_http::Server::from_listener(UnixListener::bind(&socket).unwrap(), None).unwrap();
+
+        let matches = VmArgs::augment_args(clap::Command::new("test")).get_matches_from(["test"]);
+        let api = Arc::new(Api {
+            defaults: VmArgs::from_arg_matches(&matches).unwrap(),
+            cfg: ShuruConfig::default(),
+            token: token.map(String::from),
+            sandboxes: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+        });
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let api = Arc::clone(&api);
+                std::thread::spawn(move || api.handle(request));
+            }
+        });
+        socket.to_string_lossy().into_owned()
+    }
+
+    /// Send one request and return the status code and JSON body.
+    fn request(socket: &str, method: &str, path: &str, headers: &str, body: &str) -> (u16, Value) {
+        let mut conn = UnixStream::connect(socket).unwrap();
+        write!(
+            conn,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            headers,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut reply = String::new();
+        conn.read_to_string(&mut reply).unwrap();
+        let status = reply[9..12].parse().unwrap();
+        let (_, body) = reply.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn routes_requests() {
+        let socket = start(None);
+        let (status, body) = request(&socket, "GET", "/v1/health", "", "");
+        assert_eq!((status, body["status"].as_str()), (200, Some("ok")));
+        assert_eq!(
+            request(&socket, "GET", "/v1/sandboxes", "", ""),
+            (200, json!({ "sandboxes": [] }))
+        );
+        assert_eq!(request(&socket, "GET", "/v1/openapi.json", "", "").0, 200);
+        assert_eq!(request(&socket, "GET", "/v1/nope", "", "").0, 404);
+        assert_eq!(request(&socket, "PUT", "/v1/sandboxes", "", "").0, 404);
+        assert_eq!(request(&socket, "GET", "/v1/sandboxes/sb9", "", "").0, 404);
+        assert_eq!(
+            request(&socket, "DELETE", "/v1/sandboxes/sb9", "", "").0,
+            404
+        );
+        assert_eq!(
+            request(
+                &socket,
+                "POST",
+                "/v1/sandboxes/sb9/exec",
+                "",
+                r#"{"argv":["true"]}"#
+            )
+            .0,
+            404
+        );
+
+        let (status, body) = request(&socket, "POST", "/v1/sandboxes", "", "{");
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().starts_with("invalid body"));
+
+        let (status, body) = request(&socket, "POST", "/v1/sandboxes", "", r#"{"from":"../x"}"#);
+        assert_eq!(status, 400);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid checkpoint name"));
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn requires_the_bearer_token() {
+        let socket = start(Some("s3cret"));
+        assert_eq!(request(&socket, "GET", "/v1/sandboxes", "", "").0, 401);
+        let wrong = "Authorization: Bearer s3cres\r\n";
+        assert_eq!(request(&socket, "GET", "/v1/sandboxes", wrong, "").0, 401);
+        let right = "Authorization: Bearer s3cret\r\n";
+        assert_eq!(request(&socket, "GET", "/v1/sandboxes", right, "").0, 200);
+        // Health and the schema stay public
+        assert_eq!(request(&socket, "GET", "/v1/health", "", "").0, 200);
+        assert_eq!(request(&socket, "GET", "/v1/openapi.json", "", "").0, 200);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn decodes_queries() {
+        assert_eq!(percent_decode("a%20b+c%2Fd"), "a b c/d");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%C3%A9"), "é");
+
+        let query = parse_query("path=%2Ftmp%2Fa+b&mode=644&flag&&");
+        assert_eq!(query.len(), 3);
+        assert_eq!(query["path"], "/tmp/a b");
+        assert_eq!(query["mode"], "644");
+        assert_eq!(query["flag"], "");
+    }
+
+    #[test]
+    fn requires_absolute_paths() {
+        assert_eq!(
+            query_path(&parse_query("path=/etc/hosts")).ok(),
+            Some("/etc/hosts")
+        );
+        for query in ["", "path=", "path=etc/hosts", "other=/etc/hosts"] {
+            assert_eq!(query_path(&parse_query(query)).err().unwrap().status, 400);
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        let status = |e: SandboxError| ApiError::from(e).status;
+        assert_eq!(status(SandboxError::InvalidConfig("x".into())), 400);
+        assert_eq!(status(SandboxError::Guest("x".into())), 400);
+        assert_eq!(
+            status(SandboxError::PortBindFailed {
+                port: 8080,
+                source: std::io::ErrorKind::AddrInUse.into(),
+            }),
+            409
+        );
+        assert_eq!(status(SandboxError::Unsupported("x".into())), 501);
+        assert_eq!(status(SandboxError::PoolUnavailable("x".into())), 503);
+        assert_eq!(status(SandboxError::Disconnected("x".into())), 500);
+
+        // Context doesn't hide the sandbox error
+        let wrapped = anyhow::Error::from(SandboxError::Unsupported("x".into())).context("outer");
+        assert_eq!(ApiError::from(wrapped).status, 501);
+
+        let generic = ApiError::from(anyhow::anyhow!("boom"));
+        assert_eq!((generic.status, generic.message.as_str()), (500, "boom"));
+        assert_eq!(generic.or_status(409).status, 409);
+        assert_eq!(ApiError::new(404, "x").or_status(409).status, 404);
+    }
+}
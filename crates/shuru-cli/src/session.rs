@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use anyhow::{bail, Result};
+
+use shuru_vm::{Child, PortForwardHandle, PortMapping, ReverseForwardHandle, Sandbox, Stdio};
+
+use crate::checkpoint;
+use crate::logs;
+use crate::vm::{self, PreparedVm};
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A sandbox on its own working disk that outlives a single command, for
+/// `shuru mcp` and `shuru serve`. The VM boots on first use and can be
+/// stopped and booted again, e.g. to checkpoint its disk; port forwards
+/// are reopened each time. Dropping the session stops the VM and removes
+/// its instance directory.
+pub(crate) struct VmSession {
+    prepared: PreparedVm,
+    running: Option<RunningVm>,
+    forwards: Vec<PortMapping>,
+}
+
+struct RunningVm {
+    /// Keyed by host port.
+    forwards: HashMap<u16, PortForwardHandle>,
+    exposed: Option<ReverseForwardHandle>,
+    sandbox: Sandbox,
+}
+
+impl VmSession {
+    /// A session for a prepared VM (with its working copy), forwarding
+    /// `prepared.forwards` once booted.
+    pub(crate) fn new(prepared: PreparedVm) -> Self {
+        let forwards = prepared.forwards.clone();
+        VmSession {
+            prepared,
+            running: None,
+            forwards,
+        }
+    }
+
+    pub(crate) fn prepared(&self) -> &PreparedVm {
+        &self.prepared
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    pub(crate) fn forwards(&self) -> &[PortMapping] {
+        &self.forwards
+    }
+
+    /// The running sandbox, booting it first if needed.
+    pub(crate) fn sandbox(&mut self) -> Result<&Sandbox> {
+        if self.running.is_none() {
+            self.running = Some(self.boot()?);
+        }
+        Ok(&self.running.as_ref().unwrap().sandbox)
+    }
+
+    fn boot(&self) -> Result<RunningVm> {
+        info!(
+            "shuru: booting VM ({}cpus, {}MB RAM, {}MB disk)...",
+            self.prepared.cpus, self.prepared.memory, self.prepared.disk_size
+        );
+        let sandbox = vm::sandbox_builder(&self.prepared)
+            .console_log(vm::console_log_path(&self.prepared))
+            .build()?;
+        sandbox.start()?;
+
+        let mut forwards = HashMap::new();
+        for mapping in &self.forwards {
+            let handle = sandbox.start_port_forwarding(std::slice::from_ref(mapping))?;
+            forwards.insert(mapping.host_port, handle);
+        }
+        let exposed = if self.prepared.exposes.is_empty() {
+            None
+        } else {
+            Some(sandbox.start_reverse_forwarding(&self.prepared.exposes)?)
+        };
+        Ok(RunningVm {
+            forwards,
+            exposed,
+            sandbox,
+        })
+    }
+
+    /// Stop the VM, if running. The next `sandbox()` boots it again from
+    /// the working disk.
+    pub(crate) fn stop(&mut self) {
+        if let Some(vm) = self.running.take() {
+            drop(vm.forwards);
+            drop(vm.exposed);
+            if let Err(e) = vm.sandbox.stop() {
+                warn!("shuru: failed to stop VM: {}", e);
+            }
+        }
+    }
+
+    /// Forward `mapping.host_port` on 127.0.0.1 to the guest, now if the VM
+    /// is running and whenever it boots.
+    pub(crate) fn add_forward(&mut self, mapping: PortMapping) -> Result<()> {
+        if self
+            .forwards
+            .iter()
+            .any(|m| m.host_port == mapping.host_port)
+        {
+            bail!("host port {} is already forwarded", mapping.host_port);
+        }
+        if let Some(vm) = &mut self.running {
+            let handle = vm
+                .sandbox
+                .start_port_forwarding(std::slice::from_ref(&mapping))?;
+            vm.forwards.insert(mapping.host_port, handle);
+        }
+        self.forwards.push(mapping);
+        Ok(())
+    }
+
+    /// Stop forwarding `host_port`. Returns false if it was not forwarded.
+    pub(crate) fn remove_forward(&mut self, host_port: u16) -> bool {
+        let before = self.forwards.len();
+        self.forwards.retain(|m| m.host_port != host_port);
+        if let Some(vm) = &mut self.running {
+            vm.forwards.remove(&host_port);
+        }
+        self.forwards.len() != before
+    }
+
+    /// Save the working disk as checkpoint `name`. The VM is stopped to
+    /// take it; running processes do not survive.
+    pub(crate) fn save_checkpoint(&mut self, name: &str) -> Result<()> {
+        checkpoint::checkpoint_path(&self.prepared.data_dir, name)?;
+        if let Some(vm) = &self.running {
+            // Flush the guest's page cache before cutting power
+            let _ = vm
+                .sandbox
+                .command("sync")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+        self.stop();
+
+        info!("shuru: saving checkpoint '{}'...", name);
+        checkpoint::save(&self.prepared, name)
+    }
+
+    /// Replace the working disk with checkpoint `name`, stopping the VM.
+    pub(crate) fn restore_checkpoint(&mut self, name: &str) -> Result<()> {
+        let path = checkpoint::checkpoint_path(&self.prepared.data_dir, name)?;
+        if !std::path::Path::new(&path).exists() {
+            bail!("Checkpoint '{}' not found", name);
+        }
+        self.stop();
+
+        info!("shuru: restoring checkpoint '{}'...", name);
+        vm::copy_disk(&path, &self.prepared.work_rootfs, self.prepared.disk_size)?;
+        self.prepared.base_rootfs = checkpoint::recorded_base(&self.prepared.data_dir, name);
+        Ok(())
+    }
+}
+
+impl Drop for VmSession {
+    fn drop(&mut self) {
+        self.stop();
+        logs::remove_instance(&self.prepared.instance_dir);
+    }
+}
+
+/// Wait for `child` to exit, killing it once `timeout` has passed or
+/// `cancelled` returns true. Returns whether it was killed.
+pub(crate) fn wait_or_kill(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    cancelled: impl Fn() -> bool,
+) -> Result<bool> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    while child.try_wait()?.is_none() {
+        if cancelled() || deadline.is_some_and(|d| Instant::now() >= d) {
+            child.kill()?;
+            return Ok(true);
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+    }
+    Ok(false)
+}
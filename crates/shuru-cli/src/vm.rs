@@ -4,34 +4,94 @@ use tracing::{enabled, info, Level};
 
 use anyhow::{bail, Context, Result};
 
-use shuru_vm::{MountConfig, PortMapping, Sandbox};
+use shuru_vm::{
+    DiskConfig, EgressPolicy, MountConfig, PortMapping, ReverseMapping, Sandbox, VmConfigBuilder,
+};
 
-use crate::assets;
+use crate::checkpoint;
 use crate::cli::VmArgs;
-use crate::config::ShuruConfig;
+use crate::config::{NetworkMode, ShuruConfig};
+use crate::events::EventLog;
+use crate::images;
+use crate::logs::{self, LogEcho};
+use crate::volume::{self, VolumeMount};
 
 pub(crate) struct PreparedVm {
     pub data_dir: String,
     pub instance_dir: String,
+    /// Root disk to boot: the per-instance working copy, or the source
+    /// image itself if only resolved (see `resolve_vm`).
     pub work_rootfs: String,
+    /// Image rootfs that `work_rootfs` descends from, through any
+    /// checkpoints; recorded with checkpoints for `checkpoint diff`. None
+    /// for checkpoints saved before it was recorded.
+    pub base_rootfs: Option<String>,
     pub kernel_path: String,
     pub initrd_path: Option<String>,
+    pub cmdline: Option<String>,
+    pub kernel_args: Vec<String>,
     pub cpus: usize,
     pub memory: u64,
     pub disk_size: u64,
     pub allow_net: bool,
+    /// Filter mode: network access limited to these destinations.
+    pub egress: Option<EgressPolicy>,
+    /// Proxy mode: HTTP(S) and DNS limited to these destinations.
+    pub proxy: Option<EgressPolicy>,
+    pub init_system: bool,
     pub verbose: bool,
     pub forwards: Vec<PortMapping>,
+    /// Host services the guest reaches through `--expose`.
+    pub exposes: Vec<ReverseMapping>,
     pub env: HashMap<String, String>,
     pub mounts: Vec<MountConfig>,
+    /// Attached and locked volumes.
+    pub volumes: Vec<VolumeMount>,
+    /// `--console-log`, instead of the instance's console.log.
+    pub console_log: Option<String>,
+    /// `--egress-log`, instead of the instance's egress.log.
+    pub egress_log: Option<String>,
 }
 
 /// Resolve config, create a CoW working copy of the rootfs, and extend it to disk_size.
 pub(crate) fn prepare_vm(vm: &VmArgs, cfg: &ShuruConfig, from: Option<&str>) -> Result<PreparedVm> {
+    let mut prepared = resolve_vm(vm, cfg, from)?;
+    make_working_copy(&mut prepared)?;
+    Ok(prepared)
+}
+
+/// Replace a resolved VM's source rootfs with a working copy in its
+/// `instance_dir`.
+pub(crate) fn make_working_copy(prepared: &mut PreparedVm) -> Result<()> {
+    let source = std::mem::take(&mut prepared.work_rootfs);
+    std::fs::create_dir_all(&prepared.instance_dir)?;
+    prepared.work_rootfs = format!("{}/rootfs.ext4", prepared.instance_dir);
+    info!("shuru: creating working copy...");
+    copy_disk(&source, &prepared.work_rootfs, prepared.disk_size)
+}
+
+/// Copy a root disk and extend the copy to `disk_size` MB.
+pub(crate) fn copy_disk(source: &str, dest: &str, disk_size: u64) -> Result<()> {
+    // CoW clone on APFS — near-instant
+    std::fs::copy(source, dest)?;
+
+    // Extend to requested disk size
+    let f = std::fs::OpenOptions::new().write(true).open(dest)?;
+    f.set_len(disk_size * 1024 * 1024)?;
+    Ok(())
+}
+
+/// Resolve config and the source rootfs (base image or checkpoint) without
+/// creating a working copy; `work_rootfs` is the source.
+pub(crate) fn resolve_vm(vm: &VmArgs, cfg: &ShuruConfig, from: Option<&str>) -> Result<PreparedVm> {
     let cpus = vm.cpus.or(cfg.cpus).unwrap_or(2);
     let memory = vm.memory.or(cfg.memory).unwrap_or(2048);
     let disk_size = vm.disk_size.or(cfg.disk_size).unwrap_or(4096);
-    let allow_net = vm.allow_net || cfg.allow_net.unwrap_or(false);
+    let (network, policy) = parse_network(vm, cfg)?;
+    let allow_net = network == NetworkMode::Nat;
+    let egress = (network == NetworkMode::Filter).then(|| policy.clone());
+    let proxy = (network == NetworkMode::Proxy).then_some(policy);
+    let init_system = vm.init_system || cfg.init_system.unwrap_or(false);
     let verbose = enabled!(Level::INFO);
 
     // Merge port forwards: CLI flags + config file
@@ -47,18 +107,19 @@ pub(crate) fn prepare_vm(vm: &VmArgs, cfg: &ShuruConfig, from: Option<&str>) ->
             parse_port_mapping(s).with_context(|| format!("invalid port mapping: '{}'", s))?;
         forwards.push(mapping);
     }
+    let exposes = cfg
+        .expose
+        .iter()
+        .flatten()
+        .chain(&vm.expose)
+        .map(|s| parse_expose_spec(s).with_context(|| format!("invalid --expose: '{}'", s)))
+        .collect::<Result<Vec<_>>>()?;
 
-    // Merge environment variables: config file + CLI flags
-    let mut env = HashMap::new();
-    if let Some(ref cfg_env) = cfg.env {
-        for (k, v) in cfg_env {
-            env.insert(k.clone(), v.clone());
-        }
-    }
-    for s in &vm.env {
-        let (k, v) = parse_env_var(s)?;
-        env.insert(k, v);
-    }
+    let env = merge_env(vm, cfg)?;
+
+    // Kernel arguments: config file, then CLI flags, so flags win
+    let mut kernel_args = cfg.kernel_args.clone().unwrap_or_default();
+    kernel_args.extend(vm.kernel_arg.iter().cloned());
 
     // Merge mounts: CLI flags + config file
     let mut mount_strs: Vec<&str> = vm.mount.iter().map(|s| s.as_str()).collect();
@@ -75,27 +136,48 @@ pub(crate) fn prepare_vm(vm: &VmArgs, cfg: &ShuruConfig, from: Option<&str>) ->
 
     let data_dir = shuru_vm::default_data_dir();
 
-    // Auto-download assets when using default paths
-    if vm.kernel.is_none()
-        && vm.rootfs.is_none()
-        && vm.initrd.is_none()
-        && !assets::assets_ready(&data_dir)
-    {
-        assets::download_os_image(&data_dir)?;
+    // Checkpoint to boot from, validated before anything is downloaded
+    let checkpoint = match from {
+        Some(name) => Some((name, checkpoint::checkpoint_path(&data_dir, name)?)),
+        None => None,
+    };
+
+    // Attach volumes: CLI flags + config file
+    let mut volumes = Vec::new();
+    for s in vm.volume.iter().chain(cfg.volumes.iter().flatten()) {
+        let v = volume::attach(&data_dir, s)
+            .with_context(|| format!("cannot attach volume '{}'", s))?;
+        volumes.push(v);
     }
 
-    let kernel_path = vm
-        .kernel
-        .clone()
-        .unwrap_or_else(|| format!("{}/Image", data_dir));
+    let kernel = vm.kernel.clone().or_else(|| cfg.kernel.clone());
+    let initrd = vm.initrd.clone().or_else(|| cfg.initrd.clone());
+
+    // Resolve the OS image (downloading it if needed) unless every path
+    // was given explicitly; explicit paths override the image's files.
+    let image_spec = vm.image.as_deref().or(cfg.image.as_deref());
+    let image = if kernel.is_some() && vm.rootfs.is_some() && initrd.is_some() {
+        None
+    } else {
+        let image = images::resolve(&data_dir, image_spec)?;
+        info!("shuru: image={}", image.reference());
+        Some(image)
+    };
+
+    let kernel_path = kernel
+        .or_else(|| image.as_ref().map(|i| i.kernel_path()))
+        .unwrap_or_default();
     let rootfs_path = vm
         .rootfs
         .clone()
-        .unwrap_or_else(|| format!("{}/rootfs-debian.ext4", data_dir));
-    let initrd_path_str = vm
-        .initrd
+        .or_else(|| image.as_ref().map(|i| i.rootfs_path()))
+        .unwrap_or_default();
+    let initrd_path_str = initrd.or_else(|| image.as_ref().and_then(|i| i.initrd_path()));
+    let cmdline = vm
+        .cmdline
         .clone()
-        .unwrap_or_else(|| format!("{}/initramfs.cpio.gz", data_dir));
+        .or_else(|| cfg.cmdline.clone())
+        .or_else(|| image.as_ref().and_then(|i| i.manifest.cmdline.clone()));
 
     if !std::path::Path::new(&kernel_path).exists() {
         bail!(
@@ -105,14 +187,12 @@ pub(crate) fn prepare_vm(vm: &VmArgs, cfg: &ShuruConfig, from: Option<&str>) ->
     }
 
     // Determine source for working copy: checkpoint or base rootfs
-    let checkpoints_dir = format!("{}/checkpoints", data_dir);
-    let source = match from {
-        Some(name) => {
-            let path = format!("{}/{}.ext4", checkpoints_dir, name);
+    let (source, base_rootfs) = match checkpoint {
+        Some((name, path)) => {
             if !std::path::Path::new(&path).exists() {
                 bail!("Checkpoint '{}' not found", name);
             }
-            path
+            (path, checkpoint::recorded_base(&data_dir, name))
         }
         None => {
             if !std::path::Path::new(&rootfs_path).exists() {
@@ -121,51 +201,58 @@ pub(crate) fn prepare_vm(vm: &VmArgs, cfg: &ShuruConfig, from: Option<&str>) ->
                     rootfs_path
                 );
             }
-            rootfs_path
+            (rootfs_path.clone(), Some(rootfs_path))
         }
     };
 
-    // Create per-instance working copy (CoW clone on APFS — near-instant)
     let instance_dir = format!("{}/instances/{}", data_dir, std::process::id());
-    std::fs::create_dir_all(&instance_dir)?;
-    let work_rootfs = format!("{}/rootfs.ext4", instance_dir);
-    info!("shuru: creating working copy...");
-    std::fs::copy(&source, &work_rootfs)?;
 
-    // Extend to requested disk size
-    let f = std::fs::OpenOptions::new().write(true).open(&work_rootfs)?;
-    f.set_len(disk_size * 1024 * 1024)?;
-    drop(f);
-
-    let initrd_path = if std::path::Path::new(&initrd_path_str).exists() {
-        Some(initrd_path_str)
-    } else {
-        info!(
-            "shuru: warning: initramfs not found at {}, booting without it",
-            initrd_path_str
-        );
-        None
+    let initrd_path = match initrd_path_str {
+        Some(path) if std::path::Path::new(&path).exists() => Some(path),
+        Some(path) => {
+            info!(
+                "shuru: warning: initramfs not found at {}, booting without it",
+                path
+            );
+            None
+        }
+        None => None,
     };
 
     Ok(PreparedVm {
         data_dir,
         instance_dir,
-        work_rootfs,
+        work_rootfs: source,
+        base_rootfs,
         kernel_path,
         initrd_path,
+        cmdline,
+        kernel_args,
         cpus,
         memory,
         disk_size,
         allow_net,
+        egress,
+        proxy,
+        init_system,
         verbose,
         forwards,
+        exposes,
         env,
         mounts,
+        volumes,
+        console_log: vm.console_log.clone(),
+        egress_log: vm.egress_log.clone(),
     })
 }
 
 /// Build a sandbox, start the VM, run the command, and return the exit code.
-pub(crate) fn run_command(prepared: &PreparedVm, command: &[String]) -> Result<i32> {
+/// With `events`, lifecycle events are written there as JSON lines.
+pub(crate) fn run_command(
+    prepared: &PreparedVm,
+    command: &[String],
+    events: Option<&str>,
+) -> Result<i32> {
     info!("shuru: kernel={}", prepared.kernel_path);
     info!("shuru: rootfs={} (work copy)", prepared.work_rootfs);
     info!(
@@ -173,27 +260,16 @@ pub(crate) fn run_command(prepared: &PreparedVm, command: &[String]) -> Result<i
         prepared.cpus, prepared.memory, prepared.disk_size
     );
 
-    let mut builder = Sandbox::builder()
-        .kernel(&prepared.kernel_path)
-        .rootfs(&prepared.work_rootfs)
-        .cpus(prepared.cpus)
-        .memory_mb(prepared.memory)
-        .allow_net(prepared.allow_net)
-        .console(false)
-        .quiet(!prepared.verbose);
-
-    if let Some(initrd) = &prepared.initrd_path {
-        info!("shuru: using initramfs: {}", initrd);
-        builder = builder.initrd(initrd);
-    }
-
-    for m in &prepared.mounts {
-        info!("shuru: mount {} -> {}", m.host_path, m.guest_path);
-        builder = builder.mount(m.clone());
-    }
-
-    let sandbox = builder.build()?;
+    let console_log = console_log_path(prepared);
+    let sandbox = sandbox_builder(prepared)
+        .console_log(&console_log)
+        .build()?;
     info!("shuru: VM created and validated successfully");
+    // The console no longer goes to stderr; show it there when verbose
+    let echo = prepared.verbose.then(|| LogEcho::start(&console_log));
+    let event_log = events
+        .map(|dest| EventLog::start(&sandbox, dest))
+        .transpose()?;
 
     info!("shuru: starting VM...");
     sandbox.start()?;
@@ -205,6 +281,11 @@ pub(crate) fn run_command(prepared: &PreparedVm, command: &[String]) -> Result<i
     } else {
         None
     };
+    let _exposed = if !prepared.exposes.is_empty() {
+        Some(sandbox.start_reverse_forwarding(&prepared.exposes)?)
+    } else {
+        None
+    };
 
     let exit_code = if std::io::stdin().is_terminal() {
         sandbox.shell(command, &prepared.env)?
@@ -218,9 +299,137 @@ pub(crate) fn run_command(prepared: &PreparedVm, command: &[String]) -> Result<i
     };
 
     let _ = sandbox.stop();
+    drop(_fwd);
+    drop(_exposed);
+    drop(sandbox);
+    if let Some(log) = event_log {
+        log.finish();
+    }
+    if let Some(echo) = echo {
+        echo.finish();
+    }
     Ok(exit_code)
 }
 
+/// Where a prepared VM's console log goes.
+pub(crate) fn console_log_path(prepared: &PreparedVm) -> String {
+    prepared
+        .console_log
+        .clone()
+        .unwrap_or_else(|| logs::instance_log(&prepared.instance_dir))
+}
+
+/// Headless sandbox configuration for a prepared VM.
+pub(crate) fn sandbox_builder(prepared: &PreparedVm) -> VmConfigBuilder {
+    let mut builder = Sandbox::builder()
+        .kernel(&prepared.kernel_path)
+        .rootfs(&prepared.work_rootfs)
+        .cpus(prepared.cpus)
+        .memory_mb(prepared.memory)
+        .allow_net(prepared.allow_net)
+        .init_system(prepared.init_system)
+        .console(false)
+        .quiet(!prepared.verbose);
+
+    if let Some(initrd) = &prepared.initrd_path {
+        info!("shuru: using initramfs: {}", initrd);
+        builder = builder.initrd(initrd);
+    }
+
+    if let Some(cmdline) = &prepared.cmdline {
+        builder = builder.cmdline(cmdline);
+    }
+    builder = builder.kernel_args(&prepared.kernel_args);
+    builder = add_egress(builder, prepared);
+
+    for m in &prepared.mounts {
+        info!("shuru: mount {} -> {}", m.host_path, m.guest_path);
+        builder = builder.mount(m.clone());
+    }
+    add_volumes(builder, prepared)
+}
+
+/// Apply a prepared VM's egress policy, in filter or proxy mode, to
+/// `builder`.
+pub(crate) fn add_egress(builder: VmConfigBuilder, prepared: &PreparedVm) -> VmConfigBuilder {
+    if let Some(policy) = &prepared.egress {
+        info!("shuru: network limited to: {}", policy);
+        return builder.egress(policy.clone());
+    }
+    match &prepared.proxy {
+        Some(policy) => {
+            let egress_log = prepared
+                .egress_log
+                .clone()
+                .unwrap_or_else(|| logs::instance_egress_log(&prepared.instance_dir));
+            info!("shuru: network proxy limited to: {}", policy);
+            info!("shuru: egress log: {}", egress_log);
+            builder.network_proxy(policy.clone()).egress_log(egress_log)
+        }
+        None => builder,
+    }
+}
+
+/// Attach a prepared VM's volumes to `builder`.
+pub(crate) fn add_volumes(mut builder: VmConfigBuilder, prepared: &PreparedVm) -> VmConfigBuilder {
+    for v in &prepared.volumes {
+        info!("shuru: volume {} -> {}", v.name, v.guest_path);
+        let mut disk = DiskConfig::new(&v.path);
+        disk.guest_path = Some(v.guest_path.clone());
+        disk.read_only = v.read_only;
+        builder = builder.disk(disk);
+    }
+    builder
+}
+
+/// Merge environment variables: config file + CLI flags.
+pub(crate) fn merge_env(vm: &VmArgs, cfg: &ShuruConfig) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    if let Some(ref cfg_env) = cfg.env {
+        for (k, v) in cfg_env {
+            env.insert(k.clone(), v.clone());
+        }
+    }
+    for s in &vm.env {
+        let (k, v) = parse_env_var(s)?;
+        env.insert(k, v);
+    }
+    Ok(env)
+}
+
+/// Resolve the network mode and merge allowed hosts: config file + CLI
+/// flags. Without an explicit mode, allowed hosts select filter mode, and
+/// `allow_net` NAT.
+fn parse_network(vm: &VmArgs, cfg: &ShuruConfig) -> Result<(NetworkMode, EgressPolicy)> {
+    let network = cfg.network.as_ref();
+    let rules: Vec<&String> = cfg
+        .allow_hosts
+        .iter()
+        .flatten()
+        .chain(network.and_then(|n| n.allow.as_ref()).into_iter().flatten())
+        .chain(&vm.allow_host)
+        .collect();
+    let mode = match vm.network.or(network.and_then(|n| n.mode)) {
+        Some(mode) => mode,
+        None if !rules.is_empty() => NetworkMode::Filter,
+        None if vm.allow_net || cfg.allow_net.unwrap_or(false) => NetworkMode::Nat,
+        None => NetworkMode::None,
+    };
+    if !rules.is_empty() && matches!(mode, NetworkMode::None | NetworkMode::Nat) {
+        bail!(
+            "allowed hosts need network mode \"filter\" or \"proxy\", not \"{}\"",
+            mode.as_str()
+        );
+    }
+    let mut policy = EgressPolicy::deny_all();
+    for rule in rules {
+        policy = policy
+            .allow(rule)
+            .with_context(|| format!("invalid allowed host: '{}'", rule))?;
+    }
+    Ok((mode, policy))
+}
+
 /// Parse a "HOST:GUEST[:ro|rw]" mount spec string.
 fn parse_mount_spec(s: &str) -> Result<MountConfig> {
     let parts: Vec<&str> = s.split(':').collect();
@@ -275,6 +484,25 @@ fn parse_port_mapping(s: &str) -> Result<PortMapping> {
     })
 }
 
+/// Parse a "HOST_ADDR:PORT:GUEST_PORT" reverse forward spec string.
+fn parse_expose_spec(s: &str) -> Result<ReverseMapping> {
+    let Some((host_addr, guest_port)) = s.rsplit_once(':') else {
+        bail!("expected HOST_ADDR:PORT:GUEST_PORT format (e.g. 127.0.0.1:5432:5432)");
+    };
+    let Some((_, port)) = host_addr.rsplit_once(':').filter(|(h, _)| !h.is_empty()) else {
+        bail!("expected HOST_ADDR:PORT:GUEST_PORT format (e.g. 127.0.0.1:5432:5432)");
+    };
+    port.parse::<u16>()
+        .with_context(|| format!("invalid host port: '{}'", port))?;
+    let guest_port: u16 = guest_port
+        .parse()
+        .with_context(|| format!("invalid guest port: '{}'", guest_port))?;
+    Ok(ReverseMapping {
+        host_addr: host_addr.to_string(),
+        guest_port,
+    })
+}
+
 /// Parse a "KEY=VALUE" environment variable string.
 fn parse_env_var(s: &str) -> Result<(String, String)> {
     let parts: Vec<&str> = s.splitn(2, '=').collect();
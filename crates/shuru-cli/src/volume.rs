@@ -0,0 +1,240 @@
+//! Named persistent volumes: raw disk images under `<data dir>/volumes`,
+//! attached to VMs as extra block devices. The guest agent formats a blank
+//! volume as ext4 on first use and grows its filesystem after a resize.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+
+use anyhow::{bail, Context, Result};
+
+use shuru_vm::default_data_dir;
+
+const DEFAULT_SIZE_MB: u64 = 1024;
+
+/// A volume attached to a VM. Holds a lock on the image for as long as it
+/// lives: exclusive if writable, shared if read-only.
+pub(crate) struct VolumeMount {
+    pub name: String,
+    pub path: String,
+    pub guest_path: String,
+    pub read_only: bool,
+    _lock: File,
+}
+
+/// Path of volume `name`. Names are restricted so they can appear in
+/// `NAME:/path` specs.
+pub(crate) fn volume_path(data_dir: &str, name: &str) -> Result<String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        bail!(
+            "invalid volume name: '{}' (use letters, digits, '-', '_' and '.')",
+            name
+        );
+    }
+    Ok(format!("{}/volumes/{}.img", data_dir, name))
+}
+
+/// Parse a "NAME:GUEST[:ro|rw]" volume spec and lock the volume.
+pub(crate) fn attach(data_dir: &str, spec: &str) -> Result<VolumeMount> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        bail!("expected NAME:GUEST or NAME:GUEST:MODE (e.g. cache:/root/.cache)");
+    }
+    let name = parts[0];
+    let guest_path = parts[1];
+    if !guest_path.starts_with('/') {
+        bail!(
+            "guest path must be absolute (start with /): '{}'",
+            guest_path
+        );
+    }
+    let read_only = match parts.get(2) {
+        None | Some(&"rw") => false,
+        Some(&"ro") => true,
+        Some(mode) => bail!("invalid volume mode (must be :ro or :rw): '{}'", mode),
+    };
+
+    let path = volume_path(data_dir, name)?;
+    let file = File::open(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => anyhow::anyhow!(
+            "Volume '{}' not found (create it with `shuru volume create {}`)",
+            name,
+            name
+        ),
+        _ => anyhow::Error::new(e).context(format!("Failed to open volume '{}'", name)),
+    })?;
+    if !try_lock(&file, !read_only)? {
+        bail!("Volume '{}' is in use by another VM", name);
+    }
+
+    Ok(VolumeMount {
+        name: name.to_string(),
+        path,
+        guest_path: guest_path.to_string(),
+        read_only,
+        _lock: file,
+    })
+}
+
+/// Take an flock without blocking. Returns false if another process holds
+/// a conflicting one.
+fn try_lock(file: &File, exclusive: bool) -> Result<bool> {
+    let op = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(false);
+    }
+    Err(err).context("Failed to lock volume")
+}
+
+/// Open an existing volume and lock it exclusively, for changes from the
+/// host.
+fn open_unused(name: &str) -> Result<(String, File)> {
+    let path = volume_path(&default_data_dir(), name)?;
+    if !std::path::Path::new(&path).exists() {
+        bail!("Volume '{}' not found", name);
+    }
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    if !try_lock(&file, true)? {
+        bail!("Volume '{}' is in use by a VM", name);
+    }
+    Ok((path, file))
+}
+
+pub(crate) fn create(name: &str, size_mb: Option<u64>) -> Result<()> {
+    let data_dir = default_data_dir();
+    let path = volume_path(&data_dir, name)?;
+    let size_mb = size_mb.unwrap_or(DEFAULT_SIZE_MB);
+    if size_mb == 0 {
+        bail!("volume size must be at least 1 MB");
+    }
+    let size = size_bytes(size_mb)?;
+
+    std::fs::create_dir_all(format!("{}/volumes", data_dir))?;
+    // Sparse and blank; the guest formats it when first attached
+    let file = match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            bail!("Volume '{}' already exists", name)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    file.set_len(size)?;
+    eprintln!("shuru: volume '{}' created ({})", name, format_size(size));
+    Ok(())
+}
+
+pub(crate) fn list() -> Result<()> {
+    let volumes_dir = format!("{}/volumes", default_data_dir());
+    let entries = match std::fs::read_dir(&volumes_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("No volumes found.");
+            return Ok(());
+        }
+        Err(e) => bail!("Failed to read volumes directory: {}", e),
+    };
+
+    let mut volumes: Vec<(String, u64, u64, bool)> = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("img") {
+            continue;
+        }
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("?")
+            .to_string();
+        let file = File::open(&path)?;
+        let meta = file.metadata()?;
+        let used = std::os::unix::fs::MetadataExt::blocks(&meta) * 512;
+        // A VM holds at least a shared lock on every attached volume
+        let attached = !try_lock(&file, true)?;
+        volumes.push((name, meta.len(), used, attached));
+    }
+
+    if volumes.is_empty() {
+        eprintln!("No volumes found.");
+        return Ok(());
+    }
+
+    volumes.sort();
+    println!("{:<20} {:>10} {:>10} ATTACHED", "NAME", "SIZE", "USED");
+    for (name, size, used, attached) in &volumes {
+        println!(
+            "{:<20} {:>10} {:>10} {}",
+            name,
+            format_size(*size),
+            format_size(*used),
+            if *attached { "yes" } else { "no" }
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn remove(name: &str) -> Result<()> {
+    let (path, _lock) = open_unused(name)?;
+    std::fs::remove_file(&path)?;
+    eprintln!("shuru: volume '{}' deleted", name);
+    Ok(())
+}
+
+/// Grow a volume. Its filesystem grows to match the next time it is
+/// attached read-write.
+pub(crate) fn resize(name: &str, size_mb: u64) -> Result<()> {
+    let (_, file) = open_unused(name)?;
+    let current = file.metadata()?.len();
+    let size = size_bytes(size_mb)?;
+    if size < current {
+        bail!(
+            "Volume '{}' is {}; volumes can only grow",
+            name,
+            format_size(current)
+        );
+    }
+    file.set_len(size)?;
+    eprintln!("shuru: volume '{}' resized to {}", name, format_size(size));
+    Ok(())
+}
+
+fn size_bytes(size_mb: u64) -> Result<u64> {
+    match size_mb.checked_mul(1024 * 1024) {
+        Some(size) => Ok(size),
+        None => bail!("volume size of {} MB is too large", size_mb),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 * 1024 {
+        format!("{:.1} GB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+    } else {
+        format!("{} MB", bytes / (1024 * 1024))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_sizes_that_overflow() {
+        assert_eq!(size_bytes(2).unwrap(), 2 * 1024 * 1024);
+        assert!(size_bytes(u64::MAX / (1024 * 1024)).is_ok());
+        assert!(size_bytes(u64::MAX / (1024 * 1024) + 1).is_err());
+    }
+}
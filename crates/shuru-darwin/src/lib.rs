@@ -1,29 +1,49 @@
+// Virtualization.framework bindings are macOS-only; `terminal` is plain
+// POSIX and also used by the Linux (QEMU) host.
+#[cfg(target_os = "macos")]
 mod sys;
 
+#[cfg(target_os = "macos")]
 mod error;
+#[cfg(target_os = "macos")]
 mod bootloader;
+#[cfg(target_os = "macos")]
 mod configuration;
+#[cfg(target_os = "macos")]
 mod directory_sharing;
+#[cfg(target_os = "macos")]
 mod entropy;
+#[cfg(target_os = "macos")]
 mod memory;
+#[cfg(target_os = "macos")]
 mod network;
+#[cfg(target_os = "macos")]
 mod serial;
+#[cfg(target_os = "macos")]
 mod socket;
+#[cfg(target_os = "macos")]
 mod storage;
 pub mod terminal;
+#[cfg(target_os = "macos")]
 mod vm;
 
-pub use error::{VzError, Result};
-pub use bootloader::LinuxBootLoader;
-pub use configuration::VirtualMachineConfiguration;
-pub use directory_sharing::{SharedDirectory, VirtioFileSystemDevice};
-pub use entropy::VirtioEntropyDevice;
-pub use memory::VirtioMemoryBalloonDevice;
-pub use network::{NATNetworkAttachment, MACAddress, VirtioNetworkDevice};
-pub use serial::{FileHandleSerialAttachment, VirtioConsoleSerialPort};
-pub use socket::VirtioSocketDevice;
-pub use storage::{
-    DiskImageAttachment, DiskImageCachingMode, DiskImageSynchronizationMode, StorageDevice,
-    VirtioBlockDevice,
+#[cfg(target_os = "macos")]
+pub use {
+    bootloader::LinuxBootLoader,
+    configuration::VirtualMachineConfiguration,
+    directory_sharing::{SharedDirectory, VirtioFileSystemDevice},
+    entropy::VirtioEntropyDevice,
+    error::{Result, VzError},
+    memory::VirtioMemoryBalloonDevice,
+    network::{
+        FileHandleNetworkAttachment, MACAddress, NATNetworkAttachment, NetworkAttachment,
+        VirtioNetworkDevice,
+    },
+    serial::{FileHandleSerialAttachment, VirtioConsoleSerialPort},
+    socket::VirtioSocketDevice,
+    storage::{
+        DiskImageAttachment, DiskImageCachingMode, DiskImageSynchronizationMode, StorageDevice,
+        VirtioBlockDevice,
+    },
+    vm::{VirtualMachine, VmState},
 };
-pub use vm::{VirtualMachine, VmState};
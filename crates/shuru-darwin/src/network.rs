@@ -1,10 +1,17 @@
+use std::os::fd::RawFd;
+
 use objc2::rc::Retained;
 use objc2::AnyThread;
+use objc2_foundation::NSFileHandle;
 use objc2_virtualization::{
-    VZMACAddress, VZNATNetworkDeviceAttachment, VZNetworkDeviceAttachment,
-    VZNetworkDeviceConfiguration, VZVirtioNetworkDeviceConfiguration,
+    VZFileHandleNetworkDeviceAttachment, VZMACAddress, VZNATNetworkDeviceAttachment,
+    VZNetworkDeviceAttachment, VZNetworkDeviceConfiguration, VZVirtioNetworkDeviceConfiguration,
 };
 
+pub trait NetworkAttachment {
+    fn as_network_attachment(&self) -> Retained<VZNetworkDeviceAttachment>;
+}
+
 pub struct NATNetworkAttachment {
     inner: Retained<VZNATNetworkDeviceAttachment>,
 }
@@ -25,6 +32,39 @@ impl Default for NATNetworkAttachment {
     }
 }
 
+impl NetworkAttachment for NATNetworkAttachment {
+    fn as_network_attachment(&self) -> Retained<VZNetworkDeviceAttachment> {
+        unsafe { Retained::cast_unchecked(self.inner.clone()) }
+    }
+}
+
+/// Exchanges the guest's Ethernet frames with a connected datagram socket,
+/// one frame per datagram.
+pub struct FileHandleNetworkAttachment {
+    inner: Retained<VZFileHandleNetworkDeviceAttachment>,
+}
+
+impl FileHandleNetworkAttachment {
+    /// `fd` must be a connected `SOCK_DGRAM` socket and stay open while the
+    /// VM runs.
+    pub fn new(fd: RawFd) -> Self {
+        unsafe {
+            let file_handle = NSFileHandle::initWithFileDescriptor(NSFileHandle::alloc(), fd);
+            let attachment = VZFileHandleNetworkDeviceAttachment::initWithFileHandle(
+                VZFileHandleNetworkDeviceAttachment::alloc(),
+                &file_handle,
+            );
+            FileHandleNetworkAttachment { inner: attachment }
+        }
+    }
+}
+
+impl NetworkAttachment for FileHandleNetworkAttachment {
+    fn as_network_attachment(&self) -> Retained<VZNetworkDeviceAttachment> {
+        unsafe { Retained::cast_unchecked(self.inner.clone()) }
+    }
+}
+
 pub struct MACAddress {
     inner: Retained<VZMACAddress>,
 }
@@ -64,17 +104,16 @@ impl VirtioNetworkDevice {
         }
     }
 
-    pub fn new_with_attachment(attachment: &NATNetworkAttachment) -> Self {
+    pub fn new_with_attachment(attachment: &dyn NetworkAttachment) -> Self {
         let config = Self::new();
         config.set_attachment(attachment);
         config
     }
 
-    pub fn set_attachment(&self, attachment: &NATNetworkAttachment) {
+    pub fn set_attachment(&self, attachment: &dyn NetworkAttachment) {
         unsafe {
-            let id: Retained<VZNetworkDeviceAttachment> =
-                Retained::cast_unchecked(attachment.inner.clone());
-            self.inner.setAttachment(Some(&id));
+            self.inner
+                .setAttachment(Some(&attachment.as_network_attachment()));
         }
     }
 
@@ -1,16 +1,22 @@
-#[cfg(target_os = "linux")]
-use syslog::Facility;
-
 #[cfg(target_os = "linux")]
 mod guest {
+    use std::collections::{HashMap, VecDeque};
     use std::io::{BufRead, BufReader, Read, Write};
-    use std::os::unix::io::FromRawFd;
-    use std::process::{Command, Stdio};
-
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ChildStdin, Command, Stdio};
+    use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+    use std::sync::{mpsc, Arc, Mutex, OnceLock};
+
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
     use serde::{Deserialize, Serialize};
 
     const VSOCK_PORT: u32 = 1024;
     const VSOCK_PORT_FORWARD: u32 = 1025;
+    const VSOCK_PORT_LOG: u32 = 1026;
+    const VSOCK_PORT_TUNNEL: u32 = 1027;
 
     #[derive(Deserialize)]
     pub struct ExecRequest {
@@ -23,6 +29,18 @@ mod guest {
         pub rows: u16,
         #[serde(default = "default_cols")]
         pub cols: u16,
+        #[serde(default)]
+        pub cwd: Option<String>,
+        /// Pipe the command's stdin from `stdin`/`eof` control messages.
+        #[serde(default)]
+        pub stdin: bool,
+        /// Piped mode: reply `started` once spawned and read control
+        /// messages until the command exits, which ends the connection.
+        #[serde(default)]
+        pub control: bool,
+        /// stdout, stderr and stdin data is base64 rather than text.
+        #[serde(default)]
+        pub binary: bool,
     }
 
     fn default_rows() -> u16 {
@@ -32,7 +50,7 @@ mod guest {
         80
     }
 
-    #[derive(Serialize)]
+    #[derive(Serialize, Default)]
     pub struct ExecResponse {
         #[serde(rename = "type")]
         pub msg_type: String,
@@ -40,6 +58,10 @@ mod guest {
         pub data: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub code: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub pid: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub signal: Option<i32>,
     }
 
     #[derive(Deserialize)]
@@ -49,6 +71,10 @@ mod guest {
         Stdin { data: String },
         #[serde(rename = "resize")]
         Resize { rows: u16, cols: u16 },
+        #[serde(rename = "eof")]
+        Eof,
+        #[serde(rename = "signal")]
+        Signal { signal: i32 },
     }
 
     fn mount_fs(source: &str, target: &str, fstype: &str, data: Option<&str>) -> bool {
@@ -114,6 +140,9 @@ mod guest {
         guest_path: String,
         #[serde(default)]
         pub persistent: bool,
+        /// Block device to mount as ext4 instead of the virtiofs share `tag`.
+        #[serde(default)]
+        device: Option<String>,
     }
 
     #[derive(Serialize)]
@@ -125,6 +154,13 @@ mod guest {
     }
 
     fn process_mount(req: &MountRequest) -> MountResponse {
+        if AGENT_MODE.load(Ordering::Relaxed) {
+            return MountResponse {
+                tag: req.tag.clone(),
+                ok: false,
+                error: Some("mounts are not supported in agent mode".into()),
+            };
+        }
         if let Err(e) = std::fs::create_dir_all(&req.guest_path) {
             return MountResponse {
                 tag: req.tag.clone(),
@@ -136,6 +172,21 @@ mod guest {
             };
         }
 
+        if let Some(device) = &req.device {
+            return match mount_block(device, &req.guest_path, !req.persistent) {
+                Ok(()) => MountResponse {
+                    tag: req.tag.clone(),
+                    ok: true,
+                    error: None,
+                },
+                Err(msg) => MountResponse {
+                    tag: req.tag.clone(),
+                    ok: false,
+                    error: Some(msg),
+                },
+            };
+        }
+
         if req.persistent {
             // Direct mount (persistent write mode)
             if mount_fs(&req.tag, &req.guest_path, "virtiofs", None) {
@@ -212,6 +263,179 @@ mod guest {
         Ok(())
     }
 
+    /// Offset and value of the ext2/3/4 superblock magic.
+    const EXT4_MAGIC_OFFSET: usize = 1080;
+    const EXT4_MAGIC: [u8; 2] = [0x53, 0xEF];
+
+    /// Mount an ext4 block device (a volume), formatting it first if it is
+    /// blank and growing the filesystem to the device if it was resized.
+    fn mount_block(device: &str, guest_path: &str, read_only: bool) -> Result<(), String> {
+        let mut head = [0u8; 2048];
+        std::fs::File::open(device)
+            .and_then(|mut f| f.read_exact(&mut head))
+            .map_err(|e| format!("failed to read {}: {}", device, e))?;
+
+        if head[EXT4_MAGIC_OFFSET..EXT4_MAGIC_OFFSET + 2] != EXT4_MAGIC {
+            if head.iter().any(|&b| b != 0) {
+                return Err(format!(
+                    "{} holds data but no ext4 filesystem; not formatting it",
+                    device
+                ));
+            }
+            if read_only {
+                return Err(format!("{} is blank and attached read-only", device));
+            }
+            log::info!("shuru-guest: formatting {} as ext4", device);
+            run_e2fs("mkfs.ext4", &["-q", "-F", device])?;
+        }
+
+        let flags = if read_only { libc::MS_RDONLY } else { 0 };
+        if !mount_fs_with_flags(device, guest_path, "ext4", flags, None) {
+            return Err(format!("failed to mount {} at {}", device, guest_path));
+        }
+        if !read_only {
+            // Online grow; a no-op unless the volume was resized
+            if let Err(e) = run_e2fs("resize2fs", &[device]) {
+                log::info!("shuru-guest: {}", e);
+            }
+        }
+        log::info!("shuru-guest: mounted {} -> {}", device, guest_path);
+        Ok(())
+    }
+
+    fn run_e2fs(program: &str, args: &[&str]) -> Result<(), String> {
+        let output = Command::new(program).args(args).output().map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                format!("{} not found in the guest (install e2fsprogs)", program)
+            } else {
+                format!("failed to run {}: {}", program, e)
+            }
+        })?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} failed: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    // --- File transfer protocol ---
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum FileOp {
+        Read,
+        Write,
+    }
+
+    #[derive(Deserialize)]
+    struct FileRequest {
+        op: FileOp,
+        path: String,
+        #[serde(default)]
+        size: u64,
+        #[serde(default)]
+        mode: Option<u32>,
+    }
+
+    #[derive(Serialize)]
+    struct FileResponse {
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+
+    fn write_file_response(writer: &mut impl Write, resp: &FileResponse) -> std::io::Result<()> {
+        writeln!(writer, "{}", serde_json::to_string(resp).unwrap())?;
+        writer.flush()
+    }
+
+    /// Handle a file request. A write is followed by exactly `size` raw
+    /// bytes, which are always consumed so the connection stays in sync; a
+    /// successful read response is followed by the file contents. An `Err`
+    /// means the connection is unusable.
+    fn process_file(
+        req: &FileRequest,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        match req.op {
+            FileOp::Write => {
+                let mut body = reader.take(req.size);
+                let result = std::fs::File::create(&req.path).and_then(|mut file| {
+                    let n = std::io::copy(&mut body, &mut file)?;
+                    if n != req.size {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    if let Some(mode) = req.mode {
+                        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+                    }
+                    Ok(())
+                });
+                // Drain whatever a failed write left unread
+                std::io::copy(&mut body, &mut std::io::sink())?;
+                if body.limit() > 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                let resp = match result {
+                    Ok(()) => FileResponse {
+                        ok: true,
+                        size: Some(req.size),
+                        error: None,
+                    },
+                    Err(e) => FileResponse {
+                        ok: false,
+                        size: None,
+                        error: Some(format!("{}: {}", req.path, e)),
+                    },
+                };
+                write_file_response(writer, &resp)
+            }
+            FileOp::Read => {
+                let opened = std::fs::File::open(&req.path).and_then(|file| {
+                    let meta = file.metadata()?;
+                    if meta.is_dir() {
+                        return Err(std::io::Error::other("is a directory"));
+                    }
+                    Ok((file, meta.len()))
+                });
+                match opened {
+                    Ok((file, size)) => {
+                        write_file_response(
+                            writer,
+                            &FileResponse {
+                                ok: true,
+                                size: Some(size),
+                                error: None,
+                            },
+                        )?;
+                        // The size was announced, so a file that shrank
+                        // meanwhile leaves the host short: close instead.
+                        let n = std::io::copy(&mut file.take(size), writer)?;
+                        if n != size {
+                            return Err(std::io::ErrorKind::UnexpectedEof.into());
+                        }
+                        writer.flush()
+                    }
+                    Err(e) => write_file_response(
+                        writer,
+                        &FileResponse {
+                            ok: false,
+                            size: None,
+                            error: Some(format!("{}: {}", req.path, e)),
+                        },
+                    ),
+                }
+            }
+        }
+    }
+
     fn bring_up_interface(sock: i32, name: &[u8]) {
         unsafe {
             let mut ifr: libc::ifreq = std::mem::zeroed();
@@ -365,6 +589,7 @@ mod guest {
                 msg_type: "error".into(),
                 data: Some(msg.into()),
                 code: None,
+                ..Default::default()
             },
         );
     }
@@ -396,14 +621,23 @@ mod guest {
                 continue;
             }
 
+            // File request: raw bytes follow the line, continue reading
+            if let Ok(file_req) = serde_json::from_str::<FileRequest>(line) {
+                if process_file(&file_req, &mut reader, &mut writer).is_err() {
+                    break;
+                }
+                continue;
+            }
+
             // Exec request
-            let req: ExecRequest = match serde_json::from_str(line) {
+            let mut req: ExecRequest = match serde_json::from_str(line) {
                 Ok(r) => r,
                 Err(e) => {
                     let resp = ExecResponse {
                         msg_type: "error".into(),
                         data: Some(format!("invalid request: {}", e)),
                         code: None,
+                        ..Default::default()
                     };
                     let _ = writeln!(writer, "{}", serde_json::to_string(&resp).unwrap());
                     continue;
@@ -415,26 +649,57 @@ mod guest {
                     msg_type: "error".into(),
                     data: Some("empty argv".into()),
                     code: None,
+                    ..Default::default()
                 };
                 let _ = writeln!(writer, "{}", serde_json::to_string(&resp).unwrap());
                 continue;
             }
+            add_tunnel_env(&mut req.env);
 
             if req.tty {
-                // TTY mode: hand off the raw fd, the line-based protocol is over
+                // TTY mode: hand off the raw fd, the line-based protocol is
+                // over; stdin the host sent right away may already be buffered
+                let pending = reader.buffer().to_vec();
                 let raw_fd = std::os::unix::io::AsRawFd::as_raw_fd(&writer);
                 // Prevent TcpStream from closing the fd on drop
                 std::mem::forget(writer);
-                handle_tty_exec(raw_fd, &req);
+                handle_tty_exec(raw_fd, &req, pending);
                 return;
             }
 
-            // Non-TTY mode: piped exec (original behavior)
-            handle_piped_exec(&req, &mut writer);
+            // Non-TTY mode: piped exec
+            if req.control {
+                handle_piped_exec(&req, &mut writer, Some(reader));
+                let _ = writer.shutdown(std::net::Shutdown::Both);
+                return;
+            }
+            handle_piped_exec(&req, &mut writer, None);
+        }
+    }
+
+    /// Exit code for a command that could not be executed, as in sh: 127
+    /// if the program was not found, 126 otherwise.
+    fn spawn_failure_code(err: &std::io::Error) -> i32 {
+        if err.kind() == std::io::ErrorKind::NotFound {
+            127
+        } else {
+            126
         }
     }
 
-    fn handle_piped_exec(req: &ExecRequest, writer: &mut impl Write) {
+    fn write_exec_response(writer: &mut impl Write, resp: &ExecResponse) {
+        let _ = writeln!(writer, "{}", serde_json::to_string(resp).unwrap());
+        let _ = writer.flush();
+    }
+
+    /// Run a command with piped output, streaming stdout and stderr to the
+    /// host as they are produced. With `control`, stdin and signal messages
+    /// from the host are applied while the command runs.
+    fn handle_piped_exec(
+        req: &ExecRequest,
+        writer: &mut impl Write,
+        control: Option<BufReader<std::net::TcpStream>>,
+    ) {
         let mut cmd = Command::new(&req.argv[0]);
         if req.argv.len() > 1 {
             cmd.args(&req.argv[1..]);
@@ -442,68 +707,189 @@ mod guest {
         for (k, v) in &req.env {
             cmd.env(k, v);
         }
+        if let Some(cwd) = &req.cwd {
+            cmd.current_dir(cwd);
+        }
+        if req.stdin {
+            cmd.stdin(Stdio::piped());
+        } else if req.control {
+            cmd.stdin(Stdio::null());
+        }
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
 
-        match cmd.spawn() {
-            Ok(mut child) => {
-                let mut stdout_data = String::new();
-                let mut stderr_data = String::new();
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                write_exec_response(
+                    writer,
+                    &ExecResponse {
+                        msg_type: "error".into(),
+                        data: Some(format!("failed to spawn: {}", e)),
+                        code: Some(spawn_failure_code(&e)),
+                        ..Default::default()
+                    },
+                );
+                return;
+            }
+        };
+
+        if let Some(control) = control {
+            write_exec_response(
+                writer,
+                &ExecResponse {
+                    msg_type: "started".into(),
+                    pid: Some(child.id()),
+                    ..Default::default()
+                },
+            );
+            let stdin = child.stdin.take();
+            let (pid, binary) = (child.id(), req.binary);
+            std::thread::spawn(move || control_loop(control, stdin, pid, binary));
+        }
 
-                if let Some(mut stdout) = child.stdout.take() {
-                    let _ = stdout.read_to_string(&mut stdout_data);
+        // Both pipes feed one channel so a single thread writes responses.
+        // An empty chunk marks the end of a pipe.
+        let (tx, rx) = mpsc::channel::<(usize, Vec<u8>)>();
+        let pipes: [Option<Box<dyn Read + Send>>; 2] = [
+            child.stdout.take().map(|p| Box::new(p) as _),
+            child.stderr.take().map(|p| Box::new(p) as _),
+        ];
+        for (idx, pipe) in pipes.into_iter().enumerate() {
+            let Some(mut pipe) = pipe else { continue };
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 16384];
+                loop {
+                    match pipe.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            let _ = tx.send((idx, buf[..n].to_vec()));
+                        }
+                    }
                 }
-                if let Some(mut stderr) = child.stderr.take() {
-                    let _ = stderr.read_to_string(&mut stderr_data);
+                let _ = tx.send((idx, Vec::new()));
+            });
+        }
+        drop(tx);
+
+        let mut pending = [Vec::new(), Vec::new()];
+        for (idx, chunk) in rx {
+            let eof = chunk.is_empty();
+            let data = if req.binary {
+                if eof {
+                    continue;
                 }
+                BASE64.encode(&chunk)
+            } else {
+                pending[idx].extend_from_slice(&chunk);
+                take_utf8(&mut pending[idx], eof)
+            };
+            if data.is_empty() {
+                continue;
+            }
+            write_exec_response(
+                writer,
+                &ExecResponse {
+                    msg_type: ["stdout", "stderr"][idx].into(),
+                    data: Some(data),
+                    ..Default::default()
+                },
+            );
+        }
 
-                let status = child.wait().expect("failed to wait on child");
-                let exit_code = status.code().unwrap_or(-1);
+        // The status is lost if the PID 1 reaper got to the child first.
+        let (exit_code, signal) = match child.wait() {
+            Ok(status) => match status.signal() {
+                Some(sig) => (128 + sig, Some(sig)),
+                None => (status.code().unwrap_or(-1), None),
+            },
+            Err(_) => (-1, None),
+        };
 
-                // Flush all filesystem writes to disk before reporting exit.
-                // Without this, data can be lost if the VM is stopped immediately
-                // after the exit code is sent (e.g. during checkpoint create).
-                unsafe {
-                    libc::sync();
-                }
+        // Flush all filesystem writes to disk before reporting exit.
+        // Without this, data can be lost if the VM is stopped immediately
+        // after the exit code is sent (e.g. during checkpoint create).
+        unsafe {
+            libc::sync();
+        }
 
-                if !stdout_data.is_empty() {
-                    let resp = ExecResponse {
-                        msg_type: "stdout".into(),
-                        data: Some(stdout_data),
-                        code: None,
-                    };
-                    let _ = writeln!(writer, "{}", serde_json::to_string(&resp).unwrap());
+        write_exec_response(
+            writer,
+            &ExecResponse {
+                msg_type: "exit".into(),
+                code: Some(exit_code),
+                signal,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Split off the longest prefix of `buf` that is complete UTF-8,
+    /// keeping a trailing partial character for the next chunk unless at
+    /// `eof`. Invalid sequences are replaced.
+    fn take_utf8(buf: &mut Vec<u8>, eof: bool) -> String {
+        let keep = match std::str::from_utf8(buf) {
+            Err(e) if !eof && e.error_len().is_none() => buf.len() - e.valid_up_to(),
+            _ => 0,
+        };
+        let tail = buf.split_off(buf.len() - keep);
+        let text = String::from_utf8_lossy(buf).into_owned();
+        *buf = tail;
+        text
+    }
+
+    /// Apply host control messages to a piped command until the connection
+    /// closes. Stdin is written from a separate thread so that a command
+    /// not reading its input can still be signalled.
+    fn control_loop(
+        reader: BufReader<std::net::TcpStream>,
+        stdin: Option<ChildStdin>,
+        pid: u32,
+        binary: bool,
+    ) {
+        let (stdin_tx, stdin_rx) = mpsc::channel::<Vec<u8>>();
+        if let Some(mut stdin) = stdin {
+            std::thread::spawn(move || {
+                for data in stdin_rx {
+                    if stdin.write_all(&data).is_err() {
+                        break;
+                    }
                 }
+            });
+        }
+        let mut stdin_tx = Some(stdin_tx);
 
-                if !stderr_data.is_empty() {
-                    let resp = ExecResponse {
-                        msg_type: "stderr".into(),
-                        data: Some(stderr_data),
-                        code: None,
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            let Ok(msg) = serde_json::from_str::<ControlMessage>(line.trim()) else {
+                continue;
+            };
+            match msg {
+                ControlMessage::Stdin { data } => {
+                    let data = if binary {
+                        match BASE64.decode(data) {
+                            Ok(data) => data,
+                            Err(_) => continue,
+                        }
+                    } else {
+                        data.into_bytes()
                     };
-                    let _ = writeln!(writer, "{}", serde_json::to_string(&resp).unwrap());
+                    if let Some(tx) = &stdin_tx {
+                        let _ = tx.send(data);
+                    }
                 }
-
-                let resp = ExecResponse {
-                    msg_type: "exit".into(),
-                    data: None,
-                    code: Some(exit_code),
-                };
-                let _ = writeln!(writer, "{}", serde_json::to_string(&resp).unwrap());
-            }
-            Err(e) => {
-                let resp = ExecResponse {
-                    msg_type: "error".into(),
-                    data: Some(format!("failed to spawn: {}", e)),
-                    code: None,
-                };
-                let _ = writeln!(writer, "{}", serde_json::to_string(&resp).unwrap());
+                // Dropping the sender closes the child's stdin
+                ControlMessage::Eof => stdin_tx = None,
+                ControlMessage::Signal { signal } => unsafe {
+                    libc::kill(pid as libc::pid_t, signal);
+                },
+                ControlMessage::Resize { .. } => {}
             }
         }
     }
 
-    fn handle_tty_exec(vsock_fd: i32, req: &ExecRequest) {
+    fn handle_tty_exec(vsock_fd: i32, req: &ExecRequest, pending: Vec<u8>) {
         use std::ffi::CString;
 
         unsafe {
@@ -576,6 +962,16 @@ mod guest {
                     libc::putenv(path.into_raw());
                 }
 
+                if let Some(cwd) = &req.cwd {
+                    let dir = CString::new(cwd.as_str()).unwrap_or_default();
+                    if libc::chdir(dir.as_ptr()) < 0 {
+                        let err = std::io::Error::last_os_error();
+                        let msg = format!("shuru: cd {}: {}\n", cwd, err);
+                        libc::write(2, msg.as_ptr() as *const libc::c_void, msg.len());
+                        libc::_exit(127);
+                    }
+                }
+
                 // Build argv and exec
                 let c_args: Vec<CString> = req
                     .argv
@@ -594,19 +990,63 @@ mod guest {
                 let err = std::io::Error::last_os_error();
                 let msg = format!("shuru: {}: {}\n", req.argv[0], err);
                 libc::write(2, msg.as_ptr() as *const libc::c_void, msg.len());
-                libc::_exit(127);
+                libc::_exit(spawn_failure_code(&err));
             }
 
             // === PARENT ===
             libc::close(slave);
-            pty_poll_loop(vsock_fd, master, pid);
+            pty_poll_loop(vsock_fd, master, pid, pending);
             libc::close(master);
             libc::close(vsock_fd);
         }
     }
 
-    fn pty_poll_loop(vsock_fd: i32, master_fd: i32, child_pid: libc::pid_t) {
-        let mut vsock_buf: Vec<u8> = Vec::new();
+    /// Apply the complete control messages (stdin, resize, signals) at the
+    /// start of `buf` to a tty command, leaving any partial line.
+    fn apply_tty_control(buf: &mut Vec<u8>, master_fd: i32, child_pid: libc::pid_t) {
+        while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            let line_str = String::from_utf8_lossy(&line);
+            let line_str = line_str.trim();
+            if line_str.is_empty() {
+                continue;
+            }
+
+            if let Ok(msg) = serde_json::from_str::<ControlMessage>(line_str) {
+                match msg {
+                    ControlMessage::Stdin { data } => {
+                        let bytes = data.as_bytes();
+                        unsafe {
+                            libc::write(
+                                master_fd,
+                                bytes.as_ptr() as *const libc::c_void,
+                                bytes.len(),
+                            );
+                        }
+                    }
+                    ControlMessage::Resize { rows, cols } => unsafe {
+                        let ws = libc::winsize {
+                            ws_row: rows,
+                            ws_col: cols,
+                            ws_xpixel: 0,
+                            ws_ypixel: 0,
+                        };
+                        libc::ioctl(master_fd, libc::TIOCSWINSZ, &ws);
+                    },
+                    ControlMessage::Signal { signal } => unsafe {
+                        libc::kill(child_pid, signal);
+                    },
+                    ControlMessage::Eof => {}
+                }
+            }
+        }
+    }
+
+    /// Relay a tty command's I/O until it exits. `pending` holds whatever
+    /// the host sent after the exec request and was already read.
+    fn pty_poll_loop(vsock_fd: i32, master_fd: i32, child_pid: libc::pid_t, pending: Vec<u8>) {
+        let mut vsock_buf = pending;
+        apply_tty_control(&mut vsock_buf, master_fd, child_pid);
         let mut read_buf = [0u8; 4096];
 
         loop {
@@ -650,39 +1090,7 @@ mod guest {
                 }
                 vsock_buf.extend_from_slice(&read_buf[..n as usize]);
 
-                // Process complete JSON lines
-                while let Some(pos) = vsock_buf.iter().position(|&b| b == b'\n') {
-                    let line: Vec<u8> = vsock_buf.drain(..=pos).collect();
-                    let line_str = String::from_utf8_lossy(&line);
-                    let line_str = line_str.trim();
-                    if line_str.is_empty() {
-                        continue;
-                    }
-
-                    if let Ok(msg) = serde_json::from_str::<ControlMessage>(line_str) {
-                        match msg {
-                            ControlMessage::Stdin { data } => {
-                                let bytes = data.as_bytes();
-                                unsafe {
-                                    libc::write(
-                                        master_fd,
-                                        bytes.as_ptr() as *const libc::c_void,
-                                        bytes.len(),
-                                    );
-                                }
-                            }
-                            ControlMessage::Resize { rows, cols } => unsafe {
-                                let ws = libc::winsize {
-                                    ws_row: rows,
-                                    ws_col: cols,
-                                    ws_xpixel: 0,
-                                    ws_ypixel: 0,
-                                };
-                                libc::ioctl(master_fd, libc::TIOCSWINSZ, &ws);
-                            },
-                        }
-                    }
-                }
+                apply_tty_control(&mut vsock_buf, master_fd, child_pid);
             }
 
             if fds[0].revents & (libc::POLLHUP | libc::POLLERR) != 0 {
@@ -709,6 +1117,7 @@ mod guest {
                             msg_type: "stdout".into(),
                             data: Some(data.into_owned()),
                             code: None,
+                            ..Default::default()
                         },
                     );
                 }
@@ -734,6 +1143,7 @@ mod guest {
                             msg_type: "stdout".into(),
                             data: Some(data.into_owned()),
                             code: None,
+                            ..Default::default()
                         },
                     );
                 }
@@ -761,6 +1171,7 @@ mod guest {
         } else {
             1
         };
+        let signal = libc::WIFSIGNALED(status).then(|| libc::WTERMSIG(status));
 
         send_response(
             vsock_fd,
@@ -768,6 +1179,8 @@ mod guest {
                 msg_type: "exit".into(),
                 data: None,
                 code: Some(exit_code),
+                signal,
+                ..Default::default()
             },
         );
     }
@@ -786,7 +1199,7 @@ mod guest {
         message: Option<String>,
     }
 
-    fn forward_accept_loop(listener_fd: i32) {
+    fn accept_loop(listener_fd: i32, handler: fn(i32)) {
         loop {
             let client_fd =
                 unsafe { libc::accept(listener_fd, std::ptr::null_mut(), std::ptr::null_mut()) };
@@ -795,9 +1208,7 @@ mod guest {
                 continue;
             }
 
-            std::thread::spawn(move || {
-                handle_forward_connection(client_fd);
-            });
+            std::thread::spawn(move || handler(client_fd));
         }
     }
 
@@ -887,6 +1298,628 @@ mod guest {
         let _ = t2.join();
     }
 
+    // --- Log forwarding ---
+    //
+    // The agent's own log records are kept in a bounded buffer and streamed
+    // to the host on port 1026 as JSON lines; keep in sync with shuru-vm's
+    // `LogRecord`. They also go to syslog when a daemon is listening, as
+    // under an init system.
+
+    /// Records kept for hosts that connect later.
+    const LOG_BUFFER_LEN: usize = 1000;
+
+    static LOGGER: OnceLock<GuestLogger> = OnceLock::new();
+
+    #[derive(Serialize)]
+    struct LogRecord {
+        ts_ms: u64,
+        level: String,
+        message: String,
+    }
+
+    struct GuestLogger {
+        /// Buffered records and the senders of connected hosts.
+        state: Mutex<(VecDeque<String>, Vec<mpsc::Sender<String>>)>,
+        syslog: Option<syslog::BasicLogger>,
+    }
+
+    impl log::Log for GuestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let ts_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0);
+            let line = serde_json::to_string(&LogRecord {
+                ts_ms,
+                level: record.level().to_string(),
+                message: record.args().to_string(),
+            })
+            .unwrap()
+                + "\n";
+            let mut state = self.state.lock().unwrap();
+            let (buffer, subscribers) = &mut *state;
+            if buffer.len() == LOG_BUFFER_LEN {
+                buffer.pop_front();
+            }
+            buffer.push_back(line.clone());
+            subscribers.retain(|tx| tx.send(line.clone()).is_ok());
+            drop(state);
+
+            if let Some(syslog) = &self.syslog {
+                syslog.log(record);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Install the agent's logger. syslog is only used if its socket
+    /// exists; at boot there is no daemon yet.
+    pub fn init_logging() {
+        let formatter = syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_USER,
+            hostname: None,
+            process: "shuru-guest".into(),
+            pid: std::process::id(),
+        };
+        let logger = LOGGER.get_or_init(|| GuestLogger {
+            state: Mutex::new((VecDeque::new(), Vec::new())),
+            syslog: syslog::unix(formatter).ok().map(syslog::BasicLogger::new),
+        });
+        if log::set_logger(logger).is_ok() {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+    }
+
+    /// Stream log records to the host: the buffered ones, then new ones
+    /// until the host disconnects.
+    fn handle_log_connection(fd: i32) {
+        let mut stream = unsafe { std::fs::File::from_raw_fd(fd) };
+        let Some(logger) = LOGGER.get() else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel();
+        let backlog: Vec<String> = {
+            let mut state = logger.state.lock().unwrap();
+            state.1.push(tx);
+            state.0.iter().cloned().collect()
+        };
+        for line in backlog.into_iter().chain(rx) {
+            if stream.write_all(line.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    // --- Tunnels ---
+    //
+    // Guest-to-host connections over host-initiated streams on port 1027;
+    // keep in sync with shuru-vm's `TunnelRequest`/`TunnelMessage`. The
+    // host opens a session with a `listen` request and keeps that control
+    // connection open. For each client of a session listener the guest
+    // sends `open`, and the host connects back with `accept` to carry it.
+    // Closing the control connection closes the listeners.
+
+    /// Clients not taken by the host within this time are dropped.
+    const TUNNEL_ACCEPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+    /// How long the host may take to answer a tunnelled DNS query; it tries
+    /// each of its resolvers for a few seconds.
+    const DNS_REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20);
+
+    #[derive(Deserialize, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum TunnelKind {
+        Stream,
+        Dns,
+    }
+
+    #[derive(Deserialize)]
+    struct TunnelListener {
+        addr: String,
+        kind: TunnelKind,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum TunnelRequest {
+        Listen {
+            listeners: Vec<TunnelListener>,
+            #[serde(default)]
+            env: HashMap<String, String>,
+            #[serde(default)]
+            set_resolver: bool,
+        },
+        Accept {
+            id: u64,
+        },
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum TunnelMessage {
+        Ready,
+        Error { message: String },
+        Open { id: u64, listener: usize },
+    }
+
+    enum PendingClient {
+        Stream(std::net::TcpStream),
+        Dns {
+            socket: Arc<std::net::UdpSocket>,
+            client: std::net::SocketAddr,
+            query: Vec<u8>,
+        },
+    }
+
+    #[derive(Default)]
+    struct Tunnels {
+        next_id: u64,
+        /// Clients waiting for the host, with their session and arrival.
+        pending: HashMap<u64, (u64, std::time::Instant, PendingClient)>,
+        /// Environment each session adds to commands.
+        env: HashMap<u64, HashMap<String, String>>,
+    }
+
+    static TUNNELS: OnceLock<Mutex<Tunnels>> = OnceLock::new();
+
+    /// Set in agent mode, where the host's files must not be touched.
+    static AGENT_MODE: AtomicBool = AtomicBool::new(false);
+
+    fn tunnels() -> std::sync::MutexGuard<'static, Tunnels> {
+        TUNNELS.get_or_init(Default::default).lock().unwrap()
+    }
+
+    /// Add the environment of open tunnel sessions, e.g. proxy settings,
+    /// where the request does not set the variable itself.
+    fn add_tunnel_env(env: &mut HashMap<String, String>) {
+        for session_env in tunnels().env.values() {
+            for (k, v) in session_env {
+                env.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        }
+    }
+
+    fn send_tunnel_message(stream: &Mutex<std::net::TcpStream>, msg: &TunnelMessage) -> bool {
+        let line = serde_json::to_string(msg).unwrap() + "\n";
+        stream.lock().unwrap().write_all(line.as_bytes()).is_ok()
+    }
+
+    fn handle_tunnel_connection(fd: i32) {
+        let mut stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+        let line = match read_line_raw(&mut stream) {
+            Some(l) if !l.is_empty() => l,
+            _ => return,
+        };
+        match serde_json::from_str::<TunnelRequest>(&line) {
+            Ok(TunnelRequest::Listen {
+                listeners,
+                env,
+                set_resolver,
+            }) => run_tunnel_session(stream, listeners, env, set_resolver),
+            Ok(TunnelRequest::Accept { id }) => accept_tunnel(stream, id),
+            Err(e) => {
+                let msg = TunnelMessage::Error {
+                    message: format!("invalid request: {}", e),
+                };
+                let _ = writeln!(stream, "{}", serde_json::to_string(&msg).unwrap());
+            }
+        }
+    }
+
+    /// Register a new client of `listener` and ask the host to take it.
+    fn offer_client(
+        session: u64,
+        listener: usize,
+        client: PendingClient,
+        control: &Mutex<std::net::TcpStream>,
+    ) -> bool {
+        let id = {
+            let mut tunnels = tunnels();
+            tunnels
+                .pending
+                .retain(|_, (_, since, _)| since.elapsed() < TUNNEL_ACCEPT_TIMEOUT);
+            tunnels.next_id += 1;
+            let id = tunnels.next_id;
+            tunnels
+                .pending
+                .insert(id, (session, std::time::Instant::now(), client));
+            id
+        };
+        send_tunnel_message(control, &TunnelMessage::Open { id, listener })
+    }
+
+    fn run_tunnel_session(
+        stream: std::net::TcpStream,
+        listeners: Vec<TunnelListener>,
+        env: HashMap<String, String>,
+        set_resolver: bool,
+    ) {
+        let control = Arc::new(Mutex::new(stream));
+        let fail = |message: String| {
+            log::info!("shuru-guest: tunnel session failed: {}", message);
+            send_tunnel_message(&control, &TunnelMessage::Error { message });
+        };
+
+        enum Bound {
+            Stream(std::net::TcpListener),
+            Dns(Arc<std::net::UdpSocket>),
+        }
+        let mut bound = Vec::new();
+        for listener in &listeners {
+            let result = match listener.kind {
+                TunnelKind::Stream => std::net::TcpListener::bind(&listener.addr).and_then(|l| {
+                    l.set_nonblocking(true)?;
+                    Ok(Bound::Stream(l))
+                }),
+                TunnelKind::Dns => std::net::UdpSocket::bind(&listener.addr).and_then(|s| {
+                    s.set_read_timeout(Some(std::time::Duration::from_millis(200)))?;
+                    Ok(Bound::Dns(Arc::new(s)))
+                }),
+            };
+            match result {
+                Ok(b) => bound.push(b),
+                Err(e) => return fail(format!("cannot listen on {}: {}", listener.addr, e)),
+            }
+        }
+
+        let resolver = listeners
+            .iter()
+            .find(|l| l.kind == TunnelKind::Dns)
+            .and_then(|l| l.addr.parse::<std::net::SocketAddr>().ok())
+            .filter(|_| set_resolver && !AGENT_MODE.load(Ordering::Relaxed));
+        let saved_resolv_conf = resolver.and_then(|addr| {
+            let saved = std::fs::read("/etc/resolv.conf").ok();
+            let conf = format!("nameserver {}\n", addr.ip());
+            match std::fs::write("/etc/resolv.conf", conf) {
+                Ok(()) => saved,
+                Err(e) => {
+                    log::info!("shuru-guest: failed to write /etc/resolv.conf: {}", e);
+                    None
+                }
+            }
+        });
+
+        let session = {
+            let mut tunnels = tunnels();
+            tunnels.next_id += 1;
+            let session = tunnels.next_id;
+            tunnels.env.insert(session, env);
+            session
+        };
+        if !send_tunnel_message(&control, &TunnelMessage::Ready) {
+            tunnels().env.remove(&session);
+            return;
+        }
+        log::info!(
+            "shuru-guest: tunnel session {} listening on {}",
+            session,
+            listeners
+                .iter()
+                .map(|l| l.addr.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::new();
+        for (index, b) in bound.into_iter().enumerate() {
+            let control = Arc::clone(&control);
+            let stop = Arc::clone(&stop);
+            threads.push(std::thread::spawn(move || match b {
+                Bound::Stream(listener) => {
+                    while !stop.load(Ordering::Relaxed) {
+                        match listener.accept() {
+                            Ok((client, _)) => {
+                                let _ = client.set_nonblocking(false);
+                                let client = PendingClient::Stream(client);
+                                if !offer_client(session, index, client, &control) {
+                                    return;
+                                }
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                std::thread::sleep(std::time::Duration::from_millis(50));
+                            }
+                            Err(_) => return,
+                        }
+                    }
+                }
+                Bound::Dns(socket) => {
+                    let mut buf = vec![0u8; 4096];
+                    while !stop.load(Ordering::Relaxed) {
+                        let Ok((n, client)) = socket.recv_from(&mut buf) else {
+                            continue;
+                        };
+                        let pending = PendingClient::Dns {
+                            socket: Arc::clone(&socket),
+                            client,
+                            query: buf[..n].to_vec(),
+                        };
+                        if !offer_client(session, index, pending, &control) {
+                            return;
+                        }
+                    }
+                }
+            }));
+        }
+
+        // The session lasts until the host closes the control connection
+        let mut reader = control.lock().unwrap().try_clone();
+        if let Ok(reader) = reader.as_mut() {
+            let mut buf = [0u8; 256];
+            while matches!(reader.read(&mut buf), Ok(n) if n > 0) {}
+        }
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            let _ = thread.join();
+        }
+        {
+            let mut tunnels = tunnels();
+            tunnels.env.remove(&session);
+            tunnels.pending.retain(|_, (s, _, _)| *s != session);
+        }
+        if let Some(saved) = saved_resolv_conf {
+            let _ = std::fs::write("/etc/resolv.conf", saved);
+        }
+        log::info!("shuru-guest: tunnel session {} closed", session);
+    }
+
+    /// Carry pending client `id` over the host's `accept` connection.
+    fn accept_tunnel(mut stream: std::net::TcpStream, id: u64) {
+        let Some((_, _, client)) = tunnels().pending.remove(&id) else {
+            return;
+        };
+        match client {
+            PendingClient::Stream(client) => forward_relay(stream, client),
+            PendingClient::Dns {
+                socket,
+                client,
+                query,
+            } => {
+                // Framed as DNS over TCP
+                let _ = stream.set_read_timeout(Some(DNS_REPLY_TIMEOUT));
+                let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+                framed.extend_from_slice(&query);
+                let mut len = [0u8; 2];
+                if stream.write_all(&framed).is_err() || stream.read_exact(&mut len).is_err() {
+                    return;
+                }
+                let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+                if stream.read_exact(&mut response).is_ok() {
+                    let _ = socket.send_to(&response, client);
+                }
+            }
+        }
+    }
+
+    // --- virtio-serial transport (QEMU hosts) ---
+    //
+    // Without vsock, the host multiplexes streams over one virtio-serial
+    // port. Frames are `[u32 stream][u8 op][u32 len][payload]` (big endian);
+    // keep in sync with shuru-vm's qemu backend. Each opened stream is
+    // bridged to a socketpair whose other end goes to the same handler a
+    // vsock connection on that port would.
+
+    const SERIAL_PORT_NAME: &str = "org.shuru.agent";
+
+    const FRAME_HELLO: u8 = 0;
+    const FRAME_OPEN: u8 = 1;
+    const FRAME_DATA: u8 = 2;
+    const FRAME_EOF: u8 = 3;
+    const FRAME_CLOSE: u8 = 4;
+    const MAX_FRAME: usize = 64 * 1024;
+    /// Frames queued per stream before the link reader waits for it.
+    const STREAM_BACKLOG: usize = 64;
+
+    type Channels = Mutex<HashMap<u32, mpsc::SyncSender<Option<Vec<u8>>>>>;
+
+    struct SerialMux {
+        link: Mutex<std::fs::File>,
+        channels: Channels,
+    }
+
+    /// Device node of the agent's virtio-serial port, if the host has one.
+    fn find_serial_port() -> Option<String> {
+        let entries = std::fs::read_dir("/sys/class/virtio-ports").ok()?;
+        for entry in entries.flatten() {
+            let name = std::fs::read_to_string(entry.path().join("name")).unwrap_or_default();
+            if name.trim() == SERIAL_PORT_NAME {
+                return Some(format!("/dev/{}", entry.file_name().to_string_lossy()));
+            }
+        }
+        None
+    }
+
+    impl SerialMux {
+        fn send(&self, id: u32, op: u8, payload: &[u8]) -> std::io::Result<()> {
+            let mut header = [0u8; 9];
+            header[..4].copy_from_slice(&id.to_be_bytes());
+            header[4] = op;
+            header[5..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+            let mut link = self.link.lock().unwrap();
+            link.write_all(&header)?;
+            link.write_all(payload)
+        }
+
+        fn close(&self, id: u32) {
+            if self.channels.lock().unwrap().remove(&id).is_some() {
+                let _ = self.send(id, FRAME_CLOSE, &[]);
+            }
+        }
+
+        /// Forget a stream once both directions reached EOF.
+        fn finish(&self, id: u32, done: &AtomicU8) {
+            if done.fetch_add(1, Ordering::AcqRel) == 1 {
+                self.channels.lock().unwrap().remove(&id);
+            }
+        }
+
+        /// Bridge stream `id` to a new socketpair and hand the other end
+        /// to the handler for `port`.
+        fn open(self: &Arc<Self>, id: u32, port: u32) {
+            let handler: fn(i32) = match port {
+                VSOCK_PORT => handle_connection,
+                VSOCK_PORT_FORWARD => handle_forward_connection,
+                VSOCK_PORT_LOG => handle_log_connection,
+                VSOCK_PORT_TUNNEL => handle_tunnel_connection,
+                _ => {
+                    log::info!("shuru-guest: serial open for unknown port {}", port);
+                    let _ = self.send(id, FRAME_CLOSE, &[]);
+                    return;
+                }
+            };
+            let (local, remote) = match UnixStream::pair() {
+                Ok(pair) => pair,
+                Err(_) => {
+                    let _ = self.send(id, FRAME_CLOSE, &[]);
+                    return;
+                }
+            };
+            let Ok(mut reader) = local.try_clone() else {
+                let _ = self.send(id, FRAME_CLOSE, &[]);
+                return;
+            };
+            let mut writer = local;
+            let (tx, rx) = mpsc::sync_channel::<Option<Vec<u8>>>(STREAM_BACKLOG);
+            self.channels.lock().unwrap().insert(id, tx);
+            let done = Arc::new(AtomicU8::new(0));
+
+            let mux = Arc::clone(self);
+            let writer_done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                for msg in rx {
+                    match msg {
+                        Some(data) => {
+                            if writer.write_all(&data).is_err() {
+                                mux.close(id);
+                                return;
+                            }
+                        }
+                        None => {
+                            let _ = writer.shutdown(std::net::Shutdown::Write);
+                            mux.finish(id, &writer_done);
+                        }
+                    }
+                }
+                let _ = writer.shutdown(std::net::Shutdown::Both);
+            });
+
+            let mux = Arc::clone(self);
+            std::thread::spawn(move || {
+                let mut buf = vec![0u8; MAX_FRAME];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => {
+                            let _ = mux.send(id, FRAME_EOF, &[]);
+                            mux.finish(id, &done);
+                            return;
+                        }
+                        Ok(n) => {
+                            if mux.send(id, FRAME_DATA, &buf[..n]).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(_) => {
+                            mux.close(id);
+                            return;
+                        }
+                    }
+                }
+            });
+
+            let fd = remote.into_raw_fd();
+            std::thread::spawn(move || handler(fd));
+        }
+
+        fn deliver(&self, id: u32, msg: Option<Vec<u8>>) {
+            // Send outside the lock: a full stream blocks until its writer
+            // catches up, and the writer may need the lock to close.
+            let tx = self.channels.lock().unwrap().get(&id).cloned();
+            if let Some(tx) = tx {
+                let _ = tx.send(msg);
+            }
+        }
+    }
+
+    /// Whether the host end of the serial port is open. Linux reports
+    /// POLLHUP on a virtio-serial port while it is not.
+    fn host_connected(link: &std::fs::File) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: link.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let n = unsafe { libc::poll(&mut pfd, 1, 0) };
+        n <= 0 || pfd.revents & libc::POLLHUP == 0
+    }
+
+    fn serial_loop(path: &str, reap: bool, on_ready: fn()) -> ! {
+        let link = match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+        {
+            Ok(f) => f,
+            Err(e) => panic!("shuru-guest: failed to open {}: {}", path, e),
+        };
+        let mut reader = link.try_clone().expect("clone serial port");
+        let mux = Arc::new(SerialMux {
+            link: Mutex::new(link),
+            channels: Mutex::new(HashMap::new()),
+        });
+        let _ = mux.send(0, FRAME_HELLO, &[]);
+        on_ready();
+
+        let mut header = [0u8; 9];
+        loop {
+            if reader.read_exact(&mut header).is_err() {
+                // Host side not connected; streams cannot survive that.
+                mux.channels.lock().unwrap().clear();
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                while !host_connected(&reader) {
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                }
+                // A reconnected host waits for HELLO before opening streams
+                let _ = mux.send(0, FRAME_HELLO, &[]);
+                continue;
+            }
+            let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+            let len = u32::from_be_bytes(header[5..].try_into().unwrap()) as usize;
+            if len > MAX_FRAME {
+                log::info!(
+                    "shuru-guest: oversized frame ({} bytes) on serial transport",
+                    len
+                );
+                // Skip the payload so the next header is read in step
+                let _ = std::io::copy(&mut (&mut reader).take(len as u64), &mut std::io::sink());
+                continue;
+            }
+            let mut payload = vec![0u8; len];
+            if reader.read_exact(&mut payload).is_err() {
+                log::info!("shuru-guest: bad frame on serial transport");
+                continue;
+            }
+            match header[4] {
+                FRAME_OPEN if payload.len() == 4 => {
+                    mux.open(id, u32::from_be_bytes(payload[..4].try_into().unwrap()));
+                    if reap {
+                        reap_zombies();
+                    }
+                }
+                FRAME_DATA => mux.deliver(id, Some(payload)),
+                FRAME_EOF => mux.deliver(id, None),
+                FRAME_CLOSE => {
+                    mux.channels.lock().unwrap().remove(&id);
+                }
+                _ => {}
+            }
+        }
+    }
+
     extern "C" fn sigchld_handler(_: libc::c_int) {
         // Noop — actual reaping happens in the main loop
     }
@@ -929,6 +1962,57 @@ mod guest {
             );
         }
 
+        listen_and_serve(true, || {})
+    }
+
+    // --- Service mode ---
+
+    /// Marker file for init systems without sd_notify support (OpenRC).
+    const READY_FILE: &str = "/run/shuru-guest.ready";
+
+    /// Run under the distro's init (`shuru.init=system` boots): init has
+    /// already mounted filesystems, set the hostname and configured the
+    /// network, and owns shutdown and orphaned processes. Readiness is
+    /// reported once the host can connect.
+    pub fn run_service() -> ! {
+        log::info!("shuru-guest: starting as a service");
+        let _ = std::fs::remove_file(READY_FILE);
+        listen_and_serve(false, notify_ready)
+    }
+
+    /// Tell the init system the agent accepts connections: `READY=1` on
+    /// `$NOTIFY_SOCKET` for systemd (`Type=notify`), and [`READY_FILE`].
+    fn notify_ready() {
+        if let Err(e) = std::fs::write(READY_FILE, b"") {
+            log::info!("shuru-guest: failed to write {}: {}", READY_FILE, e);
+        }
+        let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+            return;
+        };
+        let sent = std::os::unix::net::UnixDatagram::unbound().and_then(|sock| {
+            match path.strip_prefix('@') {
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                    sock.send_to_addr(b"READY=1", &addr)
+                }
+                None => sock.send_to(b"READY=1", &path),
+            }
+        });
+        if let Err(e) = sent {
+            log::info!("shuru-guest: sd_notify failed: {}", e);
+        }
+    }
+
+    /// Listen on the host transport (virtio-serial under QEMU, vsock
+    /// otherwise), call `on_ready` once connections can be accepted, and
+    /// serve them.
+    fn listen_and_serve(reap: bool, on_ready: fn()) -> ! {
+        if let Some(port) = find_serial_port() {
+            log::info!("shuru-guest: using serial transport on {}", port);
+            serial_loop(&port, reap, on_ready);
+        }
+
         let listener_fd = create_vsock_listener(VSOCK_PORT);
         log::info!("shuru-guest: vsock listening on port {}", VSOCK_PORT);
 
@@ -937,37 +2021,202 @@ mod guest {
             "shuru-guest: port forward listener on port {}",
             VSOCK_PORT_FORWARD
         );
-        std::thread::spawn(move || {
-            forward_accept_loop(fwd_listener_fd);
-        });
+        let log_listener_fd = create_vsock_listener(VSOCK_PORT_LOG);
+        let tunnel_listener_fd = create_vsock_listener(VSOCK_PORT_TUNNEL);
+        on_ready();
+        serve(
+            listener_fd,
+            fwd_listener_fd,
+            log_listener_fd,
+            tunnel_listener_fd,
+            reap,
+        )
+    }
+
+    // --- Agent mode ---
+
+    /// Where an agent-mode guest listens. Each protocol port gets its own
+    /// endpoint: `PATH_<port>` for Unix sockets, and for TCP the base port
+    /// serves 1024 and the next three 1025 to 1027. shuru-vm's
+    /// `AgentBackend` uses the same layout.
+    pub enum ListenAddr {
+        Unix(String),
+        Tcp(String, u16),
+    }
+
+    impl ListenAddr {
+        /// Parse `unix:PATH`, `tcp:HOST:PORT`, or a bare path or
+        /// `HOST:PORT`.
+        pub fn parse(spec: &str) -> Result<Self, String> {
+            if let Some(path) = spec.strip_prefix("unix:") {
+                return Ok(ListenAddr::Unix(path.to_string()));
+            }
+            let tcp = spec.strip_prefix("tcp:");
+            if tcp.is_none() && spec.contains('/') {
+                return Ok(ListenAddr::Unix(spec.to_string()));
+            }
+            let tcp = tcp.unwrap_or(spec);
+            let (host, port) = tcp
+                .rsplit_once(':')
+                .ok_or_else(|| format!("invalid listen address '{}'", spec))?;
+            let port = port
+                .parse::<u16>()
+                .ok()
+                .filter(|p| p.checked_add(3).is_some())
+                .ok_or_else(|| format!("invalid port in listen address '{}'", spec))?;
+            Ok(ListenAddr::Tcp(host.to_string(), port))
+        }
+
+        /// Whether only this machine can connect: a Unix socket, or a TCP
+        /// host that resolves to loopback addresses alone.
+        pub fn is_local(&self) -> bool {
+            match self {
+                ListenAddr::Unix(_) => true,
+                ListenAddr::Tcp(host, port) => {
+                    use std::net::ToSocketAddrs;
+                    (host.as_str(), *port)
+                        .to_socket_addrs()
+                        .map(|addrs| {
+                            let addrs: Vec<_> = addrs.collect();
+                            !addrs.is_empty() && addrs.iter().all(|a| a.ip().is_loopback())
+                        })
+                        .unwrap_or(false)
+                }
+            }
+        }
+
+        fn listen(&self, port: u32) -> std::io::Result<i32> {
+            match self {
+                ListenAddr::Unix(path) => {
+                    let path = format!("{}_{}", path, port);
+                    let _ = std::fs::remove_file(&path);
+                    let listener = std::os::unix::net::UnixListener::bind(&path)?;
+                    log::info!("shuru-guest: port {} on unix:{}", port, path);
+                    Ok(listener.into_raw_fd())
+                }
+                ListenAddr::Tcp(host, base) => {
+                    let tcp_port = base + (port - VSOCK_PORT) as u16;
+                    let listener = std::net::TcpListener::bind((host.as_str(), tcp_port))?;
+                    log::info!("shuru-guest: port {} on tcp:{}:{}", port, host, tcp_port);
+                    Ok(listener.into_raw_fd())
+                }
+            }
+        }
+    }
+
+    /// Serve the agent protocol as an ordinary process: no mounts,
+    /// hostname, networking or power-off handling, and Unix socket or TCP
+    /// listeners instead of vsock. Used to test the host side without a VM.
+    pub fn run_agent(addr: &ListenAddr) -> ! {
+        log::info!("shuru-guest: starting in agent mode");
+        AGENT_MODE.store(true, Ordering::Relaxed);
+        let listen = |port| {
+            addr.listen(port).unwrap_or_else(|e| {
+                eprintln!("shuru-guest: failed to listen for port {}: {}", port, e);
+                std::process::exit(1);
+            })
+        };
+        let listener_fd = listen(VSOCK_PORT);
+        let fwd_listener_fd = listen(VSOCK_PORT_FORWARD);
+        let log_listener_fd = listen(VSOCK_PORT_LOG);
+        let tunnel_listener_fd = listen(VSOCK_PORT_TUNNEL);
+        serve(
+            listener_fd,
+            fwd_listener_fd,
+            log_listener_fd,
+            tunnel_listener_fd,
+            false,
+        )
+    }
+
+    /// Accept exec/mount connections on `listener_fd`, port forwards on
+    /// `fwd_listener_fd`, log streams on `log_listener_fd` and tunnels on
+    /// `tunnel_listener_fd`. As PID 1 (`reap`), orphaned processes are
+    /// reaped between connections.
+    fn serve(
+        listener_fd: i32,
+        fwd_listener_fd: i32,
+        log_listener_fd: i32,
+        tunnel_listener_fd: i32,
+        reap: bool,
+    ) -> ! {
+        std::thread::spawn(move || accept_loop(fwd_listener_fd, handle_forward_connection));
+        std::thread::spawn(move || accept_loop(log_listener_fd, handle_log_connection));
+        std::thread::spawn(move || accept_loop(tunnel_listener_fd, handle_tunnel_connection));
 
         loop {
             let client_fd =
                 unsafe { libc::accept(listener_fd, std::ptr::null_mut(), std::ptr::null_mut()) };
 
             if client_fd < 0 {
-                reap_zombies();
+                if reap {
+                    reap_zombies();
+                }
                 continue;
             }
 
-            log::info!("shuru-guest: accepted vsock connection");
+            log::info!("shuru-guest: accepted connection");
 
             std::thread::spawn(move || {
                 handle_connection(client_fd);
             });
 
-            reap_zombies();
+            if reap {
+                reap_zombies();
+            }
         }
     }
 }
 
+/// Environment variable selecting agent mode, as an alternative to
+/// `--listen ADDR`.
+#[cfg(target_os = "linux")]
+const AGENT_ADDR_ENV: &str = "SHURU_AGENT_ADDR";
+
+/// The agent-mode listen address from `--listen` or the environment.
+#[cfg(target_os = "linux")]
+fn agent_listen_addr() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--listen" {
+            return args.next();
+        }
+        if let Some(addr) = arg.strip_prefix("--listen=") {
+            return Some(addr.to_string());
+        }
+    }
+    std::env::var(AGENT_ADDR_ENV).ok().filter(|a| !a.is_empty())
+}
+
 fn main() {
     #[cfg(target_os = "linux")]
-    let _ = syslog::init(
-        Facility::LOG_USER,
-        log::LevelFilter::Debug,
-        Some("shuru-guest"),
-    );
+    guest::init_logging();
+
+    #[cfg(target_os = "linux")]
+    if let Some(spec) = agent_listen_addr() {
+        match guest::ListenAddr::parse(&spec) {
+            // Anyone who can connect runs commands as this user
+            Ok(addr) if !addr.is_local() && !std::env::args().any(|a| a == "--allow-remote") => {
+                eprintln!(
+                    "shuru-guest: refusing to listen on non-loopback address '{}'; \
+                     pass --allow-remote to accept connections from other machines",
+                    spec
+                );
+                std::process::exit(2);
+            }
+            Ok(addr) => guest::run_agent(&addr),
+            Err(e) => {
+                eprintln!("shuru-guest: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if std::env::args().skip(1).any(|a| a == "--service") {
+        guest::run_service();
+    }
+
     #[cfg(target_os = "linux")]
     guest::run();
 
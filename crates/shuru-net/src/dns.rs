@@ -0,0 +1,263 @@
+//! Just enough of the DNS wire format to filter queries: read the question,
+//! answer with an error, and collect the IPv4 addresses of a response.
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+/// Response code for queries that cannot be parsed.
+pub(crate) const RCODE_FORMERR: u8 = 1;
+/// Response code for names the policy does not allow.
+pub(crate) const RCODE_NXDOMAIN: u8 = 3;
+/// Response code when no upstream resolver answered.
+pub(crate) const RCODE_SERVFAIL: u8 = 2;
+
+/// Name asked for by a standard query with a single question.
+pub(crate) fn query_name(msg: &[u8]) -> Option<String> {
+    if msg.len() < HEADER_LEN {
+        return None;
+    }
+    let is_response = msg[2] & 0x80 != 0;
+    let opcode = (msg[2] >> 3) & 0x0f;
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    if is_response || opcode != 0 || questions != 1 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Queries never use compression
+        if len > 63 {
+            return None;
+        }
+        let label = msg.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    // QTYPE and QCLASS must follow
+    msg.get(pos..pos + 4)?;
+    Some(labels.join("."))
+}
+
+/// An empty response to `query` with `rcode`; just a header (with a zero
+/// ID if even that is missing) when the question can't be found.
+pub(crate) fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    let question_end = question_end(query).unwrap_or(HEADER_LEN.min(query.len()));
+    let mut msg = query[..question_end].to_vec();
+    if msg.len() < HEADER_LEN {
+        msg.resize(HEADER_LEN, 0);
+    }
+    // QR, keep opcode and RD; RA; rcode
+    msg[2] = 0x80 | (msg[2] & 0x79);
+    msg[3] = 0x80 | rcode;
+    let questions = if question_end > HEADER_LEN { 1u16 } else { 0 };
+    msg[4..6].copy_from_slice(&questions.to_be_bytes());
+    msg[6..12].fill(0);
+    msg
+}
+
+fn question_end(msg: &[u8]) -> Option<usize> {
+    let mut pos = skip_name(msg, HEADER_LEN)?;
+    pos += 4;
+    (pos <= msg.len()).then_some(pos)
+}
+
+/// Offset just past the (possibly compressed) name at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+/// IPv4 addresses in the answer section of a response.
+pub(crate) fn answer_addrs(msg: &[u8]) -> Vec<Ipv4Addr> {
+    let mut addrs = Vec::new();
+    if msg.len() < HEADER_LEN {
+        return addrs;
+    }
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        match skip_name(msg, pos) {
+            Some(end) => pos = end + 4,
+            None => return addrs,
+        }
+    }
+    for _ in 0..answers {
+        let Some(end) = skip_name(msg, pos) else {
+            break;
+        };
+        let Some(fixed) = msg.get(end..end + 10) else {
+            break;
+        };
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let rdlen = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let Some(rdata) = msg.get(end + 10..end + 10 + rdlen) else {
+            break;
+        };
+        if rtype == TYPE_A && class == CLASS_IN && rdlen == 4 {
+            addrs.push(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+        }
+        pos = end + 10 + rdlen;
+    }
+    addrs
+}
+
+/// Send `query` to each upstream resolver in turn until one answers within
+/// `timeout`.
+pub(crate) fn forward(
+    upstreams: &[SocketAddr],
+    query: &[u8],
+    timeout: Duration,
+) -> Option<Vec<u8>> {
+    for upstream in upstreams {
+        let bind: SocketAddr = match upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let Ok(socket) = UdpSocket::bind(bind) else {
+            continue;
+        };
+        let _ = socket.set_read_timeout(Some(timeout));
+        if socket.send_to(query, upstream).is_err() {
+            continue;
+        }
+        let mut buf = vec![0; 4096];
+        if let Ok((n, from)) = socket.recv_from(&mut buf) {
+            if from == *upstream && n >= 2 && buf[..2] == query[..2] {
+                buf.truncate(n);
+                return Some(buf);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A standard query for the A records of `name`.
+    pub(crate) fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        // RD, one question
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        // Root, QTYPE A, QCLASS IN
+        msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+        msg
+    }
+
+    /// A response to `query` answering with `addrs`, after a CNAME record.
+    pub(crate) fn answer(query: &[u8], addrs: &[Ipv4Addr]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[6..8].copy_from_slice(&(addrs.len() as u16 + 1).to_be_bytes());
+        // Names point back at the question (offset 12)
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        for addr in addrs {
+            msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            msg.extend_from_slice(&addr.octets());
+        }
+        msg
+    }
+
+    #[test]
+    fn query_name_reads_the_question() {
+        assert_eq!(
+            query_name(&query(1, "API.Example.com")).as_deref(),
+            Some("api.example.com")
+        );
+
+        let mut response = query(1, "example.com");
+        response[2] |= 0x80;
+        assert_eq!(query_name(&response), None);
+        let mut two = query(1, "example.com");
+        two[5] = 2;
+        assert_eq!(query_name(&two), None);
+        let mut status = query(1, "example.com");
+        status[2] |= 2 << 3;
+        assert_eq!(query_name(&status), None);
+    }
+
+    #[test]
+    fn query_name_rejects_malformed_questions() {
+        let q = query(1, "example.com");
+        assert_eq!(query_name(&q[..11]), None);
+        // Truncated in a label, and missing QTYPE/QCLASS
+        assert_eq!(query_name(&q[..16]), None);
+        assert_eq!(query_name(&q[..q.len() - 1]), None);
+        // Compression pointer in a query
+        let mut pointer = q[..12].to_vec();
+        pointer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert_eq!(query_name(&pointer), None);
+    }
+
+    #[test]
+    fn error_response_echoes_the_question() {
+        let q = query(0xbeef, "example.com");
+        let r = error_response(&q, RCODE_NXDOMAIN);
+        assert_eq!(r[..2], [0xbe, 0xef]);
+        // QR and RD set, RA set, rcode
+        assert_eq!((r[2], r[3]), (0x81, 0x80 | RCODE_NXDOMAIN));
+        assert_eq!(r[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(r[12..], q[12..]);
+
+        // Anything after the question is dropped
+        let mut extra = q.clone();
+        extra.extend_from_slice(&[0; 11]);
+        assert_eq!(error_response(&extra, RCODE_NXDOMAIN), r);
+    }
+
+    #[test]
+    fn error_response_to_garbage_is_a_header() {
+        let r = error_response(&[0xab, 0xcd, 0x01], RCODE_FORMERR);
+        assert_eq!(r.len(), HEADER_LEN);
+        assert_eq!(r[..4], [0xab, 0xcd, 0x81, 0x80 | RCODE_FORMERR]);
+        assert!(r[4..].iter().all(|&b| b == 0));
+
+        let r = error_response(&[], RCODE_FORMERR);
+        assert_eq!(r[..4], [0, 0, 0x80, 0x80 | RCODE_FORMERR]);
+        // A header with an unterminated name has no question
+        let r = error_response(
+            &[0xab, 0xcd, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 5],
+            RCODE_FORMERR,
+        );
+        assert_eq!(r.len(), HEADER_LEN);
+        assert_eq!(r[4..6], [0, 0]);
+    }
+
+    #[test]
+    fn answer_addrs_collects_a_records() {
+        let q = query(1, "example.com");
+        let addrs = [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)];
+        let r = answer(&q, &addrs);
+        assert_eq!(answer_addrs(&r), addrs);
+
+        // Records cut short are ignored, earlier ones kept
+        assert_eq!(answer_addrs(&r[..r.len() - 1]), addrs[..1]);
+        assert!(answer_addrs(&r[..11]).is_empty());
+        assert!(answer_addrs(&error_response(&q, RCODE_NXDOMAIN)).is_empty());
+    }
+}
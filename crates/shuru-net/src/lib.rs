@@ -0,0 +1,90 @@
+//! Host-side networking for sandboxes with an egress policy.
+//!
+//! Instead of NAT, the VM's network device is attached to a socket whose
+//! other end is served by [`NetStack`]: guest traffic is terminated on the
+//! host, checked against an [`EgressPolicy`], and proxied through ordinary
+//! host sockets. The stack works on Ethernet frames and does no I/O of its
+//! own, so it can be driven directly without a VM.
+//!
+//! Guests without a network device can instead use [`EgressProxy`], an
+//! HTTP proxy and DNS resolver enforcing the same policy.
+
+mod dns;
+mod link;
+mod policy;
+mod proxy;
+mod stack;
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+pub use link::{datagram_pair, run, FrameLink, NetHandle};
+pub use policy::{Cidr, EgressPolicy, PolicyError};
+pub use proxy::{ClientStream, EgressProxy, ProxyRequest, RequestKind};
+pub use stack::NetStack;
+
+/// Addresses of the guest network.
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    /// Address of the stack itself: the guest's gateway and DNS server.
+    pub gateway: Ipv4Addr,
+    /// Address the DHCP server hands to the guest.
+    pub guest: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Resolvers allowed DNS queries are forwarded to.
+    pub dns_servers: Vec<SocketAddr>,
+}
+
+impl Default for NetConfig {
+    /// The addresses QEMU's user networking uses, and the host's resolvers.
+    fn default() -> Self {
+        NetConfig {
+            gateway: Ipv4Addr::new(10, 0, 2, 2),
+            guest: Ipv4Addr::new(10, 0, 2, 15),
+            prefix_len: 24,
+            dns_servers: host_resolvers(),
+        }
+    }
+}
+
+/// Nameservers from the host's `/etc/resolv.conf`, or a public resolver.
+pub fn host_resolvers() -> Vec<SocketAddr> {
+    let servers: Vec<SocketAddr> = std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            (words.next() == Some("nameserver"))
+                .then(|| words.next())
+                .flatten()
+                .and_then(|addr| addr.parse().ok())
+                .map(|ip| SocketAddr::new(ip, 53))
+        })
+        .collect();
+    if servers.is_empty() {
+        vec![SocketAddr::from(([8, 8, 8, 8], 53))]
+    } else {
+        servers
+    }
+}
+
+/// Traffic the policy did not allow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denial {
+    /// A DNS lookup of a name that is not allowed.
+    Dns { name: String },
+    /// A TCP connection to an address no allowed name resolved to.
+    Tcp { addr: SocketAddrV4 },
+    /// UDP other than DNS is never allowed.
+    Udp { addr: SocketAddrV4 },
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::Dns { name } => write!(f, "DNS lookup of {}", name),
+            Denial::Tcp { addr } => write!(f, "TCP connection to {}", addr),
+            Denial::Udp { addr } => write!(f, "UDP packet to {}", addr),
+        }
+    }
+}
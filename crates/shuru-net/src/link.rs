@@ -0,0 +1,195 @@
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+use crate::NetStack;
+
+/// Largest Ethernet frame the stack exchanges with the guest.
+const MAX_FRAME: usize = 65536;
+/// Socket buffer sizes for datagram links; the receive side is larger so
+/// bursts from the VM are not dropped.
+const SEND_BUFFER: libc::c_int = 1024 * 1024;
+const RECV_BUFFER: libc::c_int = 4 * 1024 * 1024;
+
+/// A socket carrying the guest's Ethernet frames.
+pub trait FrameLink: Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Wait for the next frame.
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>>;
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+    /// Wake up a pending [`recv_frame`](Self::recv_frame) and fail it.
+    fn shutdown(&self);
+}
+
+/// One frame per datagram, as Virtualization.framework's file handle
+/// attachment sends them.
+impl FrameLink for UnixDatagram {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixDatagram::try_clone(self)
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; MAX_FRAME];
+        let n = self.recv(&mut buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.send(frame).map(drop)
+    }
+
+    fn shutdown(&self) {
+        let _ = UnixDatagram::shutdown(self, Shutdown::Both);
+    }
+}
+
+/// Frames prefixed with their length as a big-endian `u32`, as QEMU's
+/// `stream` netdev sends them.
+impl FrameLink for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes", len),
+            ));
+        }
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(4 + frame.len());
+        buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        buf.extend_from_slice(frame);
+        self.write_all(&buf)
+    }
+
+    fn shutdown(&self) {
+        let _ = UnixStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+/// A connected datagram socket pair for a VM's network device, with
+/// buffers large enough for a burst of full-size frames.
+pub fn datagram_pair() -> io::Result<(UnixDatagram, UnixDatagram)> {
+    let (a, b) = UnixDatagram::pair()?;
+    for socket in [&a, &b] {
+        set_buffer(socket, libc::SO_SNDBUF, SEND_BUFFER)?;
+        set_buffer(socket, libc::SO_RCVBUF, RECV_BUFFER)?;
+    }
+    Ok((a, b))
+}
+
+fn set_buffer(socket: &UnixDatagram, option: libc::c_int, size: libc::c_int) -> io::Result<()> {
+    // SAFETY: the fd is open for the duration of the call and `size` is a
+    // c_int, as these options expect.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A running stack, stopped when dropped.
+pub struct NetHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NetHandle {
+    /// Whether the link has closed, e.g. because the VM stopped.
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+}
+
+impl Drop for NetHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serve the guest on `link` with `stack` until the link closes or the
+/// returned handle is dropped.
+pub fn run<L: FrameLink>(link: L, mut stack: NetStack) -> io::Result<NetHandle> {
+    let mut reader = link.try_clone()?;
+    let mut writer = link;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = Arc::clone(&stop);
+
+    let thread = std::thread::Builder::new()
+        .name("shuru-net".into())
+        .spawn(move || {
+            let (tx, rx) = mpsc::sync_channel(256);
+            let reader_thread = std::thread::spawn(move || {
+                while let Ok(frame) = reader.recv_frame() {
+                    if tx.send(frame).is_err() {
+                        return;
+                    }
+                }
+            });
+
+            while !thread_stop.load(Ordering::Relaxed) {
+                match rx.recv_timeout(stack.poll_delay()) {
+                    Ok(frame) => {
+                        stack.receive(&frame);
+                        // Take whatever else has arrived before polling
+                        while let Ok(frame) = rx.try_recv() {
+                            stack.receive(&frame);
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                stack.poll();
+                while let Some(frame) = stack.transmit() {
+                    if let Err(e) = writer.send_frame(&frame) {
+                        // A full socket buffer drops the frame, like a
+                        // congested link; TCP retransmits it
+                        if e.kind() != io::ErrorKind::WouldBlock
+                            && e.raw_os_error() != Some(libc::ENOBUFS)
+                        {
+                            tracing::debug!("shuru-net: link closed: {}", e);
+                            thread_stop.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                }
+            }
+            writer.shutdown();
+            drop(rx);
+            let _ = reader_thread.join();
+        })?;
+
+    Ok(NetHandle {
+        stop,
+        thread: Some(thread),
+    })
+}
@@ -0,0 +1,267 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// An invalid egress rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyError(String);
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+/// An IPv4 network, e.g. `10.0.0.0/8`. A bare address is a `/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Result<Self, PolicyError> {
+        if prefix > 32 {
+            return Err(PolicyError(format!("invalid prefix length /{}", prefix)));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        (u32::from(ip) ^ u32::from(self.addr)) & self.mask() == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Self, PolicyError> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let prefix = prefix
+                    .parse()
+                    .map_err(|_| PolicyError(format!("invalid prefix length in '{}'", s)))?;
+                (addr, prefix)
+            }
+            None => (s, 32),
+        };
+        let addr = addr
+            .parse()
+            .map_err(|_| PolicyError(format!("invalid IPv4 address in '{}'", s)))?;
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Which destinations a guest may reach; everything else is denied.
+///
+/// Domains are enforced at DNS: the stack only resolves allowed names, and
+/// a TCP connection is allowed if its address is in an allowed network or
+/// was returned for an allowed name. A domain also allows its subdomains.
+#[derive(Debug, Clone, Default)]
+pub struct EgressPolicy {
+    domains: Vec<String>,
+    networks: Vec<Cidr>,
+}
+
+impl EgressPolicy {
+    /// A policy that denies everything.
+    pub fn deny_all() -> Self {
+        EgressPolicy::default()
+    }
+
+    /// Allow a domain (`example.com`, or `*.example.com` for subdomains
+    /// only), an address or a network (`10.0.0.0/8`).
+    pub fn allow(mut self, rule: &str) -> Result<Self, PolicyError> {
+        let rule = rule.trim();
+        if rule.starts_with(|c: char| c.is_ascii_digit()) && !rule.contains(char::is_alphabetic) {
+            self.networks.push(rule.parse()?);
+            return Ok(self);
+        }
+        let domain = rule.trim_end_matches('.').to_ascii_lowercase();
+        let name = domain.strip_prefix("*.").unwrap_or(&domain);
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        if !valid {
+            return Err(PolicyError(format!("invalid domain or network '{}'", rule)));
+        }
+        self.domains.push(domain);
+        Ok(self)
+    }
+
+    /// Allow an IPv4 network.
+    pub fn allow_network(mut self, network: Cidr) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Whether DNS lookups of `name` are answered.
+    pub fn allows_domain(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.domains
+            .iter()
+            .any(|rule| match rule.strip_prefix("*.") {
+                Some(parent) => name
+                    .strip_suffix(parent)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => {
+                    name == *rule
+                        || name
+                            .strip_suffix(rule.as_str())
+                            .is_some_and(|sub| sub.ends_with('.'))
+                }
+            })
+    }
+
+    /// Whether `ip` is in an allowed network.
+    pub fn allows_ip(&self, ip: Ipv4Addr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() && self.networks.is_empty()
+    }
+}
+
+impl fmt::Display for EgressPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("deny all");
+        }
+        let rules: Vec<String> = self
+            .domains
+            .iter()
+            .cloned()
+            .chain(self.networks.iter().map(Cidr::to_string))
+            .collect();
+        write!(f, "allow {}", rules.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &[&str]) -> EgressPolicy {
+        rules
+            .iter()
+            .try_fold(EgressPolicy::deny_all(), |p, rule| p.allow(rule))
+            .unwrap()
+    }
+
+    #[test]
+    fn apex_rule_allows_the_domain_and_subdomains() {
+        let p = policy(&["example.com"]);
+        assert!(p.allows_domain("example.com"));
+        assert!(p.allows_domain("api.example.com"));
+        assert!(p.allows_domain("a.b.example.com"));
+        assert!(!p.allows_domain("badexample.com"));
+        assert!(!p.allows_domain("example.com.evil.org"));
+        assert!(!p.allows_domain("com"));
+    }
+
+    #[test]
+    fn wildcard_rule_allows_only_subdomains() {
+        let p = policy(&["*.example.com"]);
+        assert!(p.allows_domain("api.example.com"));
+        assert!(p.allows_domain("a.b.example.com"));
+        assert!(!p.allows_domain("example.com"));
+        assert!(!p.allows_domain("badexample.com"));
+    }
+
+    #[test]
+    fn domains_ignore_case_and_trailing_dot() {
+        let p = policy(&["Example.COM.", "*.Internal.Test"]);
+        assert!(p.allows_domain("example.com"));
+        assert!(p.allows_domain("API.Example.Com."));
+        assert!(p.allows_domain("db.internal.test."));
+        assert!(!p.allows_domain("internal.test"));
+        assert!(!p.allows_domain("."));
+        assert!(!EgressPolicy::deny_all().allows_domain("example.com"));
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected() {
+        for rule in ["", "*.", "a..b", "exa mple.com", "*.*.example.com"] {
+            assert!(EgressPolicy::deny_all().allow(rule).is_err(), "{:?}", rule);
+        }
+        let long = format!("{}.com", "a".repeat(64));
+        assert!(EgressPolicy::deny_all().allow(&long).is_err());
+    }
+
+    #[test]
+    fn cidr_parses_networks_and_addresses() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert_eq!(net, Cidr::new(Ipv4Addr::new(10, 1, 0, 0), 16).unwrap());
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+        assert_eq!(
+            "192.168.1.1".parse::<Cidr>().unwrap().to_string(),
+            "192.168.1.1/32"
+        );
+    }
+
+    #[test]
+    fn cidr_rejects_malformed_input() {
+        for s in [
+            "",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "256.0.0.0/8",
+            "10.0.0.0/8/8",
+            "::1/128",
+            "example.com/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{:?}", s);
+        }
+        assert!(Cidr::new(Ipv4Addr::UNSPECIFIED, 33).is_err());
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(Ipv4Addr::new(10, 1, 0, 0)));
+        assert!(net.contains(Ipv4Addr::new(10, 1, 255, 255)));
+        assert!(!net.contains(Ipv4Addr::new(10, 2, 0, 0)));
+
+        // Host bits in the network address are ignored
+        let net: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert!(net.contains(Ipv4Addr::new(10, 200, 0, 1)));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(Ipv4Addr::UNSPECIFIED));
+        assert!(all.contains(Ipv4Addr::BROADCAST));
+
+        let host: Cidr = "192.168.1.1/32".parse().unwrap();
+        assert!(host.contains(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(!host.contains(Ipv4Addr::new(192, 168, 1, 2)));
+    }
+
+    #[test]
+    fn network_rules_allow_addresses() {
+        let p = policy(&["10.0.0.0/8", "192.168.1.1"]);
+        assert!(p.allows_ip(Ipv4Addr::new(10, 9, 8, 7)));
+        assert!(p.allows_ip(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(!p.allows_ip(Ipv4Addr::new(192, 168, 1, 2)));
+        assert!(!p.allows_domain("10.0.0.1"));
+        assert!(EgressPolicy::deny_all().allow("10.0.0.0/40").is_err());
+    }
+}
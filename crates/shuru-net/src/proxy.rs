@@ -0,0 +1,602 @@
+//! Allowlist proxies for guests without a network device: an HTTP proxy
+//! (`CONNECT` tunnels and plain forward requests) and a DNS resolver, each
+//! served on a stream the host gets from the guest.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::dns;
+use crate::policy::EgressPolicy;
+
+/// How long connecting to an allowed destination may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request head the HTTP proxy reads.
+const MAX_HEAD: usize = 64 * 1024;
+
+type RequestHook = Box<dyn Fn(&ProxyRequest) + Send + Sync>;
+
+/// A socket the proxies serve a client on. Depending on the transport, the
+/// host's end of a guest stream is a TCP or a Unix socket.
+pub trait ClientStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl ClientStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl ClientStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    /// A DNS lookup.
+    Dns,
+    /// An HTTP `CONNECT` tunnel, usually for HTTPS.
+    Connect,
+    /// A plain HTTP request.
+    Http,
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RequestKind::Dns => "dns",
+            RequestKind::Connect => "connect",
+            RequestKind::Http => "http",
+        })
+    }
+}
+
+/// One request handled by an [`EgressProxy`].
+#[derive(Debug, Clone)]
+pub struct ProxyRequest {
+    pub kind: RequestKind,
+    pub host: String,
+    /// Destination port; `None` for DNS.
+    pub port: Option<u16>,
+    /// Method of a plain HTTP request.
+    pub method: Option<String>,
+    pub allowed: bool,
+    /// Why an allowed request failed, e.g. the connection was refused.
+    pub error: Option<String>,
+}
+
+/// HTTP proxy and DNS resolver that only serve destinations an
+/// [`EgressPolicy`] allows. Every request is logged and passed to the
+/// [`on_request`](Self::on_request) hook.
+pub struct EgressProxy {
+    policy: EgressPolicy,
+    dns_servers: Vec<SocketAddr>,
+    on_request: Option<RequestHook>,
+}
+
+impl EgressProxy {
+    /// A proxy resolving names with the host's resolvers.
+    pub fn new(policy: EgressPolicy) -> Self {
+        EgressProxy {
+            policy,
+            dns_servers: crate::host_resolvers(),
+            on_request: None,
+        }
+    }
+
+    /// Call `f` for every request, allowed or not.
+    pub fn on_request(mut self, f: impl Fn(&ProxyRequest) + Send + Sync + 'static) -> Self {
+        self.on_request = Some(Box::new(f));
+        self
+    }
+
+    pub fn policy(&self) -> &EgressPolicy {
+        &self.policy
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => self.policy.allows_ip(ip),
+            Ok(IpAddr::V6(_)) => false,
+            Err(_) => self.policy.allows_domain(host),
+        }
+    }
+
+    fn report(&self, request: ProxyRequest) {
+        let target = match request.port {
+            Some(port) => format!("{}:{}", request.host, port),
+            None => request.host.clone(),
+        };
+        if !request.allowed {
+            tracing::warn!("shuru-net: denied {} {}", request.kind, target);
+        } else if let Some(error) = &request.error {
+            tracing::debug!("shuru-net: {} {} failed: {}", request.kind, target, error);
+        } else {
+            tracing::debug!("shuru-net: {} {}", request.kind, target);
+        }
+        if let Some(f) = &self.on_request {
+            f(&request);
+        }
+    }
+
+    /// Serve one HTTP proxy client: a `CONNECT` tunnel, or a plain request
+    /// with an absolute URL, forwarded with `Connection: close`.
+    pub fn serve_http(&self, mut client: impl ClientStream) -> io::Result<()> {
+        let head = read_head(&mut client)?;
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return respond(&mut client, "400 Bad Request", "malformed request\n");
+        };
+
+        let (kind, authority, path) = if method.eq_ignore_ascii_case("CONNECT") {
+            (RequestKind::Connect, target, None)
+        } else if let Some(rest) = target.strip_prefix("http://") {
+            let (authority, path) = match rest.find('/') {
+                Some(i) => rest.split_at(i),
+                None => (rest, "/"),
+            };
+            (RequestKind::Http, authority, Some(path))
+        } else {
+            return respond(
+                &mut client,
+                "400 Bad Request",
+                "only CONNECT and absolute http:// URLs are proxied\n",
+            );
+        };
+        let Some((host, port)) = split_authority(authority, kind) else {
+            return respond(&mut client, "400 Bad Request", "invalid host\n");
+        };
+
+        let mut request = ProxyRequest {
+            kind,
+            host: host.clone(),
+            port: Some(port),
+            method: (kind == RequestKind::Http).then(|| method.to_string()),
+            allowed: self.allows(&host),
+            error: None,
+        };
+        if !request.allowed {
+            self.report(request);
+            return respond(
+                &mut client,
+                "403 Forbidden",
+                &format!("{} is not allowed by the sandbox network policy\n", host),
+            );
+        }
+        let mut upstream = match connect(&host, port) {
+            Ok(stream) => stream,
+            Err(e) => {
+                request.error = Some(e.to_string());
+                self.report(request);
+                return respond(
+                    &mut client,
+                    "502 Bad Gateway",
+                    &format!("cannot connect to {}:{}: {}\n", host, port, e),
+                );
+            }
+        };
+        self.report(request);
+
+        match path {
+            None => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+            Some(path) => {
+                let mut forwarded = format!("{} {} {}\r\n", method, path, version);
+                for line in lines.filter(|l| !l.is_empty()) {
+                    let name = line.split(':').next().unwrap_or_default().trim();
+                    if !["proxy-connection", "proxy-authorization", "connection"]
+                        .iter()
+                        .any(|h| name.eq_ignore_ascii_case(h))
+                    {
+                        forwarded.push_str(line);
+                        forwarded.push_str("\r\n");
+                    }
+                }
+                forwarded.push_str("Connection: close\r\n\r\n");
+                upstream.write_all(forwarded.as_bytes())?;
+            }
+        }
+        relay(client, upstream);
+        Ok(())
+    }
+
+    /// Answer DNS queries framed as over TCP (each prefixed with its
+    /// length as a big-endian `u16`) until the client closes the stream.
+    pub fn serve_dns(&self, mut client: impl Read + Write) -> io::Result<()> {
+        loop {
+            let mut len = [0; 2];
+            match client.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            client.read_exact(&mut query)?;
+
+            // Always answer, so the client never waits on a reply
+            let Some(name) = dns::query_name(&query) else {
+                tracing::debug!("shuru-net: malformed dns query");
+                write_dns(
+                    &mut client,
+                    &dns::error_response(&query, dns::RCODE_FORMERR),
+                )?;
+                continue;
+            };
+            let mut request = ProxyRequest {
+                kind: RequestKind::Dns,
+                host: name,
+                port: None,
+                method: None,
+                allowed: false,
+                error: None,
+            };
+            request.allowed = self.allows(&request.host);
+            let response = if request.allowed {
+                dns::forward(&self.dns_servers, &query, DNS_TIMEOUT).unwrap_or_else(|| {
+                    request.error = Some("no resolver answered".into());
+                    dns::error_response(&query, dns::RCODE_SERVFAIL)
+                })
+            } else {
+                dns::error_response(&query, dns::RCODE_NXDOMAIN)
+            };
+            self.report(request);
+            write_dns(&mut client, &response)?;
+        }
+    }
+}
+
+/// Write a DNS message framed as over TCP.
+fn write_dns(client: &mut impl Write, msg: &[u8]) -> io::Result<()> {
+    let mut framed = (msg.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(msg);
+    client.write_all(&framed)
+}
+
+/// Read a request head up to and including the blank line, byte by byte so
+/// nothing after it is consumed.
+fn read_head(stream: &mut impl Read) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if head.len() == MAX_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Host and port of `host[:port]`; `CONNECT` targets need the port.
+fn split_authority(authority: &str, kind: RequestKind) -> Option<(String, u16)> {
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, a)| a);
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None if kind == RequestKind::Http => (authority, 80),
+        None => return None,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    (!host.is_empty()).then_some((host, port))
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    // Only IPv4: the policy's networks are IPv4
+    for addr in (host, port).to_socket_addrs()?.filter(SocketAddr::is_ipv4) {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+fn respond(client: &mut impl Write, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        client,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Copy both ways until both sides are done.
+fn relay(a: impl ClientStream, b: TcpStream) {
+    let (Ok(mut a_read), Ok(mut b_write)) = (a.try_clone(), b.try_clone()) else {
+        return;
+    };
+    let upload = std::thread::spawn(move || {
+        let _ = io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(Shutdown::Write);
+    });
+    let (mut b_read, mut a_write) = (b, a);
+    let _ = io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(Shutdown::Write);
+    let _ = upload.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::dns::tests::{answer, query};
+
+    /// A proxy allowing `rules` and resolving with `dns_servers`, and the
+    /// requests it reports.
+    fn proxy(
+        rules: &[&str],
+        dns_servers: Vec<SocketAddr>,
+    ) -> (Arc<EgressProxy>, Arc<Mutex<Vec<ProxyRequest>>>) {
+        let policy = rules
+            .iter()
+            .try_fold(EgressPolicy::deny_all(), |p, rule| p.allow(rule))
+            .unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&log);
+        let proxy = EgressProxy {
+            policy,
+            dns_servers,
+            on_request: None,
+        }
+        .on_request(move |request| sink.lock().unwrap().push(request.clone()));
+        (Arc::new(proxy), log)
+    }
+
+    /// Send one HTTP proxy request (and, for tunnels, `body`) and read
+    /// everything the proxy sends back.
+    fn http(proxy: &Arc<EgressProxy>, request: &str, body: &str) -> String {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let proxy = Arc::clone(proxy);
+        let handle = std::thread::spawn(move || proxy.serve_http(server));
+        client.write_all(request.as_bytes()).unwrap();
+        client.write_all(body.as_bytes()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        handle.join().unwrap().unwrap();
+        reply
+    }
+
+    /// A local server running `handle` on each connection.
+    fn upstream(handle: fn(TcpStream)) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for conn in listener.incoming().flatten() {
+                handle(conn);
+            }
+        });
+        port
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn read_dns(client: &mut UnixStream) -> Vec<u8> {
+        let mut len = [0; 2];
+        client.read_exact(&mut len).unwrap();
+        let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+        client.read_exact(&mut msg).unwrap();
+        msg
+    }
+
+    #[test]
+    fn split_authority_needs_a_port_for_tunnels() {
+        assert_eq!(
+            split_authority("Example.COM.:8443", RequestKind::Connect),
+            Some(("example.com".into(), 8443))
+        );
+        assert_eq!(
+            split_authority("example.com", RequestKind::Http),
+            Some(("example.com".into(), 80))
+        );
+        assert_eq!(
+            split_authority("user:pw@example.com:81", RequestKind::Http),
+            Some(("example.com".into(), 81))
+        );
+        assert_eq!(split_authority("example.com", RequestKind::Connect), None);
+        assert_eq!(split_authority("example.com:http", RequestKind::Http), None);
+        assert_eq!(split_authority(":443", RequestKind::Connect), None);
+    }
+
+    #[test]
+    fn dns_answers_allowed_names_and_refuses_the_rest() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver_addr = resolver.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((n, from)) = resolver.recv_from(&mut buf) {
+                let reply = answer(&buf[..n], &[Ipv4Addr::new(192, 0, 2, 1)]);
+                resolver.send_to(&reply, from).unwrap();
+            }
+        });
+        let (proxy, log) = proxy(&["example.com"], vec![resolver_addr]);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || proxy.serve_dns(server));
+
+        write_dns(&mut client, &query(1, "api.example.com")).unwrap();
+        let reply = read_dns(&mut client);
+        assert_eq!(reply[..2], [0, 1]);
+        assert_eq!(dns::answer_addrs(&reply), [Ipv4Addr::new(192, 0, 2, 1)]);
+
+        write_dns(&mut client, &query(2, "example.org")).unwrap();
+        let reply = read_dns(&mut client);
+        assert_eq!(reply[..2], [0, 2]);
+        assert_eq!(reply[3] & 0x0f, dns::RCODE_NXDOMAIN);
+
+        // Malformed queries still get a reply, and the stream stays usable
+        write_dns(&mut client, &[0, 3, 1]).unwrap();
+        let reply = read_dns(&mut client);
+        assert_eq!(reply.len(), 12);
+        assert_eq!(reply[..2], [0, 3]);
+        assert_eq!(reply[3] & 0x0f, dns::RCODE_FORMERR);
+        write_dns(&mut client, &[]).unwrap();
+        assert_eq!(read_dns(&mut client)[3] & 0x0f, dns::RCODE_FORMERR);
+
+        write_dns(&mut client, &query(4, "example.com")).unwrap();
+        assert_eq!(read_dns(&mut client)[..2], [0, 4]);
+
+        drop(client);
+        handle.join().unwrap().unwrap();
+        let log = log.lock().unwrap();
+        let seen: Vec<_> = log
+            .iter()
+            .map(|r| (r.kind, r.host.as_str(), r.port, r.allowed))
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                (RequestKind::Dns, "api.example.com", None, true),
+                (RequestKind::Dns, "example.org", None, false),
+                (RequestKind::Dns, "example.com", None, true),
+            ]
+        );
+        assert!(log.iter().all(|r| r.error.is_none()));
+    }
+
+    #[test]
+    fn dns_without_resolvers_fails_allowed_names() {
+        let (proxy, log) = proxy(&["example.com"], Vec::new());
+        let (mut client, server) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || proxy.serve_dns(server));
+
+        write_dns(&mut client, &query(7, "example.com")).unwrap();
+        assert_eq!(read_dns(&mut client)[3] & 0x0f, dns::RCODE_SERVFAIL);
+        let log = log.lock().unwrap();
+        assert!(log[0].allowed);
+        assert_eq!(log[0].error.as_deref(), Some("no resolver answered"));
+    }
+
+    #[test]
+    fn http_tunnels_to_allowed_hosts() {
+        // Echoes its input reversed
+        let port = upstream(|mut conn| {
+            let mut data = Vec::new();
+            conn.read_to_end(&mut data).unwrap();
+            data.reverse();
+            conn.write_all(&data).unwrap();
+        });
+        let (proxy, log) = proxy(&["127.0.0.1"], Vec::new());
+
+        let request = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port);
+        assert_eq!(
+            http(&proxy, &request, "shuru"),
+            "HTTP/1.1 200 Connection established\r\n\r\nuruhs"
+        );
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(
+            (
+                log[0].kind,
+                log[0].host.as_str(),
+                log[0].port,
+                log[0].allowed
+            ),
+            (RequestKind::Connect, "127.0.0.1", Some(port), true)
+        );
+    }
+
+    #[test]
+    fn http_forwards_plain_requests() {
+        // Replies with the request head it received
+        let port = upstream(|mut conn| {
+            let head = read_head(&mut conn).unwrap();
+            write!(conn, "HTTP/1.1 200 OK\r\n\r\n{}", head).unwrap();
+        });
+        let (proxy, log) = proxy(&["127.0.0.1"], Vec::new());
+
+        let request = format!(
+            "GET http://127.0.0.1:{}/path?q=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\
+             Proxy-Connection: keep-alive\r\nConnection: keep-alive\r\nAccept: */*\r\n\r\n",
+            port
+        );
+        assert_eq!(
+            http(&proxy, &request, ""),
+            "HTTP/1.1 200 OK\r\n\r\nGET /path?q=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\
+             Accept: */*\r\nConnection: close\r\n\r\n"
+        );
+        let log = log.lock().unwrap();
+        assert_eq!(
+            (log[0].kind, log[0].method.as_deref(), log[0].allowed),
+            (RequestKind::Http, Some("GET"), true)
+        );
+    }
+
+    #[test]
+    fn http_refuses_denied_and_malformed_requests() {
+        let (proxy, log) = proxy(&["127.0.0.1"], Vec::new());
+
+        let reply = http(&proxy, "CONNECT example.org:443 HTTP/1.1\r\n\r\n", "");
+        assert!(reply.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", reply);
+        assert!(reply.ends_with("example.org is not allowed by the sandbox network policy\n"));
+
+        for request in [
+            "garbage\r\n\r\n",
+            "GET /relative HTTP/1.1\r\n\r\n",
+            "GET https://example.org/ HTTP/1.1\r\n\r\n",
+            "CONNECT example.org HTTP/1.1\r\n\r\n",
+        ] {
+            let reply = http(&proxy, request, "");
+            assert!(
+                reply.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{}",
+                reply
+            );
+        }
+
+        // Allowed, but nothing listens there
+        let port = free_port();
+        let reply = http(
+            &proxy,
+            &format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port),
+            "",
+        );
+        assert!(
+            reply.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            reply
+        );
+
+        // Only requests that named a destination are reported
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            (log[0].host.as_str(), log[0].allowed),
+            ("example.org", false)
+        );
+        assert!(log[0].error.is_none());
+        assert!(log[1].allowed);
+        assert!(log[1].error.is_some());
+    }
+}
@@ -0,0 +1,802 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{ChecksumCapabilities, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant as SmolInstant;
+use smoltcp::wire::{
+    DhcpMessageType, DhcpPacket, DhcpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, HardwareAddress, IpAddress, IpCidr, IpProtocol, Ipv4Packet, Ipv4Repr, TcpPacket,
+    UdpPacket, UdpRepr, DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+};
+
+use crate::dns;
+use crate::policy::EgressPolicy;
+use crate::{Denial, NetConfig};
+
+/// MAC address of the stack's side of the link.
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+const DNS_PORT: u16 = 53;
+const TCP_BUFFER: usize = 64 * 1024;
+/// How long an outbound connection or upstream DNS query may take.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+/// Listening sockets whose SYN never arrived are dropped after this.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Repeats of the same denial within this window are not reported again.
+const DENIAL_INTERVAL: Duration = Duration::from_secs(5);
+const DHCP_LEASE_SECS: u32 = 24 * 60 * 60;
+
+type DenialHook = Box<dyn Fn(&Denial) + Send>;
+
+/// Frame queues between the link and smoltcp.
+#[derive(Default)]
+struct Queues {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+struct RxToken(Vec<u8>);
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl smoltcp::phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+impl smoltcp::phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        self.0.push_back(frame);
+        result
+    }
+}
+
+impl Device for Queues {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: SmolInstant) -> Option<(RxToken, TxToken<'_>)> {
+        let frame = self.rx.pop_front()?;
+        Some((RxToken(frame), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: SmolInstant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = 1514;
+        caps
+    }
+}
+
+enum Upstream {
+    Connecting(mpsc::Receiver<std::io::Result<TcpStream>>),
+    Connected(TcpStream),
+    Failed,
+}
+
+/// A guest TCP connection proxied to the host network.
+struct Conn {
+    handle: SocketHandle,
+    guest: SocketAddrV4,
+    dst: SocketAddrV4,
+    created: Instant,
+    upstream: Upstream,
+    /// Read from upstream, not yet accepted by the guest socket.
+    pending: Vec<u8>,
+    upstream_eof: bool,
+    guest_eof: bool,
+}
+
+struct DnsReply {
+    guest: SocketAddrV4,
+    server: SocketAddrV4,
+    name: String,
+    response: Vec<u8>,
+}
+
+/// Userspace TCP/IP stack terminating a guest's Ethernet link.
+///
+/// The guest gets its address from the stack's DHCP server and uses it as
+/// gateway and DNS server. DNS queries (to any address) are answered for
+/// allowed names by forwarding them to the host's resolvers; TCP
+/// connections to allowed destinations are accepted by smoltcp and proxied
+/// through host sockets. Everything else is dropped or reset and reported
+/// as a [`Denial`].
+///
+/// The stack does no I/O on the link itself: feed it guest frames with
+/// [`receive`](Self::receive), call [`poll`](Self::poll), and send what
+/// [`transmit`](Self::transmit) returns. [`run`](crate::run) does this for
+/// a socket.
+pub struct NetStack {
+    config: NetConfig,
+    policy: EgressPolicy,
+    iface: Interface,
+    device: Queues,
+    sockets: SocketSet<'static>,
+    conns: Vec<Conn>,
+    /// Addresses allowed names resolved to, with the name.
+    resolved: HashMap<Ipv4Addr, String>,
+    guest_mac: Option<EthernetAddress>,
+    dns_tx: mpsc::Sender<DnsReply>,
+    dns_rx: mpsc::Receiver<DnsReply>,
+    denied: HashMap<String, Instant>,
+    on_denied: Option<DenialHook>,
+    start: Instant,
+}
+
+impl NetStack {
+    pub fn new(config: NetConfig, policy: EgressPolicy) -> Self {
+        let mut device = Queues::default();
+        let mut iface_config = Config::new(HardwareAddress::Ethernet(GATEWAY_MAC));
+        iface_config.random_seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let mut iface = Interface::new(iface_config, &mut device, SmolInstant::from_millis(0));
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(
+                IpAddress::Ipv4(config.gateway),
+                config.prefix_len,
+            ));
+        });
+        // Accept packets to any address: the stack stands in for every
+        // host the guest talks to.
+        let _ = iface.routes_mut().add_default_ipv4_route(config.gateway);
+        iface.set_any_ip(true);
+
+        let (dns_tx, dns_rx) = mpsc::channel();
+        NetStack {
+            config,
+            policy,
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            conns: Vec::new(),
+            resolved: HashMap::new(),
+            guest_mac: None,
+            dns_tx,
+            dns_rx,
+            denied: HashMap::new(),
+            on_denied: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// Call `f` for every denied lookup or connection, in addition to
+    /// logging it. Repeats within a few seconds are reported once.
+    pub fn on_denied(mut self, f: impl Fn(&Denial) + Send + 'static) -> Self {
+        self.on_denied = Some(Box::new(f));
+        self
+    }
+
+    fn now(&self) -> SmolInstant {
+        SmolInstant::from_micros(self.start.elapsed().as_micros() as i64)
+    }
+
+    /// Take one Ethernet frame from the guest.
+    pub fn receive(&mut self, frame: &[u8]) {
+        let Ok(eth) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        if eth.src_addr().is_unicast() {
+            self.guest_mac = Some(eth.src_addr());
+        }
+        match eth.ethertype() {
+            EthernetProtocol::Arp => self.device.rx.push_back(frame.to_vec()),
+            EthernetProtocol::Ipv4 if self.filter_ipv4(eth.payload()) => {
+                self.device.rx.push_back(frame.to_vec())
+            }
+            // No IPv6: the guest only gets an IPv4 address
+            _ => {}
+        }
+    }
+
+    /// Handle UDP and apply the policy to new TCP connections. Returns
+    /// whether smoltcp should see the packet.
+    fn filter_ipv4(&mut self, packet: &[u8]) -> bool {
+        let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+            return false;
+        };
+        let (src, dst) = (ip.src_addr(), ip.dst_addr());
+        match ip.next_header() {
+            IpProtocol::Udp => {
+                let Ok(udp) = UdpPacket::new_checked(ip.payload()) else {
+                    return false;
+                };
+                let guest = SocketAddrV4::new(src, udp.src_port());
+                let target = SocketAddrV4::new(dst, udp.dst_port());
+                match udp.dst_port() {
+                    DHCP_SERVER_PORT => self.handle_dhcp(udp.payload()),
+                    DNS_PORT => self.handle_dns(guest, target, udp.payload()),
+                    _ => self.deny(Denial::Udp { addr: target }),
+                }
+                false
+            }
+            IpProtocol::Tcp => {
+                let Ok(tcp) = TcpPacket::new_checked(ip.payload()) else {
+                    return false;
+                };
+                if tcp.syn() && !tcp.ack() {
+                    let guest = SocketAddrV4::new(src, tcp.src_port());
+                    let target = SocketAddrV4::new(dst, tcp.dst_port());
+                    if self.allows(dst) {
+                        self.accept(guest, target);
+                    } else {
+                        // With no listening socket, smoltcp resets it
+                        self.deny(Denial::Tcp { addr: target });
+                    }
+                }
+                true
+            }
+            // Only pings to the gateway itself are answered
+            IpProtocol::Icmp => dst == self.config.gateway,
+            _ => false,
+        }
+    }
+
+    fn allows(&self, ip: Ipv4Addr) -> bool {
+        self.policy.allows_ip(ip) || self.resolved.contains_key(&ip)
+    }
+
+    fn deny(&mut self, denial: Denial) {
+        let key = denial.to_string();
+        let now = Instant::now();
+        if self
+            .denied
+            .get(&key)
+            .is_some_and(|last| now.duration_since(*last) < DENIAL_INTERVAL)
+        {
+            return;
+        }
+        self.denied
+            .retain(|_, last| now.duration_since(*last) < DENIAL_INTERVAL);
+        self.denied.insert(key, now);
+        tracing::warn!("shuru-net: denied {}", denial);
+        if let Some(f) = &self.on_denied {
+            f(&denial);
+        }
+    }
+
+    /// Listen for a new guest connection to `dst` and start connecting to
+    /// it from the host.
+    fn accept(&mut self, guest: SocketAddrV4, dst: SocketAddrV4) {
+        // A retransmitted SYN
+        if self.conns.iter().any(|c| c.guest == guest && c.dst == dst) {
+            return;
+        }
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+        );
+        if socket
+            .listen((IpAddress::Ipv4(*dst.ip()), dst.port()))
+            .is_err()
+        {
+            return;
+        }
+        socket.set_nagle_enabled(false);
+        let handle = self.sockets.add(socket);
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(TcpStream::connect_timeout(
+                &SocketAddr::V4(dst),
+                UPSTREAM_TIMEOUT,
+            ));
+        });
+        tracing::debug!("shuru-net: {} -> {}", guest, dst);
+        self.conns.push(Conn {
+            handle,
+            guest,
+            dst,
+            created: Instant::now(),
+            upstream: Upstream::Connecting(rx),
+            pending: Vec::new(),
+            upstream_eof: false,
+            guest_eof: false,
+        });
+    }
+
+    fn handle_dhcp(&mut self, payload: &[u8]) {
+        let Ok(packet) = DhcpPacket::new_checked(payload) else {
+            return;
+        };
+        let Ok(request) = DhcpRepr::parse(&packet) else {
+            return;
+        };
+        let message_type = match request.message_type {
+            DhcpMessageType::Discover => DhcpMessageType::Offer,
+            DhcpMessageType::Request => DhcpMessageType::Ack,
+            _ => return,
+        };
+        let mut dns_servers = heapless::Vec::new();
+        let _ = dns_servers.push(self.config.gateway);
+        let reply = DhcpRepr {
+            message_type,
+            transaction_id: request.transaction_id,
+            secs: 0,
+            client_hardware_address: request.client_hardware_address,
+            client_ip: Ipv4Addr::UNSPECIFIED,
+            your_ip: self.config.guest,
+            server_ip: self.config.gateway,
+            router: Some(self.config.gateway),
+            subnet_mask: Some(prefix_mask(self.config.prefix_len)),
+            relay_agent_ip: Ipv4Addr::UNSPECIFIED,
+            broadcast: false,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(self.config.gateway),
+            parameter_request_list: None,
+            dns_servers: Some(dns_servers),
+            max_size: None,
+            lease_duration: Some(DHCP_LEASE_SECS),
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        };
+        let mut payload = vec![0; reply.buffer_len()];
+        if reply
+            .emit(&mut DhcpPacket::new_unchecked(&mut payload))
+            .is_err()
+        {
+            return;
+        }
+        self.send_udp(
+            SocketAddrV4::new(self.config.gateway, DHCP_SERVER_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+            EthernetAddress::BROADCAST,
+            &payload,
+        );
+    }
+
+    /// Answer a query for a denied name, or forward it upstream.
+    fn handle_dns(&mut self, guest: SocketAddrV4, server: SocketAddrV4, query: &[u8]) {
+        let Some(name) = dns::query_name(query) else {
+            return;
+        };
+        if !self.policy.allows_domain(&name) {
+            self.deny(Denial::Dns { name });
+            self.reply_dns(
+                guest,
+                server,
+                &dns::error_response(query, dns::RCODE_NXDOMAIN),
+            );
+            return;
+        }
+
+        let upstreams = self.config.dns_servers.clone();
+        let query = query.to_vec();
+        let tx = self.dns_tx.clone();
+        std::thread::spawn(move || {
+            let response = dns::forward(&upstreams, &query, UPSTREAM_TIMEOUT / 2)
+                .unwrap_or_else(|| dns::error_response(&query, dns::RCODE_SERVFAIL));
+            let _ = tx.send(DnsReply {
+                guest,
+                server,
+                name,
+                response,
+            });
+        });
+    }
+
+    fn reply_dns(&mut self, guest: SocketAddrV4, server: SocketAddrV4, response: &[u8]) {
+        let Some(mac) = self.guest_mac else {
+            return;
+        };
+        self.send_udp(server, guest, mac, response);
+    }
+
+    fn send_udp(
+        &mut self,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        dst_mac: EthernetAddress,
+        payload: &[u8],
+    ) {
+        self.device
+            .tx
+            .push_back(udp_frame(GATEWAY_MAC, dst_mac, src, dst, payload));
+    }
+
+    /// Process queued frames, DNS answers and proxied connections.
+    pub fn poll(&mut self) {
+        while let Ok(reply) = self.dns_rx.try_recv() {
+            for addr in dns::answer_addrs(&reply.response) {
+                self.resolved.insert(addr, reply.name.clone());
+            }
+            self.reply_dns(reply.guest, reply.server, &reply.response);
+        }
+
+        let now = self.now();
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+        let mut changed = false;
+        for i in (0..self.conns.len()).rev() {
+            let done = self.service(i, &mut changed);
+            if done {
+                let conn = self.conns.swap_remove(i);
+                self.sockets.remove(conn.handle);
+            }
+        }
+        if changed {
+            self.iface.poll(now, &mut self.device, &mut self.sockets);
+        }
+    }
+
+    /// Move data for one connection. Returns whether it is finished.
+    fn service(&mut self, i: usize, changed: &mut bool) -> bool {
+        let conn = &mut self.conns[i];
+        let socket = self.sockets.get_mut::<tcp::Socket>(conn.handle);
+
+        if let Upstream::Connecting(rx) = &conn.upstream {
+            match rx.try_recv() {
+                Ok(Ok(stream)) => {
+                    let _ = stream.set_nonblocking(true);
+                    let _ = stream.set_nodelay(true);
+                    conn.upstream = Upstream::Connected(stream);
+                }
+                Ok(Err(e)) => {
+                    tracing::debug!("shuru-net: connect to {} failed: {}", conn.dst, e);
+                    conn.upstream = Upstream::Failed;
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+                Err(mpsc::TryRecvError::Disconnected) => conn.upstream = Upstream::Failed,
+            }
+        }
+
+        match socket.state() {
+            tcp::State::Closed | tcp::State::TimeWait => return true,
+            tcp::State::Listen => return conn.created.elapsed() > LISTEN_TIMEOUT,
+            _ => {}
+        }
+        let stream = match &mut conn.upstream {
+            Upstream::Connected(stream) => stream,
+            Upstream::Failed => {
+                socket.abort();
+                *changed = true;
+                return false;
+            }
+            Upstream::Connecting(_) => return false,
+        };
+
+        // Guest to upstream
+        while socket.can_recv() {
+            let written = socket.recv(|buf| match stream.write(buf) {
+                Ok(n) => (n, n),
+                Err(_) => (0, 0),
+            });
+            match written {
+                Ok(0) | Err(_) => break,
+                Ok(_) => *changed = true,
+            }
+        }
+        if !socket.may_recv() && !conn.guest_eof && socket.recv_queue() == 0 {
+            conn.guest_eof = true;
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+
+        // Upstream to guest
+        if conn.pending.is_empty() && !conn.upstream_eof && socket.can_send() {
+            let mut buf = vec![0; socket.send_capacity() - socket.send_queue()];
+            match stream.read(&mut buf) {
+                Ok(0) => conn.upstream_eof = true,
+                Ok(n) => conn.pending.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => {
+                    socket.abort();
+                    *changed = true;
+                    return false;
+                }
+            }
+        }
+        if !conn.pending.is_empty() {
+            if let Ok(n) = socket.send_slice(&conn.pending) {
+                conn.pending.drain(..n);
+                *changed |= n > 0;
+            }
+        }
+        if conn.upstream_eof && conn.pending.is_empty() && socket.may_send() {
+            socket.close();
+            *changed = true;
+        }
+        false
+    }
+
+    /// Next frame for the guest, if any.
+    pub fn transmit(&mut self) -> Option<Vec<u8>> {
+        self.device.tx.pop_front()
+    }
+
+    /// How long the caller may wait for guest frames before polling again.
+    pub fn poll_delay(&mut self) -> Duration {
+        let now = self.now();
+        let timers = self
+            .iface
+            .poll_delay(now, &self.sockets)
+            .map(|d| Duration::from_micros(d.total_micros()))
+            .unwrap_or(Duration::MAX);
+        // Upstream sockets and DNS answers are polled, not waited on
+        let busy = if self.conns.is_empty() {
+            Duration::from_millis(50)
+        } else {
+            Duration::from_millis(5)
+        };
+        timers.min(busy)
+    }
+}
+
+fn prefix_mask(prefix_len: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0))
+}
+
+/// An Ethernet frame carrying a UDP datagram.
+fn udp_frame(
+    src_mac: EthernetAddress,
+    dst_mac: EthernetAddress,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let ip = Ipv4Repr {
+        src_addr: *src.ip(),
+        dst_addr: *dst.ip(),
+        next_header: IpProtocol::Udp,
+        payload_len: udp.header_len() + payload.len(),
+        hop_limit: 64,
+    };
+    let eth = EthernetRepr {
+        src_addr: src_mac,
+        dst_addr: dst_mac,
+        ethertype: EthernetProtocol::Ipv4,
+    };
+    let checksums = ChecksumCapabilities::default();
+    let mut frame = vec![0; eth.buffer_len() + ip.buffer_len() + ip.payload_len];
+    let mut eth_frame = EthernetFrame::new_unchecked(&mut frame[..]);
+    eth.emit(&mut eth_frame);
+    let mut ip_packet = Ipv4Packet::new_unchecked(eth_frame.payload_mut());
+    ip.emit(&mut ip_packet, &checksums);
+    udp.emit(
+        &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+        &IpAddress::Ipv4(*src.ip()),
+        &IpAddress::Ipv4(*dst.ip()),
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &checksums,
+    );
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use smoltcp::wire::{ArpOperation, ArpPacket, ArpRepr, TcpControl, TcpRepr, TcpSeqNumber};
+
+    use super::*;
+
+    const GUEST_MAC: EthernetAddress = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+    /// A stack allowing only `example.com`, and the denials it reports.
+    fn stack() -> (NetStack, NetConfig, Arc<Mutex<Vec<Denial>>>) {
+        let config = NetConfig {
+            dns_servers: Vec::new(),
+            ..NetConfig::default()
+        };
+        let policy = EgressPolicy::deny_all().allow("example.com").unwrap();
+        let denials = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&denials);
+        let stack = NetStack::new(config.clone(), policy)
+            .on_denied(move |denial| sink.lock().unwrap().push(denial.clone()));
+        (stack, config, denials)
+    }
+
+    /// Source, destination and payload of a UDP frame.
+    fn parse_udp(frame: &[u8]) -> (SocketAddrV4, SocketAddrV4, Vec<u8>) {
+        let eth = EthernetFrame::new_checked(frame).unwrap();
+        let ip = Ipv4Packet::new_checked(eth.payload()).unwrap();
+        assert_eq!(ip.next_header(), IpProtocol::Udp);
+        let udp = UdpPacket::new_checked(ip.payload()).unwrap();
+        (
+            SocketAddrV4::new(ip.src_addr(), udp.src_port()),
+            SocketAddrV4::new(ip.dst_addr(), udp.dst_port()),
+            udp.payload().to_vec(),
+        )
+    }
+
+    fn dhcp_request(message_type: DhcpMessageType, requested_ip: Option<Ipv4Addr>) -> Vec<u8> {
+        let repr = DhcpRepr {
+            message_type,
+            transaction_id: 0x1234_5678,
+            secs: 0,
+            client_hardware_address: GUEST_MAC,
+            client_ip: Ipv4Addr::UNSPECIFIED,
+            your_ip: Ipv4Addr::UNSPECIFIED,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            router: None,
+            subnet_mask: None,
+            relay_agent_ip: Ipv4Addr::UNSPECIFIED,
+            broadcast: false,
+            requested_ip,
+            client_identifier: None,
+            server_identifier: None,
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        };
+        let mut payload = vec![0; repr.buffer_len()];
+        repr.emit(&mut DhcpPacket::new_unchecked(&mut payload))
+            .unwrap();
+        udp_frame(
+            GUEST_MAC,
+            EthernetAddress::BROADCAST,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT),
+            &payload,
+        )
+    }
+
+    fn arp_request(sender: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: GUEST_MAC,
+            source_protocol_addr: sender,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: target,
+        };
+        let eth = EthernetRepr {
+            src_addr: GUEST_MAC,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let mut frame = vec![0; eth.buffer_len() + arp.buffer_len()];
+        let mut eth_frame = EthernetFrame::new_unchecked(&mut frame[..]);
+        eth.emit(&mut eth_frame);
+        arp.emit(&mut ArpPacket::new_unchecked(eth_frame.payload_mut()));
+        frame
+    }
+
+    fn tcp_syn(src: SocketAddrV4, dst: SocketAddrV4, seq: i32) -> Vec<u8> {
+        let tcp = TcpRepr {
+            src_port: src.port(),
+            dst_port: dst.port(),
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(seq),
+            ack_number: None,
+            window_len: 64240,
+            window_scale: None,
+            max_seg_size: Some(1460),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            timestamp: None,
+            payload: &[],
+        };
+        let ip = Ipv4Repr {
+            src_addr: *src.ip(),
+            dst_addr: *dst.ip(),
+            next_header: IpProtocol::Tcp,
+            payload_len: tcp.buffer_len(),
+            hop_limit: 64,
+        };
+        let eth = EthernetRepr {
+            src_addr: GUEST_MAC,
+            dst_addr: GATEWAY_MAC,
+            ethertype: EthernetProtocol::Ipv4,
+        };
+        let checksums = ChecksumCapabilities::default();
+        let mut frame = vec![0; eth.buffer_len() + ip.buffer_len() + ip.payload_len];
+        let mut eth_frame = EthernetFrame::new_unchecked(&mut frame[..]);
+        eth.emit(&mut eth_frame);
+        let mut ip_packet = Ipv4Packet::new_unchecked(eth_frame.payload_mut());
+        ip.emit(&mut ip_packet, &checksums);
+        tcp.emit(
+            &mut TcpPacket::new_unchecked(ip_packet.payload_mut()),
+            &IpAddress::Ipv4(*src.ip()),
+            &IpAddress::Ipv4(*dst.ip()),
+            &checksums,
+        );
+        frame
+    }
+
+    #[test]
+    fn dhcp_leases_the_guest_address() {
+        let (mut stack, config, _) = stack();
+        for (request, requested_ip, expected) in [
+            (DhcpMessageType::Discover, None, DhcpMessageType::Offer),
+            (
+                DhcpMessageType::Request,
+                Some(config.guest),
+                DhcpMessageType::Ack,
+            ),
+        ] {
+            stack.receive(&dhcp_request(request, requested_ip));
+            let frame = stack.transmit().expect("no DHCP reply");
+            let (src, dst, payload) = parse_udp(&frame);
+            assert_eq!(src, SocketAddrV4::new(config.gateway, DHCP_SERVER_PORT));
+            assert_eq!(
+                dst,
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT)
+            );
+
+            let packet = DhcpPacket::new_checked(&payload[..]).unwrap();
+            let reply = DhcpRepr::parse(&packet).unwrap();
+            assert_eq!(reply.message_type, expected);
+            assert_eq!(reply.transaction_id, 0x1234_5678);
+            assert_eq!(reply.client_hardware_address, GUEST_MAC);
+            assert_eq!(reply.your_ip, config.guest);
+            assert_eq!(reply.router, Some(config.gateway));
+            assert_eq!(reply.subnet_mask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+            assert_eq!(reply.dns_servers.as_deref(), Some(&[config.gateway][..]));
+            assert_eq!(reply.lease_duration, Some(DHCP_LEASE_SECS));
+        }
+        assert!(stack.transmit().is_none());
+    }
+
+    #[test]
+    fn dns_query_for_denied_name_gets_nxdomain() {
+        let (mut stack, config, denials) = stack();
+        let guest = SocketAddrV4::new(config.guest, 40000);
+        // Queries are intercepted whichever server they are sent to
+        let server = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), DNS_PORT);
+        let query = dns::tests::query(0xbeef, "Example.org");
+        stack.receive(&udp_frame(GUEST_MAC, GATEWAY_MAC, guest, server, &query));
+
+        let frame = stack.transmit().expect("no DNS reply");
+        assert_eq!(
+            EthernetFrame::new_checked(&frame[..]).unwrap().dst_addr(),
+            GUEST_MAC
+        );
+        let (src, dst, response) = parse_udp(&frame);
+        assert_eq!((src, dst), (server, guest));
+        assert_eq!(response[..2], [0xbe, 0xef]);
+        assert_eq!(response[3] & 0x0f, dns::RCODE_NXDOMAIN);
+        assert_eq!(
+            *denials.lock().unwrap(),
+            [Denial::Dns {
+                name: "example.org".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn tcp_syn_to_denied_address_is_reset() {
+        let (mut stack, config, denials) = stack();
+        // The guest looks up its gateway first, which is how the stack
+        // learns where to send replies
+        stack.receive(&arp_request(config.guest, config.gateway));
+        stack.poll();
+        while stack.transmit().is_some() {}
+
+        let guest = SocketAddrV4::new(config.guest, 40000);
+        let target = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 443);
+        stack.receive(&tcp_syn(guest, target, 1000));
+        stack.poll();
+
+        let frame = stack.transmit().expect("no reset");
+        let eth = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ip = Ipv4Packet::new_checked(eth.payload()).unwrap();
+        assert_eq!((ip.src_addr(), ip.dst_addr()), (*target.ip(), config.guest));
+        let tcp = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(tcp.rst());
+        assert_eq!((tcp.src_port(), tcp.dst_port()), (443, 40000));
+        assert_eq!(tcp.ack_number(), TcpSeqNumber(1001));
+        assert_eq!(*denials.lock().unwrap(), [Denial::Tcp { addr: target }]);
+        assert!(stack.conns.is_empty());
+    }
+}
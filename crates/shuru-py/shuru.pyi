@@ -0,0 +1,108 @@
+from types import TracebackType
+from typing import Iterator, Mapping, Optional, Sequence, Tuple, Type
+
+__version__: str
+
+class SandboxBuilder:
+    def __init__(self) -> None: ...
+    def kernel(self, path: str) -> SandboxBuilder: ...
+    def rootfs(self, path: str) -> SandboxBuilder: ...
+    def initrd(self, path: str) -> SandboxBuilder: ...
+    def cmdline(self, cmdline: str) -> SandboxBuilder: ...
+    def kernel_arg(self, arg: str) -> SandboxBuilder: ...
+    def cpus(self, n: int) -> SandboxBuilder: ...
+    def memory_mb(self, mb: int) -> SandboxBuilder: ...
+    def allow_net(self, enabled: bool = True) -> SandboxBuilder: ...
+    def allow_host(self, rule: str) -> SandboxBuilder: ...
+    def network_proxy(self, enabled: bool = True) -> SandboxBuilder: ...
+    def egress_log(self, path: str) -> SandboxBuilder: ...
+    def init_system(self, enabled: bool = True) -> SandboxBuilder: ...
+    def console_log(self, path: str) -> SandboxBuilder: ...
+    def mount(self, host_path: str, guest_path: str, persistent: bool = False) -> SandboxBuilder: ...
+    def disk(self, path: str, guest_path: Optional[str] = None, read_only: bool = False) -> SandboxBuilder: ...
+    def disk_size_mb(self, mb: int) -> SandboxBuilder: ...
+    def copy_rootfs(self, enabled: bool = True) -> SandboxBuilder: ...
+    def agent(self, addr: str) -> SandboxBuilder: ...
+    def build(self) -> Sandbox: ...
+
+class Sandbox:
+    @staticmethod
+    def builder() -> SandboxBuilder: ...
+    def start(self) -> None: ...
+    def stop(self) -> None: ...
+    def close(self) -> None: ...
+    @property
+    def closed(self) -> bool: ...
+    def __enter__(self) -> Sandbox: ...
+    def __exit__(
+        self,
+        exc_type: Optional[Type[BaseException]],
+        exc: Optional[BaseException],
+        tb: Optional[TracebackType],
+    ) -> bool: ...
+    def exec(
+        self,
+        argv: Sequence[str],
+        *,
+        env: Optional[Mapping[str, str]] = None,
+        cwd: Optional[str] = None,
+        input: Optional[bytes] = None,
+        timeout: Optional[float] = None,
+    ) -> ExecResult: ...
+    def spawn(
+        self,
+        argv: Sequence[str],
+        *,
+        env: Optional[Mapping[str, str]] = None,
+        cwd: Optional[str] = None,
+        stdin: bool = False,
+    ) -> Process: ...
+    def read_file(self, path: str) -> bytes: ...
+    def write_file(self, path: str, data: bytes, mode: Optional[int] = None) -> None: ...
+    def forward_ports(self, ports: Sequence[Tuple[int, int]]) -> PortForward: ...
+    def expose(self, services: Sequence[Tuple[str, int]]) -> PortForward: ...
+
+class ExecResult:
+    @property
+    def returncode(self) -> int: ...
+    @property
+    def stdout(self) -> bytes: ...
+    @property
+    def stderr(self) -> bytes: ...
+    @property
+    def timed_out(self) -> bool: ...
+
+class Process:
+    @property
+    def pid(self) -> int: ...
+    def __iter__(self) -> Iterator[Tuple[str, bytes]]: ...
+    def __next__(self) -> Tuple[str, bytes]: ...
+    def write(self, data: bytes) -> None: ...
+    def close_stdin(self) -> None: ...
+    def poll(self) -> Optional[int]: ...
+    def wait(self, timeout: Optional[float] = None) -> int: ...
+    def kill(self) -> None: ...
+    def send_signal(self, signal: int) -> None: ...
+
+class PortForward:
+    def close(self) -> None: ...
+    def __enter__(self) -> PortForward: ...
+    def __exit__(
+        self,
+        exc_type: Optional[Type[BaseException]],
+        exc: Optional[BaseException],
+        tb: Optional[TracebackType],
+    ) -> bool: ...
+
+class SandboxError(Exception): ...
+class UnsupportedError(SandboxError): ...
+class ConfigError(SandboxError): ...
+class BootError(SandboxError): ...
+class GuestUnreachableError(SandboxError): ...
+class MountError(SandboxError): ...
+class ProtocolMismatchError(SandboxError): ...
+class PortBindError(SandboxError): ...
+class ExecSpawnError(SandboxError): ...
+class CommandNotFoundError(ExecSpawnError): ...
+class GuestError(SandboxError): ...
+class DisconnectedError(SandboxError): ...
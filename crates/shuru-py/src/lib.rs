@@ -0,0 +1,147 @@
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyOSError};
+use pyo3::prelude::*;
+
+mod process;
+mod sandbox;
+
+use process::{ExecResult, Process};
+use sandbox::{PortForward, Sandbox, SandboxBuilder};
+
+// --- Exceptions ---
+
+create_exception!(
+    shuru,
+    SandboxError,
+    PyException,
+    "Base class of shuru errors."
+);
+create_exception!(
+    shuru,
+    UnsupportedError,
+    SandboxError,
+    "The host or backend cannot do this."
+);
+create_exception!(
+    shuru,
+    ConfigError,
+    SandboxError,
+    "The sandbox configuration was rejected."
+);
+create_exception!(shuru, BootError, SandboxError, "The VM failed to boot.");
+create_exception!(
+    shuru,
+    GuestUnreachableError,
+    SandboxError,
+    "The guest agent could not be reached."
+);
+create_exception!(
+    shuru,
+    MountError,
+    SandboxError,
+    "A directory mount failed in the guest."
+);
+create_exception!(
+    shuru,
+    ProtocolMismatchError,
+    SandboxError,
+    "The guest agent is too old for this request."
+);
+create_exception!(
+    shuru,
+    PortBindError,
+    SandboxError,
+    "A host port for forwarding could not be bound."
+);
+create_exception!(
+    shuru,
+    ExecSpawnError,
+    SandboxError,
+    "The guest could not start a command."
+);
+create_exception!(
+    shuru,
+    CommandNotFoundError,
+    ExecSpawnError,
+    "The command does not exist in the guest."
+);
+create_exception!(
+    shuru,
+    GuestError,
+    SandboxError,
+    "The guest agent reported an error, e.g. a missing file."
+);
+create_exception!(
+    shuru,
+    DisconnectedError,
+    SandboxError,
+    "The guest closed the connection early."
+);
+
+/// Convert a library error to the matching Python exception, keeping the
+/// chain of causes in the message.
+pub(crate) fn to_py(err: shuru_vm::SandboxError) -> PyErr {
+    use shuru_vm::SandboxError as E;
+
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(&err);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    match err {
+        E::Unsupported(_) => UnsupportedError::new_err(message),
+        E::InvalidConfig(_) => ConfigError::new_err(message),
+        E::BootFailed { .. } => BootError::new_err(message),
+        E::GuestUnreachable { .. } => GuestUnreachableError::new_err(message),
+        E::MountFailed { .. } => MountError::new_err(message),
+        E::ProtocolMismatch { .. } => ProtocolMismatchError::new_err(message),
+        E::PortBindFailed { .. } => PortBindError::new_err(message),
+        E::ExecSpawnFailed {
+            not_found: true, ..
+        } => CommandNotFoundError::new_err(message),
+        E::ExecSpawnFailed { .. } => ExecSpawnError::new_err(message),
+        E::Guest(_) => GuestError::new_err(message),
+        E::Disconnected(_) => DisconnectedError::new_err(message),
+        E::Io(_) => PyOSError::new_err(message),
+        _ => SandboxError::new_err(message),
+    }
+}
+
+// --- Module ---
+
+#[pymodule]
+fn shuru(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+
+    m.add_class::<SandboxBuilder>()?;
+    m.add_class::<Sandbox>()?;
+    m.add_class::<Process>()?;
+    m.add_class::<ExecResult>()?;
+    m.add_class::<PortForward>()?;
+
+    m.add("SandboxError", py.get_type::<SandboxError>())?;
+    m.add("UnsupportedError", py.get_type::<UnsupportedError>())?;
+    m.add("ConfigError", py.get_type::<ConfigError>())?;
+    m.add("BootError", py.get_type::<BootError>())?;
+    m.add(
+        "GuestUnreachableError",
+        py.get_type::<GuestUnreachableError>(),
+    )?;
+    m.add("MountError", py.get_type::<MountError>())?;
+    m.add(
+        "ProtocolMismatchError",
+        py.get_type::<ProtocolMismatchError>(),
+    )?;
+    m.add("PortBindError", py.get_type::<PortBindError>())?;
+    m.add("ExecSpawnError", py.get_type::<ExecSpawnError>())?;
+    m.add(
+        "CommandNotFoundError",
+        py.get_type::<CommandNotFoundError>(),
+    )?;
+    m.add("GuestError", py.get_type::<GuestError>())?;
+    m.add("DisconnectedError", py.get_type::<DisconnectedError>())?;
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use pyo3::exceptions::{PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use shuru_vm::{Child, ChildStdin, Command, ExitStatus, Output};
+
+use crate::to_py;
+
+/// How often blocking waits return to Python to check for Ctrl-C.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A guest command for `argv` with the given environment and directory.
+pub(crate) fn command<'a>(
+    sandbox: &'a shuru_vm::Sandbox,
+    argv: &[String],
+    env: Option<HashMap<String, String>>,
+    cwd: Option<String>,
+) -> shuru_vm::Result<Command<'a>> {
+    let (program, args) = argv.split_first().ok_or_else(|| {
+        shuru_vm::SandboxError::InvalidConfig("argv must not be empty".to_string())
+    })?;
+    let mut cmd = sandbox.command(program.as_str());
+    cmd.args(args);
+    if let Some(env) = env {
+        cmd.envs(env);
+    }
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    Ok(cmd)
+}
+
+/// Wait for `child` to exit, killing it at `deadline`. Returns whether it
+/// was killed. Ctrl-C kills the command and raises `KeyboardInterrupt`.
+pub(crate) fn wait_until(
+    py: Python<'_>,
+    child: &mut Child,
+    deadline: Option<Instant>,
+) -> PyResult<bool> {
+    loop {
+        if child.try_wait().map_err(to_py)?.is_some() {
+            return Ok(false);
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            child.kill().map_err(to_py)?;
+            return Ok(true);
+        }
+        if let Err(e) = py.check_signals() {
+            let _ = child.kill();
+            return Err(e);
+        }
+        py.detach(|| std::thread::sleep(POLL_INTERVAL));
+    }
+}
+
+/// `subprocess`-style return code: negative for a signal.
+fn returncode(status: ExitStatus) -> i32 {
+    match status.signal() {
+        Some(signal) => -signal,
+        None => status.code().unwrap_or(-1),
+    }
+}
+
+// --- ExecResult ---
+
+/// Collected result of `Sandbox.exec`.
+#[pyclass(module = "shuru", frozen, get_all)]
+pub(crate) struct ExecResult {
+    /// Exit code, or minus the signal number if the command was killed.
+    returncode: i32,
+    stdout: Py<PyBytes>,
+    stderr: Py<PyBytes>,
+    /// Whether the command was killed for running past its timeout.
+    timed_out: bool,
+}
+
+impl ExecResult {
+    pub(crate) fn new(py: Python<'_>, output: Output, timed_out: bool) -> Self {
+        ExecResult {
+            returncode: returncode(output.status),
+            stdout: PyBytes::new(py, &output.stdout).unbind(),
+            stderr: PyBytes::new(py, &output.stderr).unbind(),
+            timed_out,
+        }
+    }
+}
+
+#[pymethods]
+impl ExecResult {
+    fn __repr__(&self, py: Python<'_>) -> String {
+        format!(
+            "ExecResult(returncode={}, stdout=<{} bytes>, stderr=<{} bytes>, timed_out={})",
+            self.returncode,
+            self.stdout.bind(py).as_bytes().len(),
+            self.stderr.bind(py).as_bytes().len(),
+            if self.timed_out { "True" } else { "False" }
+        )
+    }
+}
+
+// --- Process ---
+
+/// A running guest command from `Sandbox.spawn`. Iterating it yields
+/// `("stdout" | "stderr", bytes)` chunks as they arrive, until both
+/// streams close.
+#[pyclass(module = "shuru", frozen)]
+pub(crate) struct Process {
+    pid: u32,
+    child: Mutex<Child>,
+    stdin: Mutex<Option<ChildStdin>>,
+    output: Mutex<Receiver<(&'static str, Vec<u8>)>>,
+}
+
+impl Process {
+    pub(crate) fn new(mut child: Child) -> Self {
+        let (tx, rx) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            spawn_reader("stdout", stdout, tx.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            spawn_reader("stderr", stderr, tx);
+        }
+        Process {
+            pid: child.id(),
+            stdin: Mutex::new(child.stdin.take()),
+            child: Mutex::new(child),
+            output: Mutex::new(rx),
+        }
+    }
+}
+
+fn spawn_reader(
+    name: &'static str,
+    mut pipe: impl Read + Send + 'static,
+    tx: Sender<(&'static str, Vec<u8>)>,
+) {
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send((name, buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[pymethods]
+impl Process {
+    /// The command's process id inside the guest.
+    #[getter]
+    fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    fn __iter__(slf: Bound<'_, Self>) -> Bound<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> PyResult<Option<(&'static str, Bound<'py, PyBytes>)>> {
+        loop {
+            let received = py.detach(|| self.output.lock().unwrap().recv_timeout(POLL_INTERVAL));
+            match received {
+                Ok((stream, data)) => return Ok(Some((stream, PyBytes::new(py, &data)))),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => py.check_signals()?,
+            }
+        }
+    }
+
+    /// Write to the command's stdin (requires `spawn(..., stdin=True)`).
+    fn write(&self, py: Python<'_>, data: &[u8]) -> PyResult<()> {
+        let data = data.to_vec();
+        py.detach(|| {
+            let mut stdin = self.stdin.lock().unwrap();
+            let pipe = stdin
+                .as_mut()
+                .ok_or_else(|| PyValueError::new_err("stdin is not open"))?;
+            pipe.write_all(&data).map_err(|e| to_py(e.into()))
+        })
+    }
+
+    /// Close stdin, sending end-of-file to the command.
+    fn close_stdin(&self) {
+        self.stdin.lock().unwrap().take();
+    }
+
+    /// The return code if the command has exited, else `None`.
+    fn poll(&self) -> PyResult<Option<i32>> {
+        let status = self.child.lock().unwrap().try_wait().map_err(to_py)?;
+        Ok(status.map(returncode))
+    }
+
+    /// Wait for the command to exit and return its return code. Raises
+    /// `TimeoutError` if `timeout` seconds pass first; the command keeps
+    /// running. Output not yet iterated stays available.
+    #[pyo3(signature = (timeout = None))]
+    fn wait(&self, py: Python<'_>, timeout: Option<f64>) -> PyResult<i32> {
+        self.close_stdin();
+        let deadline = timeout.map(|t| Instant::now() + Duration::from_secs_f64(t));
+        loop {
+            if let Some(status) = self.poll()? {
+                return Ok(status);
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(PyTimeoutError::new_err("command did not exit in time"));
+            }
+            py.check_signals()?;
+            py.detach(|| std::thread::sleep(POLL_INTERVAL));
+        }
+    }
+
+    /// Send `SIGKILL`.
+    fn kill(&self) -> PyResult<()> {
+        self.child.lock().unwrap().kill().map_err(to_py)
+    }
+
+    fn send_signal(&self, signal: i32) -> PyResult<()> {
+        self.child.lock().unwrap().signal(signal).map_err(to_py)
+    }
+}
@@ -0,0 +1,522 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyType};
+
+use shuru_vm::{
+    AgentAddr, AgentBackend, DiskConfig, EgressPolicy, MountConfig, PortMapping, ReverseMapping,
+    Stdio,
+};
+
+use crate::process::{self, ExecResult, Process};
+use crate::{to_py, ConfigError, SandboxError, UnsupportedError};
+
+/// Numbers the working copies made by this process.
+static NEXT_INSTANCE: AtomicU64 = AtomicU64::new(1);
+
+// --- SandboxBuilder ---
+
+/// Configures a sandbox, like `Sandbox::builder()` in Rust. Every setter
+/// returns the builder, so calls chain:
+///
+///     Sandbox.builder().cpus(4).memory_mb(4096).allow_net().build()
+#[pyclass(module = "shuru", frozen)]
+pub(crate) struct SandboxBuilder {
+    state: Mutex<BuilderState>,
+}
+
+#[derive(Clone)]
+struct BuilderState {
+    template: shuru_vm::VmConfigBuilder,
+    kernel: Option<String>,
+    rootfs: Option<String>,
+    initrd: Option<String>,
+    disk_size_mb: Option<u64>,
+    copy_rootfs: bool,
+    agent: Option<String>,
+    egress: Option<EgressPolicy>,
+    /// Enforce `egress` with proxy mode instead of a filtered device.
+    proxy: bool,
+}
+
+impl SandboxBuilder {
+    fn update<'py>(slf: Bound<'py, Self>, f: impl FnOnce(&mut BuilderState)) -> Bound<'py, Self> {
+        f(&mut slf.get().state.lock().unwrap());
+        slf
+    }
+}
+
+#[pymethods]
+impl SandboxBuilder {
+    #[new]
+    fn new() -> Self {
+        SandboxBuilder {
+            state: Mutex::new(BuilderState {
+                template: shuru_vm::Sandbox::builder().console(false).quiet(true),
+                kernel: None,
+                rootfs: None,
+                initrd: None,
+                disk_size_mb: None,
+                copy_rootfs: true,
+                agent: None,
+                egress: None,
+                proxy: false,
+            }),
+        }
+    }
+
+    /// Kernel image (default: `$SHURU_KERNEL`).
+    fn kernel(slf: Bound<'_, Self>, path: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.kernel = Some(path))
+    }
+
+    /// Root disk image (default: `$SHURU_ROOTFS`).
+    fn rootfs(slf: Bound<'_, Self>, path: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.rootfs = Some(path))
+    }
+
+    /// Initramfs (default: `$SHURU_INITRD`, if set).
+    fn initrd(slf: Bound<'_, Self>, path: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.initrd = Some(path))
+    }
+
+    fn cmdline(slf: Bound<'_, Self>, cmdline: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().cmdline(cmdline))
+    }
+
+    /// Append an argument to the kernel command line.
+    fn kernel_arg(slf: Bound<'_, Self>, arg: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().kernel_arg(arg))
+    }
+
+    fn cpus(slf: Bound<'_, Self>, n: usize) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().cpus(n))
+    }
+
+    fn memory_mb(slf: Bound<'_, Self>, mb: u64) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().memory_mb(mb))
+    }
+
+    #[pyo3(signature = (enabled = true))]
+    fn allow_net(slf: Bound<'_, Self>, enabled: bool) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().allow_net(enabled))
+    }
+
+    /// Allow network access to a domain (and its subdomains) or an IPv4
+    /// network (`10.0.0.0/8`); everything else is blocked and logged.
+    fn allow_host<'py>(slf: Bound<'py, Self>, rule: String) -> PyResult<Bound<'py, Self>> {
+        let policy = {
+            let state = slf.get().state.lock().unwrap();
+            state
+                .egress
+                .clone()
+                .unwrap_or_default()
+                .allow(&rule)
+                .map_err(|e| ConfigError::new_err(e.to_string()))?
+        };
+        Ok(Self::update(slf, |s| s.egress = Some(policy)))
+    }
+
+    /// Give the guest no network device, but an HTTP(S) proxy and DNS
+    /// resolver on the host that only reach `allow_host` destinations.
+    #[pyo3(signature = (enabled = true))]
+    fn network_proxy(slf: Bound<'_, Self>, enabled: bool) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.proxy = enabled)
+    }
+
+    /// Append each `network_proxy` request to `path` as a JSON line.
+    fn egress_log(slf: Bound<'_, Self>, path: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().egress_log(path))
+    }
+
+    #[pyo3(signature = (enabled = true))]
+    fn init_system(slf: Bound<'_, Self>, enabled: bool) -> Bound<'_, Self> {
+        Self::update(slf, |s| {
+            s.template = s.template.clone().init_system(enabled)
+        })
+    }
+
+    /// Write the serial console and the guest agent's log records to
+    /// `path`, one `<unix ms> <source> <text>` line each.
+    fn console_log(slf: Bound<'_, Self>, path: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.template = s.template.clone().console_log(path))
+    }
+
+    /// Mount a host directory at `guest_path`. Guest writes go to an overlay
+    /// unless `persistent` is true.
+    #[pyo3(signature = (host_path, guest_path, persistent = false))]
+    fn mount(
+        slf: Bound<'_, Self>,
+        host_path: String,
+        guest_path: String,
+        persistent: bool,
+    ) -> Bound<'_, Self> {
+        let config = MountConfig {
+            host_path,
+            guest_path,
+            persistent,
+        };
+        Self::update(slf, |s| s.template = s.template.clone().mount(config))
+    }
+
+    /// Attach a raw disk image, mounted at `guest_path` if given (formatted
+    /// as ext4 first if blank).
+    #[pyo3(signature = (path, guest_path = None, read_only = false))]
+    fn disk(
+        slf: Bound<'_, Self>,
+        path: String,
+        guest_path: Option<String>,
+        read_only: bool,
+    ) -> Bound<'_, Self> {
+        let mut config = DiskConfig::new(path);
+        config.guest_path = guest_path;
+        config.read_only = read_only;
+        Self::update(slf, |s| s.template = s.template.clone().disk(config))
+    }
+
+    /// Extend the rootfs working copy to this size.
+    fn disk_size_mb(slf: Bound<'_, Self>, mb: u64) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.disk_size_mb = Some(mb))
+    }
+
+    /// Boot from a private copy of the rootfs, deleted on `close()`
+    /// (default), or from the rootfs itself.
+    #[pyo3(signature = (enabled = true))]
+    fn copy_rootfs(slf: Bound<'_, Self>, enabled: bool) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.copy_rootfs = enabled)
+    }
+
+    /// Use a `shuru-guest --listen` agent at `addr` (`unix:PATH` or
+    /// `tcp:HOST:PORT`) instead of booting a VM.
+    fn agent(slf: Bound<'_, Self>, addr: String) -> Bound<'_, Self> {
+        Self::update(slf, |s| s.agent = Some(addr))
+    }
+
+    /// Create the sandbox. It is not started until `start()` or `with`.
+    fn build(&self, py: Python<'_>) -> PyResult<Sandbox> {
+        let state = self.state.lock().unwrap().clone();
+        py.detach(|| state.build())
+    }
+}
+
+impl BuilderState {
+    fn build(mut self) -> PyResult<Sandbox> {
+        let policy = self.egress.take();
+        if self.proxy {
+            let policy = policy.unwrap_or_else(EgressPolicy::deny_all);
+            self.template = self.template.network_proxy(policy);
+        } else if let Some(policy) = policy {
+            self.template = self.template.egress(policy);
+        }
+
+        if let Some(addr) = &self.agent {
+            // The agent backend boots nothing, so there are no paths to resolve
+            let config = self
+                .template
+                .kernel("")
+                .rootfs("")
+                .config()
+                .map_err(to_py)?;
+            if !config.shares.is_empty() || !config.disks.is_empty() {
+                return Err(UnsupportedError::new_err(
+                    "mounts and disks are not supported by the agent backend",
+                ));
+            }
+            let addr = AgentAddr::parse(addr).map_err(to_py)?;
+            let sandbox = shuru_vm::Sandbox::from_backend(AgentBackend::new(addr), &config);
+            return Ok(Sandbox::new(sandbox, None));
+        }
+
+        let kernel = path_or_env(self.kernel, "SHURU_KERNEL", "kernel")?;
+        let rootfs = path_or_env(self.rootfs, "SHURU_ROOTFS", "rootfs")?;
+        let mut template = self.template.kernel(kernel);
+        if let Some(initrd) = self.initrd.or_else(|| std::env::var("SHURU_INITRD").ok()) {
+            template = template.initrd(initrd);
+        }
+
+        if !self.copy_rootfs {
+            let sandbox = template.rootfs(rootfs).build().map_err(to_py)?;
+            return Ok(Sandbox::new(sandbox, None));
+        }
+
+        let dir = PathBuf::from(format!(
+            "{}/instances/{}/py-{}",
+            shuru_vm::default_data_dir(),
+            std::process::id(),
+            NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let built = working_copy(&rootfs, &dir, self.disk_size_mb)
+            .map_err(|e| to_py(e.into()))
+            .and_then(|copy| template.rootfs(copy).build().map_err(to_py));
+        match built {
+            Ok(sandbox) => Ok(Sandbox::new(sandbox, Some(dir))),
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                Err(e)
+            }
+        }
+    }
+}
+
+fn path_or_env(path: Option<String>, var: &str, what: &str) -> PyResult<String> {
+    path.or_else(|| std::env::var(var).ok()).ok_or_else(|| {
+        ConfigError::new_err(format!(
+            "{} path is required (.{}() or ${})",
+            what, what, var
+        ))
+    })
+}
+
+/// Copy `rootfs` into `dir`, extended to `disk_size_mb` if given.
+fn working_copy(rootfs: &str, dir: &Path, disk_size_mb: Option<u64>) -> std::io::Result<String> {
+    std::fs::create_dir_all(dir)?;
+    let copy = dir.join("rootfs.ext4");
+    std::fs::copy(rootfs, &copy)?;
+    if let Some(mb) = disk_size_mb {
+        let f = std::fs::OpenOptions::new().write(true).open(&copy)?;
+        f.set_len(mb * 1024 * 1024)?;
+    }
+    Ok(copy.to_string_lossy().into_owned())
+}
+
+// --- Sandbox ---
+
+/// A microVM sandbox. Use it as a context manager to start it and to stop
+/// it and delete its working copy afterwards, even on errors:
+///
+///     with Sandbox.builder().build() as sb:
+///         print(sb.exec(["uname", "-a"]).stdout)
+#[pyclass(module = "shuru", frozen)]
+pub(crate) struct Sandbox {
+    /// `None` once closed.
+    inner: RwLock<Option<shuru_vm::Sandbox>>,
+    instance_dir: Option<PathBuf>,
+}
+
+impl Sandbox {
+    fn new(sandbox: shuru_vm::Sandbox, instance_dir: Option<PathBuf>) -> Self {
+        Sandbox {
+            inner: RwLock::new(Some(sandbox)),
+            instance_dir,
+        }
+    }
+
+    /// Run `f` on the open sandbox without holding the GIL.
+    fn with<T: Send>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&shuru_vm::Sandbox) -> shuru_vm::Result<T> + Send,
+    ) -> PyResult<T> {
+        py.detach(|| {
+            let inner = self.inner.read().unwrap();
+            let sandbox = inner
+                .as_ref()
+                .ok_or_else(|| SandboxError::new_err("sandbox is closed"))?;
+            f(sandbox).map_err(to_py)
+        })
+    }
+
+    /// Stop the VM and delete the working copy. Safe to call repeatedly.
+    fn shutdown(&self) -> shuru_vm::Result<()> {
+        let sandbox = self.inner.write().unwrap().take();
+        let stopped = sandbox.map_or(Ok(()), |sandbox| sandbox.stop());
+        if let Some(dir) = &self.instance_dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        stopped
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[pymethods]
+impl Sandbox {
+    #[staticmethod]
+    fn builder() -> SandboxBuilder {
+        SandboxBuilder::new()
+    }
+
+    /// Boot the VM; commands wait for the guest to come up.
+    fn start(&self, py: Python<'_>) -> PyResult<()> {
+        self.with(py, |sb| sb.start())
+    }
+
+    /// Stop the VM. It cannot be started again; see `close()`.
+    fn stop(&self, py: Python<'_>) -> PyResult<()> {
+        self.with(py, |sb| sb.stop())
+    }
+
+    /// Stop the VM if needed and delete the working copy.
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        py.detach(|| self.shutdown()).map_err(to_py)
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.inner.read().unwrap().is_none()
+    }
+
+    fn __enter__(slf: Bound<'_, Self>) -> PyResult<Bound<'_, Self>> {
+        if let Err(e) = slf.get().start(slf.py()) {
+            let _ = slf.get().close(slf.py());
+            return Err(e);
+        }
+        Ok(slf)
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyType>>,
+        _exc: Option<&Bound<'_, PyAny>>,
+        _tb: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+
+    /// Run `argv` (no shell) and collect its output. With `timeout`
+    /// (seconds), the command is killed once it runs out and the result
+    /// has `timed_out` set.
+    #[pyo3(signature = (argv, *, env = None, cwd = None, input = None, timeout = None))]
+    fn exec(
+        &self,
+        py: Python<'_>,
+        argv: Vec<String>,
+        env: Option<HashMap<String, String>>,
+        cwd: Option<String>,
+        input: Option<&[u8]>,
+        timeout: Option<f64>,
+    ) -> PyResult<ExecResult> {
+        let input = input.map(<[u8]>::to_vec);
+        let mut child = self.with(py, |sb| {
+            let mut cmd = process::command(sb, &argv, env, cwd)?;
+            if input.is_some() {
+                cmd.stdin(Stdio::piped());
+            }
+            let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+            if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+                std::io::Write::write_all(&mut stdin, &input)?;
+            }
+            Ok(child)
+        })?;
+
+        let deadline = timeout.map(|t| Instant::now() + Duration::from_secs_f64(t));
+        let timed_out = process::wait_until(py, &mut child, deadline)?;
+        let output = py.detach(|| child.wait_with_output()).map_err(to_py)?;
+        Ok(ExecResult::new(py, output, timed_out))
+    }
+
+    /// Start `argv` (no shell) and return a `Process` that yields its
+    /// output as it arrives.
+    #[pyo3(signature = (argv, *, env = None, cwd = None, stdin = false))]
+    fn spawn(
+        &self,
+        py: Python<'_>,
+        argv: Vec<String>,
+        env: Option<HashMap<String, String>>,
+        cwd: Option<String>,
+        stdin: bool,
+    ) -> PyResult<Process> {
+        let child = self.with(py, |sb| {
+            let mut cmd = process::command(sb, &argv, env, cwd)?;
+            if stdin {
+                cmd.stdin(Stdio::piped());
+            }
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        })?;
+        Ok(Process::new(child))
+    }
+
+    fn read_file<'py>(&self, py: Python<'py>, path: String) -> PyResult<Bound<'py, PyBytes>> {
+        let data = self.with(py, |sb| sb.read_file(&path))?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Create or replace a guest file; `mode` defaults to 0o644.
+    #[pyo3(signature = (path, data, mode = None))]
+    fn write_file(
+        &self,
+        py: Python<'_>,
+        path: String,
+        data: &[u8],
+        mode: Option<u32>,
+    ) -> PyResult<()> {
+        let data = data.to_vec();
+        self.with(py, |sb| sb.write_file(&path, &data, mode))
+    }
+
+    /// Forward each `(host_port, guest_port)` from 127.0.0.1 into the guest
+    /// until the returned `PortForward` is closed.
+    fn forward_ports(&self, py: Python<'_>, ports: Vec<(u16, u16)>) -> PyResult<PortForward> {
+        let mappings: Vec<PortMapping> = ports
+            .into_iter()
+            .map(|(host_port, guest_port)| PortMapping {
+                host_port,
+                guest_port,
+            })
+            .collect();
+        let handle = self.with(py, |sb| sb.start_port_forwarding(&mappings))?;
+        Ok(PortForward {
+            handle: Mutex::new(Some(Box::new(handle))),
+        })
+    }
+
+    /// Let the guest reach host services: each `(host_addr, guest_port)`
+    /// makes the guest listen on 127.0.0.1:`guest_port` and connect its
+    /// clients to `host_addr` (`"HOST:PORT"`), until the returned
+    /// `PortForward` is closed.
+    fn expose(&self, py: Python<'_>, services: Vec<(String, u16)>) -> PyResult<PortForward> {
+        let mappings: Vec<ReverseMapping> = services
+            .into_iter()
+            .map(|(host_addr, guest_port)| ReverseMapping {
+                host_addr,
+                guest_port,
+            })
+            .collect();
+        let handle = self.with(py, |sb| sb.start_reverse_forwarding(&mappings))?;
+        Ok(PortForward {
+            handle: Mutex::new(Some(Box::new(handle))),
+        })
+    }
+}
+
+// --- PortForward ---
+
+/// Active port forwards from `Sandbox.forward_ports` or `Sandbox.expose`;
+/// a context manager.
+#[pyclass(module = "shuru", frozen)]
+pub(crate) struct PortForward {
+    /// A `PortForwardHandle` or `ReverseForwardHandle`.
+    handle: Mutex<Option<Box<dyn Send>>>,
+}
+
+#[pymethods]
+impl PortForward {
+    /// Stop listening on the forwarded ports.
+    fn close(&self) {
+        self.handle.lock().unwrap().take();
+    }
+
+    fn __enter__(slf: Bound<'_, Self>) -> Bound<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        _exc_type: Option<&Bound<'_, PyType>>,
+        _exc: Option<&Bound<'_, PyAny>>,
+        _tb: Option<&Bound<'_, PyAny>>,
+    ) -> bool {
+        self.close();
+        false
+    }
+}
@@ -0,0 +1,118 @@
+"""Tests for the Python bindings against a `shuru-guest` in agent mode.
+
+Build the module and the guest, then run with the standard library runner
+(pytest collects the same tests):
+
+    maturin develop -m crates/shuru-py/Cargo.toml
+    cargo build -p shuru-guest
+    python -m unittest discover crates/shuru-py/tests
+
+`SHURU_GUEST_BIN` overrides the guest binary, which defaults to
+`target/debug/shuru-guest`.
+"""
+
+import os
+import shutil
+import subprocess
+import sys
+import tempfile
+import time
+import unittest
+
+import shuru
+
+REPO = os.path.abspath(os.path.join(os.path.dirname(__file__), "..", "..", ".."))
+GUEST_BIN = os.environ.get(
+    "SHURU_GUEST_BIN", os.path.join(REPO, "target", "debug", "shuru-guest")
+)
+
+
+@unittest.skipUnless(sys.platform.startswith("linux"), "agent mode needs Linux")
+class AgentTestCase(unittest.TestCase):
+    """Runs each test against a fresh `shuru-guest --listen`."""
+
+    def setUp(self):
+        self.dir = tempfile.mkdtemp(prefix="shuru-py-")
+        self.addCleanup(shutil.rmtree, self.dir, ignore_errors=True)
+        sock = os.path.join(self.dir, "sock")
+        guest = subprocess.Popen(
+            [GUEST_BIN, "--listen", sock],
+            stdout=subprocess.DEVNULL,
+            stderr=subprocess.DEVNULL,
+        )
+        self.addCleanup(guest.wait)
+        self.addCleanup(guest.kill)
+
+        deadline = time.monotonic() + 10
+        while not all(os.path.exists(f"{sock}_{p}") for p in range(1024, 1028)):
+            self.assertLess(time.monotonic(), deadline, "shuru-guest did not start")
+            time.sleep(0.02)
+        self.builder = shuru.Sandbox.builder().agent(f"unix:{sock}")
+
+    def sandbox(self):
+        sb = self.builder.build()
+        self.addCleanup(sb.close)
+        return sb
+
+
+class ExecTest(AgentTestCase):
+    def test_output_and_exit_code(self):
+        r = self.sandbox().exec(["sh", "-c", "echo out; echo err >&2; exit 3"])
+        self.assertEqual((r.returncode, r.stdout, r.stderr), (3, b"out\n", b"err\n"))
+        self.assertFalse(r.timed_out)
+
+    def test_env_cwd_and_input(self):
+        r = self.sandbox().exec(
+            ["sh", "-c", 'echo "$NAME $(pwd)"; cat'],
+            env={"NAME": "shuru"},
+            cwd=self.dir,
+            input=b"from stdin",
+        )
+        self.assertEqual(r.stdout, f"shuru {self.dir}\nfrom stdin".encode())
+
+    def test_timeout_kills_the_command(self):
+        start = time.monotonic()
+        r = self.sandbox().exec(["sleep", "30"], timeout=0.5)
+        self.assertTrue(r.timed_out)
+        self.assertLess(time.monotonic() - start, 10)
+
+    def test_missing_program(self):
+        with self.assertRaises(shuru.CommandNotFoundError):
+            self.sandbox().exec(["/nonexistent/program"])
+
+    def test_spawn_streams_output(self):
+        proc = self.sandbox().spawn(["cat"], stdin=True)
+        proc.write(b"hello")
+        proc.close_stdin()
+        output = b"".join(data for stream, data in proc if stream == "stdout")
+        self.assertEqual(output, b"hello")
+        self.assertEqual(proc.wait(timeout=10), 0)
+
+
+class FileTest(AgentTestCase):
+    def test_write_then_read(self):
+        sb = self.sandbox()
+        path = os.path.join(self.dir, "data.bin")
+        data = bytes(range(256)) * 1024
+        sb.write_file(path, data)
+        self.assertEqual(sb.read_file(path), data)
+        self.assertEqual(os.stat(path).st_mode & 0o777, 0o644)
+
+        sb.write_file(path, b"#!/bin/sh\necho hi\n", mode=0o755)
+        self.assertEqual(os.stat(path).st_mode & 0o777, 0o755)
+        self.assertEqual(sb.exec([path]).stdout, b"hi\n")
+
+    def test_missing_file(self):
+        with self.assertRaises(shuru.GuestError):
+            self.sandbox().read_file(os.path.join(self.dir, "missing"))
+
+
+class ConfigTest(AgentTestCase):
+    def test_mounts_are_unsupported(self):
+        self.builder.mount(self.dir, "/mnt/host")
+        with self.assertRaises(shuru.UnsupportedError):
+            self.builder.build()
+
+
+if __name__ == "__main__":
+    unittest.main()
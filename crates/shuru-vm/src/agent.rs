@@ -0,0 +1,128 @@
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::backend::{Backend, GuestStream, VmConfig, VmState};
+use crate::error::{Result, SandboxError};
+use crate::VSOCK_PORT;
+
+/// Environment variable naming the agent address for
+/// [`AgentBackend::configure`]; `shuru-guest` reads the same variable.
+pub const AGENT_ADDR_ENV: &str = "SHURU_AGENT_ADDR";
+
+/// Address of a `shuru-guest` running in agent mode (`--listen`).
+///
+/// Each guest port has its own endpoint: `PATH_<port>` for Unix sockets;
+/// for TCP the base port serves 1024 and the next three 1025 to 1027.
+#[derive(Debug, Clone)]
+pub enum AgentAddr {
+    Unix(String),
+    Tcp(String, u16),
+}
+
+impl AgentAddr {
+    /// Parse `unix:PATH`, `tcp:HOST:PORT`, or a bare path or `HOST:PORT`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            return Ok(AgentAddr::Unix(path.to_string()));
+        }
+        let tcp = spec.strip_prefix("tcp:");
+        if tcp.is_none() && spec.contains('/') {
+            return Ok(AgentAddr::Unix(spec.to_string()));
+        }
+        let tcp = tcp.unwrap_or(spec);
+        let (host, port) = tcp.rsplit_once(':').ok_or_else(|| {
+            SandboxError::InvalidConfig(format!("invalid agent address '{}'", spec))
+        })?;
+        let port = port
+            .parse::<u16>()
+            .ok()
+            .filter(|p| p.checked_add(3).is_some())
+            .ok_or_else(|| {
+                SandboxError::InvalidConfig(format!("invalid port in agent address '{}'", spec))
+            })?;
+        Ok(AgentAddr::Tcp(host.to_string(), port))
+    }
+}
+
+/// Backend that talks to an already running `shuru-guest` in agent mode
+/// instead of booting a VM. It lets `Sandbox::exec`, `shell` and port
+/// forwarding run against a plain Linux process, e.g. in integration tests.
+///
+/// There is no machine to start or stop: `start` and `stop` only report
+/// state changes, and shared directories are not supported.
+pub struct AgentBackend {
+    addr: AgentAddr,
+    states_tx: Sender<VmState>,
+    states: Receiver<VmState>,
+}
+
+impl AgentBackend {
+    pub fn new(addr: AgentAddr) -> Self {
+        let (states_tx, states) = crossbeam_channel::unbounded();
+        AgentBackend {
+            addr,
+            states_tx,
+            states,
+        }
+    }
+}
+
+impl Backend for AgentBackend {
+    /// Uses the agent at `$SHURU_AGENT_ADDR`; the rest of `config` only
+    /// matters for a real VM.
+    fn configure(config: &VmConfig) -> Result<Self> {
+        if !config.shares.is_empty() {
+            return Err(SandboxError::Unsupported(
+                "directory mounts are not supported by the agent backend".into(),
+            ));
+        }
+        let spec = std::env::var(AGENT_ADDR_ENV)
+            .map_err(|_| SandboxError::InvalidConfig(format!("{} is not set", AGENT_ADDR_ENV)))?;
+        Ok(AgentBackend::new(AgentAddr::parse(&spec)?))
+    }
+
+    fn start(&self) -> Result<()> {
+        let _ = self.states_tx.send(VmState::Running);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        let _ = self.states_tx.send(VmState::Stopped);
+        Ok(())
+    }
+
+    fn state_channel(&self) -> Receiver<VmState> {
+        self.states.clone()
+    }
+
+    fn connect(&self, port: u32) -> Result<GuestStream> {
+        let no_endpoint = || {
+            SandboxError::Unsupported(format!("agent backend has no endpoint for port {}", port))
+        };
+        let offset = port.checked_sub(VSOCK_PORT).ok_or_else(no_endpoint)?;
+        match &self.addr {
+            AgentAddr::Unix(path) => {
+                let path = format!("{}_{}", path, port);
+                let stream = UnixStream::connect(&path).map_err(|e| {
+                    SandboxError::unreachable(format!("connect to agent at {}", path), e.into())
+                })?;
+                Ok(stream.into())
+            }
+            AgentAddr::Tcp(host, base) => {
+                let tcp_port = u16::try_from(offset)
+                    .ok()
+                    .and_then(|o| base.checked_add(o))
+                    .ok_or_else(no_endpoint)?;
+                let stream = TcpStream::connect((host.as_str(), tcp_port)).map_err(|e| {
+                    SandboxError::unreachable(
+                        format!("connect to agent at {}:{}", host, tcp_port),
+                        e.into(),
+                    )
+                })?;
+                Ok(stream.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crossbeam_channel::Receiver;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+    ReadHalf, WriteHalf,
+};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::info;
+
+use crate::backend::{GuestStream, VmState};
+use crate::error::{Result, SandboxError};
+use crate::events::{ExecSpan, ForwardSpan, SandboxEvent};
+use crate::proto::{ExecRequest, ExecResponse, FileOp, FileRequest, PortMapping, ReverseMapping};
+use crate::sandbox::{exec_error, parse_file_response, ReverseForwardHandle, Sandbox};
+
+/// Tokio front end for a [`Sandbox`] (`async` feature).
+///
+/// Guest connections are switched to non-blocking mode and driven by the
+/// tokio reactor; only connection setup (which retries while the guest
+/// boots) and starting or stopping the VM run on the blocking pool. Must be
+/// used from within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncSandbox {
+    inner: Arc<Sandbox>,
+}
+
+/// Collected output of [`AsyncSandbox::exec`].
+#[derive(Debug, Clone, Default)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: i32,
+}
+
+/// One item of an [`ExecStream`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecEvent {
+    Stdout(String),
+    Stderr(String),
+    /// The command exited; always the last event.
+    Exit(i32),
+}
+
+impl From<Sandbox> for AsyncSandbox {
+    fn from(sandbox: Sandbox) -> Self {
+        AsyncSandbox::new(sandbox)
+    }
+}
+
+impl AsyncSandbox {
+    pub fn new(sandbox: Sandbox) -> Self {
+        AsyncSandbox {
+            inner: Arc::new(sandbox),
+        }
+    }
+
+    /// The wrapped blocking sandbox, e.g. for [`Sandbox::shell`].
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.inner
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let inner = Arc::clone(&self.inner);
+        blocking(move || inner.start()).await
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        let inner = Arc::clone(&self.inner);
+        blocking(move || inner.stop()).await
+    }
+
+    pub fn state_channel(&self) -> Receiver<VmState> {
+        self.inner.state_channel()
+    }
+
+    /// See [`Sandbox::events`].
+    pub fn events(&self) -> Receiver<SandboxEvent> {
+        self.inner.events()
+    }
+
+    /// Run a command and collect its output.
+    pub async fn exec(
+        &self,
+        argv: &[impl AsRef<str>],
+        env: &HashMap<String, String>,
+    ) -> Result<ExecOutput> {
+        let mut stream = self.exec_stream(argv, env).await?;
+        let mut output = ExecOutput::default();
+        while let Some(event) = stream.next().await? {
+            match event {
+                ExecEvent::Stdout(data) => output.stdout.push_str(&data),
+                ExecEvent::Stderr(data) => output.stderr.push_str(&data),
+                ExecEvent::Exit(code) => output.code = code,
+            }
+        }
+        Ok(output)
+    }
+
+    /// Run a command and return its stdout and stderr as they arrive.
+    pub async fn exec_stream(
+        &self,
+        argv: &[impl AsRef<str>],
+        env: &HashMap<String, String>,
+    ) -> Result<ExecStream> {
+        let (reader, mut writer) = self.connect().await?;
+        let req = ExecRequest {
+            argv: argv.iter().map(|s| s.as_ref().to_string()).collect(),
+            env: env.clone(),
+            tty: None,
+            rows: None,
+            cols: None,
+            ..Default::default()
+        };
+        write_line(&mut writer, &serde_json::to_string(&req)?).await?;
+        Ok(ExecStream {
+            reader,
+            _writer: writer,
+            span: ExecSpan::start(self.inner.event_hub(), &req.argv, false),
+            program: req.argv[0].clone(),
+            done: false,
+        })
+    }
+
+    /// Write `size` bytes from `data` to `path` in the guest, replacing any
+    /// existing file. See [`Sandbox::write_file`] for `mode`.
+    pub async fn upload(
+        &self,
+        path: &str,
+        data: impl AsyncRead + Unpin,
+        size: u64,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        let (mut reader, mut writer) = self.connect().await?;
+        let req = FileRequest {
+            op: FileOp::Write,
+            path: path.to_string(),
+            size,
+            mode,
+        };
+        write_line(&mut writer, &serde_json::to_string(&req)?).await?;
+        let sent = tokio::io::copy(&mut data.take(size), &mut writer).await?;
+        if sent != size {
+            return Err(SandboxError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{}: source ended after {} of {} bytes", path, sent, size),
+            )));
+        }
+        writer.flush().await?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        parse_file_response(&line, path)?;
+        Ok(())
+    }
+
+    /// Copy `path` from the guest into `out`. Returns the number of bytes.
+    pub async fn download(&self, path: &str, mut out: impl AsyncWrite + Unpin) -> Result<u64> {
+        let (mut reader, mut writer) = self.connect().await?;
+        let req = FileRequest {
+            op: FileOp::Read,
+            path: path.to_string(),
+            size: 0,
+            mode: None,
+        };
+        write_line(&mut writer, &serde_json::to_string(&req)?).await?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let size = parse_file_response(&line, path)?.size.unwrap_or(0);
+
+        let received = tokio::io::copy(&mut reader.take(size), &mut out).await?;
+        if received != size {
+            return Err(SandboxError::Disconnected(format!(
+                "guest closed connection while sending {}",
+                path
+            )));
+        }
+        out.flush().await?;
+        Ok(size)
+    }
+
+    pub async fn write_file(&self, path: &str, data: &[u8], mode: Option<u32>) -> Result<()> {
+        self.upload(path, data, data.len() as u64, mode).await
+    }
+
+    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.download(path, &mut data).await?;
+        Ok(data)
+    }
+
+    /// Start port forwarding proxies on the current runtime. Returns a
+    /// handle that stops all listeners when dropped.
+    pub async fn start_port_forwarding(
+        &self,
+        forwards: &[PortMapping],
+    ) -> Result<AsyncPortForwardHandle> {
+        let mut listeners = Vec::new();
+        for mapping in forwards {
+            listeners.push((
+                TcpListener::bind(("127.0.0.1", mapping.host_port))
+                    .await
+                    .map_err(|source| SandboxError::PortBindFailed {
+                        port: mapping.host_port,
+                        source,
+                    })?,
+                mapping.clone(),
+            ));
+        }
+
+        let mut tasks = Vec::new();
+        for (listener, mapping) in listeners {
+            info!(
+                "shuru: forwarding 127.0.0.1:{} -> guest:{}",
+                mapping.host_port, mapping.guest_port
+            );
+            let inner = Arc::clone(&self.inner);
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    let tcp = match listener.accept().await {
+                        Ok((tcp, _)) => tcp,
+                        Err(e) => {
+                            tracing::debug!("accept error on port forward listener: {}", e);
+                            break;
+                        }
+                    };
+                    let inner = Arc::clone(&inner);
+                    let mapping = mapping.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = forward_connection(inner, tcp, &mapping).await {
+                            info!("shuru: port forward error: {}", e);
+                        }
+                    });
+                }
+            }));
+        }
+        Ok(AsyncPortForwardHandle { tasks })
+    }
+
+    /// Start reverse port forwarding on the blocking pool; see
+    /// [`Sandbox::start_reverse_forwarding`]. Connections are relayed on
+    /// plain threads, not the runtime.
+    pub async fn start_reverse_forwarding(
+        &self,
+        forwards: &[ReverseMapping],
+    ) -> Result<ReverseForwardHandle> {
+        let inner = Arc::clone(&self.inner);
+        let forwards = forwards.to_vec();
+        blocking(move || inner.start_reverse_forwarding(&forwards)).await
+    }
+
+    /// Open a guest connection on the blocking pool, then hand it to tokio.
+    async fn connect(
+        &self,
+    ) -> Result<(
+        BufReader<ReadHalf<AsyncGuestStream>>,
+        WriteHalf<AsyncGuestStream>,
+    )> {
+        let inner = Arc::clone(&self.inner);
+        let stream = into_tokio(blocking(move || inner.connect_agent()).await?)?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok((BufReader::new(reader), writer))
+    }
+}
+
+// --- Exec stream ---
+
+/// Output of a command started with [`AsyncSandbox::exec_stream`].
+pub struct ExecStream {
+    reader: BufReader<ReadHalf<AsyncGuestStream>>,
+    _writer: WriteHalf<AsyncGuestStream>,
+    span: ExecSpan,
+    program: String,
+    done: bool,
+}
+
+impl ExecStream {
+    /// The next chunk of output, or `None` after [`ExecEvent::Exit`].
+    pub async fn next(&mut self) -> Result<Option<ExecEvent>> {
+        let mut line = String::new();
+        while !self.done {
+            line.clear();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(SandboxError::Disconnected(
+                    "guest closed connection before the command exited".into(),
+                ));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+
+            let resp: ExecResponse = serde_json::from_str(line)?;
+            match resp.msg_type.as_str() {
+                "stdout" => return Ok(Some(ExecEvent::Stdout(resp.data.unwrap_or_default()))),
+                "stderr" => return Ok(Some(ExecEvent::Stderr(resp.data.unwrap_or_default()))),
+                "exit" => {
+                    let code = resp.code.unwrap_or(0);
+                    self.done = true;
+                    self.span.exited(code);
+                    return Ok(Some(ExecEvent::Exit(code)));
+                }
+                "error" => return Err(exec_error(resp, &self.program)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+// --- Port forwarding ---
+
+/// Handle returned by [`AsyncSandbox::start_port_forwarding`]. Stops all
+/// listeners when dropped; connections already accepted run to completion.
+pub struct AsyncPortForwardHandle {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for AsyncPortForwardHandle {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn forward_connection(
+    inner: Arc<Sandbox>,
+    mut tcp: TcpStream,
+    mapping: &PortMapping,
+) -> Result<()> {
+    let events = Arc::clone(inner.event_hub());
+    let guest_port = mapping.guest_port;
+    let mut guest = into_tokio(blocking(move || inner.connect_forward(guest_port)).await?)?;
+    let _span = ForwardSpan::open(&events, mapping.host_port, guest_port);
+    tokio::io::copy_bidirectional(&mut tcp, &mut guest).await?;
+    Ok(())
+}
+
+// --- Helpers ---
+
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            // Cancelled because the runtime is shutting down
+            Err(e) => Err(std::io::Error::from(e).into()),
+        },
+    }
+}
+
+/// A [`GuestStream`] registered with the tokio runtime.
+enum AsyncGuestStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for AsyncGuestStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            AsyncGuestStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            AsyncGuestStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncGuestStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            AsyncGuestStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            AsyncGuestStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            AsyncGuestStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            AsyncGuestStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            AsyncGuestStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            AsyncGuestStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+fn into_tokio(stream: GuestStream) -> Result<AsyncGuestStream> {
+    stream.set_nonblocking(true)?;
+    Ok(match stream {
+        GuestStream::Tcp(s) => AsyncGuestStream::Tcp(TcpStream::from_std(s)?),
+        GuestStream::Unix(s) => AsyncGuestStream::Unix(UnixStream::from_std(s)?),
+    })
+}
+
+async fn write_line(writer: &mut WriteHalf<AsyncGuestStream>, line: &str) -> Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
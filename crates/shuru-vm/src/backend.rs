@@ -0,0 +1,246 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+use shuru_net::EgressPolicy;
+
+use crate::error::Result;
+
+/// Lifecycle state of a VM, as reported by its backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    Stopped,
+    Running,
+    Paused,
+    Error,
+    Starting,
+    Pausing,
+    Resuming,
+    Stopping,
+    Unknown,
+}
+
+/// A host directory exposed to the guest as a virtio-fs share.
+#[derive(Debug, Clone)]
+pub struct SharedDirConfig {
+    /// virtio-fs tag the guest mounts by.
+    pub tag: String,
+    pub host_path: String,
+    /// Where the guest agent mounts the share.
+    pub guest_path: String,
+    /// Read-only shares are mounted with a tmpfs overlay in the guest.
+    pub read_only: bool,
+}
+
+/// How the host caches an extra disk's image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskCaching {
+    /// Let the hypervisor decide.
+    Automatic,
+    /// Use the host page cache (like the rootfs).
+    #[default]
+    Cached,
+    /// Bypass the host page cache.
+    Uncached,
+}
+
+/// When guest flushes reach the host disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiskSync {
+    /// Flush through to the physical disk (`F_FULLFSYNC` on macOS).
+    Full,
+    /// `fsync` the image on guest flushes (like the rootfs).
+    #[default]
+    Fsync,
+    /// Ignore guest flushes; fastest, but a host crash can lose data.
+    None,
+}
+
+/// A disk image attached as an extra virtio-blk device. Disks follow the
+/// rootfs in order, so the first one is `/dev/vdb` in the guest.
+#[derive(Debug, Clone)]
+pub struct DiskConfig {
+    /// Raw disk image on the host.
+    pub path: String,
+    /// Where the guest agent mounts the disk's ext4 filesystem, formatting
+    /// it first if the disk is blank. `None` leaves it unmounted.
+    pub guest_path: Option<String>,
+    pub read_only: bool,
+    pub caching: DiskCaching,
+    pub sync: DiskSync,
+}
+
+impl DiskConfig {
+    /// A read-write, unmounted disk with default caching.
+    pub fn new(path: impl Into<String>) -> Self {
+        DiskConfig {
+            path: path.into(),
+            guest_path: None,
+            read_only: false,
+            caching: DiskCaching::default(),
+            sync: DiskSync::default(),
+        }
+    }
+}
+
+/// Hypervisor-independent description of a VM, produced by
+/// [`VmConfigBuilder`](crate::VmConfigBuilder).
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub kernel: String,
+    pub initrd: Option<String>,
+    /// Root disk image, attached read-write as the first virtio-blk device.
+    pub rootfs: String,
+    /// Full kernel command line.
+    pub cmdline: String,
+    pub cpus: usize,
+    pub memory_mb: u64,
+    /// Connect the serial console to stdin/stdout instead of writing it to
+    /// stderr (or `console_log`).
+    pub console: bool,
+    /// Timestamped log of the serial console and guest agent records; see
+    /// [`VmConfigBuilder::console_log`](crate::VmConfigBuilder::console_log).
+    pub console_log: Option<String>,
+    pub allow_net: bool,
+    /// Route the guest's traffic through a userspace stack enforcing this
+    /// policy instead of NAT; see
+    /// [`VmConfigBuilder::egress`](crate::VmConfigBuilder::egress).
+    pub egress: Option<EgressPolicy>,
+    /// Serve an HTTP proxy and DNS resolver enforcing this policy inside
+    /// the guest; see
+    /// [`VmConfigBuilder::network_proxy`](crate::VmConfigBuilder::network_proxy).
+    pub proxy: Option<EgressPolicy>,
+    /// Where proxy mode logs each request, as JSON lines.
+    pub egress_log: Option<String>,
+    pub shares: Vec<SharedDirConfig>,
+    /// Extra disks, attached after the rootfs.
+    pub disks: Vec<DiskConfig>,
+}
+
+impl VmConfig {
+    /// Whether the guest boots its own init system (see
+    /// [`VmConfigBuilder::init_system`](crate::VmConfigBuilder::init_system)).
+    pub fn init_system(&self) -> bool {
+        self.cmdline
+            .split_whitespace()
+            .any(|arg| arg == crate::INIT_SYSTEM_ARG)
+    }
+
+    /// Guest device node of `disks[index]`.
+    pub fn disk_device(index: usize) -> String {
+        format!("/dev/vd{}", (b'b' + index as u8) as char)
+    }
+}
+
+/// A byte stream to a guest port, opened by [`Backend::connect`].
+#[derive(Debug)]
+pub enum GuestStream {
+    /// A TCP connection, or a socket handed out by the hypervisor (vsock
+    /// on macOS).
+    Tcp(TcpStream),
+    /// A Unix socket: an agent socket, or one end of a multiplexed stream.
+    Unix(UnixStream),
+}
+
+impl GuestStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            GuestStream::Tcp(s) => s.try_clone().map(GuestStream::Tcp),
+            GuestStream::Unix(s) => s.try_clone().map(GuestStream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            GuestStream::Tcp(s) => s.shutdown(how),
+            GuestStream::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            GuestStream::Tcp(s) => s.set_read_timeout(timeout),
+            GuestStream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            GuestStream::Tcp(s) => s.set_nonblocking(nonblocking),
+            GuestStream::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl From<TcpStream> for GuestStream {
+    fn from(stream: TcpStream) -> Self {
+        GuestStream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for GuestStream {
+    fn from(stream: UnixStream) -> Self {
+        GuestStream::Unix(stream)
+    }
+}
+
+impl shuru_net::ClientStream for GuestStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        GuestStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        GuestStream::shutdown(self, how)
+    }
+}
+
+impl Read for GuestStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            GuestStream::Tcp(s) => s.read(buf),
+            GuestStream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for GuestStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            GuestStream::Tcp(s) => s.write(buf),
+            GuestStream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            GuestStream::Tcp(s) => s.flush(),
+            GuestStream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// A hypervisor that can run a shuru guest.
+///
+/// Backends only provide the machine: boot it from a [`VmConfig`], report
+/// state changes, and open byte streams to guest ports (vsock 1024 for
+/// exec/mount, 1025 for port forwards, 1026 for guest logs, 1027 for
+/// tunnels). The host/guest protocol on top is implemented once in
+/// [`Sandbox`](crate::Sandbox).
+pub trait Backend: Send + Sync + 'static {
+    /// Create a VM from `config`. The VM is not started.
+    fn configure(config: &VmConfig) -> Result<Self>
+    where
+        Self: Sized;
+
+    fn start(&self) -> Result<()>;
+
+    fn stop(&self) -> Result<()>;
+
+    /// Receiver for state changes from now on.
+    fn state_channel(&self) -> Receiver<VmState>;
+
+    /// Open a stream to `port` on the guest agent.
+    fn connect(&self, port: u32) -> Result<GuestStream>;
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::backend::GuestStream;
+use crate::error::{Result, SandboxError};
+use crate::events::ExecSpan;
+use crate::proto::{ControlMessage, ExecRequest, ExecResponse};
+use crate::sandbox::{exec_error, Sandbox};
+
+const SIGKILL: i32 = 9;
+/// Bytes of the host's stdin read at a time for inherited stdin.
+const STDIN_CHUNK_SIZE: usize = 16 * 1024;
+
+// --- Stdio ---
+
+/// How a guest command's stdin, stdout or stderr is connected, as with
+/// [`std::process::Stdio`]. `inherit` means the host process's own stdio.
+pub struct Stdio(StdioKind);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StdioKind {
+    Null,
+    Piped,
+    Inherit,
+}
+
+impl Stdio {
+    pub fn null() -> Self {
+        Stdio(StdioKind::Null)
+    }
+
+    pub fn piped() -> Self {
+        Stdio(StdioKind::Piped)
+    }
+
+    pub fn inherit() -> Self {
+        Stdio(StdioKind::Inherit)
+    }
+}
+
+// --- Command ---
+
+/// A command to run in the guest, built like [`std::process::Command`].
+/// Created by [`Sandbox::command`].
+///
+/// Unlike `std`, stdin defaults to null for `spawn` and `status` too; use
+/// `Stdio::inherit()` to forward the host's stdin.
+pub struct Command<'a> {
+    sandbox: &'a Sandbox,
+    program: String,
+    args: Vec<String>,
+    env: HashMap<String, String>,
+    cwd: Option<String>,
+    stdin: Option<StdioKind>,
+    stdout: Option<StdioKind>,
+    stderr: Option<StdioKind>,
+}
+
+impl<'a> Command<'a> {
+    pub(crate) fn new(sandbox: &'a Sandbox, program: String) -> Self {
+        Command {
+            sandbox,
+            program,
+            args: Vec::new(),
+            env: HashMap::new(),
+            cwd: None,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn env(&mut self, key: impl Into<String>, val: impl Into<String>) -> &mut Self {
+        self.env.insert(key.into(), val.into());
+        self
+    }
+
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env
+            .extend(vars.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Working directory in the guest.
+    pub fn current_dir(&mut self, dir: impl Into<String>) -> &mut Self {
+        self.cwd = Some(dir.into());
+        self
+    }
+
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Self {
+        self.stdin = Some(cfg.0);
+        self
+    }
+
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Self {
+        self.stdout = Some(cfg.0);
+        self
+    }
+
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut Self {
+        self.stderr = Some(cfg.0);
+        self
+    }
+
+    /// Start the command. stdout and stderr default to inherit.
+    pub fn spawn(&mut self) -> Result<Child> {
+        self.spawn_with(StdioKind::Inherit)
+    }
+
+    /// Run the command to completion with stdout and stderr inherited.
+    pub fn status(&mut self) -> Result<ExitStatus> {
+        self.spawn_with(StdioKind::Inherit)?.wait()
+    }
+
+    /// Run the command to completion, capturing stdout and stderr.
+    pub fn output(&mut self) -> Result<Output> {
+        self.spawn_with(StdioKind::Piped)?.wait_with_output()
+    }
+
+    fn spawn_with(&mut self, default_output: StdioKind) -> Result<Child> {
+        let stdin = self.stdin.unwrap_or(StdioKind::Null);
+        let stdout = self.stdout.unwrap_or(default_output);
+        let stderr = self.stderr.unwrap_or(default_output);
+
+        let stream = self.sandbox.connect_agent()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        let req = ExecRequest {
+            argv: std::iter::once(self.program.clone())
+                .chain(self.args.iter().cloned())
+                .collect(),
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            stdin: stdin != StdioKind::Null,
+            control: true,
+            binary: true,
+            ..Default::default()
+        };
+        writeln!(writer, "{}", serde_json::to_string(&req)?)?;
+        writer.flush()?;
+
+        let resp = read_response(&mut reader)?.ok_or_else(|| {
+            SandboxError::Disconnected(format!(
+                "guest closed connection before starting {}",
+                self.program
+            ))
+        })?;
+        let pid = match (resp.msg_type.as_str(), resp.pid) {
+            ("started", Some(pid)) => pid,
+            ("error", _) => return Err(exec_error(resp, &self.program)),
+            _ => return Err(SandboxError::outdated_guest("commands with piped I/O")),
+        };
+
+        let control = Arc::new(Mutex::new(writer));
+        let mut child_stdin = (stdin != StdioKind::Null).then(|| ChildStdin {
+            control: Arc::clone(&control),
+        });
+        // Dropped by the waiter when the command exits
+        let (exited_tx, exited_rx) = crossbeam_channel::bounded::<()>(0);
+        if stdin == StdioKind::Inherit {
+            let mut pipe = child_stdin.take().unwrap();
+            std::thread::spawn(move || loop {
+                crossbeam_channel::select! {
+                    recv(host_stdin()) -> chunk => match chunk {
+                        Ok(chunk) if pipe.write_all(&chunk).is_ok() => {}
+                        _ => break,
+                    },
+                    recv(exited_rx) -> _ => break,
+                }
+            });
+        }
+
+        let (stdout_sink, child_stdout) = output_sink(stdout, Sink::Stdout);
+        let (stderr_sink, child_stderr) = output_sink(stderr, Sink::Stderr);
+        let mut span = ExecSpan::start(self.sandbox.event_hub(), &req.argv, false);
+        let waiter = std::thread::spawn(move || {
+            let _exited = exited_tx;
+            let status = pump_output(reader, stdout_sink, stderr_sink)?;
+            span.exited(status.code);
+            Ok(status)
+        });
+
+        Ok(Child {
+            stdin: child_stdin,
+            stdout: child_stdout.map(|inner| ChildStdout { inner }),
+            stderr: child_stderr.map(|inner| ChildStderr { inner }),
+            pid,
+            control,
+            waiter: Some(waiter),
+            status: None,
+        })
+    }
+}
+
+/// Chunks of the host's stdin, disconnected at EOF. One thread reads it
+/// for every command with inherited stdin, so a command that exits leaves
+/// no copier blocked on it, and input it didn't take goes to the next one.
+fn host_stdin() -> &'static Receiver<Vec<u8>> {
+    static STDIN: OnceLock<Receiver<Vec<u8>>> = OnceLock::new();
+    STDIN.get_or_init(|| {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            let mut buf = vec![0; STDIN_CHUNK_SIZE];
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+        rx
+    })
+}
+
+// --- Child ---
+
+/// A command running in the guest, returned by [`Command::spawn`].
+pub struct Child {
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+    pid: u32,
+    control: Arc<Mutex<GuestStream>>,
+    waiter: Option<JoinHandle<Result<ExitStatus>>>,
+    status: Option<ExitStatus>,
+}
+
+impl Child {
+    /// The command's process id inside the guest.
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    /// Send `SIGKILL` to the command.
+    pub fn kill(&mut self) -> Result<()> {
+        self.signal(SIGKILL)
+    }
+
+    /// Send a signal to the command. Does nothing once it has exited.
+    pub fn signal(&mut self, signal: i32) -> Result<()> {
+        if self.try_wait()?.is_some() {
+            return Ok(());
+        }
+        match send_control(&self.control, &ControlMessage::Signal { signal }) {
+            // The guest closes the connection when the command exits
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    /// Close stdin and wait for the command to exit.
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        drop(self.stdin.take());
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        let waiter = self.waiter.take().expect("waiter taken without status");
+        let status = waiter
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        self.status = Some(status);
+        Ok(status)
+    }
+
+    /// The exit status if the command has exited, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.status.is_none() && self.waiter.as_ref().is_some_and(|w| !w.is_finished()) {
+            return Ok(None);
+        }
+        self.wait().map(Some)
+    }
+
+    /// Wait for the command and collect whatever of stdout and stderr is
+    /// piped and not yet read.
+    pub fn wait_with_output(mut self) -> Result<Output> {
+        let status = self.wait()?;
+        let mut stdout = Vec::new();
+        if let Some(mut pipe) = self.stdout.take() {
+            pipe.read_to_end(&mut stdout)?;
+        }
+        let mut stderr = Vec::new();
+        if let Some(mut pipe) = self.stderr.take() {
+            pipe.read_to_end(&mut stderr)?;
+        }
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Writes to the command's stdin. Dropping it closes the stream.
+pub struct ChildStdin {
+    control: Arc<Mutex<GuestStream>>,
+}
+
+impl Write for ChildStdin {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = BASE64.encode(buf);
+        send_control(&self.control, &ControlMessage::Stdin { data })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ChildStdin {
+    fn drop(&mut self) {
+        let _ = send_control(&self.control, &ControlMessage::Eof);
+    }
+}
+
+/// The command's stdout. Output is buffered on the host until read.
+pub struct ChildStdout {
+    inner: ChunkReader,
+}
+
+/// The command's stderr. Output is buffered on the host until read.
+pub struct ChildStderr {
+    inner: ChunkReader,
+}
+
+impl Read for ChildStdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Read for ChildStderr {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Reads chunks forwarded by the output thread; EOF once it drops the
+/// sender.
+struct ChunkReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// --- Status ---
+
+/// How a guest command finished, as with [`std::process::ExitStatus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    code: i32,
+    signal: Option<i32>,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.code == 0 && self.signal.is_none()
+    }
+
+    /// The exit code, or `None` if the command was killed by a signal.
+    pub fn code(&self) -> Option<i32> {
+        self.signal.is_none().then_some(self.code)
+    }
+
+    /// The signal that killed the command, if any.
+    pub fn signal(&self) -> Option<i32> {
+        self.signal
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.signal {
+            Some(signal) => write!(f, "signal: {}", signal),
+            None => write!(f, "exit status: {}", self.code),
+        }
+    }
+}
+
+/// Captured result of [`Command::output`].
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+// --- Output relay ---
+
+enum Sink {
+    Null,
+    Stdout,
+    Stderr,
+    Pipe(Sender<Vec<u8>>),
+}
+
+impl Sink {
+    fn write(&mut self, data: Vec<u8>) {
+        match self {
+            Sink::Null => {}
+            Sink::Stdout => {
+                let mut out = io::stdout().lock();
+                let _ = out.write_all(&data).and_then(|_| out.flush());
+            }
+            Sink::Stderr => {
+                let _ = io::stderr().lock().write_all(&data);
+            }
+            // The reader may have been dropped; keep draining the stream
+            Sink::Pipe(tx) => {
+                let _ = tx.send(data);
+            }
+        }
+    }
+}
+
+fn output_sink(kind: StdioKind, inherit: Sink) -> (Sink, Option<ChunkReader>) {
+    match kind {
+        StdioKind::Null => (Sink::Null, None),
+        StdioKind::Inherit => (inherit, None),
+        StdioKind::Piped => {
+            let (tx, rx) = crossbeam_channel::unbounded();
+            (
+                Sink::Pipe(tx),
+                Some(ChunkReader {
+                    rx,
+                    chunk: Vec::new(),
+                    pos: 0,
+                }),
+            )
+        }
+    }
+}
+
+/// Route the guest's output messages until the command exits.
+fn pump_output(
+    mut reader: BufReader<GuestStream>,
+    mut stdout: Sink,
+    mut stderr: Sink,
+) -> Result<ExitStatus> {
+    while let Some(resp) = read_response(&mut reader)? {
+        let decode = |data: Option<String>| -> Result<Vec<u8>> {
+            BASE64
+                .decode(data.unwrap_or_default())
+                .map_err(|e| SandboxError::ProtocolMismatch {
+                    reason: "malformed command output from guest".into(),
+                    source: Some(Box::new(e)),
+                })
+        };
+        match resp.msg_type.as_str() {
+            "stdout" => stdout.write(decode(resp.data)?),
+            "stderr" => stderr.write(decode(resp.data)?),
+            "exit" => {
+                return Ok(ExitStatus {
+                    code: resp.code.unwrap_or(0),
+                    signal: resp.signal,
+                })
+            }
+            "error" => return Err(SandboxError::Guest(resp.data.unwrap_or_default())),
+            _ => {}
+        }
+    }
+    Err(SandboxError::Disconnected(
+        "guest closed connection before the command exited".into(),
+    ))
+}
+
+fn read_response(reader: &mut BufReader<GuestStream>) -> Result<Option<ExecResponse>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.trim().is_empty() {
+            return Ok(Some(serde_json::from_str(line.trim())?));
+        }
+    }
+}
+
+fn send_control(control: &Mutex<GuestStream>, msg: &ControlMessage) -> io::Result<()> {
+    let line = serde_json::to_string(msg)?;
+    let mut stream = control.lock().unwrap();
+    writeln!(stream, "{}", line)?;
+    stream.flush()
+}
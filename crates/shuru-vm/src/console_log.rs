@@ -0,0 +1,107 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, PipeWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::proto::LogRecord;
+
+/// Source tag of serial console lines.
+pub const LOG_SOURCE_CONSOLE: &str = "console";
+/// Source tag of the guest agent's own log records.
+pub const LOG_SOURCE_GUEST: &str = "guest";
+/// Source tag of traffic denied by the egress policy.
+pub const LOG_SOURCE_NET: &str = "net";
+
+/// Append-only writer for a VM's console log (see
+/// [`VmConfigBuilder::console_log`](crate::VmConfigBuilder::console_log)).
+///
+/// Each line is `<unix ms> <source> <text>`. The backend and the sandbox
+/// open the file separately; every line is a single append, so their lines
+/// interleave but never tear.
+pub(crate) struct ConsoleLog {
+    file: File,
+}
+
+impl ConsoleLog {
+    /// Create or truncate the log at `path`.
+    pub(crate) fn create(path: &str) -> std::io::Result<()> {
+        File::create(path).map(drop)
+    }
+
+    pub(crate) fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(ConsoleLog { file })
+    }
+
+    /// Log `text`, one line per line of it.
+    pub(crate) fn write_line(&mut self, ts_ms: u64, source: &str, text: &str) {
+        let mut out = String::new();
+        for line in text.trim_end().lines() {
+            out.push_str(&format!("{} {} {}\n", ts_ms, source, line.trim_end()));
+        }
+        let _ = self.file.write_all(out.as_bytes());
+    }
+
+    /// A pipe whose output is logged line by line under `source`, stamped
+    /// with the time each line arrives. The logging thread ends when every
+    /// copy of the returned writer is closed.
+    pub(crate) fn capture(path: &str, source: &'static str) -> std::io::Result<PipeWriter> {
+        let mut log = ConsoleLog::open(path)?;
+        let (reader, writer) = std::io::pipe()?;
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut buf = Vec::new();
+            loop {
+                buf.clear();
+                match reader.read_until(b'\n', &mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {
+                        let text = String::from_utf8_lossy(&buf);
+                        let text = text.trim_end_matches(['\r', '\n']);
+                        if !text.is_empty() {
+                            log.write_line(now_ms(), source, text);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(writer)
+    }
+
+    /// Log the guest agent's records from a port 1026 connection until it
+    /// closes.
+    pub(crate) fn pump_guest(path: &str, stream: impl std::io::Read + Send + 'static) {
+        let mut log = match ConsoleLog::open(path) {
+            Ok(log) => log,
+            Err(e) => {
+                tracing::debug!("cannot open console log {}: {}", path, e);
+                return;
+            }
+        };
+        std::thread::spawn(move || {
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else { return };
+                match serde_json::from_str::<LogRecord>(&line) {
+                    Ok(record) => {
+                        let text = format!("{} {}", record.level, record.message);
+                        log.write_line(
+                            record.ts_ms.unwrap_or_else(now_ms),
+                            LOG_SOURCE_GUEST,
+                            &text,
+                        );
+                    }
+                    Err(_) if !line.trim().is_empty() => {
+                        log.write_line(now_ms(), LOG_SOURCE_GUEST, &line);
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+    }
+}
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,211 @@
+use std::io::PipeWriter;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+
+use crossbeam_channel::Receiver;
+
+use shuru_darwin::*;
+use shuru_net::NetHandle;
+
+use crate::backend::{Backend, DiskCaching, DiskSync, GuestStream, VmConfig, VmState};
+use crate::console_log::{ConsoleLog, LOG_SOURCE_CONSOLE};
+use crate::error::{Result, SandboxError};
+use crate::net;
+
+/// Apple Virtualization.framework backend (macOS).
+pub struct DarwinBackend {
+    vm: VirtualMachine,
+    states: Receiver<VmState>,
+    /// Write end of the console log pipe; the serial attachment does not
+    /// own its file descriptor.
+    _console_log: Option<PipeWriter>,
+    /// With an egress policy: the network attachment's socket, which it
+    /// does not own either, and the stack serving its other end.
+    _net: Option<(UnixDatagram, NetHandle)>,
+}
+
+impl DarwinBackend {
+    /// The underlying Virtualization.framework VM, for advanced use.
+    pub fn virtual_machine(&self) -> &VirtualMachine {
+        &self.vm
+    }
+}
+
+impl Backend for DarwinBackend {
+    fn configure(cfg: &VmConfig) -> Result<Self> {
+        if !VirtualMachine::supported() {
+            return Err(SandboxError::Unsupported(
+                "Virtualization is not supported on this machine".into(),
+            ));
+        }
+
+        let boot_loader = LinuxBootLoader::new_with_kernel(&cfg.kernel);
+        if let Some(ref initrd) = cfg.initrd {
+            boot_loader.set_initrd(initrd);
+        }
+        boot_loader.set_command_line(&cfg.cmdline);
+
+        let memory_bytes = cfg.memory_mb * 1024 * 1024;
+        let config = VirtualMachineConfiguration::new(&boot_loader, cfg.cpus, memory_bytes);
+
+        let console_log = match &cfg.console_log {
+            Some(path) if !cfg.console => {
+                Some(ConsoleLog::capture(path, LOG_SOURCE_CONSOLE).map_err(|e| {
+                    SandboxError::boot_failed("Failed to open console log", e.into())
+                })?)
+            }
+            _ => None,
+        };
+        let serial_attachment = if cfg.console {
+            FileHandleSerialAttachment::new(
+                std::io::stdin().as_raw_fd(),
+                std::io::stdout().as_raw_fd(),
+            )
+        } else if let Some(pipe) = &console_log {
+            FileHandleSerialAttachment::new_write_only(pipe.as_raw_fd())
+        } else {
+            FileHandleSerialAttachment::new_write_only(std::io::stderr().as_raw_fd())
+        };
+        let serial = VirtioConsoleSerialPort::new_with_attachment(&serial_attachment);
+        config.set_serial_ports(&[serial]);
+
+        let disk_attachment = DiskImageAttachment::new_with_options(
+            &cfg.rootfs,
+            false,
+            DiskImageCachingMode::Cached,
+            DiskImageSynchronizationMode::Fsync,
+        )
+        .map_err(|e| SandboxError::boot_failed("Failed to create disk attachment", e.into()))?;
+        let mut block_devices = vec![VirtioBlockDevice::new(&disk_attachment)];
+        for disk in &cfg.disks {
+            let attachment = DiskImageAttachment::new_with_options(
+                &disk.path,
+                disk.read_only,
+                match disk.caching {
+                    DiskCaching::Automatic => DiskImageCachingMode::Automatic,
+                    DiskCaching::Cached => DiskImageCachingMode::Cached,
+                    DiskCaching::Uncached => DiskImageCachingMode::Uncached,
+                },
+                match disk.sync {
+                    DiskSync::Full => DiskImageSynchronizationMode::Full,
+                    DiskSync::Fsync => DiskImageSynchronizationMode::Fsync,
+                    DiskSync::None => DiskImageSynchronizationMode::None,
+                },
+            )
+            .map_err(|e| {
+                SandboxError::boot_failed(format!("Failed to attach disk {}", disk.path), e.into())
+            })?;
+            block_devices.push(VirtioBlockDevice::new(&attachment));
+        }
+        let storage: Vec<&dyn StorageDevice> = block_devices
+            .iter()
+            .map(|d| d as &dyn StorageDevice)
+            .collect();
+        config.set_storage_devices(&storage);
+
+        let mut net = None;
+        if let Some(policy) = &cfg.egress {
+            let (vm_end, stack_end) = shuru_net::datagram_pair().map_err(|e| {
+                SandboxError::boot_failed("Failed to create network socket", e.into())
+            })?;
+            let net_attachment = FileHandleNetworkAttachment::new(vm_end.as_raw_fd());
+            let net_device = VirtioNetworkDevice::new_with_attachment(&net_attachment);
+            net_device.set_mac_address(&MACAddress::random_local());
+            config.set_network_devices(&[net_device]);
+            let handle =
+                net::start(stack_end, policy, cfg.console_log.as_deref()).map_err(|e| {
+                    SandboxError::boot_failed("Failed to start network stack", e.into())
+                })?;
+            net = Some((vm_end, handle));
+        } else if cfg.allow_net {
+            let net_attachment = NATNetworkAttachment::new();
+            let net_device = VirtioNetworkDevice::new_with_attachment(&net_attachment);
+            net_device.set_mac_address(&MACAddress::random_local());
+            config.set_network_devices(&[net_device]);
+        }
+
+        // Set up directory sharing devices (virtio-fs)
+        let fs_devices: Vec<VirtioFileSystemDevice> = cfg
+            .shares
+            .iter()
+            .map(|share| {
+                let shared_dir = SharedDirectory::new(&share.host_path, share.read_only);
+                VirtioFileSystemDevice::new(&share.tag, &shared_dir)
+            })
+            .collect();
+        if !fs_devices.is_empty() {
+            config.set_directory_sharing_devices(&fs_devices);
+        }
+
+        let socket_device = VirtioSocketDevice::new();
+        config.set_socket_devices(&[socket_device]);
+
+        config.set_entropy_devices(&[VirtioEntropyDevice::new()]);
+        config.set_memory_balloon_devices(&[VirtioMemoryBalloonDevice::new()]);
+
+        config
+            .validate()
+            .map_err(|e| SandboxError::boot_failed("VM configuration invalid", e.into()))?;
+
+        let vm = VirtualMachine::new(&config);
+
+        // Translate Virtualization.framework states on a relay thread; it
+        // exits once the VM (and with it the source channel) is dropped.
+        let source = vm.state_channel();
+        let (tx, states) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            for state in source {
+                if tx.send(convert_state(state)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(DarwinBackend {
+            vm,
+            states,
+            _console_log: console_log,
+            _net: net,
+        })
+    }
+
+    fn start(&self) -> Result<()> {
+        self.vm
+            .start()
+            .map_err(|e| SandboxError::boot_failed("Failed to start VM", e.into()))
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.vm.stop().map_err(|e| SandboxError::Backend {
+            reason: "Failed to stop VM".into(),
+            source: e.into(),
+        })
+    }
+
+    fn state_channel(&self) -> Receiver<VmState> {
+        self.states.clone()
+    }
+
+    fn connect(&self, port: u32) -> Result<GuestStream> {
+        self.vm
+            .connect_to_vsock_port(port)
+            .map(GuestStream::from)
+            .map_err(|e| {
+                SandboxError::unreachable(format!("vsock connect to port {}", port), e.into())
+            })
+    }
+}
+
+fn convert_state(state: shuru_darwin::VmState) -> VmState {
+    match state {
+        shuru_darwin::VmState::Stopped => VmState::Stopped,
+        shuru_darwin::VmState::Running => VmState::Running,
+        shuru_darwin::VmState::Paused => VmState::Paused,
+        shuru_darwin::VmState::Error => VmState::Error,
+        shuru_darwin::VmState::Starting => VmState::Starting,
+        shuru_darwin::VmState::Pausing => VmState::Pausing,
+        shuru_darwin::VmState::Resuming => VmState::Resuming,
+        shuru_darwin::VmState::Stopping => VmState::Stopping,
+        shuru_darwin::VmState::Unknown => VmState::Unknown,
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::io;
+
+/// Boxed error used as the source of a [`SandboxError`].
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors returned by [`Sandbox`](crate::Sandbox) and the types built on it.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SandboxError {
+    /// The host, backend or sandbox kind cannot do what was asked.
+    Unsupported(String),
+    /// The configuration was rejected before anything was booted.
+    InvalidConfig(String),
+    /// The VM could not be created or started, or stopped before its guest
+    /// agent came up.
+    BootFailed {
+        reason: String,
+        source: Option<BoxError>,
+    },
+    /// The guest agent could not be reached.
+    GuestUnreachable {
+        reason: String,
+        source: Option<BoxError>,
+    },
+    MountFailed {
+        tag: String,
+        guest_path: String,
+        reason: String,
+    },
+    /// The guest agent is older than the host side and does not understand
+    /// a request, or sent something the host does not understand.
+    ProtocolMismatch {
+        reason: String,
+        source: Option<BoxError>,
+    },
+    PortBindFailed {
+        port: u16,
+        source: io::Error,
+    },
+    /// The guest could not start a command. `not_found` is set when the
+    /// program does not exist, as opposed to e.g. not being executable.
+    ExecSpawnFailed {
+        program: String,
+        reason: String,
+        not_found: bool,
+    },
+    /// The guest agent reported an error, e.g. a missing file.
+    Guest(String),
+    /// The guest agent closed the connection before the operation finished.
+    Disconnected(String),
+    /// A [`SandboxPool`](crate::SandboxPool) could not provide a VM.
+    PoolUnavailable(String),
+    /// The backend failed outside of booting, e.g. while stopping.
+    Backend {
+        reason: String,
+        source: BoxError,
+    },
+    /// An I/O error on the host or on a guest connection.
+    Io(io::Error),
+}
+
+impl SandboxError {
+    /// Keep a `SandboxError` carried by `err` as is; wrap any other error
+    /// with `wrap`.
+    pub(crate) fn from_anyhow(err: anyhow::Error, wrap: impl FnOnce(BoxError) -> Self) -> Self {
+        match err.downcast::<SandboxError>() {
+            Ok(err) => err,
+            Err(err) => wrap(err.into()),
+        }
+    }
+
+    pub(crate) fn boot_failed(reason: impl Into<String>, source: BoxError) -> Self {
+        SandboxError::BootFailed {
+            reason: reason.into(),
+            source: Some(source),
+        }
+    }
+
+    pub(crate) fn unreachable(reason: impl Into<String>, source: BoxError) -> Self {
+        SandboxError::GuestUnreachable {
+            reason: reason.into(),
+            source: Some(source),
+        }
+    }
+
+    /// A guest too old for `feature`.
+    pub(crate) fn outdated_guest(feature: &str) -> Self {
+        SandboxError::ProtocolMismatch {
+            reason: format!(
+                "guest does not support {}. \
+                 Run `shuru upgrade` and recreate the checkpoint to use it.",
+                feature
+            ),
+            source: None,
+        }
+    }
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::Unsupported(msg)
+            | SandboxError::InvalidConfig(msg)
+            | SandboxError::Guest(msg)
+            | SandboxError::Disconnected(msg)
+            | SandboxError::PoolUnavailable(msg) => write!(f, "{}", msg),
+            SandboxError::BootFailed { reason, .. }
+            | SandboxError::GuestUnreachable { reason, .. }
+            | SandboxError::ProtocolMismatch { reason, .. }
+            | SandboxError::Backend { reason, .. } => write!(f, "{}", reason),
+            SandboxError::MountFailed {
+                tag,
+                guest_path,
+                reason,
+            } => write!(f, "mount failed: {} -> {}: {}", tag, guest_path, reason),
+            SandboxError::PortBindFailed { port, .. } => {
+                write!(f, "Failed to bind port {}", port)
+            }
+            SandboxError::ExecSpawnFailed {
+                program, reason, ..
+            } => write!(f, "{}: {}", program, reason),
+            SandboxError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SandboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SandboxError::BootFailed { source, .. }
+            | SandboxError::GuestUnreachable { source, .. }
+            | SandboxError::ProtocolMismatch { source, .. } => source
+                .as_deref()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            SandboxError::Backend { source, .. } => Some(source.as_ref()),
+            SandboxError::PortBindFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SandboxError {
+    fn from(err: io::Error) -> Self {
+        SandboxError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SandboxError {
+    fn from(err: serde_json::Error) -> Self {
+        SandboxError::ProtocolMismatch {
+            reason: "unexpected message from guest".into(),
+            source: Some(Box::new(err)),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SandboxError>;
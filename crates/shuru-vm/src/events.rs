@@ -0,0 +1,295 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use serde::Serialize;
+
+use crate::backend::VmState;
+
+/// How often the state relay checks whether its sandbox is gone.
+const RELAY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A lifecycle milestone of a [`Sandbox`](crate::Sandbox), from
+/// [`Sandbox::events`](crate::Sandbox::events).
+///
+/// Serializes as a JSON object whose `event` field names the variant, e.g.
+/// `{"event":"mount_completed","tag":"mount0","guest_path":"/data"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SandboxEvent {
+    VmStarting,
+    VmRunning,
+    /// The guest agent accepted its first connection.
+    AgentReady,
+    MountCompleted {
+        tag: String,
+        guest_path: String,
+    },
+    MountFailed {
+        tag: String,
+        guest_path: String,
+        error: String,
+    },
+    /// `id` pairs the command with its [`ExecExited`](Self::ExecExited).
+    ExecStarted {
+        id: u64,
+        argv: Vec<String>,
+        tty: bool,
+    },
+    /// `code` is `None` if the connection to the guest ended before the
+    /// command exited.
+    ExecExited {
+        id: u64,
+        code: Option<i32>,
+    },
+    ForwardOpened {
+        id: u64,
+        host_port: u16,
+        guest_port: u16,
+    },
+    ForwardClosed {
+        id: u64,
+        host_port: u16,
+        guest_port: u16,
+    },
+    VmStopped {
+        reason: StopReason,
+    },
+}
+
+/// Why a VM stopped, in [`SandboxEvent::VmStopped`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// [`Sandbox::stop`](crate::Sandbox::stop) was called.
+    Requested,
+    /// The guest powered off by itself, e.g. after its agent exited.
+    GuestExited,
+    /// The backend reported an error.
+    Error,
+}
+
+// --- Event hub ---
+
+/// Fans a sandbox's backend states and lifecycle events out to subscribers.
+///
+/// The backend's state channel is consumed by a single relay thread, since
+/// its receivers share one queue; [`Sandbox::state_channel`] subscribers
+/// get their own copy of each state from here instead.
+///
+/// [`Sandbox::state_channel`]: crate::Sandbox::state_channel
+pub(crate) struct EventHub {
+    states: Mutex<Vec<Sender<VmState>>>,
+    events: Mutex<Vec<Sender<SandboxEvent>>>,
+    lifecycle: Mutex<Lifecycle>,
+    next_id: AtomicU64,
+}
+
+/// Milestones already reported since the last start, so each is emitted
+/// once even when several sources observe it.
+struct Lifecycle {
+    state: VmState,
+    running: bool,
+    agent_ready: bool,
+    stop_requested: bool,
+    stopped: bool,
+}
+
+impl EventHub {
+    /// Create a hub and start relaying `source` into it. The relay thread
+    /// exits once the hub is dropped.
+    pub(crate) fn new(source: Receiver<VmState>) -> Arc<Self> {
+        let hub = Arc::new(EventHub {
+            states: Mutex::new(Vec::new()),
+            events: Mutex::new(Vec::new()),
+            lifecycle: Mutex::new(Lifecycle {
+                state: VmState::Unknown,
+                running: false,
+                agent_ready: false,
+                stop_requested: false,
+                stopped: false,
+            }),
+            next_id: AtomicU64::new(1),
+        });
+        let weak = Arc::downgrade(&hub);
+        std::thread::spawn(move || relay_states(source, weak));
+        hub
+    }
+
+    pub(crate) fn subscribe(&self) -> Receiver<SandboxEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.events.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub(crate) fn subscribe_states(&self) -> Receiver<VmState> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.states.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// The last state reported by the backend.
+    pub(crate) fn state(&self) -> VmState {
+        self.lifecycle.lock().unwrap().state
+    }
+
+    pub(crate) fn emit(&self, event: SandboxEvent) {
+        self.events
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn starting(&self) {
+        *self.lifecycle.lock().unwrap() = Lifecycle {
+            state: VmState::Starting,
+            running: false,
+            agent_ready: false,
+            stop_requested: false,
+            stopped: false,
+        };
+        self.emit(SandboxEvent::VmStarting);
+    }
+
+    pub(crate) fn stop_requested(&self) {
+        self.lifecycle.lock().unwrap().stop_requested = true;
+    }
+
+    pub(crate) fn agent_ready(&self) {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        // A connection proves the VM is running even if the backend's
+        // state has not been relayed yet.
+        if !lifecycle.running {
+            lifecycle.running = true;
+            self.emit(SandboxEvent::VmRunning);
+        }
+        if !lifecycle.agent_ready {
+            lifecycle.agent_ready = true;
+            self.emit(SandboxEvent::AgentReady);
+        }
+    }
+
+    pub(crate) fn stopped(&self, reason: StopReason) {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        if !lifecycle.stopped {
+            lifecycle.stopped = true;
+            self.emit(SandboxEvent::VmStopped { reason });
+        }
+    }
+
+    fn on_state(&self, state: VmState) {
+        self.states
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(state).is_ok());
+
+        let stop_requested = {
+            let mut lifecycle = self.lifecycle.lock().unwrap();
+            lifecycle.state = state;
+            if state == VmState::Running && !lifecycle.running {
+                lifecycle.running = true;
+                self.emit(SandboxEvent::VmRunning);
+            }
+            lifecycle.stop_requested
+        };
+        match state {
+            VmState::Stopped if stop_requested => self.stopped(StopReason::Requested),
+            VmState::Stopped => self.stopped(StopReason::GuestExited),
+            VmState::Error => self.stopped(StopReason::Error),
+            _ => {}
+        }
+    }
+}
+
+fn relay_states(source: Receiver<VmState>, hub: Weak<EventHub>) {
+    loop {
+        match source.recv_timeout(RELAY_POLL_INTERVAL) {
+            Ok(state) => match hub.upgrade() {
+                Some(hub) => hub.on_state(state),
+                None => return,
+            },
+            Err(RecvTimeoutError::Timeout) if hub.strong_count() > 0 => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// --- Spans ---
+
+/// Reports [`SandboxEvent::ExecStarted`] on creation and
+/// [`SandboxEvent::ExecExited`] when dropped.
+pub(crate) struct ExecSpan {
+    hub: Arc<EventHub>,
+    id: u64,
+    code: Option<i32>,
+}
+
+impl ExecSpan {
+    pub(crate) fn start(hub: &Arc<EventHub>, argv: &[String], tty: bool) -> Self {
+        let id = hub.next_id();
+        hub.emit(SandboxEvent::ExecStarted {
+            id,
+            argv: argv.to_vec(),
+            tty,
+        });
+        ExecSpan {
+            hub: Arc::clone(hub),
+            id,
+            code: None,
+        }
+    }
+
+    pub(crate) fn exited(&mut self, code: i32) {
+        self.code = Some(code);
+    }
+}
+
+impl Drop for ExecSpan {
+    fn drop(&mut self) {
+        self.hub.emit(SandboxEvent::ExecExited {
+            id: self.id,
+            code: self.code,
+        });
+    }
+}
+
+/// Reports [`SandboxEvent::ForwardOpened`] on creation and
+/// [`SandboxEvent::ForwardClosed`] when dropped.
+pub(crate) struct ForwardSpan {
+    hub: Arc<EventHub>,
+    id: u64,
+    host_port: u16,
+    guest_port: u16,
+}
+
+impl ForwardSpan {
+    pub(crate) fn open(hub: &Arc<EventHub>, host_port: u16, guest_port: u16) -> Self {
+        let id = hub.next_id();
+        hub.emit(SandboxEvent::ForwardOpened {
+            id,
+            host_port,
+            guest_port,
+        });
+        ForwardSpan {
+            hub: Arc::clone(hub),
+            id,
+            host_port,
+            guest_port,
+        }
+    }
+}
+
+impl Drop for ForwardSpan {
+    fn drop(&mut self) {
+        self.hub.emit(SandboxEvent::ForwardClosed {
+            id: self.id,
+            host_port: self.host_port,
+            guest_port: self.guest_port,
+        });
+    }
+}
@@ -1,21 +1,70 @@
 #![forbid(unsafe_code)]
 
+mod agent;
+#[cfg(feature = "async")]
+mod async_sandbox;
+mod backend;
+mod command;
+mod console_log;
+#[cfg(target_os = "macos")]
+mod darwin;
+mod error;
+mod events;
+mod net;
+mod pool;
 mod proto;
+mod qemu;
 mod sandbox;
+mod tunnel;
+
+pub use agent::{AgentAddr, AgentBackend, AGENT_ADDR_ENV};
+#[cfg(feature = "async")]
+pub use async_sandbox::{AsyncPortForwardHandle, AsyncSandbox, ExecEvent, ExecOutput, ExecStream};
+pub use backend::{
+    Backend, DiskCaching, DiskConfig, DiskSync, GuestStream, SharedDirConfig, VmConfig, VmState,
+};
+pub use command::{
+    Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Output, Stdio,
+};
+pub use console_log::{LOG_SOURCE_CONSOLE, LOG_SOURCE_GUEST, LOG_SOURCE_NET};
+#[cfg(target_os = "macos")]
+pub use darwin::DarwinBackend;
+pub use error::{BoxError, Result, SandboxError};
+pub use events::{SandboxEvent, StopReason};
+pub use pool::{Lease, PoolBuilder, PoolStatus, SandboxPool};
+pub use qemu::QemuBackend;
 
 pub use proto::{
-    ControlMessage, ExecRequest, ExecResponse, ForwardRequest, ForwardResponse, MountRequest,
-    MountResponse, PortMapping,
+    ControlMessage, ExecRequest, ExecResponse, FileOp, FileRequest, FileResponse, ForwardRequest,
+    ForwardResponse, LogRecord, MountRequest, MountResponse, PortMapping, ReverseMapping,
+    TunnelKind, TunnelListener, TunnelMessage, TunnelRequest,
 };
-pub use sandbox::{MountConfig, PortForwardHandle, Sandbox, VmConfigBuilder};
+pub use sandbox::{MountConfig, PortForwardHandle, ReverseForwardHandle, Sandbox, VmConfigBuilder};
+pub use shuru_net::{Cidr, EgressPolicy, PolicyError};
 
 // Re-exports from shuru-darwin for advanced/escape-hatch use
+#[cfg(target_os = "macos")]
 pub use shuru_darwin::VirtualMachine;
-pub use shuru_darwin::VmState;
+#[cfg(target_os = "macos")]
 pub use shuru_darwin::VzError;
 
+/// Backend used by [`VmConfigBuilder::build`] on this platform.
+#[cfg(target_os = "macos")]
+pub type DefaultBackend = DarwinBackend;
+#[cfg(not(target_os = "macos"))]
+pub type DefaultBackend = QemuBackend;
+
 pub const VSOCK_PORT: u32 = 1024;
 pub const VSOCK_PORT_FORWARD: u32 = 1025;
+/// Guest port streaming the agent's own log records (see [`LogRecord`]).
+pub const VSOCK_PORT_LOG: u32 = 1026;
+/// Guest port for connections from guest listeners to the host (see
+/// [`TunnelRequest`]).
+pub const VSOCK_PORT_TUNNEL: u32 = 1027;
+
+/// Kernel argument selecting full-OS boot: the initramfs hands off to the
+/// rootfs's `/sbin/init`, which starts `shuru-guest` as a service.
+pub const INIT_SYSTEM_ARG: &str = "shuru.init=system";
 
 pub fn default_data_dir() -> String {
     let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use shuru_net::{
+    EgressPolicy, EgressProxy, FrameLink, NetConfig, NetHandle, NetStack, ProxyRequest,
+};
+
+use crate::backend::Backend;
+use crate::console_log::{now_ms, ConsoleLog, LOG_SOURCE_NET};
+use crate::error::Result;
+use crate::proto::{TunnelKind, TunnelListener};
+use crate::tunnel::{self, TunnelService, TunnelSession};
+
+/// Guest address of the HTTP proxy in proxy mode.
+pub(crate) const PROXY_HTTP_ADDR: &str = "127.0.0.1:3128";
+/// Guest address of the DNS resolver in proxy mode.
+pub(crate) const PROXY_DNS_ADDR: &str = "127.0.0.1:53";
+
+/// Serve a VM's network device on `link` with a userspace stack that
+/// enforces `policy`. Denied traffic is written to the console log, if
+/// there is one.
+pub(crate) fn start(
+    link: impl FrameLink,
+    policy: &EgressPolicy,
+    console_log: Option<&str>,
+) -> std::io::Result<NetHandle> {
+    let log = Mutex::new(console_log.and_then(|path| ConsoleLog::open(path).ok()));
+    let stack = NetStack::new(NetConfig::default(), policy.clone()).on_denied(move |denial| {
+        if let Some(log) = log.lock().unwrap().as_mut() {
+            log.write_line(now_ms(), LOG_SOURCE_NET, &format!("denied {}", denial));
+        }
+    });
+    shuru_net::run(link, stack)
+}
+
+/// Serve an HTTP proxy and a DNS resolver enforcing `policy` inside the
+/// guest, and point its commands and resolver at them. Every request is
+/// appended to `egress_log` as a JSON line; denied ones also go to the
+/// console log.
+pub(crate) fn start_proxy(
+    backend: Arc<dyn Backend>,
+    policy: &EgressPolicy,
+    egress_log: Option<&str>,
+    console_log: Option<&str>,
+) -> Result<TunnelSession> {
+    let egress_log = Mutex::new(egress_log.and_then(|path| {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| tracing::warn!("cannot open egress log {}: {}", path, e))
+            .ok()
+    }));
+    let console_log = Mutex::new(console_log.and_then(|path| ConsoleLog::open(path).ok()));
+    let proxy = Arc::new(EgressProxy::new(policy.clone()).on_request(move |request| {
+        if let Some(file) = egress_log.lock().unwrap().as_mut() {
+            if let Ok(line) = serde_json::to_string(&EgressRecord::new(request)) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+        if !request.allowed {
+            if let Some(log) = console_log.lock().unwrap().as_mut() {
+                let target = match request.port {
+                    Some(port) => format!("{}:{}", request.host, port),
+                    None => request.host.clone(),
+                };
+                let line = format!("denied {} {}", request.kind, target);
+                log.write_line(now_ms(), LOG_SOURCE_NET, &line);
+            }
+        }
+    }));
+
+    let http: TunnelService = {
+        let proxy = Arc::clone(&proxy);
+        Arc::new(move |stream| {
+            if let Err(e) = proxy.serve_http(stream) {
+                tracing::debug!("proxy connection failed: {}", e);
+            }
+        })
+    };
+    let dns: TunnelService = Arc::new(move |stream| {
+        if let Err(e) = proxy.serve_dns(stream) {
+            tracing::debug!("proxy DNS connection failed: {}", e);
+        }
+    });
+    let listeners = vec![
+        (
+            TunnelListener {
+                addr: PROXY_HTTP_ADDR.into(),
+                kind: TunnelKind::Stream,
+            },
+            http,
+        ),
+        (
+            TunnelListener {
+                addr: PROXY_DNS_ADDR.into(),
+                kind: TunnelKind::Dns,
+            },
+            dns,
+        ),
+    ];
+
+    let proxy_url = format!("http://{}", PROXY_HTTP_ADDR);
+    let mut env = HashMap::new();
+    for name in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+        env.insert(name.to_string(), proxy_url.clone());
+    }
+    for name in ["NO_PROXY", "no_proxy"] {
+        env.insert(name.to_string(), "localhost,127.0.0.1".to_string());
+    }
+    tunnel::open_session(backend, listeners, env, true, "network proxy mode")
+}
+
+/// One line of the egress log.
+#[derive(Serialize)]
+struct EgressRecord<'a> {
+    /// Host time, in milliseconds since the Unix epoch.
+    ts: u64,
+    kind: String,
+    host: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<&'a str>,
+    allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl<'a> EgressRecord<'a> {
+    fn new(request: &'a ProxyRequest) -> Self {
+        EgressRecord {
+            ts: now_ms(),
+            kind: request.kind.to_string(),
+            host: &request.host,
+            port: request.port,
+            method: request.method.as_deref(),
+            allowed: request.allowed,
+            error: request.error.as_deref(),
+        }
+    }
+}
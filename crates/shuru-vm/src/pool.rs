@@ -0,0 +1,701 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Deref;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use tracing::info;
+
+use crate::backend::{Backend, GuestStream, VmConfig, VmState};
+use crate::error::{Result, SandboxError};
+use crate::sandbox::{relay, Sandbox, VmConfigBuilder};
+use crate::{DefaultBackend, VSOCK_PORT};
+
+// --- PoolBuilder ---
+
+/// Configures a [`SandboxPool`]. Created by [`SandboxPool::builder`].
+pub struct PoolBuilder {
+    template: VmConfigBuilder,
+    work_dir: Option<PathBuf>,
+    disk_size_mb: Option<u64>,
+    min_idle: usize,
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl PoolBuilder {
+    /// Directory for the VMs' working copies of the rootfs
+    /// (default: `<data dir>/instances/pool-<pid>`, cleaned up by
+    /// `shuru prune`).
+    pub fn work_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.work_dir = Some(dir.into());
+        self
+    }
+
+    /// Extend each working copy to this size.
+    pub fn disk_size_mb(mut self, mb: u64) -> Self {
+        self.disk_size_mb = Some(mb);
+        self
+    }
+
+    /// Booted VMs to keep ready for leasing (default: 1).
+    pub fn min_idle(mut self, n: usize) -> Self {
+        self.min_idle = n;
+        self
+    }
+
+    /// Upper bound on VMs booting, ready and leased together (default: 4).
+    pub fn max_size(mut self, n: usize) -> Self {
+        self.max_size = n;
+        self
+    }
+
+    /// Stop the ready VMs once nothing has been leased for this long; the
+    /// pool warms up again on the next lease. By default ready VMs are kept
+    /// indefinitely.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Create the pool on this platform's default backend and start
+    /// warming VMs.
+    pub fn build(self) -> Result<SandboxPool> {
+        self.build_with::<DefaultBackend>()
+    }
+
+    /// Create the pool on a specific backend.
+    pub fn build_with<B: Backend>(self) -> Result<SandboxPool> {
+        if self.max_size == 0 {
+            return Err(SandboxError::InvalidConfig(
+                "pool max size must be at least 1".into(),
+            ));
+        }
+        if self.min_idle > self.max_size {
+            return Err(SandboxError::InvalidConfig(format!(
+                "pool min idle ({}) exceeds max size ({})",
+                self.min_idle, self.max_size
+            )));
+        }
+        let source = PathBuf::from(self.template.config()?.rootfs);
+        if !source.exists() {
+            return Err(SandboxError::InvalidConfig(format!(
+                "rootfs not found at {}",
+                source.display()
+            )));
+        }
+        let work_dir = self.work_dir.unwrap_or_else(|| {
+            PathBuf::from(format!(
+                "{}/instances/pool-{}",
+                crate::default_data_dir(),
+                std::process::id()
+            ))
+        });
+        std::fs::create_dir_all(&work_dir)?;
+
+        let inner = Arc::new(PoolInner {
+            template: self.template,
+            build: |builder| builder.build_with::<B>(),
+            source,
+            work_dir,
+            disk_size_mb: self.disk_size_mb,
+            min_idle: self.min_idle,
+            max_size: self.max_size,
+            idle_timeout: self.idle_timeout,
+            next_id: AtomicU64::new(0),
+            state: Mutex::new(PoolState {
+                idle: VecDeque::new(),
+                booting: 0,
+                leased: 0,
+                waiting: 0,
+                closed: false,
+                last_lease: Instant::now(),
+                failures: 0,
+                last_error: None,
+            }),
+            changed: Condvar::new(),
+        });
+        inner.refill(&mut inner.lock());
+        let maintainer = Arc::clone(&inner);
+        std::thread::spawn(move || maintainer.maintain());
+        Ok(SandboxPool { inner })
+    }
+}
+
+// --- SandboxPool ---
+
+/// A set of booted, ready VMs for fast task startup.
+///
+/// Every VM boots from its own copy of the template's rootfs (a base image
+/// or a checkpoint) and counts as ready once its guest agent answers, with
+/// any mounts applied. [`lease`](Self::lease) hands one out; when the
+/// [`Lease`] is dropped the VM is stopped and discarded, never reused, and
+/// the pool boots a replacement in the background.
+pub struct SandboxPool {
+    inner: Arc<PoolInner>,
+}
+
+/// Snapshot of a pool's VMs, from [`SandboxPool::status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub idle: usize,
+    pub booting: usize,
+    pub leased: usize,
+}
+
+impl SandboxPool {
+    /// Start configuring a pool. The template's rootfs is the source image
+    /// copied for every VM; set `console(false)` for headless use.
+    pub fn builder(template: VmConfigBuilder) -> PoolBuilder {
+        PoolBuilder {
+            template,
+            work_dir: None,
+            disk_size_mb: None,
+            min_idle: 1,
+            max_size: 4,
+            idle_timeout: None,
+        }
+    }
+
+    /// Take a ready VM, waiting for one to boot if none is idle.
+    pub fn lease(&self) -> Result<Lease> {
+        self.inner.lease(None)
+    }
+
+    /// Like [`lease`](Self::lease), but give up after `timeout`.
+    pub fn lease_timeout(&self, timeout: Duration) -> Result<Lease> {
+        self.inner.lease(Some(timeout))
+    }
+
+    pub fn status(&self) -> PoolStatus {
+        let state = self.inner.lock();
+        PoolStatus {
+            idle: state.idle.len(),
+            booting: state.booting,
+            leased: state.leased,
+        }
+    }
+
+    /// Serve leases on a Unix socket until accepting fails. Each connection
+    /// leases a VM and is relayed to its guest agent, so clients speak the
+    /// exec protocol as if connected to the VM directly; the VM is
+    /// discarded when the client disconnects. See [`SandboxPool::client`].
+    pub fn serve(&self, listener: UnixListener) -> Result<()> {
+        for conn in listener.incoming() {
+            let conn = conn?;
+            let inner = Arc::clone(&self.inner);
+            std::thread::spawn(move || inner.serve_connection(conn));
+        }
+        Ok(())
+    }
+
+    /// A sandbox whose `exec` and `shell` calls each run in a fresh VM
+    /// leased from the pool serving `socket`. Port forwarding and mounts
+    /// are not available through it.
+    pub fn client(socket: impl Into<PathBuf>) -> Sandbox {
+        let config = VmConfig {
+            kernel: String::new(),
+            initrd: None,
+            rootfs: String::new(),
+            cmdline: String::new(),
+            cpus: 0,
+            memory_mb: 0,
+            console: false,
+            console_log: None,
+            allow_net: false,
+            egress: None,
+            proxy: None,
+            egress_log: None,
+            shares: Vec::new(),
+            disks: Vec::new(),
+        };
+        Sandbox::from_backend(PoolBackend::new(socket.into()), &config)
+    }
+
+    /// Stop the idle VMs and refuse further leases. Leased VMs are
+    /// discarded as usual when their lease is dropped.
+    pub fn shutdown(&self) {
+        let idle: Vec<Warm> = {
+            let mut state = self.inner.lock();
+            state.closed = true;
+            state.idle.drain(..).collect()
+        };
+        self.inner.changed.notify_all();
+        for warm in idle {
+            warm.discard();
+        }
+        let _ = std::fs::remove_dir(&self.inner.work_dir);
+    }
+}
+
+impl Drop for SandboxPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// --- Lease ---
+
+/// A VM leased from a [`SandboxPool`]; dereferences to its [`Sandbox`].
+/// Dropping the lease stops and discards the VM.
+pub struct Lease {
+    warm: Option<Warm>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for Lease {
+    type Target = Sandbox;
+
+    fn deref(&self) -> &Sandbox {
+        &self.warm.as_ref().unwrap().sandbox
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let Some(warm) = self.warm.take() else {
+            return;
+        };
+        let pool = Arc::clone(&self.pool);
+        std::thread::spawn(move || {
+            warm.discard();
+            let mut state = pool.lock();
+            state.leased -= 1;
+            pool.refill(&mut state);
+            pool.changed.notify_all();
+        });
+    }
+}
+
+// --- Pool internals ---
+
+struct PoolInner {
+    template: VmConfigBuilder,
+    build: fn(VmConfigBuilder) -> Result<Sandbox>,
+    source: PathBuf,
+    work_dir: PathBuf,
+    disk_size_mb: Option<u64>,
+    min_idle: usize,
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+    next_id: AtomicU64,
+    state: Mutex<PoolState>,
+    /// Signalled whenever a VM becomes ready, fails or goes away.
+    changed: Condvar,
+}
+
+struct PoolState {
+    idle: VecDeque<Warm>,
+    booting: usize,
+    leased: usize,
+    /// Callers blocked in `lease`.
+    waiting: usize,
+    closed: bool,
+    last_lease: Instant,
+    failures: u64,
+    last_error: Option<String>,
+}
+
+/// A booted VM and the directory holding its rootfs copy.
+struct Warm {
+    sandbox: Sandbox,
+    dir: PathBuf,
+}
+
+impl Warm {
+    fn discard(self) {
+        let _ = self.sandbox.stop();
+        drop(self.sandbox);
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl PoolInner {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap()
+    }
+
+    fn lease(self: &Arc<Self>, timeout: Option<Duration>) -> Result<Lease> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = self.lock();
+        state.last_lease = Instant::now();
+        state.waiting += 1;
+        let failures = state.failures;
+
+        let warm = loop {
+            if state.closed {
+                break Err(SandboxError::PoolUnavailable(
+                    "sandbox pool is shut down".into(),
+                ));
+            }
+            if let Some(warm) = state.idle.pop_front() {
+                break Ok(warm);
+            }
+            // Fail rather than wait forever if every boot since we started
+            // waiting has failed.
+            if state.failures != failures && state.booting == 0 {
+                break Err(SandboxError::PoolUnavailable(format!(
+                    "pooled VM failed to boot: {}",
+                    state.last_error.as_deref().unwrap_or("unknown error")
+                )));
+            }
+            self.refill(&mut state);
+            state = match deadline {
+                None => self.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break Err(SandboxError::PoolUnavailable(
+                            "timed out waiting for a pooled VM".into(),
+                        ));
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        };
+        state.waiting -= 1;
+        let warm = warm?;
+        state.leased += 1;
+        self.refill(&mut state);
+
+        Ok(Lease {
+            warm: Some(warm),
+            pool: Arc::clone(self),
+        })
+    }
+
+    /// Boot VMs until enough are ready or booting for the waiting callers
+    /// plus `min_idle`, within `max_size`. A pool idle past its timeout
+    /// only boots for waiting callers.
+    fn refill(self: &Arc<Self>, state: &mut PoolState) {
+        if state.closed {
+            return;
+        }
+        let cold = self
+            .idle_timeout
+            .is_some_and(|t| state.last_lease.elapsed() >= t);
+        let wanted = state.waiting + if cold { 0 } else { self.min_idle };
+        let have = state.idle.len() + state.booting;
+        let room = self.max_size.saturating_sub(have + state.leased);
+        for _ in 0..wanted.saturating_sub(have).min(room) {
+            state.booting += 1;
+            let pool = Arc::clone(self);
+            std::thread::spawn(move || pool.boot());
+        }
+    }
+
+    fn boot(self: Arc<Self>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let dir = self.work_dir.join(format!("vm-{}", id));
+        let started = Instant::now();
+        let result = self.start_vm(&dir);
+
+        let mut state = self.lock();
+        state.booting -= 1;
+        match result {
+            Ok(sandbox) if !state.closed => {
+                info!("shuru: pool: vm-{} ready in {:?}", id, started.elapsed());
+                state.idle.push_back(Warm { sandbox, dir });
+            }
+            Ok(sandbox) => {
+                drop(state);
+                Warm { sandbox, dir }.discard();
+                return;
+            }
+            Err(e) => {
+                let e = anyhow::Error::new(e);
+                info!("shuru: pool: vm-{} failed to boot: {:#}", id, e);
+                state.failures += 1;
+                state.last_error = Some(format!("{:#}", e));
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+        }
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    fn start_vm(&self, dir: &Path) -> Result<Sandbox> {
+        std::fs::create_dir_all(dir)?;
+        let rootfs = dir.join("rootfs.ext4");
+        std::fs::copy(&self.source, &rootfs)?;
+        if let Some(mb) = self.disk_size_mb {
+            let f = std::fs::OpenOptions::new().write(true).open(&rootfs)?;
+            f.set_len(mb * 1024 * 1024)?;
+        }
+
+        let sandbox = (self.build)(
+            self.template
+                .clone()
+                .rootfs(rootfs.to_string_lossy().into_owned()),
+        )?;
+        sandbox.start()?;
+        // Ready once the agent answers; this also applies the mounts.
+        if let Err(e) = sandbox.connect_agent() {
+            let _ = sandbox.stop();
+            return Err(e);
+        }
+        Ok(sandbox)
+    }
+
+    /// Expire idle VMs and keep the pool topped up until it is closed.
+    fn maintain(self: Arc<Self>) {
+        let mut state = self.lock();
+        while !state.closed {
+            let expired = self
+                .idle_timeout
+                .is_some_and(|t| state.waiting == 0 && state.last_lease.elapsed() >= t);
+            if expired && !state.idle.is_empty() {
+                let idle: Vec<Warm> = state.idle.drain(..).collect();
+                info!("shuru: pool: idle timeout, stopping {} VM(s)", idle.len());
+                std::thread::spawn(move || idle.into_iter().for_each(Warm::discard));
+            }
+            self.refill(&mut state);
+            state = self
+                .changed
+                .wait_timeout(state, Duration::from_secs(1))
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn serve_connection(self: Arc<Self>, conn: UnixStream) {
+        let mut client = GuestStream::from(conn);
+        let agent = self
+            .lease(None)
+            .and_then(|lease| Ok((lease.connect_agent()?, lease)));
+        match agent {
+            Ok((agent, _lease)) => relay(client, agent),
+            Err(e) => {
+                let resp = serde_json::json!({
+                    "type": "error",
+                    "data": format!("sandbox pool: {:#}", e),
+                });
+                let _ = writeln!(client, "{}", resp);
+            }
+        }
+    }
+}
+
+// --- PoolBackend ---
+
+/// Backend behind [`SandboxPool::client`]: connects exec sessions to a
+/// pool's socket instead of a VM.
+struct PoolBackend {
+    socket: PathBuf,
+    states_tx: Sender<VmState>,
+    states: Receiver<VmState>,
+}
+
+impl PoolBackend {
+    fn new(socket: PathBuf) -> Self {
+        let (states_tx, states) = crossbeam_channel::unbounded();
+        PoolBackend {
+            socket,
+            states_tx,
+            states,
+        }
+    }
+}
+
+impl Backend for PoolBackend {
+    fn configure(_config: &VmConfig) -> Result<Self> {
+        Err(SandboxError::Unsupported(
+            "pool clients are created with SandboxPool::client".into(),
+        ))
+    }
+
+    fn start(&self) -> Result<()> {
+        let _ = self.states_tx.send(VmState::Running);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        let _ = self.states_tx.send(VmState::Stopped);
+        Ok(())
+    }
+
+    fn state_channel(&self) -> Receiver<VmState> {
+        self.states.clone()
+    }
+
+    fn connect(&self, port: u32) -> Result<GuestStream> {
+        if port != VSOCK_PORT {
+            return Err(SandboxError::Unsupported(
+                "port forwarding is not available through a sandbox pool".into(),
+            ));
+        }
+        let stream = UnixStream::connect(&self.socket).map_err(|e| {
+            SandboxError::unreachable(
+                format!("connect to sandbox pool at {}", self.socket.display()),
+                e.into(),
+            )
+        })?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A VM that boots at once, or fails to if its rootfs says `fail`.
+    struct FakeVm {
+        fail: bool,
+        states: (Sender<VmState>, Receiver<VmState>),
+    }
+
+    impl Backend for FakeVm {
+        fn configure(config: &VmConfig) -> Result<Self> {
+            Ok(FakeVm {
+                fail: std::fs::read(&config.rootfs)? == b"fail",
+                states: crossbeam_channel::unbounded(),
+            })
+        }
+
+        fn start(&self) -> Result<()> {
+            if self.fail {
+                return Err(SandboxError::BootFailed {
+                    reason: "fake boot failure".into(),
+                    source: None,
+                });
+            }
+            let _ = self.states.0.send(VmState::Running);
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<()> {
+            let _ = self.states.0.send(VmState::Stopped);
+            Ok(())
+        }
+
+        fn state_channel(&self) -> Receiver<VmState> {
+            self.states.1.clone()
+        }
+
+        fn connect(&self, _port: u32) -> Result<GuestStream> {
+            Ok(UnixStream::pair()?.0.into())
+        }
+    }
+
+    /// A pool builder over a fresh source rootfs holding `rootfs`, and its
+    /// work directory.
+    fn builder(name: &str, rootfs: &[u8]) -> (PoolBuilder, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("shuru-pool-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.ext4");
+        std::fs::write(&source, rootfs).unwrap();
+        let template = Sandbox::builder()
+            .kernel("unused")
+            .rootfs(source.to_string_lossy().into_owned())
+            .console(false);
+        let work_dir = dir.join("work");
+        (SandboxPool::builder(template).work_dir(&work_dir), work_dir)
+    }
+
+    fn wait_for(pool: &SandboxPool, done: impl Fn(PoolStatus) -> bool) -> PoolStatus {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let status = pool.status();
+            if done(status) {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "pool stuck at {:?}", status);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// VM directories in the work directory.
+    fn vm_dirs(work_dir: &Path) -> usize {
+        std::fs::read_dir(work_dir).map_or(0, |entries| entries.count())
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        let (empty, _) = builder("sizes", b"ok");
+        assert!(empty.max_size(0).build_with::<FakeVm>().is_err());
+        let (crowded, _) = builder("sizes", b"ok");
+        let err = crowded.min_idle(3).max_size(2).build_with::<FakeVm>();
+        assert!(matches!(err, Err(SandboxError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn leases_within_min_idle_and_max_size() {
+        let (builder, work_dir) = builder("sizes-ok", b"ok");
+        let pool = builder
+            .min_idle(2)
+            .max_size(3)
+            .build_with::<FakeVm>()
+            .unwrap();
+        let status = |idle, booting, leased| PoolStatus {
+            idle,
+            booting,
+            leased,
+        };
+        wait_for(&pool, |s| s == status(2, 0, 0));
+
+        // Each lease is replaced while there is room
+        let a = pool.lease().unwrap();
+        wait_for(&pool, |s| s == status(2, 0, 1));
+        let b = pool.lease().unwrap();
+        assert_eq!(pool.status(), status(1, 0, 2));
+        let c = pool.lease().unwrap();
+        assert_eq!(pool.status(), status(0, 0, 3));
+        match pool.lease_timeout(Duration::from_millis(50)) {
+            Err(SandboxError::PoolUnavailable(msg)) => assert!(msg.contains("timed out")),
+            other => panic!("expected a timeout, got {:?}", other.err()),
+        }
+
+        // A dropped lease is discarded, not reused, and replaced
+        drop(a);
+        wait_for(&pool, |s| s == status(1, 0, 2));
+        assert_eq!(vm_dirs(&work_dir), 3);
+        drop((b, c));
+        wait_for(&pool, |s| s == status(2, 0, 0));
+        assert_eq!(vm_dirs(&work_dir), 2);
+
+        pool.shutdown();
+        assert_eq!(pool.status(), status(0, 0, 0));
+        assert!(!work_dir.exists());
+        match pool.lease() {
+            Err(SandboxError::PoolUnavailable(msg)) => assert!(msg.contains("shut down")),
+            other => panic!("expected a closed pool, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn idle_timeout_stops_ready_vms() {
+        let (builder, work_dir) = builder("idle", b"ok");
+        let pool = builder
+            .idle_timeout(Duration::from_millis(100))
+            .build_with::<FakeVm>()
+            .unwrap();
+        wait_for(&pool, |s| s.idle == 1);
+        wait_for(&pool, |s| s.idle == 0 && s.booting == 0);
+        wait_for(&pool, |_| vm_dirs(&work_dir) == 0);
+
+        // The next lease warms the pool again
+        let lease = pool.lease().unwrap();
+        assert_eq!(pool.status().leased, 1);
+        drop(lease);
+    }
+
+    #[test]
+    fn lease_fails_when_every_boot_fails() {
+        let (builder, work_dir) = builder("fail", b"fail");
+        let pool = builder.min_idle(0).build_with::<FakeVm>().unwrap();
+        match pool.lease() {
+            Err(SandboxError::PoolUnavailable(msg)) => {
+                assert!(msg.contains("failed to boot"), "{}", msg);
+                assert!(msg.contains("fake boot failure"), "{}", msg);
+            }
+            other => panic!("expected a boot failure, got {:?}", other.err()),
+        }
+        // The failed VM's copy is removed
+        assert_eq!(vm_dirs(&work_dir), 0);
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 pub struct ExecRequest {
     pub argv: Vec<String>,
     pub env: HashMap<String, String>,
@@ -11,6 +11,18 @@ pub struct ExecRequest {
     pub rows: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cols: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Pipe the command's stdin from `Stdin`/`Eof` control messages.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stdin: bool,
+    /// Piped mode: the guest replies `started` (with the pid) once the
+    /// command is spawned and reads control messages until it exits.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub control: bool,
+    /// stdout, stderr and stdin data is base64 rather than text.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub binary: bool,
 }
 
 #[derive(Deserialize)]
@@ -19,9 +31,15 @@ pub struct ExecResponse {
     pub msg_type: String,
     pub data: Option<String>,
     pub code: Option<i32>,
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Set on `exit` when the command was killed by a signal.
+    #[serde(default)]
+    pub signal: Option<i32>,
 }
 
-/// Host-to-guest control messages sent after the initial ExecRequest (TTY mode only).
+/// Host-to-guest control messages sent after the initial ExecRequest (TTY
+/// mode, or piped mode with `control`).
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ControlMessage {
@@ -29,6 +47,11 @@ pub enum ControlMessage {
     Stdin { data: String },
     #[serde(rename = "resize")]
     Resize { rows: u16, cols: u16 },
+    /// Close the command's stdin (piped mode).
+    #[serde(rename = "eof")]
+    Eof,
+    #[serde(rename = "signal")]
+    Signal { signal: i32 },
 }
 
 // --- Port forwarding protocol ---
@@ -40,6 +63,16 @@ pub struct PortMapping {
     pub guest_port: u16,
 }
 
+/// A guest port whose connections are forwarded to a host address, the
+/// reverse of a [`PortMapping`].
+#[derive(Debug, Clone)]
+pub struct ReverseMapping {
+    /// Host address to connect to, `HOST:PORT`.
+    pub host_addr: String,
+    /// Port the guest listens on, on 127.0.0.1.
+    pub guest_port: u16,
+}
+
 /// Sent by the host over vsock to request forwarding to a guest port.
 #[derive(Serialize, Deserialize)]
 pub struct ForwardRequest {
@@ -56,13 +89,19 @@ pub struct ForwardResponse {
 
 // --- Mount protocol ---
 
-/// Sent by the host over vsock to instruct the guest to mount a virtiofs device.
+/// Sent by the host over vsock to instruct the guest to mount a virtiofs
+/// device, or with `device`, an ext4 block device.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MountRequest {
     pub tag: String,
     pub guest_path: String,
+    /// Writable mount; for virtiofs, without a tmpfs overlay.
     #[serde(default)]
     pub persistent: bool,
+    /// Block device to mount instead of the virtiofs share `tag`. The
+    /// guest formats it as ext4 if it is blank.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
 }
 
 /// Sent by the guest in response to a MountRequest.
@@ -73,3 +112,105 @@ pub struct MountResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
 }
+
+// --- File transfer protocol ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileOp {
+    Read,
+    Write,
+}
+
+/// Sent by the host over vsock to read or write a guest file. A write
+/// request is followed by exactly `size` raw bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRequest {
+    pub op: FileOp,
+    pub path: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+/// Sent by the guest in response to a FileRequest. A successful read
+/// response is followed by exactly `size` raw bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// --- Guest log protocol ---
+
+/// One of the guest agent's log records, sent as a JSON line on vsock port
+/// 1026. On connect the guest first replays the records it has buffered
+/// since boot, then streams new ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Guest wall-clock time, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts_ms: Option<u64>,
+    pub level: String,
+    pub message: String,
+}
+
+// --- Tunnel protocol ---
+
+/// What a tunnel listener accepts in the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelKind {
+    /// TCP connections, carried as they are.
+    Stream,
+    /// UDP DNS queries, carried framed as DNS over TCP.
+    Dns,
+}
+
+/// A guest address whose clients are carried to the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelListener {
+    /// Guest address to listen on, e.g. `127.0.0.1:3128`.
+    pub addr: String,
+    pub kind: TunnelKind,
+}
+
+/// First line of a connection to guest port 1027. `Listen` opens a session
+/// that lasts as long as its connection; `Accept` takes one client of a
+/// session, announced by [`TunnelMessage::Open`], onto its connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TunnelRequest {
+    Listen {
+        listeners: Vec<TunnelListener>,
+        /// Environment added to commands while the session is open.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        env: HashMap<String, String>,
+        /// Point the guest's resolver at the session's DNS listener.
+        #[serde(default)]
+        set_resolver: bool,
+    },
+    Accept {
+        id: u64,
+    },
+}
+
+/// Guest messages on a tunnel session's connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TunnelMessage {
+    /// All listeners are bound.
+    Ready,
+    Error {
+        message: String,
+    },
+    /// A client of `listeners[listener]` is waiting as `id`.
+    Open {
+        id: u64,
+        listener: usize,
+    },
+}
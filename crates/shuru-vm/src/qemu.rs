@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use shuru_net::{EgressPolicy, NetHandle};
+
+use crate::backend::{Backend, DiskCaching, DiskSync, GuestStream, VmConfig, VmState};
+use crate::console_log::{ConsoleLog, LOG_SOURCE_CONSOLE};
+use crate::error::SandboxError;
+use crate::net;
+
+/// QEMU binary; the guest images are aarch64 on every host.
+const QEMU_BINARY: &str = "qemu-system-aarch64";
+const QEMU_ENV: &str = "SHURU_QEMU";
+const VIRTIOFSD_ENV: &str = "SHURU_VIRTIOFSD";
+
+/// Name of the virtio-serial port that carries the agent protocol. The
+/// guest agent looks it up in /sys/class/virtio-ports.
+const AGENT_PORT_NAME: &str = "org.shuru.agent";
+
+/// How long QEMU and virtiofsd get to create or connect their sockets.
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `connect` waits for the guest agent. Booting under TCG
+/// emulation is much slower than with KVM.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// QEMU backend for Linux hosts (KVM when /dev/kvm is usable, TCG
+/// otherwise).
+///
+/// There is no vsock between host and guest; instead a single
+/// virtio-serial port carries multiplexed streams, one per `connect`, and
+/// the guest agent demultiplexes them onto its exec and forward handlers.
+pub struct QemuBackend {
+    qemu: String,
+    args: Vec<String>,
+    console: bool,
+    console_log: Option<String>,
+    egress: Option<EgressPolicy>,
+    /// Stack serving the network device under an egress policy.
+    net: Mutex<Option<NetHandle>>,
+    /// virtiofsd invocations, one per shared directory.
+    virtiofsd: Vec<(String, Vec<String>, PathBuf)>,
+    runtime_dir: PathBuf,
+    child: Arc<Mutex<Option<Child>>>,
+    helpers: Mutex<Vec<Child>>,
+    mux: Mutex<Option<Arc<Mux>>>,
+    stopping: Arc<AtomicBool>,
+    states_tx: Sender<VmState>,
+    states: Receiver<VmState>,
+}
+
+impl QemuBackend {
+    fn spawn_virtiofsd(&self) -> Result<()> {
+        for (program, args, socket) in &self.virtiofsd {
+            let child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .spawn()
+                .with_context(|| format!("failed to launch {}", program))?;
+            self.helpers.lock().unwrap().push(child);
+
+            let deadline = Instant::now() + LAUNCH_TIMEOUT;
+            while !socket.exists() {
+                if Instant::now() > deadline {
+                    bail!("virtiofsd did not create {}", socket.display());
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for QEMU to connect to one of its sockets (`what`), failing
+    /// early if it exits (bad arguments, missing firmware, ...).
+    fn accept(&self, listener: &UnixListener, what: &str) -> Result<UnixStream> {
+        listener.set_nonblocking(true)?;
+        let deadline = Instant::now() + LAUNCH_TIMEOUT;
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(stream);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).with_context(|| format!("{} socket accept", what)),
+            }
+            if let Some(child) = self.child.lock().unwrap().as_mut() {
+                if let Some(status) = child.try_wait()? {
+                    bail!("{} exited during startup ({})", self.qemu, status);
+                }
+            }
+            if Instant::now() > deadline {
+                bail!("{} did not connect to the {} socket", self.qemu, what);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn kill_all(&self) {
+        if let Some(child) = self.child.lock().unwrap().as_mut() {
+            let _ = child.kill();
+        }
+        for helper in self.helpers.lock().unwrap().iter_mut() {
+            let _ = helper.kill();
+            let _ = helper.wait();
+        }
+    }
+
+    /// Build the QEMU command line and virtiofsd invocations for `cfg`.
+    fn create(cfg: &VmConfig) -> Result<Self> {
+        let qemu = std::env::var(QEMU_ENV).unwrap_or_else(|_| QEMU_BINARY.to_string());
+        let runtime_dir = runtime_dir()?;
+        let agent_socket = runtime_dir.join("agent.sock");
+
+        let kvm = std::env::consts::ARCH == "aarch64"
+            && std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/kvm")
+                .is_ok();
+
+        let mut args: Vec<String> = vec![
+            "-nodefaults".into(),
+            "-no-user-config".into(),
+            "-display".into(),
+            "none".into(),
+            "-machine".into(),
+            "virt,gic-version=max".into(),
+            "-accel".into(),
+            if kvm { "kvm" } else { "tcg" }.into(),
+            "-cpu".into(),
+            if kvm { "host" } else { "max" }.into(),
+            "-smp".into(),
+            cfg.cpus.to_string(),
+            "-m".into(),
+            format!("{}M", cfg.memory_mb),
+            "-kernel".into(),
+            cfg.kernel.clone(),
+            "-append".into(),
+            cfg.cmdline.clone(),
+        ];
+        if let Some(ref initrd) = cfg.initrd {
+            args.extend(["-initrd".into(), initrd.clone()]);
+        }
+
+        args.extend([
+            "-drive".into(),
+            format!(
+                "file={},if=none,id=root,format=raw,cache=writeback",
+                escape_opt(&cfg.rootfs)
+            ),
+            "-device".into(),
+            "virtio-blk-pci,drive=root".into(),
+        ]);
+        // Extra disks right after the rootfs, so the guest probes them in
+        // order as vdb, vdc, ...
+        for (i, disk) in cfg.disks.iter().enumerate() {
+            let mut drive = format!(
+                "file={},if=none,id=disk{},format=raw",
+                escape_opt(&disk.path),
+                i
+            );
+            if disk.read_only {
+                drive.push_str(",readonly=on");
+            }
+            drive.push_str(match disk.caching {
+                DiskCaching::Uncached => ",cache.direct=on",
+                DiskCaching::Automatic | DiskCaching::Cached => ",cache.direct=off",
+            });
+            if disk.sync == DiskSync::None {
+                drive.push_str(",cache.no-flush=on");
+            }
+            args.extend([
+                "-drive".into(),
+                drive,
+                "-device".into(),
+                format!("virtio-blk-pci,drive=disk{}", i),
+            ]);
+        }
+
+        // hvc0 (the console) and the agent port share one virtio-serial bus.
+        args.extend([
+            "-device".into(),
+            "virtio-serial-pci".into(),
+            "-chardev".into(),
+            "stdio,id=console".into(),
+            "-device".into(),
+            "virtconsole,chardev=console".into(),
+            "-chardev".into(),
+            format!(
+                "socket,id=agent,path={}",
+                escape_opt(&agent_socket.to_string_lossy())
+            ),
+            "-device".into(),
+            format!("virtserialport,chardev=agent,name={}", AGENT_PORT_NAME),
+        ]);
+
+        // With an egress policy QEMU sends the guest's frames to our stack
+        // over a stream socket instead of its own user-mode NAT.
+        if cfg.egress.is_some() {
+            args.extend([
+                "-netdev".into(),
+                format!(
+                    "stream,id=net0,server=off,addr.type=unix,addr.path={}",
+                    escape_opt(&runtime_dir.join("net.sock").to_string_lossy())
+                ),
+                "-device".into(),
+                "virtio-net-pci,netdev=net0".into(),
+            ]);
+        } else if cfg.allow_net {
+            args.extend([
+                "-netdev".into(),
+                "user,id=net0".into(),
+                "-device".into(),
+                "virtio-net-pci,netdev=net0".into(),
+            ]);
+        }
+
+        args.extend(["-device".into(), "virtio-rng-pci".into()]);
+
+        // virtio-fs needs an external virtiofsd per share and guest memory
+        // that the daemons can map.
+        let mut virtiofsd = Vec::new();
+        if !cfg.shares.is_empty() {
+            let program = find_virtiofsd().context(
+                "directory mounts on Linux need virtiofsd (install it or set SHURU_VIRTIOFSD)",
+            )?;
+            args.extend([
+                "-object".into(),
+                format!(
+                    "memory-backend-memfd,id=mem,size={}M,share=on",
+                    cfg.memory_mb
+                ),
+                "-numa".into(),
+                "node,memdev=mem".into(),
+            ]);
+            for share in &cfg.shares {
+                let socket = runtime_dir.join(format!("{}.sock", share.tag));
+                let mut fs_args = vec![
+                    format!("--socket-path={}", socket.display()),
+                    format!("--shared-dir={}", share.host_path),
+                    "--cache=auto".to_string(),
+                    "--sandbox=none".to_string(),
+                ];
+                if share.read_only {
+                    fs_args.push("--readonly".to_string());
+                }
+                args.extend([
+                    "-chardev".into(),
+                    format!(
+                        "socket,id={},path={}",
+                        share.tag,
+                        escape_opt(&socket.to_string_lossy())
+                    ),
+                    "-device".into(),
+                    format!("vhost-user-fs-pci,chardev={},tag={}", share.tag, share.tag),
+                ]);
+                virtiofsd.push((program.clone(), fs_args, socket));
+            }
+        }
+
+        let (states_tx, states) = crossbeam_channel::unbounded();
+        Ok(QemuBackend {
+            qemu,
+            args,
+            console: cfg.console,
+            console_log: cfg.console_log.clone(),
+            egress: cfg.egress.clone(),
+            net: Mutex::new(None),
+            virtiofsd,
+            runtime_dir,
+            child: Arc::new(Mutex::new(None)),
+            helpers: Mutex::new(Vec::new()),
+            mux: Mutex::new(None),
+            stopping: Arc::new(AtomicBool::new(false)),
+            states_tx,
+            states,
+        })
+    }
+
+    /// Launch virtiofsd and QEMU, then take over the agent link.
+    fn launch(&self) -> Result<()> {
+        let _ = self.states_tx.send(VmState::Starting);
+        std::fs::create_dir_all(&self.runtime_dir)?;
+        self.spawn_virtiofsd()?;
+
+        let agent_socket = self.runtime_dir.join("agent.sock");
+        let _ = std::fs::remove_file(&agent_socket);
+        let listener = UnixListener::bind(&agent_socket)
+            .with_context(|| format!("failed to bind {}", agent_socket.display()))?;
+        let net_listener = if self.egress.is_some() {
+            let net_socket = self.runtime_dir.join("net.sock");
+            let _ = std::fs::remove_file(&net_socket);
+            Some(
+                UnixListener::bind(&net_socket)
+                    .with_context(|| format!("failed to bind {}", net_socket.display()))?,
+            )
+        } else {
+            None
+        };
+
+        // Without --console the guest console goes to the console log or
+        // stderr, like on macOS.
+        let (stdin, stdout) = match &self.console_log {
+            _ if self.console => (Stdio::inherit(), Stdio::inherit()),
+            Some(path) => (
+                Stdio::null(),
+                Stdio::from(ConsoleLog::capture(path, LOG_SOURCE_CONSOLE)?),
+            ),
+            None => (Stdio::null(), Stdio::from(std::io::stderr())),
+        };
+        let child = Command::new(&self.qemu)
+            .args(&self.args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .spawn()
+            .with_context(|| format!("failed to launch {} (is QEMU installed?)", self.qemu))?;
+        *self.child.lock().unwrap() = Some(child);
+
+        let link = match self.accept(&listener, "agent").and_then(|link| {
+            if let (Some(listener), Some(policy)) = (&net_listener, &self.egress) {
+                let net_link = self.accept(listener, "network")?;
+                let handle = net::start(net_link, policy, self.console_log.as_deref())?;
+                *self.net.lock().unwrap() = Some(handle);
+            }
+            Ok(link)
+        }) {
+            Ok(link) => link,
+            Err(e) => {
+                self.kill_all();
+                let _ = self.states_tx.send(VmState::Error);
+                return Err(e);
+            }
+        };
+        let mux = Mux::new(link)?;
+        *self.mux.lock().unwrap() = Some(Arc::clone(&mux));
+        let _ = self.states_tx.send(VmState::Running);
+
+        // Report the exit of the QEMU process. The guest powers off when
+        // its agent exits, which ends QEMU with status 0.
+        let child = Arc::clone(&self.child);
+        let stopping = Arc::clone(&self.stopping);
+        let tx = self.states_tx.clone();
+        std::thread::spawn(move || loop {
+            let status = match child.lock().unwrap().as_mut() {
+                Some(c) => c.try_wait(),
+                None => return,
+            };
+            match status {
+                Ok(None) => std::thread::sleep(Duration::from_millis(100)),
+                Ok(Some(status)) => {
+                    mux.shutdown();
+                    let state = if status.success() || stopping.load(Ordering::Relaxed) {
+                        VmState::Stopped
+                    } else {
+                        VmState::Error
+                    };
+                    let _ = tx.send(state);
+                    return;
+                }
+                Err(_) => {
+                    mux.shutdown();
+                    let _ = tx.send(VmState::Error);
+                    return;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl Backend for QemuBackend {
+    fn configure(cfg: &VmConfig) -> crate::Result<Self> {
+        QemuBackend::create(cfg).map_err(|e| {
+            SandboxError::from_anyhow(e, |e| SandboxError::boot_failed("Failed to create VM", e))
+        })
+    }
+
+    fn start(&self) -> crate::Result<()> {
+        self.launch().map_err(|e| {
+            SandboxError::from_anyhow(e, |e| SandboxError::boot_failed("Failed to start VM", e))
+        })
+    }
+
+    fn stop(&self) -> crate::Result<()> {
+        self.stopping.store(true, Ordering::Relaxed);
+        let _ = self.states_tx.send(VmState::Stopping);
+        self.kill_all();
+        // Port forward threads may keep the backend alive past this point.
+        let _ = std::fs::remove_dir_all(&self.runtime_dir);
+        Ok(())
+    }
+
+    fn state_channel(&self) -> Receiver<VmState> {
+        self.states.clone()
+    }
+
+    fn connect(&self, port: u32) -> crate::Result<GuestStream> {
+        let unreachable = |reason: &str| SandboxError::GuestUnreachable {
+            reason: reason.into(),
+            source: None,
+        };
+        let mux = self
+            .mux
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| unreachable("VM is not running"))?;
+        if !mux.wait_ready(AGENT_TIMEOUT) {
+            return Err(unreachable(
+                "guest agent did not come up on the serial transport",
+            ));
+        }
+        let stream = mux.open(port).map_err(|e| {
+            SandboxError::unreachable(format!("serial connect to port {}", port), e.into())
+        })?;
+        Ok(stream.into())
+    }
+}
+
+impl Drop for QemuBackend {
+    fn drop(&mut self) {
+        self.kill_all();
+        if let Some(child) = self.child.lock().unwrap().as_mut() {
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.runtime_dir);
+    }
+}
+
+fn runtime_dir() -> Result<PathBuf> {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "shuru-qemu-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// QEMU option values use `,` as a separator; a literal comma is doubled.
+fn escape_opt(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+fn find_virtiofsd() -> Option<String> {
+    if let Ok(path) = std::env::var(VIRTIOFSD_ENV) {
+        return Some(path);
+    }
+    let path_dirs = std::env::var_os("PATH")
+        .map(|p| std::env::split_paths(&p).collect::<Vec<_>>())
+        .unwrap_or_default();
+    path_dirs
+        .into_iter()
+        .chain(["/usr/libexec".into(), "/usr/lib/qemu".into()])
+        .map(|dir: PathBuf| dir.join("virtiofsd"))
+        .find(|p| Path::new(p).is_file())
+        .map(|p| p.to_string_lossy().into_owned())
+}
+
+// --- Serial multiplexer ---
+//
+// Frames on the virtio-serial port are `[u32 stream][u8 op][u32 len][payload]`
+// (big endian). The host opens streams; the guest announces itself with
+// HELLO once its agent is listening. Keep in sync with shuru-guest.
+
+const FRAME_HELLO: u8 = 0;
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_EOF: u8 = 3;
+const FRAME_CLOSE: u8 = 4;
+const MAX_FRAME: usize = 64 * 1024;
+/// Frames queued per stream before the link reader waits for it.
+const STREAM_BACKLOG: usize = 64;
+
+/// Data for a stream's local end; `None` marks EOF from the guest.
+type Channel = Sender<Option<Vec<u8>>>;
+
+#[derive(Default)]
+struct LinkState {
+    ready: bool,
+    closed: bool,
+}
+
+struct Mux {
+    link: Mutex<UnixStream>,
+    channels: Mutex<HashMap<u32, Channel>>,
+    next_id: AtomicU32,
+    state: Mutex<LinkState>,
+    state_cv: Condvar,
+}
+
+impl Mux {
+    fn new(link: UnixStream) -> Result<Arc<Self>> {
+        let reader = link.try_clone()?;
+        let mux = Arc::new(Mux {
+            link: Mutex::new(link),
+            channels: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            state: Mutex::new(LinkState::default()),
+            state_cv: Condvar::new(),
+        });
+        let m = Arc::clone(&mux);
+        std::thread::spawn(move || m.read_loop(reader));
+        Ok(mux)
+    }
+
+    fn send(&self, id: u32, op: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut header = [0u8; 9];
+        header[..4].copy_from_slice(&id.to_be_bytes());
+        header[4] = op;
+        header[5..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+        let mut link = self.link.lock().unwrap();
+        link.write_all(&header)?;
+        link.write_all(payload)
+    }
+
+    /// Block until the guest has said HELLO, the link closed, or `timeout`.
+    fn wait_ready(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .state_cv
+            .wait_timeout_while(state, timeout, |s| !s.ready && !s.closed)
+            .unwrap();
+        state.ready && !state.closed
+    }
+
+    fn open(self: &Arc<Self>, port: u32) -> std::io::Result<UnixStream> {
+        let (local, remote) = UnixStream::pair()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.attach(id, local)?;
+        if let Err(e) = self.send(id, FRAME_OPEN, &port.to_be_bytes()) {
+            self.channels.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        Ok(remote)
+    }
+
+    /// Pump `local` to and from stream `id` on two threads.
+    fn attach(self: &Arc<Self>, id: u32, local: UnixStream) -> std::io::Result<()> {
+        let (tx, rx) = crossbeam_channel::bounded::<Option<Vec<u8>>>(STREAM_BACKLOG);
+        // Incremented once per direction reaching EOF.
+        let done = Arc::new(AtomicU8::new(0));
+        let mut reader = local.try_clone()?;
+        let mut writer = local;
+        self.channels.lock().unwrap().insert(id, tx);
+
+        let mux = Arc::clone(self);
+        let writer_done = Arc::clone(&done);
+        std::thread::spawn(move || {
+            for msg in rx {
+                match msg {
+                    Some(data) => {
+                        if writer.write_all(&data).is_err() {
+                            mux.close(id);
+                            return;
+                        }
+                    }
+                    None => {
+                        let _ = writer.shutdown(Shutdown::Write);
+                        mux.finish(id, &writer_done);
+                    }
+                }
+            }
+            // Channel dropped: closed by the guest or the link went away.
+            let _ = writer.shutdown(Shutdown::Both);
+        });
+
+        let mux = Arc::clone(self);
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_FRAME];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => {
+                        let _ = mux.send(id, FRAME_EOF, &[]);
+                        mux.finish(id, &done);
+                        return;
+                    }
+                    Ok(n) => {
+                        if mux.send(id, FRAME_DATA, &buf[..n]).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => {
+                        mux.close(id);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Forget a stream once both directions reached EOF.
+    fn finish(&self, id: u32, done: &AtomicU8) {
+        if done.fetch_add(1, Ordering::AcqRel) == 1 {
+            self.channels.lock().unwrap().remove(&id);
+        }
+    }
+
+    fn close(&self, id: u32) {
+        if self.channels.lock().unwrap().remove(&id).is_some() {
+            let _ = self.send(id, FRAME_CLOSE, &[]);
+        }
+    }
+
+    /// Mark the link dead and drop every stream so local ends see EOF.
+    fn shutdown(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.state_cv.notify_all();
+        self.channels.lock().unwrap().clear();
+        let _ = self.link.lock().unwrap().shutdown(Shutdown::Both);
+    }
+
+    fn read_loop(self: Arc<Self>, mut link: UnixStream) {
+        let mut header = [0u8; 9];
+        while link.read_exact(&mut header).is_ok() {
+            let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+            let len = u32::from_be_bytes(header[5..].try_into().unwrap()) as usize;
+            if len > MAX_FRAME {
+                tracing::warn!("serial transport: oversized frame ({} bytes)", len);
+                break;
+            }
+            let mut payload = vec![0u8; len];
+            if link.read_exact(&mut payload).is_err() {
+                break;
+            }
+            match header[4] {
+                FRAME_HELLO => {
+                    self.state.lock().unwrap().ready = true;
+                    self.state_cv.notify_all();
+                }
+                FRAME_DATA => self.deliver(id, Some(payload)),
+                FRAME_EOF => self.deliver(id, None),
+                FRAME_CLOSE => {
+                    self.channels.lock().unwrap().remove(&id);
+                }
+                op => tracing::debug!("serial transport: unknown op {}", op),
+            }
+        }
+        self.shutdown();
+    }
+
+    fn deliver(&self, id: u32, msg: Option<Vec<u8>>) {
+        // Send outside the lock: a full stream blocks until its writer
+        // catches up, and the writer may need the lock to close.
+        let channel = self.channels.lock().unwrap().get(&id).cloned();
+        if let Some(channel) = channel {
+            let _ = channel.send(msg);
+        }
+    }
+}
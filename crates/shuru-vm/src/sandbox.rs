@@ -4,20 +4,29 @@ use std::net::{Shutdown, TcpListener, TcpStream};
 use std::os::fd::AsRawFd;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::info;
 
-use anyhow::{bail, Context, Result};
 use crossbeam_channel::Receiver;
 
 use shuru_darwin::terminal;
-use shuru_darwin::*;
+use shuru_net::EgressPolicy;
 
+use crate::backend::{Backend, DiskConfig, GuestStream, SharedDirConfig, VmConfig, VmState};
+use crate::command::Command;
+use crate::console_log::ConsoleLog;
+use crate::error::{Result, SandboxError};
+use crate::events::{EventHub, ExecSpan, ForwardSpan, SandboxEvent, StopReason};
 use crate::proto::{
-    ControlMessage, ExecRequest, ExecResponse, ForwardRequest, ForwardResponse, MountRequest,
-    MountResponse, PortMapping,
+    ControlMessage, ExecRequest, ExecResponse, FileOp, FileRequest, FileResponse, ForwardRequest,
+    ForwardResponse, MountRequest, MountResponse, PortMapping, ReverseMapping, TunnelKind,
+    TunnelListener,
 };
-use crate::{VSOCK_PORT, VSOCK_PORT_FORWARD};
+use crate::tunnel::{self, TunnelService, TunnelSession};
+use crate::{DefaultBackend, INIT_SYSTEM_ARG, VSOCK_PORT, VSOCK_PORT_FORWARD, VSOCK_PORT_LOG};
+
+/// Delay between attempts to reach a booting guest's agent.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
 // --- Mount types ---
 
@@ -30,16 +39,25 @@ pub struct MountConfig {
 
 // --- VmConfigBuilder ---
 
+#[derive(Clone)]
 pub struct VmConfigBuilder {
     kernel: Option<String>,
     rootfs: Option<String>,
     initrd: Option<String>,
+    cmdline: Option<String>,
+    kernel_args: Vec<String>,
     cpus: usize,
     memory_mb: u64,
     console: bool,
+    console_log: Option<String>,
     quiet: bool,
     allow_net: bool,
+    egress: Option<EgressPolicy>,
+    proxy: Option<EgressPolicy>,
+    egress_log: Option<String>,
+    init_system: bool,
     mounts: Vec<MountConfig>,
+    disks: Vec<DiskConfig>,
 }
 
 impl VmConfigBuilder {
@@ -48,12 +66,20 @@ impl VmConfigBuilder {
             kernel: None,
             rootfs: None,
             initrd: None,
+            cmdline: None,
+            kernel_args: Vec::new(),
             cpus: 2,
             memory_mb: 2048,
             console: true,
+            console_log: None,
             quiet: false,
             allow_net: false,
+            egress: None,
+            proxy: None,
+            egress_log: None,
+            init_system: false,
             mounts: Vec::new(),
+            disks: Vec::new(),
         }
     }
 
@@ -70,6 +96,18 @@ impl VmConfigBuilder {
         self
     }
 
+    /// Write the serial console and the guest agent's log records to
+    /// `path` instead of stderr, one line each: `<unix ms> <source> <text>`
+    /// with source [`LOG_SOURCE_CONSOLE`](crate::LOG_SOURCE_CONSOLE),
+    /// [`LOG_SOURCE_GUEST`](crate::LOG_SOURCE_GUEST) or, with an
+    /// [`egress`](Self::egress) policy,
+    /// [`LOG_SOURCE_NET`](crate::LOG_SOURCE_NET). The file is truncated
+    /// when the sandbox is built. Ignored for the console in console mode.
+    pub fn console_log(mut self, path: impl Into<String>) -> Self {
+        self.console_log = Some(path.into());
+        self
+    }
+
     pub fn kernel(mut self, path: impl Into<String>) -> Self {
         self.kernel = Some(path.into());
         self
@@ -85,6 +123,32 @@ impl VmConfigBuilder {
         self
     }
 
+    /// Override the base kernel command line
+    /// (default: `console=hvc0 root=/dev/vda rw`).
+    pub fn cmdline(mut self, cmdline: impl Into<String>) -> Self {
+        self.cmdline = Some(cmdline.into());
+        self
+    }
+
+    /// Append an argument to the kernel command line, e.g.
+    /// `mitigations=off` or `systemd.unit=multi-user.target`. Appended
+    /// arguments come last, so they override earlier ones the kernel
+    /// reads only once.
+    pub fn kernel_arg(mut self, arg: impl Into<String>) -> Self {
+        self.kernel_args.push(arg.into());
+        self
+    }
+
+    /// Append several arguments; see [`kernel_arg`](Self::kernel_arg).
+    pub fn kernel_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.kernel_args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
     pub fn cpus(mut self, n: usize) -> Self {
         self.cpus = n;
         self
@@ -101,106 +165,190 @@ impl VmConfigBuilder {
         self
     }
 
-    /// Add a host directory mount (virtio-fs).
-    pub fn mount(mut self, config: MountConfig) -> Self {
-        self.mounts.push(config);
+    /// Give the guest network access limited to `policy`. Instead of NAT,
+    /// the network device is served by a userspace stack on the host that
+    /// only resolves allowed domains and only connects to allowed
+    /// destinations; denied traffic is logged, and written to the console
+    /// log as [`LOG_SOURCE_NET`](crate::LOG_SOURCE_NET). Implies
+    /// [`allow_net`](Self::allow_net).
+    pub fn egress(mut self, policy: EgressPolicy) -> Self {
+        self.egress = Some(policy);
         self
     }
 
-    pub fn build(self) -> Result<Sandbox> {
-        let kernel_path = self.kernel.context("kernel path is required")?;
-        let rootfs_path = self.rootfs.context("rootfs path is required")?;
+    /// Give the guest HTTP(S) and DNS access limited to `policy`, without
+    /// a network device. The host serves an HTTP proxy on the guest's
+    /// `127.0.0.1:3128` and a resolver on `127.0.0.1:53`, over vsock;
+    /// commands get `HTTP_PROXY`/`HTTPS_PROXY` pointing at the proxy and
+    /// the guest's `/etc/resolv.conf` points at the resolver. Only allowed
+    /// names resolve, and only allowed hosts can be reached. Starts with
+    /// the first connection to the guest.
+    pub fn network_proxy(mut self, policy: EgressPolicy) -> Self {
+        self.proxy = Some(policy);
+        self
+    }
 
-        if !VirtualMachine::supported() {
-            bail!("Virtualization is not supported on this machine");
-        }
+    /// Append every [`network_proxy`](Self::network_proxy) request to
+    /// `path` as a JSON line: `ts` (unix ms), `kind` (`dns`, `connect` or
+    /// `http`), `host`, `port`, `method`, `allowed` and, when an allowed
+    /// request failed, `error`. The file is truncated when the sandbox is
+    /// built.
+    pub fn egress_log(mut self, path: impl Into<String>) -> Self {
+        self.egress_log = Some(path.into());
+        self
+    }
 
-        let boot_loader = LinuxBootLoader::new_with_kernel(&kernel_path);
-        if let Some(ref initrd) = self.initrd {
-            boot_loader.set_initrd(initrd);
-        }
+    /// Boot the rootfs's own init system (systemd, OpenRC) and run the
+    /// guest agent as a service under it, instead of the agent replacing
+    /// init as PID 1. Passes `shuru.init=system` on the kernel command line.
+    pub fn init_system(mut self, enabled: bool) -> Self {
+        self.init_system = enabled;
+        self
+    }
 
-        let cmdline = if self.quiet {
-            "console=hvc0 root=/dev/vda rw quiet loglevel=3"
-        } else {
-            "console=hvc0 root=/dev/vda rw"
-        };
-        boot_loader.set_command_line(cmdline);
+    /// Add a host directory mount (virtio-fs).
+    pub fn mount(mut self, config: MountConfig) -> Self {
+        self.mounts.push(config);
+        self
+    }
 
-        let memory_bytes = self.memory_mb * 1024 * 1024;
-        let config = VirtualMachineConfiguration::new(&boot_loader, self.cpus, memory_bytes);
+    /// Attach an extra disk image (virtio-blk) after the rootfs.
+    pub fn disk(mut self, config: DiskConfig) -> Self {
+        self.disks.push(config);
+        self
+    }
 
-        let serial_attachment = if self.console {
-            FileHandleSerialAttachment::new(
-                std::io::stdin().as_raw_fd(),
-                std::io::stdout().as_raw_fd(),
-            )
+    /// Resolve the builder into a hypervisor-independent [`VmConfig`].
+    pub fn config(&self) -> Result<VmConfig> {
+        let kernel = self
+            .kernel
+            .clone()
+            .ok_or_else(|| SandboxError::InvalidConfig("kernel path is required".into()))?;
+        let rootfs = self
+            .rootfs
+            .clone()
+            .ok_or_else(|| SandboxError::InvalidConfig("rootfs path is required".into()))?;
+
+        let base_cmdline = self
+            .cmdline
+            .as_deref()
+            .unwrap_or("console=hvc0 root=/dev/vda rw");
+        let mut cmdline = if self.quiet {
+            format!("{} quiet loglevel=3", base_cmdline)
         } else {
-            FileHandleSerialAttachment::new_write_only(std::io::stderr().as_raw_fd())
+            base_cmdline.to_string()
         };
-        let serial = VirtioConsoleSerialPort::new_with_attachment(&serial_attachment);
-        config.set_serial_ports(&[serial]);
-
-        let disk_attachment = DiskImageAttachment::new_with_options(
-            &rootfs_path,
-            false,
-            DiskImageCachingMode::Cached,
-            DiskImageSynchronizationMode::Fsync,
-        )
-        .map_err(|e| anyhow::anyhow!("Failed to create disk attachment: {}", e))?;
-        let block_device = VirtioBlockDevice::new(&disk_attachment);
-        config.set_storage_devices(&[&block_device]);
-
-        if self.allow_net {
-            let net_attachment = NATNetworkAttachment::new();
-            let net_device = VirtioNetworkDevice::new_with_attachment(&net_attachment);
-            net_device.set_mac_address(&MACAddress::random_local());
-            config.set_network_devices(&[net_device]);
+        if self.init_system {
+            cmdline.push(' ');
+            cmdline.push_str(INIT_SYSTEM_ARG);
+        }
+        for arg in &self.kernel_args {
+            if arg.is_empty() || arg.contains(char::is_control) {
+                return Err(SandboxError::InvalidConfig(format!(
+                    "invalid kernel argument: {:?}",
+                    arg
+                )));
+            }
+            cmdline.push(' ');
+            cmdline.push_str(arg);
         }
 
-        // Set up directory sharing devices (virtio-fs) and mount metadata
-        let mut fs_devices: Vec<VirtioFileSystemDevice> = Vec::new();
-        let mut mount_requests: Vec<MountRequest> = Vec::new();
-
-        for (i, m) in self.mounts.iter().enumerate() {
-            let tag = format!("mount{}", i);
-            // Host directory is read-only unless persistent=true.
-            // If not persistent, the guest will use OverlayFS with tmpfs.
-            let shared_dir = SharedDirectory::new(&m.host_path, !m.persistent);
-            fs_devices.push(VirtioFileSystemDevice::new(&tag, &shared_dir));
-            mount_requests.push(MountRequest {
-                tag,
+        // Host directory is read-only unless persistent=true.
+        // If not persistent, the guest will use OverlayFS with tmpfs.
+        let shares = self
+            .mounts
+            .iter()
+            .enumerate()
+            .map(|(i, m)| SharedDirConfig {
+                tag: format!("mount{}", i),
+                host_path: m.host_path.clone(),
                 guest_path: m.guest_path.clone(),
-                persistent: m.persistent,
-            });
+                read_only: !m.persistent,
+            })
+            .collect();
+
+        // vdb..vdz
+        if self.disks.len() > 25 {
+            return Err(SandboxError::InvalidConfig(
+                "at most 25 extra disks can be attached".into(),
+            ));
         }
-
-        if !fs_devices.is_empty() {
-            config.set_directory_sharing_devices(&fs_devices);
+        for disk in &self.disks {
+            if let Some(path) = &disk.guest_path {
+                if !path.starts_with('/') {
+                    return Err(SandboxError::InvalidConfig(format!(
+                        "disk mount point must be absolute: '{}'",
+                        path
+                    )));
+                }
+            }
         }
 
-        let socket_device = VirtioSocketDevice::new();
-        config.set_socket_devices(&[socket_device]);
-
-        config.set_entropy_devices(&[VirtioEntropyDevice::new()]);
-        config.set_memory_balloon_devices(&[VirtioMemoryBalloonDevice::new()]);
+        Ok(VmConfig {
+            kernel,
+            initrd: self.initrd.clone(),
+            rootfs,
+            cmdline,
+            cpus: self.cpus,
+            memory_mb: self.memory_mb,
+            console: self.console,
+            console_log: self.console_log.clone(),
+            allow_net: self.allow_net || self.egress.is_some(),
+            egress: self.egress.clone(),
+            proxy: self.proxy.clone(),
+            egress_log: self.egress_log.clone(),
+            shares,
+            disks: self.disks.clone(),
+        })
+    }
 
-        config
-            .validate()
-            .map_err(|e| anyhow::anyhow!("VM configuration invalid: {}", e))?;
+    /// Build a sandbox on this platform's default backend.
+    pub fn build(self) -> Result<Sandbox> {
+        self.build_with::<DefaultBackend>()
+    }
 
-        Ok(Sandbox {
-            vm: Arc::new(VirtualMachine::new(&config)),
-            mounts: Mutex::new(mount_requests),
-        })
+    /// Build a sandbox on a specific backend.
+    pub fn build_with<B: Backend>(self) -> Result<Sandbox> {
+        let config = self.config()?;
+        if let Some(path) = &config.console_log {
+            ConsoleLog::create(path).map_err(|e| {
+                SandboxError::InvalidConfig(format!("cannot create console log {}: {}", path, e))
+            })?;
+        }
+        if let Some(path) = config
+            .egress_log
+            .as_ref()
+            .filter(|_| config.proxy.is_some())
+        {
+            std::fs::File::create(path).map_err(|e| {
+                SandboxError::InvalidConfig(format!("cannot create egress log {}: {}", path, e))
+            })?;
+        }
+        let backend = B::configure(&config)?;
+        Ok(Sandbox::from_backend(backend, &config))
     }
 }
 
 // --- Sandbox ---
 
 pub struct Sandbox {
-    vm: Arc<VirtualMachine>,
+    backend: Arc<dyn Backend>,
     mounts: Mutex<Vec<MountRequest>>,
+    events: Arc<EventHub>,
+    /// How long to keep retrying the first guest connection.
+    connect_timeout: Duration,
+    /// Where guest log records go, until they are being collected.
+    guest_log: Mutex<Option<String>>,
+    /// Proxy mode settings, until the proxy is running.
+    proxy: Mutex<Option<ProxyConfig>>,
+    /// Open tunnel sessions, e.g. proxy mode's; closed on drop.
+    tunnels: Mutex<Vec<TunnelSession>>,
+}
+
+struct ProxyConfig {
+    policy: EgressPolicy,
+    egress_log: Option<String>,
+    console_log: Option<String>,
 }
 
 impl Sandbox {
@@ -208,20 +356,72 @@ impl Sandbox {
         VmConfigBuilder::new()
     }
 
+    /// Wrap an already configured backend. `config.disks` with a mount
+    /// point and `config.shares` are mounted by the guest on the first
+    /// connection.
+    pub fn from_backend(backend: impl Backend, config: &VmConfig) -> Self {
+        let disk_mounts = config.disks.iter().enumerate().filter_map(|(i, disk)| {
+            Some(MountRequest {
+                tag: format!("disk{}", i),
+                guest_path: disk.guest_path.clone()?,
+                persistent: !disk.read_only,
+                device: Some(VmConfig::disk_device(i)),
+            })
+        });
+        let share_mounts = config.shares.iter().map(|share| MountRequest {
+            tag: share.tag.clone(),
+            guest_path: share.guest_path.clone(),
+            persistent: !share.read_only,
+            device: None,
+        });
+        let mount_requests = disk_mounts.chain(share_mounts).collect();
+        // A full init system brings up its services before the agent.
+        let connect_timeout = Duration::from_secs(if config.init_system() { 60 } else { 10 });
+        let events = EventHub::new(backend.state_channel());
+        Sandbox {
+            backend: Arc::new(backend),
+            mounts: Mutex::new(mount_requests),
+            events,
+            connect_timeout,
+            guest_log: Mutex::new(config.console_log.clone()),
+            proxy: Mutex::new(config.proxy.clone().map(|policy| ProxyConfig {
+                policy,
+                egress_log: config.egress_log.clone(),
+                console_log: config.console_log.clone(),
+            })),
+            tunnels: Mutex::new(Vec::new()),
+        }
+    }
+
     pub fn start(&self) -> Result<()> {
-        self.vm
+        self.events.starting();
+        self.backend
             .start()
-            .map_err(|e| anyhow::anyhow!("Failed to start VM: {}", e))
+            .inspect_err(|_| self.events.stopped(StopReason::Error))
     }
 
     pub fn stop(&self) -> Result<()> {
-        self.vm
-            .stop()
-            .map_err(|e| anyhow::anyhow!("Failed to stop VM: {}", e))
+        self.events.stop_requested();
+        self.backend.stop()?;
+        self.events.stopped(StopReason::Requested);
+        Ok(())
     }
 
+    /// Receiver for the backend's state changes from now on. Every
+    /// receiver gets every state.
     pub fn state_channel(&self) -> Receiver<VmState> {
-        self.vm.state_channel()
+        self.events.subscribe_states()
+    }
+
+    /// Receiver for lifecycle events from now on: VM start and stop, agent
+    /// readiness, mounts, commands and port-forward connections. Subscribe
+    /// before [`start`](Self::start) to see the whole lifecycle.
+    pub fn events(&self) -> Receiver<SandboxEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn event_hub(&self) -> &Arc<EventHub> {
+        &self.events
     }
 
     /// Send pending mount requests over an established vsock connection.
@@ -229,36 +429,47 @@ impl Sandbox {
     fn send_mount_requests(
         &self,
         writer: &mut impl Write,
-        reader: &mut BufReader<TcpStream>,
+        reader: &mut BufReader<GuestStream>,
     ) -> Result<()> {
         let mounts = std::mem::take(&mut *self.mounts.lock().unwrap());
         for req in &mounts {
             writeln!(writer, "{}", serde_json::to_string(req)?)?;
             writer.flush()?;
             let mut line = String::new();
-            reader
-                .read_line(&mut line)
-                .context("reading mount response")?;
+            reader.read_line(&mut line)?;
             let line = line.trim();
-            if line.is_empty() {
-                bail!("guest closed connection during mount init");
-            }
-            let resp: MountResponse = match serde_json::from_str(line) {
-                Ok(r) => r,
-                Err(_) => {
-                    bail!(
-                        "guest does not support directory mounts. \
-                         Run `shuru upgrade` and recreate the checkpoint to enable --mount."
-                    );
+            let result = if line.is_empty() {
+                Err(SandboxError::Disconnected(
+                    "guest closed connection during mount init".into(),
+                ))
+            } else {
+                match serde_json::from_str::<MountResponse>(line) {
+                    Ok(resp) if resp.ok => Ok(()),
+                    Ok(resp) => Err(SandboxError::MountFailed {
+                        tag: req.tag.clone(),
+                        guest_path: req.guest_path.clone(),
+                        reason: resp.error.unwrap_or_else(|| "unknown error".into()),
+                    }),
+                    Err(_) => Err(SandboxError::outdated_guest("directory mounts")),
                 }
             };
-            if !resp.ok {
-                bail!(
-                    "mount failed: {} -> {}: {}",
-                    req.tag,
-                    req.guest_path,
-                    resp.error.unwrap_or_else(|| "unknown error".into())
-                );
+            match result {
+                Ok(()) => self.events.emit(SandboxEvent::MountCompleted {
+                    tag: req.tag.clone(),
+                    guest_path: req.guest_path.clone(),
+                }),
+                Err(e) => {
+                    let error = match &e {
+                        SandboxError::MountFailed { reason, .. } => reason.clone(),
+                        e => e.to_string(),
+                    };
+                    self.events.emit(SandboxEvent::MountFailed {
+                        tag: req.tag.clone(),
+                        guest_path: req.guest_path.clone(),
+                        error,
+                    });
+                    return Err(e);
+                }
             }
         }
         Ok(())
@@ -285,20 +496,19 @@ impl Sandbox {
             tty: None,
             rows: None,
             cols: None,
+            ..Default::default()
         };
         writeln!(writer, "{}", serde_json::to_string(&req)?)?;
         writer.flush()?;
-
-        let mut exit_code = 0;
+        let mut span = ExecSpan::start(&self.events, &req.argv, false);
 
         for line in reader.lines() {
-            let line = line.context("reading vsock response")?;
+            let line = line?;
             if line.is_empty() {
                 continue;
             }
 
-            let resp: ExecResponse =
-                serde_json::from_str(&line).context("parsing vsock response")?;
+            let resp: ExecResponse = serde_json::from_str(&line)?;
 
             match resp.msg_type.as_str() {
                 "stdout" => {
@@ -312,21 +522,78 @@ impl Sandbox {
                     }
                 }
                 "exit" => {
-                    exit_code = resp.code.unwrap_or(0);
-                    break;
-                }
-                "error" => {
-                    if let Some(data) = &resp.data {
-                        write!(stderr, "guest error: {}", data)?;
-                    }
-                    exit_code = 1;
-                    break;
+                    let exit_code = resp.code.unwrap_or(0);
+                    span.exited(exit_code);
+                    return Ok(exit_code);
                 }
+                "error" => return Err(exec_error(resp, &req.argv[0])),
                 _ => {}
             }
         }
 
-        Ok(exit_code)
+        Err(SandboxError::Disconnected(
+            "guest closed connection before the command exited".into(),
+        ))
+    }
+
+    /// Start building a command to run in the guest, in the style of
+    /// [`std::process::Command`].
+    pub fn command(&self, program: impl Into<String>) -> Command<'_> {
+        Command::new(self, program.into())
+    }
+
+    /// Write `data` to `path` in the guest, replacing any existing file.
+    /// `mode` sets the permission bits; new files otherwise get 0644
+    /// (subject to the agent's umask).
+    pub fn write_file(&self, path: &str, data: &[u8], mode: Option<u32>) -> Result<()> {
+        let stream = self.connect_agent()?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let req = FileRequest {
+            op: FileOp::Write,
+            path: path.to_string(),
+            size: data.len() as u64,
+            mode,
+        };
+        writeln!(writer, "{}", serde_json::to_string(&req)?)?;
+        writer.write_all(data)?;
+        writer.flush()?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        parse_file_response(&line, path)?;
+        Ok(())
+    }
+
+    /// Read the contents of `path` in the guest.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let stream = self.connect_agent()?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let req = FileRequest {
+            op: FileOp::Read,
+            path: path.to_string(),
+            size: 0,
+            mode: None,
+        };
+        writeln!(writer, "{}", serde_json::to_string(&req)?)?;
+        writer.flush()?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let size = parse_file_response(&line, path)?.size.unwrap_or(0);
+
+        let mut data = Vec::new();
+        reader.take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(SandboxError::Disconnected(format!(
+                "guest closed connection while sending {}",
+                path
+            )));
+        }
+        Ok(data)
     }
 
     /// Run an interactive shell session with PTY support.
@@ -351,9 +618,11 @@ impl Sandbox {
             tty: Some(true),
             rows: Some(rows),
             cols: Some(cols),
+            ..Default::default()
         };
         writeln!(writer, "{}", serde_json::to_string(&req)?)?;
         writer.flush()?;
+        let mut span = ExecSpan::start(&self.events, &req.argv, true);
 
         // Enter raw mode - TerminalState restores on drop
         let _raw_guard = terminal::TerminalState::enter_raw_mode(stdin_fd);
@@ -362,7 +631,8 @@ impl Sandbox {
         terminal::install_sigwinch_handler();
 
         let done = Arc::new(AtomicBool::new(false));
-        let exit_code = Arc::new(Mutex::new(0i32));
+        // The exit code, or the guest's error if the command did not start.
+        let outcome = Arc::new(Mutex::new(None::<ExecResponse>));
 
         // Thread A: stdin → vsock (send stdin data + resize messages)
         let done_a = done.clone();
@@ -400,7 +670,7 @@ impl Sandbox {
 
         // Thread B: vsock → stdout (read responses, write output)
         let done_b = done.clone();
-        let exit_code_b = exit_code.clone();
+        let outcome_b = outcome.clone();
         let vsock_thread = std::thread::spawn(move || {
             let mut stdout = std::io::stdout();
             for line in reader.lines() {
@@ -424,16 +694,8 @@ impl Sandbox {
                             let _ = stdout.flush();
                         }
                     }
-                    "exit" => {
-                        *exit_code_b.lock().unwrap() = resp.code.unwrap_or(0);
-                        break;
-                    }
-                    "error" => {
-                        if let Some(data) = &resp.data {
-                            let _ = std::io::stderr()
-                                .write_all(format!("guest error: {}\r\n", data).as_bytes());
-                        }
-                        *exit_code_b.lock().unwrap() = 1;
+                    "exit" | "error" => {
+                        *outcome_b.lock().unwrap() = Some(resp);
                         break;
                     }
                     _ => {}
@@ -450,8 +712,18 @@ impl Sandbox {
         terminal::reset_sigwinch_handler();
 
         // Terminal restored by _raw_guard drop
-        let code = *exit_code.lock().unwrap();
-        Ok(code)
+        let outcome = outcome.lock().unwrap().take();
+        match outcome {
+            Some(resp) if resp.msg_type == "exit" => {
+                let code = resp.code.unwrap_or(0);
+                span.exited(code);
+                Ok(code)
+            }
+            Some(resp) => Err(exec_error(resp, &req.argv[0])),
+            None => Err(SandboxError::Disconnected(
+                "guest closed connection before the command exited".into(),
+            )),
+        }
     }
 
     /// Start port forwarding proxies. Returns a handle that stops all
@@ -462,12 +734,17 @@ impl Sandbox {
 
         for mapping in forwards {
             let addr = format!("127.0.0.1:{}", mapping.host_port);
-            let tcp_listener = TcpListener::bind(&addr)
-                .with_context(|| format!("Failed to bind port {}", mapping.host_port))?;
+            let tcp_listener =
+                TcpListener::bind(&addr).map_err(|source| SandboxError::PortBindFailed {
+                    port: mapping.host_port,
+                    source,
+                })?;
             tcp_listener.set_nonblocking(true)?;
 
+            let host_port = mapping.host_port;
             let guest_port = mapping.guest_port;
-            let vm = Arc::clone(&self.vm);
+            let backend = Arc::clone(&self.backend);
+            let events = Arc::clone(&self.events);
             let stop_flag = stop.clone();
 
             info!(
@@ -482,11 +759,12 @@ impl Sandbox {
                             // macOS accept() inherits non-blocking from the
                             // listener — force blocking for the relay.
                             let _ = tcp_stream.set_nonblocking(false);
-                            let vm = Arc::clone(&vm);
+                            let backend = Arc::clone(&backend);
+                            let events = Arc::clone(&events);
                             std::thread::spawn(move || {
-                                if let Err(e) =
-                                    handle_forward_connection(tcp_stream, &vm, guest_port)
-                                {
+                                if let Err(e) = handle_forward_connection(
+                                    tcp_stream, &*backend, &events, host_port, guest_port,
+                                ) {
                                     info!("shuru: port forward error: {}", e);
                                 }
                             });
@@ -513,32 +791,134 @@ impl Sandbox {
         })
     }
 
-    fn connect_vsock(&self) -> Result<TcpStream> {
-        let state_rx = self.vm.state_channel();
-        for attempt in 1..=10 {
+    /// Start reverse port forwarding: the guest listens on 127.0.0.1 at
+    /// each mapping's guest port, and every connection is forwarded to its
+    /// host address, dialed from the host. Waits for the guest to be up.
+    /// Returns a handle that closes the guest listeners when dropped.
+    pub fn start_reverse_forwarding(
+        &self,
+        forwards: &[ReverseMapping],
+    ) -> Result<ReverseForwardHandle> {
+        drop(self.connect_vsock()?);
+
+        let listeners = forwards
+            .iter()
+            .map(|mapping| {
+                info!(
+                    "shuru: forwarding guest:{} -> {}",
+                    mapping.guest_port, mapping.host_addr
+                );
+                let listener = TunnelListener {
+                    addr: format!("127.0.0.1:{}", mapping.guest_port),
+                    kind: TunnelKind::Stream,
+                };
+                let host_addr = mapping.host_addr.clone();
+                let service: TunnelService =
+                    Arc::new(move |guest_stream| match TcpStream::connect(&host_addr) {
+                        Ok(host_stream) => relay(guest_stream, host_stream.into()),
+                        Err(e) => info!("shuru: reverse forward to {} failed: {}", host_addr, e),
+                    });
+                (listener, service)
+            })
+            .collect();
+        let session = tunnel::open_session(
+            Arc::clone(&self.backend),
+            listeners,
+            HashMap::new(),
+            false,
+            "reverse port forwarding",
+        )?;
+        Ok(ReverseForwardHandle { _session: session })
+    }
+
+    /// Open a connection to the guest agent ready for a request, with any
+    /// pending mounts already applied.
+    pub(crate) fn connect_agent(&self) -> Result<GuestStream> {
+        let stream = self.connect_vsock()?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        self.send_mount_requests(&mut writer, &mut reader)?;
+        Ok(stream)
+    }
+
+    /// Open a forward connection to `guest_port`, ready for relaying.
+    #[cfg(feature = "async")]
+    pub(crate) fn connect_forward(&self, guest_port: u16) -> Result<GuestStream> {
+        open_forward(&*self.backend, guest_port)
+    }
+
+    fn connect_vsock(&self) -> Result<GuestStream> {
+        let deadline = Instant::now() + self.connect_timeout;
+        for attempt in 1.. {
             // Check if VM died (e.g. guest mount failure -> reboot POWER_OFF)
-            if let Ok(state) = state_rx.try_recv() {
-                match state {
-                    VmState::Stopped => {
-                        bail!("VM stopped during startup - check boot output above for errors")
-                    }
-                    VmState::Error => bail!("VM encountered an error during startup"),
-                    _ => {}
+            let reason = match self.events.state() {
+                VmState::Stopped => {
+                    "VM stopped during startup - check the boot output or console log for errors"
                 }
+                VmState::Error => "VM encountered an error during startup",
+                _ => "",
+            };
+            if !reason.is_empty() {
+                return Err(SandboxError::BootFailed {
+                    reason: reason.into(),
+                    source: None,
+                });
             }
-            match self.vm.connect_to_vsock_port(VSOCK_PORT) {
-                Ok(s) => return Ok(s),
+            match self.backend.connect(VSOCK_PORT) {
+                Ok(s) => {
+                    self.events.agent_ready();
+                    self.collect_guest_log();
+                    self.start_proxy()?;
+                    return Ok(s);
+                }
                 Err(e) => {
-                    if attempt == 10 {
-                        bail!("Failed to connect to guest after 10 attempts: {}", e);
+                    if Instant::now() >= deadline {
+                        return Err(SandboxError::unreachable(
+                            format!(
+                                "Failed to connect to guest after {} seconds",
+                                self.connect_timeout.as_secs()
+                            ),
+                            e.into(),
+                        ));
                     }
                     tracing::debug!("vsock connect attempt {} failed: {}", attempt, e);
-                    std::thread::sleep(Duration::from_secs(1));
+                    std::thread::sleep(CONNECT_RETRY_INTERVAL);
                 }
             }
         }
         unreachable!()
     }
+
+    /// Start copying the guest agent's log records into the console log,
+    /// once the agent is up. Agents without a log port are skipped.
+    fn collect_guest_log(&self) {
+        let Some(path) = self.guest_log.lock().unwrap().take() else {
+            return;
+        };
+        match self.backend.connect(VSOCK_PORT_LOG) {
+            Ok(stream) => ConsoleLog::pump_guest(&path, stream),
+            Err(e) => tracing::debug!("guest log port unavailable: {}", e),
+        }
+    }
+
+    /// Start proxy mode's proxies, once the agent is up. Retried on the
+    /// next connection if it fails.
+    fn start_proxy(&self) -> Result<()> {
+        let mut proxy = self.proxy.lock().unwrap();
+        let Some(config) = proxy.as_ref() else {
+            return Ok(());
+        };
+        let session = crate::net::start_proxy(
+            Arc::clone(&self.backend),
+            &config.policy,
+            config.egress_log.as_deref(),
+            config.console_log.as_deref(),
+        )?;
+        info!("network proxy started, allowing {}", config.policy);
+        *proxy = None;
+        self.tunnels.lock().unwrap().push(session);
+        Ok(())
+    }
 }
 
 // --- Port forwarding ---
@@ -550,6 +930,12 @@ pub struct PortForwardHandle {
     _threads: Vec<std::thread::JoinHandle<()>>,
 }
 
+/// Handle returned by `start_reverse_forwarding`. The guest stops listening
+/// when it is dropped; connections already forwarded run to completion.
+pub struct ReverseForwardHandle {
+    _session: TunnelSession,
+}
+
 impl Drop for PortForwardHandle {
     fn drop(&mut self) {
         self.stop.store(true, Ordering::Relaxed);
@@ -558,12 +944,23 @@ impl Drop for PortForwardHandle {
 
 fn handle_forward_connection(
     tcp_stream: TcpStream,
-    vm: &VirtualMachine,
+    backend: &dyn Backend,
+    events: &Arc<EventHub>,
+    host_port: u16,
     guest_port: u16,
 ) -> Result<()> {
-    let mut vsock_stream = vm
-        .connect_to_vsock_port(VSOCK_PORT_FORWARD)
-        .map_err(|e| anyhow::anyhow!("vsock connect for port forward: {}", e))?;
+    let vsock_stream = open_forward(backend, guest_port)?;
+    let _span = ForwardSpan::open(events, host_port, guest_port);
+
+    // Bidirectional relay between TCP and vsock
+    relay(tcp_stream.into(), vsock_stream);
+    Ok(())
+}
+
+/// Connect to the guest's forward port and ask it to connect to
+/// `guest_port`. Returns the stream once the guest has accepted.
+fn open_forward(backend: &dyn Backend, guest_port: u16) -> Result<GuestStream> {
+    let mut vsock_stream = backend.connect(VSOCK_PORT_FORWARD)?;
 
     // Send forward request
     let req = ForwardRequest { port: guest_port };
@@ -571,30 +968,65 @@ fn handle_forward_connection(
     vsock_stream.flush()?;
 
     // Read response - byte-by-byte to avoid buffering past the newline
-    let line = read_line_raw(&mut vsock_stream).context("reading forward response")?;
-    let resp: ForwardResponse =
-        serde_json::from_str(line.trim()).context("parsing forward response")?;
+    let line = read_line_raw(&mut vsock_stream)?;
+    let resp: ForwardResponse = serde_json::from_str(line.trim())?;
 
     if resp.status != "ok" {
-        bail!(
+        return Err(SandboxError::Guest(format!(
             "guest refused forward: {}",
             resp.message.unwrap_or_default()
-        );
+        )));
     }
+    Ok(vsock_stream)
+}
 
-    // Bidirectional relay between TCP and vsock
-    relay(tcp_stream, vsock_stream);
-    Ok(())
+// --- File transfer ---
+
+/// Parse the guest's reply to a FileRequest, failing if the guest refused.
+pub(crate) fn parse_file_response(line: &str, path: &str) -> Result<FileResponse> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Err(SandboxError::Disconnected(
+            "guest closed connection during file transfer".into(),
+        ));
+    }
+    let resp: FileResponse =
+        serde_json::from_str(line).map_err(|_| SandboxError::outdated_guest("file transfer"))?;
+    if !resp.ok {
+        return Err(SandboxError::Guest(
+            resp.error
+                .unwrap_or_else(|| format!("{}: unknown error", path)),
+        ));
+    }
+    Ok(resp)
+}
+
+/// The error for an exec `error` reply. The guest sets `code` (126, or 127
+/// for a missing program) when the command could not be started.
+pub(crate) fn exec_error(resp: ExecResponse, program: &str) -> SandboxError {
+    let reason = resp.data.unwrap_or_else(|| "unknown error".into());
+    match resp.code {
+        Some(code) => SandboxError::ExecSpawnFailed {
+            program: program.to_string(),
+            reason,
+            not_found: code == 127,
+        },
+        None => SandboxError::Guest(reason),
+    }
 }
 
 /// Read one line from a stream without any buffering beyond the newline.
 /// This prevents a BufReader from consuming bytes that belong to the relay phase.
-fn read_line_raw(stream: &mut TcpStream) -> Result<String> {
+pub(crate) fn read_line_raw(stream: &mut GuestStream) -> Result<String> {
     let mut buf = Vec::new();
     let mut byte = [0u8; 1];
     loop {
         match stream.read(&mut byte) {
-            Ok(0) => bail!("unexpected EOF"),
+            Ok(0) => {
+                return Err(SandboxError::Disconnected(
+                    "guest closed connection before replying".into(),
+                ))
+            }
             Ok(_) => {
                 if byte[0] == b'\n' {
                     break;
@@ -604,10 +1036,10 @@ fn read_line_raw(stream: &mut TcpStream) -> Result<String> {
             Err(e) => return Err(e.into()),
         }
     }
-    Ok(String::from_utf8(buf)?)
+    Ok(String::from_utf8_lossy(&buf).into_owned())
 }
 
-fn relay(a: TcpStream, b: TcpStream) {
+pub(crate) fn relay(a: GuestStream, b: GuestStream) {
     let mut a_read = a.try_clone().expect("clone tcp stream");
     let mut b_write = b.try_clone().expect("clone vsock stream");
     let mut b_read = b;
@@ -624,3 +1056,242 @@ fn relay(a: TcpStream, b: TcpStream) {
     let _ = t1.join();
     let _ = t2.join();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    use crossbeam_channel::Sender;
+    use serde_json::{json, Value};
+
+    /// A backend whose "guest" is a thread per connection speaking a
+    /// scripted version of the agent protocol:
+    ///
+    /// - mounts succeed for `mount*` tags and fail for anything else;
+    /// - `echo ARGS` prints its arguments, `fail` prints to stderr and
+    ///   exits 3, and any other program is not found;
+    /// - forwards to guest port 80 echo their input; other ports are
+    ///   refused.
+    struct FakeGuest {
+        states: (Sender<VmState>, Receiver<VmState>),
+    }
+
+    impl FakeGuest {
+        fn new() -> Self {
+            FakeGuest {
+                states: crossbeam_channel::unbounded(),
+            }
+        }
+    }
+
+    impl Backend for FakeGuest {
+        fn configure(_: &VmConfig) -> Result<Self> {
+            Ok(FakeGuest::new())
+        }
+
+        fn start(&self) -> Result<()> {
+            let _ = self.states.0.send(VmState::Running);
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<()> {
+            let _ = self.states.0.send(VmState::Stopped);
+            Ok(())
+        }
+
+        fn state_channel(&self) -> Receiver<VmState> {
+            self.states.1.clone()
+        }
+
+        fn connect(&self, port: u32) -> Result<GuestStream> {
+            let (host, guest) = UnixStream::pair()?;
+            match port {
+                VSOCK_PORT => std::thread::spawn(move || serve_agent(guest)),
+                VSOCK_PORT_FORWARD => std::thread::spawn(move || serve_forward(guest)),
+                _ => return Err(SandboxError::Unsupported(format!("no port {}", port))),
+            };
+            Ok(host.into())
+        }
+    }
+
+    fn send(stream: &mut UnixStream, msg: Value) {
+        let _ = writeln!(stream, "{}", msg);
+    }
+
+    fn serve_agent(stream: UnixStream) {
+        let mut writer = stream.try_clone().unwrap();
+        for line in BufReader::new(stream).lines() {
+            let req: Value = serde_json::from_str(&line.unwrap()).unwrap();
+            if let Some(tag) = req["tag"].as_str() {
+                let ok = tag.starts_with("mount");
+                let error = (!ok).then(|| format!("cannot mount {}", tag));
+                send(&mut writer, json!({"tag": tag, "ok": ok, "error": error}));
+                continue;
+            }
+            let argv: Vec<&str> = req["argv"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|a| a.as_str().unwrap())
+                .collect();
+            match argv[0] {
+                "echo" => {
+                    let data = format!("{}\n", argv[1..].join(" "));
+                    send(&mut writer, json!({"type": "stdout", "data": data}));
+                    send(&mut writer, json!({"type": "exit", "code": 0}));
+                }
+                "fail" => {
+                    send(&mut writer, json!({"type": "stderr", "data": "boom\n"}));
+                    send(&mut writer, json!({"type": "exit", "code": 3}));
+                }
+                program => send(
+                    &mut writer,
+                    json!({"type": "error", "data": format!("{}: not found", program), "code": 127}),
+                ),
+            }
+            return;
+        }
+    }
+
+    fn serve_forward(mut stream: UnixStream) {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        let req: Value = serde_json::from_slice(&line).unwrap();
+        if req["port"] != 80 {
+            send(
+                &mut stream,
+                json!({"status": "error", "message": "connection refused"}),
+            );
+            return;
+        }
+        send(&mut stream, json!({"status": "ok"}));
+        let mut reader = stream.try_clone().unwrap();
+        let _ = std::io::copy(&mut reader, &mut stream);
+    }
+
+    fn sandbox(builder: VmConfigBuilder) -> Sandbox {
+        let config = builder
+            .kernel("Image")
+            .rootfs("rootfs.ext4")
+            .config()
+            .unwrap();
+        Sandbox::from_backend(FakeGuest::new(), &config)
+    }
+
+    fn exec(sandbox: &Sandbox, argv: &[&str]) -> Result<(i32, String, String)> {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let code = sandbox.exec(argv, &HashMap::new(), &mut stdout, &mut stderr)?;
+        Ok((
+            code,
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        ))
+    }
+
+    #[test]
+    fn exec_streams_output_and_exit_code() {
+        let sandbox = sandbox(Sandbox::builder());
+        let (code, stdout, stderr) = exec(&sandbox, &["echo", "hello", "world"]).unwrap();
+        assert_eq!(
+            (code, stdout.as_str(), stderr.as_str()),
+            (0, "hello world\n", "")
+        );
+
+        let (code, stdout, stderr) = exec(&sandbox, &["fail"]).unwrap();
+        assert_eq!((code, stdout.as_str(), stderr.as_str()), (3, "", "boom\n"));
+    }
+
+    #[test]
+    fn exec_error_reply_is_spawn_failure() {
+        let sandbox = sandbox(Sandbox::builder());
+        match exec(&sandbox, &["missing"]) {
+            Err(SandboxError::ExecSpawnFailed {
+                program, not_found, ..
+            }) => {
+                assert_eq!(program, "missing");
+                assert!(not_found);
+            }
+            other => panic!("expected a spawn failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mounts_are_sent_once_before_the_first_request() {
+        let sandbox = sandbox(Sandbox::builder().mount(MountConfig {
+            host_path: "/tmp".into(),
+            guest_path: "/mnt/tmp".into(),
+            persistent: false,
+        }));
+        let events = sandbox.events();
+        exec(&sandbox, &["echo"]).unwrap();
+        exec(&sandbox, &["echo"]).unwrap();
+
+        let mounted: Vec<_> = events
+            .try_iter()
+            .filter_map(|e| match e {
+                SandboxEvent::MountCompleted { tag, guest_path } => Some((tag, guest_path)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(mounted, [("mount0".to_string(), "/mnt/tmp".to_string())]);
+    }
+
+    #[test]
+    fn failed_mount_is_reported() {
+        let mut disk = DiskConfig::new("data.img");
+        disk.guest_path = Some("/data".into());
+        let sandbox = sandbox(Sandbox::builder().disk(disk));
+        match exec(&sandbox, &["echo"]) {
+            Err(SandboxError::MountFailed {
+                tag,
+                guest_path,
+                reason,
+            }) => {
+                assert_eq!((tag.as_str(), guest_path.as_str()), ("disk0", "/data"));
+                assert_eq!(reason, "cannot mount disk0");
+            }
+            other => panic!("expected a mount failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn port_forward_relays_and_closes_refused_connections() {
+        let sandbox = sandbox(Sandbox::builder());
+        let free_port = || {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let (open_port, refused_port) = (free_port(), free_port());
+        let _forwards = sandbox
+            .start_port_forwarding(&[
+                PortMapping {
+                    host_port: open_port,
+                    guest_port: 80,
+                },
+                PortMapping {
+                    host_port: refused_port,
+                    guest_port: 81,
+                },
+            ])
+            .unwrap();
+
+        let mut client = TcpStream::connect(("127.0.0.1", open_port)).unwrap();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = String::new();
+        client.read_to_string(&mut echoed).unwrap();
+        assert_eq!(echoed, "ping");
+
+        let mut client = TcpStream::connect(("127.0.0.1", refused_port)).unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backend::{Backend, GuestStream};
+use crate::error::{Result, SandboxError};
+use crate::proto::{TunnelListener, TunnelMessage, TunnelRequest};
+use crate::sandbox::read_line_raw;
+use crate::VSOCK_PORT_TUNNEL;
+
+/// How long the guest may take to bind a session's listeners.
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handles one guest client of a tunnel listener, on a stream to the guest.
+pub(crate) type TunnelService = Arc<dyn Fn(GuestStream) + Send + Sync>;
+
+/// An open tunnel session: the guest listens until this is dropped.
+pub(crate) struct TunnelSession {
+    control: GuestStream,
+}
+
+impl Drop for TunnelSession {
+    fn drop(&mut self) {
+        // The guest closes the session's listeners when it sees EOF
+        let _ = self.control.shutdown(Shutdown::Both);
+    }
+}
+
+/// Have the guest listen on each listener and hand every client to its
+/// service. `feature` names what needs the session in errors.
+pub(crate) fn open_session(
+    backend: Arc<dyn Backend>,
+    listeners: Vec<(TunnelListener, TunnelService)>,
+    env: HashMap<String, String>,
+    set_resolver: bool,
+    feature: &str,
+) -> Result<TunnelSession> {
+    let (listeners, services): (Vec<_>, Vec<_>) = listeners.into_iter().unzip();
+    let mut control = backend.connect(VSOCK_PORT_TUNNEL)?;
+    let req = TunnelRequest::Listen {
+        listeners,
+        env,
+        set_resolver,
+    };
+    writeln!(control, "{}", serde_json::to_string(&req)?)?;
+    control.flush()?;
+
+    // Guests without tunnels close the connection or send something else
+    control.set_read_timeout(Some(READY_TIMEOUT))?;
+    let line = read_line_raw(&mut control).map_err(|e| match e {
+        SandboxError::Disconnected(_) => SandboxError::outdated_guest(feature),
+        SandboxError::Io(e) => {
+            SandboxError::unreachable(format!("guest did not start {}", feature), e.into())
+        }
+        e => e,
+    })?;
+    control.set_read_timeout(None)?;
+    match serde_json::from_str(line.trim()) {
+        Ok(TunnelMessage::Ready) => {}
+        Ok(TunnelMessage::Error { message }) => {
+            return Err(SandboxError::Guest(format!("{}: {}", feature, message)))
+        }
+        _ => return Err(SandboxError::outdated_guest(feature)),
+    }
+
+    let mut reader = control.try_clone()?;
+    std::thread::spawn(move || {
+        while let Ok(line) = read_line_raw(&mut reader) {
+            let Ok(TunnelMessage::Open { id, listener }) = serde_json::from_str(line.trim()) else {
+                continue;
+            };
+            let Some(service) = services.get(listener).cloned() else {
+                continue;
+            };
+            let backend = Arc::clone(&backend);
+            std::thread::spawn(move || match accept(&*backend, id) {
+                Ok(stream) => service(stream),
+                Err(e) => tracing::debug!("tunnel accept {} failed: {}", id, e),
+            });
+        }
+    });
+    Ok(TunnelSession { control })
+}
+
+/// Take client `id` onto a new stream.
+fn accept(backend: &dyn Backend, id: u64) -> Result<GuestStream> {
+    let mut stream = backend.connect(VSOCK_PORT_TUNNEL)?;
+    writeln!(
+        stream,
+        "{}",
+        serde_json::to_string(&TunnelRequest::Accept { id })?
+    )?;
+    stream.flush()?;
+    Ok(stream)
+}
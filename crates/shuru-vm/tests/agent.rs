@@ -0,0 +1,543 @@
+//! End-to-end tests against a real `shuru-guest` in agent mode
+//! (`--listen`), talking to it over Unix sockets instead of a VM.
+
+#![cfg(target_os = "linux")]
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use shuru_vm::{
+    AgentAddr, AgentBackend, DiskConfig, PortMapping, Sandbox, SandboxError, SandboxEvent,
+    VmConfig, VSOCK_PORT,
+};
+
+/// The `shuru-guest` binary: `$SHURU_GUEST_BIN`, or the one next to this
+/// test's target directory, built on first use.
+fn guest_bin() -> &'static Path {
+    static BIN: OnceLock<PathBuf> = OnceLock::new();
+    BIN.get_or_init(|| {
+        if let Some(path) = std::env::var_os("SHURU_GUEST_BIN") {
+            return path.into();
+        }
+        // target/<profile>/deps/agent-<hash>
+        let exe = std::env::current_exe().unwrap();
+        let bin = exe.parent().unwrap().parent().unwrap().join("shuru-guest");
+        if !bin.exists() {
+            let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+            let status = Command::new(cargo)
+                .args(["build", "-p", "shuru-guest"])
+                .status()
+                .expect("failed to run cargo");
+            assert!(status.success(), "failed to build shuru-guest");
+        }
+        bin
+    })
+}
+
+/// A `shuru-guest --listen` process on sockets in its own directory,
+/// killed when dropped.
+struct Agent {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Agent {
+    fn spawn() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "shuru-agent-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let child = Command::new(guest_bin())
+            .arg("--listen")
+            .arg(dir.join("sock"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to spawn shuru-guest");
+        let agent = Agent { child, dir };
+
+        // Wait until every endpoint is bound.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !(VSOCK_PORT..VSOCK_PORT + 4).all(|p| agent.endpoint(p).exists()) {
+            assert!(Instant::now() < deadline, "shuru-guest did not start");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        agent
+    }
+
+    fn endpoint(&self, port: u32) -> PathBuf {
+        self.dir.join(format!("sock_{}", port))
+    }
+
+    /// A sandbox on this agent, not yet started.
+    fn backend_sandbox(&self, config: &VmConfig) -> Sandbox {
+        let addr = AgentAddr::Unix(self.dir.join("sock").to_string_lossy().into_owned());
+        Sandbox::from_backend(AgentBackend::new(addr), config)
+    }
+
+    fn sandbox(&self, config: &VmConfig) -> Sandbox {
+        let sandbox = self.backend_sandbox(config);
+        sandbox.start().unwrap();
+        sandbox
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn config(builder: shuru_vm::VmConfigBuilder) -> VmConfig {
+    builder.kernel("unused").rootfs("unused").config().unwrap()
+}
+
+fn exec(sandbox: &Sandbox, argv: &[&str]) -> shuru_vm::Result<(i32, String, String)> {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let env = HashMap::from([("GREETING".to_string(), "hello".to_string())]);
+    let code = sandbox.exec(argv, &env, &mut stdout, &mut stderr)?;
+    Ok((
+        code,
+        String::from_utf8(stdout).unwrap(),
+        String::from_utf8(stderr).unwrap(),
+    ))
+}
+
+#[test]
+fn exec_runs_commands_in_the_agent() {
+    let agent = Agent::spawn();
+    let sandbox = agent.sandbox(&config(Sandbox::builder()));
+
+    let (code, stdout, stderr) = exec(
+        &sandbox,
+        &["sh", "-c", "echo $GREETING; echo oops >&2; exit 7"],
+    )
+    .unwrap();
+    assert_eq!(
+        (code, stdout.as_str(), stderr.as_str()),
+        (7, "hello\n", "oops\n")
+    );
+
+    match exec(&sandbox, &["/nonexistent/program"]) {
+        Err(SandboxError::ExecSpawnFailed { not_found, .. }) => assert!(not_found),
+        other => panic!("expected a spawn failure, got {:?}", other),
+    }
+}
+
+#[test]
+fn command_output_and_status() {
+    let agent = Agent::spawn();
+    let sandbox = agent.sandbox(&config(Sandbox::builder()));
+
+    let output = sandbox
+        .command("sh")
+        .args(["-c", "echo out; echo err >&2; exit 3"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(!output.status.success());
+    assert_eq!(
+        (&output.stdout[..], &output.stderr[..]),
+        (&b"out\n"[..], &b"err\n"[..])
+    );
+
+    // status() inherits output; only the exit status comes back
+    let status = sandbox
+        .command("true")
+        .stdout(shuru_vm::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Binary output survives unchanged
+    let output = sandbox
+        .command("printf")
+        .arg("\\000\\377\\n\\200")
+        .output()
+        .unwrap();
+    assert_eq!(output.stdout, [0x00, 0xff, b'\n', 0x80]);
+
+    match sandbox.command("/nonexistent/program").output() {
+        Err(SandboxError::ExecSpawnFailed { not_found, .. }) => assert!(not_found),
+        other => panic!(
+            "expected a spawn failure, got {:?}",
+            other.map(|o| o.status)
+        ),
+    }
+}
+
+#[test]
+fn command_env_and_current_dir() {
+    let agent = Agent::spawn();
+    let sandbox = agent.sandbox(&config(Sandbox::builder()));
+
+    let dir = agent.dir.to_string_lossy().into_owned();
+    let output = sandbox
+        .command("sh")
+        .args(["-c", "echo \"$A $B\"; pwd"])
+        .env("A", "one")
+        .envs([("B", "two")])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("one two\n{}\n", dir)
+    );
+}
+
+#[test]
+fn command_piped_stdin_reaches_eof() {
+    let agent = Agent::spawn();
+    let sandbox = agent.sandbox(&config(Sandbox::builder()));
+
+    let mut child = sandbox
+        .command("sh")
+        .args(["-c", "wc -c; echo done"])
+        .stdin(shuru_vm::Stdio::piped())
+        .stdout(shuru_vm::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&[0xff; 100_000]).unwrap();
+    // Dropping stdin closes it, so wc sees EOF
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "100000\ndone\n");
+
+    // Without stdin configured, the command reads EOF at once
+    let output = sandbox.command("cat").output().unwrap();
+    assert!(output.status.success() && output.stdout.is_empty());
+}
+
+#[test]
+fn command_kill_stops_the_command() {
+    let agent = Agent::spawn();
+    let sandbox = agent.sandbox(&config(Sandbox::builder()));
+
+    let mut child = sandbox
+        .command("sh")
+        .args(["-c", "echo ready; exec sleep 30"])
+        .stdout(shuru_vm::Stdio::piped())
+        .spawn()
+        .unwrap();
+    assert!(child.id() > 0);
+    let mut line = [0; 6];
+    child
+        .stdout
+        .as_mut()
+        .unwrap()
+        .read_exact(&mut line)
+        .unwrap();
+    assert_eq!(&line, b"ready\n");
+    assert_eq!(child.try_wait().unwrap(), None);
+
+    let start = Instant::now();
+    child.kill().unwrap();
+    let status = child.wait().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!((status.signal(), status.code()), (Some(9), None));
+    // Killing an exited command is a no-op
+    child.kill().unwrap();
+    assert_eq!(child.try_wait().unwrap(), Some(status));
+}
+
+/// Set in the child process `shell_runs_on_a_pty` starts, to the agent's
+/// socket.
+const SHELL_AGENT_ENV: &str = "SHURU_TEST_SHELL_AGENT";
+
+#[test]
+fn shell_runs_on_a_pty() {
+    // `shell` relays this process's own stdin and stdout, so it runs in a
+    // copy of this test binary with both piped.
+    if let Ok(sock) = std::env::var(SHELL_AGENT_ENV) {
+        let sandbox = Sandbox::from_backend(
+            AgentBackend::new(AgentAddr::Unix(sock)),
+            &config(Sandbox::builder()),
+        );
+        sandbox.start().unwrap();
+        let script =
+            "test -t 0 && test -t 1 && echo tty:$(stty size); read line; echo got:$line; exit 5";
+        let code = sandbox
+            .shell(&["sh", "-c", script], &HashMap::new())
+            .unwrap();
+        std::process::exit(code);
+    }
+
+    let agent = Agent::spawn();
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "shell_runs_on_a_pty", "--nocapture"])
+        .env(SHELL_AGENT_ENV, agent.dir.join("sock"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Typed ahead, before the guest has started the command
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"hello\n").unwrap();
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    drop(stdin);
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(5), "{}", stdout);
+    // Without a host terminal the guest pty gets the default size, and
+    // translates newlines as a terminal does
+    assert!(stdout.contains("tty:24 80\r\n"), "{}", stdout);
+    assert!(stdout.contains("got:hello\r\n"), "{}", stdout);
+}
+
+#[test]
+fn tcp_listeners_must_be_loopback() {
+    let output = Command::new(guest_bin())
+        .args(["--listen", "tcp:0.0.0.0:1"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--allow-remote"));
+}
+
+#[test]
+fn mounts_are_refused_and_reported_once() {
+    let agent = Agent::spawn();
+    let mut disk = DiskConfig::new("unused.img");
+    disk.guest_path = Some("/data".into());
+    let sandbox = agent.sandbox(&config(Sandbox::builder().disk(disk)));
+    let events = sandbox.events();
+
+    match exec(&sandbox, &["true"]) {
+        Err(SandboxError::MountFailed {
+            tag,
+            guest_path,
+            reason,
+        }) => {
+            assert_eq!((tag.as_str(), guest_path.as_str()), ("disk0", "/data"));
+            assert_eq!(reason, "mounts are not supported in agent mode");
+        }
+        other => panic!("expected a mount failure, got {:?}", other),
+    }
+    assert!(events
+        .try_iter()
+        .any(|e| matches!(e, SandboxEvent::MountFailed { tag, .. } if tag == "disk0")));
+
+    // The mount is not retried; later commands run normally.
+    assert_eq!(exec(&sandbox, &["true"]).unwrap().0, 0);
+}
+
+#[test]
+fn port_forward_reaches_agent_side_listeners() {
+    let agent = Agent::spawn();
+    let sandbox = agent.sandbox(&config(Sandbox::builder()));
+
+    // The agent shares this host's network, so a local listener stands
+    // in for a server inside the guest.
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let guest_port = server.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for mut conn in server.incoming().flatten() {
+            let mut data = Vec::new();
+            conn.read_to_end(&mut data).unwrap();
+            data.reverse();
+            conn.write_all(&data).unwrap();
+        }
+    });
+
+    let free_port = || {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    };
+    let host_port = free_port();
+    let refused_port = free_port();
+    let _forwards = sandbox
+        .start_port_forwarding(&[
+            PortMapping {
+                host_port,
+                guest_port,
+            },
+            PortMapping {
+                host_port: refused_port,
+                // Released at once, so nothing listens there.
+                guest_port: free_port(),
+            },
+        ])
+        .unwrap();
+
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(("127.0.0.1", host_port)).unwrap();
+        client.write_all(b"shuru").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "uruhs");
+    }
+
+    let mut client = TcpStream::connect(("127.0.0.1", refused_port)).unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[cfg(feature = "async")]
+mod async_sandbox {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    use shuru_vm::{AsyncSandbox, ExecEvent, PortMapping, Sandbox, SandboxError};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{config, Agent};
+
+    async fn sandbox(agent: &Agent) -> AsyncSandbox {
+        let sandbox = AsyncSandbox::new(agent.backend_sandbox(&config(Sandbox::builder())));
+        sandbox.start().await.unwrap();
+        sandbox
+    }
+
+    #[tokio::test]
+    async fn exec_collects_output() {
+        let agent = Agent::spawn();
+        let sandbox = sandbox(&agent).await;
+
+        let env = HashMap::from([("GREETING".to_string(), "hello".to_string())]);
+        let output = sandbox
+            .exec(&["sh", "-c", "echo $GREETING; echo oops >&2; exit 7"], &env)
+            .await
+            .unwrap();
+        assert_eq!(
+            (output.stdout.as_str(), output.stderr.as_str(), output.code),
+            ("hello\n", "oops\n", 7)
+        );
+
+        match sandbox
+            .exec(&["/nonexistent/program"], &HashMap::new())
+            .await
+        {
+            Err(SandboxError::ExecSpawnFailed { not_found, .. }) => assert!(not_found),
+            other => panic!("expected a spawn failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_stream_keeps_output_in_order() {
+        let agent = Agent::spawn();
+        let sandbox = sandbox(&agent).await;
+
+        let script = "echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three";
+        let mut stream = sandbox
+            .exec_stream(&["sh", "-c", script], &HashMap::new())
+            .await
+            .unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = stream.next().await.unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            [
+                ExecEvent::Stdout("one\n".into()),
+                ExecEvent::Stderr("two\n".into()),
+                ExecEvent::Stdout("three\n".into()),
+                ExecEvent::Exit(0),
+            ]
+        );
+        // Nothing follows the exit
+        assert_eq!(stream.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn upload_and_download_round_trip() {
+        let agent = Agent::spawn();
+        let sandbox = sandbox(&agent).await;
+
+        let path = agent.dir.join("data.bin").to_string_lossy().into_owned();
+        let data: Vec<u8> = (0..=255u8).cycle().take(300_000).collect();
+        sandbox
+            .upload(&path, &data[..], data.len() as u64, Some(0o600))
+            .await
+            .unwrap();
+        let mut copy = Vec::new();
+        let size = sandbox.download(&path, &mut copy).await.unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert!(copy == data);
+
+        sandbox.write_file(&path, b"small", None).await.unwrap();
+        assert_eq!(sandbox.read_file(&path).await.unwrap(), b"small");
+
+        // The source must provide `size` bytes
+        assert!(sandbox
+            .upload(&path, &b"short"[..], 10, None)
+            .await
+            .is_err());
+        assert!(sandbox.read_file("/nonexistent/file").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn port_forward_reaches_agent_side_listeners() {
+        let agent = Agent::spawn();
+        let sandbox = sandbox(&agent).await;
+
+        // As in the blocking test, a local listener stands in for a server
+        // inside the guest
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let guest_port = server.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            use std::io::{Read, Write};
+            for mut conn in server.incoming().flatten() {
+                let mut data = Vec::new();
+                conn.read_to_end(&mut data).unwrap();
+                data.reverse();
+                conn.write_all(&data).unwrap();
+            }
+        });
+        let host_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let forwards = sandbox
+            .start_port_forwarding(&[PortMapping {
+                host_port,
+                guest_port,
+            }])
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            let mut client = tokio::net::TcpStream::connect(("127.0.0.1", host_port))
+                .await
+                .unwrap();
+            client.write_all(b"shuru").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).await.unwrap();
+            assert_eq!(reply, "uruhs");
+        }
+
+        // Dropping the handle closes the listener
+        // (the aborted task drops it once the runtime gets to run it)
+        drop(forwards);
+        tokio::task::yield_now().await;
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", host_port))
+            .await
+            .is_err());
+    }
+}
@@ -5,8 +5,10 @@ DEBIAN_RELEASE="bookworm"
 DEBIAN_VERSION="12"
 ARCH="arm64"
 DATA_DIR="${HOME}/.local/share/shuru"
-ROOTFS_IMG="${DATA_DIR}/rootfs-debian.ext4"
+IMAGE_DIR="${DATA_DIR}/images/debian-${DEBIAN_VERSION}@local"
+ROOTFS_IMG="${IMAGE_DIR}/rootfs.ext4"
 GUEST_BINARY="target/aarch64-unknown-linux-gnu/release/shuru-guest"
+SERVICE_DIR="scripts/guest"
 ROOTFS_SIZE_MB=2048
 
 EXTRA_PACKAGES="libgomp1,zsh,xz-utils,less,git,openssh-client,jq,file"
@@ -34,7 +36,21 @@ if [ ! -f "$GUEST_BINARY" ]; then
     exit 1
 fi
 
-mkdir -p "$DATA_DIR"
+# The Debian image reuses the kernel and initramfs of an installed Alpine
+# image (run `shuru init` or ./scripts/prepare-rootfs.sh first).
+KERNEL_SOURCE_DIR=""
+for dir in "${DATA_DIR}"/images/alpine@*/; do
+    if [ -f "${dir}Image" ] && [ -f "${dir}initramfs.cpio.gz" ]; then
+        KERNEL_SOURCE_DIR="$dir"
+    fi
+done
+if [ -z "$KERNEL_SOURCE_DIR" ]; then
+    echo "ERROR: No Alpine image found to take the kernel and initramfs from."
+    echo "       Run: shuru init"
+    exit 1
+fi
+
+mkdir -p "$DATA_DIR" "$IMAGE_DIR"
 
 # --- Create Debian base rootfs using debootstrap ---
 DEBIAN_ROOTFS_TAR="${DATA_DIR}/debian-${DEBIAN_RELEASE}-${ARCH}.tar.gz"
@@ -53,7 +69,7 @@ if [ ! -f "$DEBIAN_ROOTFS_TAR" ]; then
             
             echo 'Running debootstrap...'
             debootstrap --arch=arm64 --variant=minbase \
-                --include=kmod,udev,iproute2,iputils-ping,ca-certificates,${EXTRA_PACKAGES} \
+                --include=kmod,udev,systemd,systemd-sysv,iproute2,iputils-ping,ca-certificates,e2fsprogs,${EXTRA_PACKAGES} \
                 ${DEBIAN_RELEASE} /target ${DEBIAN_MIRROR}
             
             echo 'Creating tarball...'
@@ -80,6 +96,7 @@ echo ""
 DOCKER_WORKDIR=$(mktemp -d)
 cp "$DEBIAN_ROOTFS_TAR" "${DOCKER_WORKDIR}/rootfs.tar.gz"
 cp "$GUEST_BINARY" "${DOCKER_WORKDIR}/shuru-guest"
+cp "${SERVICE_DIR}/shuru-guest.service" "${DOCKER_WORKDIR}/shuru-guest.service"
 
 # Format + populate entirely inside Docker
 container run --rm \
@@ -115,11 +132,13 @@ container run --rm \
         echo "nameserver 8.8.8.8" > /mnt/rootfs/etc/resolv.conf
         echo "nameserver 8.8.4.4" >> /mnt/rootfs/etc/resolv.conf
         
-        # Prevent systemd from interfering (if it exists)
-        if [ -f /mnt/rootfs/bin/systemctl ]; then
-            rm -f /mnt/rootfs/bin/systemctl
-            rm -f /mnt/rootfs/sbin/init
-        fi
+        # systemd unit for shuru.init=system boots; the console already
+        # carries boot output, so no login prompt on it
+        cp /workdir/shuru-guest.service /mnt/rootfs/etc/systemd/system/
+        mkdir -p /mnt/rootfs/etc/systemd/system/multi-user.target.wants
+        ln -sf /etc/systemd/system/shuru-guest.service \
+            /mnt/rootfs/etc/systemd/system/multi-user.target.wants/shuru-guest.service
+        ln -sf /dev/null /mnt/rootfs/etc/systemd/system/serial-getty@hvc0.service
         
         # Set proper permissions
         chmod 1777 /mnt/rootfs/tmp /mnt/rootfs/var/tmp
@@ -130,12 +149,26 @@ container run --rm \
 
 rm -rf "$DOCKER_WORKDIR"
 
+# --- Register in the shuru image catalog ---
+cp "${KERNEL_SOURCE_DIR}Image" "${KERNEL_SOURCE_DIR}initramfs.cpio.gz" "$IMAGE_DIR/"
+cat > "${IMAGE_DIR}/manifest.json" << EOF
+{
+  "name": "debian-${DEBIAN_VERSION}",
+  "version": "local",
+  "kernel": "Image",
+  "initrd": "initramfs.cpio.gz",
+  "rootfs": "rootfs.ext4"
+}
+EOF
+
 echo ""
 echo "==> Done!"
+echo "    Image:      debian-${DEBIAN_VERSION}@local"
 echo "    Rootfs:     ${ROOTFS_IMG}"
 echo ""
 echo "    IMPORTANT: Build guest binary with glibc target:"
 echo "               cargo build -p shuru-guest --target aarch64-unknown-linux-gnu --release"
 echo ""
 echo "    To run:    cargo build -p shuru-cli && codesign --entitlements shuru.entitlements --force -s - target/debug/shuru"
-echo "               ./target/debug/shuru run -- echo hello"
+echo "               ./target/debug/shuru run --image debian-${DEBIAN_VERSION}@local -- echo hello"
+echo "    With systemd: ./target/debug/shuru run --image debian-${DEBIAN_VERSION}@local --init-system -- systemctl is-system-running"
@@ -5,10 +5,12 @@ ALPINE_VERSION="3.21"
 ALPINE_RELEASE="3.21.3"
 ARCH="aarch64"
 DATA_DIR="${HOME}/.local/share/shuru"
-ROOTFS_IMG="${DATA_DIR}/rootfs.ext4"
-KERNEL_PATH="${DATA_DIR}/Image"
-INITRAMFS_PATH="${DATA_DIR}/initramfs.cpio.gz"
+IMAGE_DIR="${DATA_DIR}/images/alpine@local"
+ROOTFS_IMG="${IMAGE_DIR}/rootfs.ext4"
+KERNEL_PATH="${IMAGE_DIR}/Image"
+INITRAMFS_PATH="${IMAGE_DIR}/initramfs.cpio.gz"
 GUEST_BINARY="target/aarch64-unknown-linux-musl/release/shuru-guest"
+SERVICE_DIR="scripts/guest"
 ROOTFS_SIZE_MB=512
 
 ALPINE_MIRROR="https://dl-cdn.alpinelinux.org/alpine"
@@ -42,7 +44,7 @@ if [ ! -f "$GUEST_BINARY" ]; then
     exit 1
 fi
 
-mkdir -p "$DATA_DIR"
+mkdir -p "$DATA_DIR" "$IMAGE_DIR"
 
 # --- Download Alpine minirootfs ---
 MINIROOTFS_TAR="${DATA_DIR}/alpine-minirootfs-${ALPINE_RELEASE}-${ARCH}.tar.gz"
@@ -141,7 +143,7 @@ DHCPEOF
 
     docker run --rm \
         --platform linux/arm64/v8 \
-        -v "${DATA_DIR}:/output" \
+        -v "${IMAGE_DIR}:/output" \
         -v "${UDHCPC_SCRIPT}:/tmp/udhcpc.sh:ro" \
         alpine:3.21 /bin/sh -c '
             set -e
@@ -268,10 +270,24 @@ if ifconfig eth0 up 2>/dev/null; then
     fi
 fi
 
+# Full-OS boot (shuru.init=system): hand off to the rootfs init, which
+# starts shuru-guest as a service. /sbin/init is usually an absolute
+# symlink, so test the link itself.
+TARGET_INIT=/usr/bin/shuru-init
+case " $(cat /proc/cmdline) " in
+*" shuru.init=system "*)
+    if [ -L /newroot/sbin/init ] || [ -x /newroot/sbin/init ]; then
+        TARGET_INIT=/sbin/init
+    else
+        echo "initramfs: no /sbin/init on root, starting shuru-init as PID 1"
+    fi
+    ;;
+esac
+
 /bin/umount /proc
 /bin/umount /sys
 /bin/umount /dev
-exec /bin/switch_root /newroot /usr/bin/shuru-init
+exec /bin/switch_root /newroot "$TARGET_INIT"
 INITEOF
             chmod 755 /initramfs/init
 
@@ -300,6 +316,7 @@ if [[ "$(uname)" == "Darwin" ]]; then
     DOCKER_WORKDIR=$(mktemp -d)
     cp "$MINIROOTFS_TAR" "${DOCKER_WORKDIR}/rootfs.tar.gz"
     cp "$GUEST_BINARY" "${DOCKER_WORKDIR}/shuru-guest"
+    cp "${SERVICE_DIR}/shuru-guest.openrc" "${DOCKER_WORKDIR}/shuru-guest.openrc"
 
     # Format + populate entirely inside Docker
     docker run --rm --privileged \
@@ -318,7 +335,12 @@ if [[ "$(uname)" == "Darwin" ]]; then
             mkdir -p /mnt/rootfs/proc /mnt/rootfs/sys /mnt/rootfs/dev /mnt/rootfs/tmp /mnt/rootfs/run
             echo "shuru" > /mnt/rootfs/etc/hostname
             echo "nameserver 8.8.8.8" > /mnt/rootfs/etc/resolv.conf
-            apk add --no-cache --root /mnt/rootfs gcompat > /dev/null 2>&1
+            apk add --no-cache --root /mnt/rootfs gcompat openrc e2fsprogs e2fsprogs-extra > /dev/null 2>&1
+            # OpenRC service for shuru.init=system boots
+            install -m 755 /workdir/shuru-guest.openrc /mnt/rootfs/etc/init.d/shuru-guest
+            for svc in sysinit/devfs boot/hostname boot/loopback default/shuru-guest; do
+                ln -sf "/etc/init.d/${svc#*/}" "/mnt/rootfs/etc/runlevels/${svc}"
+            done
             umount /mnt/rootfs
             echo "==> Rootfs populated successfully"
         '
@@ -338,11 +360,27 @@ else
     sudo mkdir -p "${MOUNT_DIR}/proc" "${MOUNT_DIR}/sys" "${MOUNT_DIR}/dev" "${MOUNT_DIR}/tmp" "${MOUNT_DIR}/run"
     echo "shuru" | sudo tee "${MOUNT_DIR}/etc/hostname" > /dev/null
     echo "nameserver 8.8.8.8" | sudo tee "${MOUNT_DIR}/etc/resolv.conf" > /dev/null
-    sudo apk add --no-cache --root "$MOUNT_DIR" gcompat > /dev/null 2>&1
+    sudo apk add --no-cache --root "$MOUNT_DIR" gcompat openrc e2fsprogs e2fsprogs-extra > /dev/null 2>&1
+    # OpenRC service for shuru.init=system boots
+    sudo install -m 755 "${SERVICE_DIR}/shuru-guest.openrc" "${MOUNT_DIR}/etc/init.d/shuru-guest"
+    for svc in sysinit/devfs boot/hostname boot/loopback default/shuru-guest; do
+        sudo ln -sf "/etc/init.d/${svc#*/}" "${MOUNT_DIR}/etc/runlevels/${svc}"
+    done
     sudo umount "$MOUNT_DIR"
     rmdir "$MOUNT_DIR" 2>/dev/null || true
 fi
 
+# --- Register in the shuru image catalog ---
+cat > "${IMAGE_DIR}/manifest.json" << EOF
+{
+  "name": "alpine",
+  "version": "local",
+  "kernel": "Image",
+  "initrd": "initramfs.cpio.gz",
+  "rootfs": "rootfs.ext4"
+}
+EOF
+
 echo ""
 echo "==> Done!"
 echo "    Kernel:     ${KERNEL_PATH}"
@@ -350,4 +388,4 @@ echo "    Initramfs:  ${INITRAMFS_PATH}"
 echo "    Rootfs:     ${ROOTFS_IMG}"
 echo ""
 echo "    To run:  cargo build -p shuru-cli && codesign --entitlements shuru.entitlements --force -s - target/debug/shuru"
-echo "             ./target/debug/shuru run -- echo hello"
+echo "             ./target/debug/shuru run --image alpine@local -- echo hello"